@@ -0,0 +1,511 @@
+// GDB Remote Serial Protocol stub, turned on with --gdb. It talks over COM2 unless --gdb_com=N picks another port,
+// since COM1 already has the kernel log on it. Point gdb at it with "target remote" on QEMU's serial socket.
+//
+// Everything here runs inside an exception with interrupts off, and whatever got stopped might be holding the
+// allocator or any other lock, so the stub sticks to static buffers and its own polled UART access.
+use core::sync::atomic::{AtomicBool, AtomicU16, AtomicU32, AtomicUsize, Ordering};
+use x86_64::instructions::port::{PortRead, PortWrite};
+use crate::arch::{APIC, GDT, CurrentHart};
+use crate::arch::Task::State;
+use spin::Mutex;
+
+const COM_PORTS: [u16; 4] = [0x3F8, 0x2F8, 0x3E8, 0x2E8];
+const BUFFER_SIZE: usize = 4096;
+const MAX_BREAKPOINTS: usize = 64;
+const TRAP_FLAG: u64 = 1 << 8;
+const INTERRUPT_FLAG: u64 = 1 << 9;
+
+// Signal numbers as gdb knows them
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+const SIGFPE: u8 = 8;
+const SIGSEGV: u8 = 11;
+
+static PORT: AtomicU16 = AtomicU16::new(0); // Zero while the stub is off
+static OWNER: AtomicU32 = AtomicU32::new(u32::MAX); // The hart that's talking to gdb
+static HALT: AtomicBool = AtomicBool::new(false); // Set while every other hart should be parked
+static PARKED: AtomicUsize = AtomicUsize::new(0);
+static INTERRUPTED: AtomicBool = AtomicBool::new(false); // gdb sent ^C, so the next stop gets reported as SIGINT
+static STEPPING: AtomicBool = AtomicBool::new(false);
+static STEP_IF: AtomicBool = AtomicBool::new(false); // Interrupts stay off while stepping, this is what to put back
+
+static BREAKPOINTS: Mutex<[(u64, u8); MAX_BREAKPOINTS]> = Mutex::new([(0, 0); MAX_BREAKPOINTS]); // Address and the byte int3 replaced
+
+static mut INPUT: [u8; BUFFER_SIZE] = [0; BUFFER_SIZE];
+static mut OUTPUT: Reply = Reply {buf: [0; BUFFER_SIZE], len: 0};
+
+struct Reply {
+    buf: [u8; BUFFER_SIZE],
+    len: usize,
+}
+
+impl Reply {
+    fn Clear(&mut self) {
+        self.len = 0;
+    }
+    fn Push(&mut self, s: &[u8]) {
+        for &b in s {
+            if self.len < BUFFER_SIZE {
+                self.buf[self.len] = b;
+                self.len += 1;
+            }
+        }
+    }
+    fn Hex(&mut self, b: u8) {
+        const DIGITS: &[u8; 16] = b"0123456789abcdef";
+        self.Push(&[DIGITS[(b >> 4) as usize], DIGITS[(b & 0xF) as usize]]);
+    }
+    // Registers go over the wire in target byte order, which is little endian
+    fn HexLE(&mut self, val: u64, size: usize) {
+        for i in 0..size {
+            self.Hex((val >> (i * 8)) as u8);
+        }
+    }
+}
+
+pub fn Port() -> Option<u16> {
+    match PORT.load(Ordering::SeqCst) {
+        0 => None,
+        port => Some(port),
+    }
+}
+
+fn ReadByte(port: u16) -> u8 {
+    unsafe {
+        while u8::read_from_port(port+5) & 0x01 == 0 {core::hint::spin_loop()}
+        u8::read_from_port(port)
+    }
+}
+
+fn WriteByte(port: u16, b: u8) {
+    unsafe {
+        while u8::read_from_port(port+5) & 0x20 == 0 {core::hint::spin_loop()}
+        u8::write_to_port(port,b);
+    }
+}
+
+fn FromHex(c: u8) -> Option<u8> {
+    match c {
+        b'0'..=b'9' => Some(c - b'0'),
+        b'a'..=b'f' => Some(c - b'a' + 10),
+        b'A'..=b'F' => Some(c - b'A' + 10),
+        _ => None,
+    }
+}
+
+// Parses hex until the first character that isn't, and hands back the rest
+fn ParseHex(s: &[u8]) -> (u64, &[u8]) {
+    let mut val: u64 = 0;
+    let mut i = 0;
+    while i < s.len() {
+        match FromHex(s[i]) {
+            Some(d) => val = (val << 4) | d as u64,
+            None => break,
+        }
+        i += 1;
+    }
+    (val, &s[i..])
+}
+
+fn ParseHexLE(s: &[u8], size: usize) -> Option<u64> {
+    if s.len() < size * 2 {
+        return None;
+    }
+    let mut val: u64 = 0;
+    for i in 0..size {
+        let b = (FromHex(s[i*2])? << 4) | FromHex(s[i*2+1])?;
+        val |= (b as u64) << (i * 8);
+    }
+    Some(val)
+}
+
+// Waits for a whole "$packet#xx" and returns how long it is. Bad checksums get a '-' so gdb sends it again.
+fn ReceivePacket(port: u16) -> usize {
+    loop {
+        while ReadByte(port) != b'$' {}
+        let mut len = 0;
+        let mut sum: u8 = 0;
+        loop {
+            let b = ReadByte(port);
+            if b == b'#' {
+                break;
+            }
+            if b == b'$' { // gdb gave up on that one and started over
+                len = 0;
+                sum = 0;
+                continue;
+            }
+            if len < BUFFER_SIZE {
+                unsafe {INPUT[len] = b;}
+                len += 1;
+            }
+            sum = sum.wrapping_add(b);
+        }
+        let expected = (FromHex(ReadByte(port)).unwrap_or(0) << 4) | FromHex(ReadByte(port)).unwrap_or(0);
+        if expected == sum {
+            WriteByte(port,b'+');
+            return len;
+        }
+        WriteByte(port,b'-');
+    }
+}
+
+fn SendPacket(port: u16, data: &[u8]) {
+    let sum = data.iter().fold(0u8,|acc, &b| acc.wrapping_add(b));
+    loop {
+        WriteByte(port,b'$');
+        for &b in data {
+            WriteByte(port,b);
+        }
+        WriteByte(port,b'#');
+        const DIGITS: &[u8; 16] = b"0123456789abcdef";
+        WriteByte(port,DIGITS[(sum >> 4) as usize]);
+        WriteByte(port,DIGITS[(sum & 0xF) as usize]);
+        match ReadByte(port) {
+            b'+' => return,
+            b'-' => continue,
+            _ => return, // Not worth getting stuck over, gdb will ask again if it missed it
+        }
+    }
+}
+
+// Checking first means gdb poking at a bad address doesn't page fault in here
+fn ReadMemory(addr: u64) -> Option<u8> {
+    if !crate::arch::Memory::IsMapped(addr) {
+        return None;
+    }
+    Some(unsafe {core::ptr::read_volatile(addr as *const u8)})
+}
+
+// Kernel text is mapped read only, so patching in breakpoints means switching off write protection for a moment
+fn WriteMemory(addr: u64, val: u8) -> bool {
+    if !crate::arch::Memory::IsMapped(addr) {
+        return false;
+    }
+    unsafe {
+        use x86_64::registers::control::{Cr0, Cr0Flags};
+        let cr0 = Cr0::read();
+        Cr0::write(cr0 - Cr0Flags::WRITE_PROTECT);
+        core::ptr::write_volatile(addr as *mut u8,val);
+        Cr0::write(cr0);
+    }
+    true
+}
+
+// The order the registers come in for "g" and "G", up to gs. The segment registers and eflags are 4 bytes each.
+fn Register(regs: &mut State, n: usize) -> Option<(&mut u64, usize)> {
+    Some(match n {
+        0 => (&mut regs.rax, 8),
+        1 => (&mut regs.rbx, 8),
+        2 => (&mut regs.rcx, 8),
+        3 => (&mut regs.rdx, 8),
+        4 => (&mut regs.rsi, 8),
+        5 => (&mut regs.rdi, 8),
+        6 => (&mut regs.rbp, 8),
+        7 => (&mut regs.rsp, 8),
+        8 => (&mut regs.r8, 8),
+        9 => (&mut regs.r9, 8),
+        10 => (&mut regs.r10, 8),
+        11 => (&mut regs.r11, 8),
+        12 => (&mut regs.r12, 8),
+        13 => (&mut regs.r13, 8),
+        14 => (&mut regs.r14, 8),
+        15 => (&mut regs.r15, 8),
+        16 => (&mut regs.rip, 8),
+        17 => (&mut regs.rflags, 4),
+        18 => (&mut regs.cs, 4),
+        19 => (&mut regs.ss, 4),
+        _ => return None,
+    })
+}
+const REGISTER_COUNT: usize = 24; // ds, es, fs, and gs always read as zero
+
+fn Signal(index: u64) -> u8 {
+    match index {
+        0x00 | 0x10 | 0x13 => SIGFPE,
+        0x01 | 0x03 => SIGTRAP,
+        0x06 => SIGILL,
+        _ => SIGSEGV,
+    }
+}
+
+fn StopReply(out: &mut Reply, sig: u8, swbreak: bool) {
+    out.Clear();
+    out.Push(b"T");
+    out.Hex(sig);
+    if swbreak {
+        out.Push(b"swbreak:;");
+    }
+    out.Push(b"thread:1;");
+}
+
+fn OtherHarts() -> usize {
+    unsafe {(*core::ptr::addr_of!(GDT::HARTS)).iter().filter(|h| h.is_some()).count().saturating_sub(1)}
+}
+
+// Sends everyone else into Park, and waits a bit for them to show up. A hart that's wedged with NMIs blocked
+// won't ever arrive, so this gives up instead of hanging the debugger too.
+fn StopOthers() {
+    HALT.store(true,Ordering::SeqCst);
+    let others = OtherHarts();
+    if others == 0 || !APIC::LAPIC_READY.load(Ordering::SeqCst) || crate::CommandLine::FLAGS.get().map_or(true,|f| f.contains("--nosmp")) {
+        return;
+    }
+    APIC::SendIPI(CurrentHart() as u8,APIC::ICR_DSH_OTHER,APIC::ICR_MESSAGE_TYPE_NMI,0);
+    let end = crate::arch::Timer::GetMicroseconds() + 100000;
+    while PARKED.load(Ordering::SeqCst) < others && crate::arch::Timer::GetMicroseconds() < end {
+        core::hint::spin_loop();
+    }
+}
+
+// Called from the NMI handler. Returns false if the NMI wasn't the debugger's, which means the kernel is going down.
+pub fn Park() -> bool {
+    if !HALT.load(Ordering::SeqCst) || OWNER.load(Ordering::SeqCst) == CurrentHart() {
+        return false;
+    }
+    PARKED.fetch_add(1,Ordering::SeqCst);
+    while HALT.load(Ordering::SeqCst) {
+        core::hint::spin_loop();
+    }
+    PARKED.fetch_sub(1,Ordering::SeqCst);
+    true
+}
+
+// Hands an exception to gdb. Returns true once gdb lets the kernel carry on, false if the stub isn't running.
+pub fn Trap(index: u64, regs: &mut State) -> bool {
+    let port = match Port() {
+        Some(port) => port,
+        None => return false,
+    };
+    let hart = CurrentHart();
+    // Two harts can hit breakpoints at once, the loser gets parked by the winner and comes back here afterwards
+    while OWNER.compare_exchange(u32::MAX,hart,Ordering::SeqCst,Ordering::SeqCst).is_err() {
+        core::hint::spin_loop();
+    }
+    StopOthers();
+
+    let mut sig = Signal(index);
+    let mut swbreak = false;
+    if index == 0x01 && STEPPING.swap(false,Ordering::SeqCst) {
+        regs.rflags &= !TRAP_FLAG;
+        if STEP_IF.load(Ordering::SeqCst) {
+            regs.rflags |= INTERRUPT_FLAG;
+        }
+    } else if index == 0x03 {
+        // Our own int3s leave rip just past them, gdb wants to see the breakpoint's address
+        let addr = regs.rip.wrapping_sub(1);
+        if BREAKPOINTS.lock().iter().any(|&(a, _)| a == addr && a != 0) {
+            regs.rip = addr;
+            swbreak = true;
+        }
+    }
+    if INTERRUPTED.swap(false,Ordering::SeqCst) {
+        sig = SIGINT;
+    }
+
+    let out = unsafe {&mut *core::ptr::addr_of_mut!(OUTPUT)};
+    StopReply(out,sig,swbreak);
+    SendPacket(port,&out.buf[..out.len]);
+    loop {
+        let len = ReceivePacket(port);
+        let packet = unsafe {&INPUT[..len]};
+        out.Clear();
+        if packet.is_empty() {
+            SendPacket(port,b"");
+            continue;
+        }
+        let args = &packet[1..];
+        match packet[0] {
+            b'?' => StopReply(out,sig,swbreak),
+            b'g' => {
+                for n in 0..REGISTER_COUNT {
+                    match Register(regs,n) {
+                        Some((val, size)) => {let val = *val; out.HexLE(val,size)}
+                        None => out.HexLE(0,4),
+                    }
+                }
+            }
+            b'G' => {
+                let mut rest = args;
+                for n in 0..20 {
+                    let (reg, size) = Register(regs,n).unwrap();
+                    match ParseHexLE(rest,size) {
+                        Some(val) => *reg = val,
+                        None => break,
+                    }
+                    rest = &rest[size*2..];
+                }
+                out.Push(b"OK");
+            }
+            b'p' => {
+                let (n, _) = ParseHex(args);
+                match Register(regs,n as usize) {
+                    Some((val, size)) => {let val = *val; out.HexLE(val,size)}
+                    None if (n as usize) < REGISTER_COUNT => out.HexLE(0,4),
+                    None => out.Push(b"E01"),
+                }
+            }
+            b'P' => {
+                let (n, rest) = ParseHex(args);
+                match (Register(regs,n as usize), rest.split_first()) {
+                    (Some((reg, size)), Some((b'=', hex))) => {
+                        match ParseHexLE(hex,size) {
+                            Some(val) => {*reg = val; out.Push(b"OK")}
+                            None => out.Push(b"E01"),
+                        }
+                    }
+                    (None, _) if (n as usize) < REGISTER_COUNT => out.Push(b"OK"), // Segment registers we don't track
+                    _ => out.Push(b"E01"),
+                }
+            }
+            b'm' => {
+                let (addr, rest) = ParseHex(args);
+                let (len, _) = ParseHex(rest.get(1..).unwrap_or(&[]));
+                let len = (len as usize).min((BUFFER_SIZE - 4) / 2);
+                for i in 0..len as u64 {
+                    match ReadMemory(addr.wrapping_add(i)) {
+                        Some(b) => out.Hex(b),
+                        None => break,
+                    }
+                }
+                if out.len == 0 && len > 0 {
+                    out.Push(b"E14"); // EFAULT
+                }
+            }
+            b'M' => {
+                let (addr, rest) = ParseHex(args);
+                let (len, rest) = ParseHex(rest.get(1..).unwrap_or(&[]));
+                let data = rest.get(1..).unwrap_or(&[]);
+                let mut ok = data.len() >= len as usize * 2;
+                for i in 0..len as usize {
+                    if !ok {
+                        break;
+                    }
+                    ok = match ParseHexLE(&data[i*2..],1) {
+                        Some(b) => WriteMemory(addr.wrapping_add(i as u64),b as u8),
+                        None => false,
+                    };
+                }
+                out.Push(if ok {b"OK"} else {b"E14"});
+            }
+            b'Z' | b'z' if args.first() == Some(&b'0') => {
+                let (addr, _) = ParseHex(args.get(2..).unwrap_or(&[]));
+                let mut bps = BREAKPOINTS.lock();
+                let ok = if packet[0] == b'Z' {
+                    if bps.iter().any(|&(a, _)| a == addr) {
+                        true
+                    } else if let (Some(slot), Some(orig)) = (bps.iter_mut().find(|(a, _)| *a == 0), ReadMemory(addr)) {
+                        *slot = (addr, orig);
+                        WriteMemory(addr,0xCC)
+                    } else {
+                        false
+                    }
+                } else {
+                    match bps.iter_mut().find(|(a, _)| *a == addr && addr != 0) {
+                        Some(slot) => {
+                            let orig = slot.1;
+                            *slot = (0, 0);
+                            WriteMemory(addr,orig)
+                        }
+                        None => true,
+                    }
+                };
+                drop(bps);
+                out.Push(if ok {b"OK"} else {b"E22"});
+            }
+            b'c' | b's' => {
+                if !args.is_empty() {
+                    regs.rip = ParseHex(args).0;
+                }
+                if packet[0] == b's' {
+                    // Interrupts would take the step somewhere else entirely
+                    STEP_IF.store(regs.rflags & INTERRUPT_FLAG != 0,Ordering::SeqCst);
+                    regs.rflags = (regs.rflags | TRAP_FLAG) & !INTERRUPT_FLAG;
+                    STEPPING.store(true,Ordering::SeqCst);
+                }
+                break;
+            }
+            b'D' => {
+                let mut bps = BREAKPOINTS.lock();
+                for slot in bps.iter_mut().filter(|(a, _)| *a != 0) {
+                    WriteMemory(slot.0,slot.1);
+                    *slot = (0, 0);
+                }
+                drop(bps);
+                SendPacket(port,b"OK");
+                break;
+            }
+            b'k' => break, // There's nothing to kill, so just let the kernel go
+            b'H' | b'T' => out.Push(b"OK"),
+            b'q' => {
+                if args.starts_with(b"Supported") {
+                    out.Push(b"PacketSize=1000;swbreak+");
+                } else if args.starts_with(b"Attached") {
+                    out.Push(b"1");
+                } else if args.starts_with(b"C") {
+                    out.Push(b"QC1");
+                } else if args.starts_with(b"fThreadInfo") {
+                    out.Push(b"m1");
+                } else if args.starts_with(b"sThreadInfo") {
+                    out.Push(b"l");
+                }
+            }
+            _ => {} // Anything else gets the empty reply, which means we don't do that
+        }
+        SendPacket(port,&out.buf[..out.len]);
+    }
+
+    HALT.store(false,Ordering::SeqCst);
+    OWNER.store(u32::MAX,Ordering::SeqCst);
+    true
+}
+
+// gdb sends a lone ^C when the user wants the kernel stopped. The serial interrupt handler checks for it here.
+pub fn Interrupt() {
+    let port = match Port() {
+        Some(port) => port,
+        None => return,
+    };
+    let mut stop = false;
+    unsafe {
+        while u8::read_from_port(port+5) & 0x01 != 0 {
+            if u8::read_from_port(port) == 0x03 {
+                stop = true;
+            }
+        }
+    }
+    if stop && OWNER.load(Ordering::SeqCst) == u32::MAX {
+        INTERRUPTED.store(true,Ordering::SeqCst);
+        unsafe {core::arch::asm!("int3");}
+    }
+}
+
+pub fn Initalize() {
+    let flags = match crate::CommandLine::FLAGS.get() {
+        Some(flags) => flags,
+        None => return,
+    };
+    if !flags.contains("--gdb") {
+        return;
+    }
+    let com = crate::CommandLine::OPTIONS.get().and_then(|o| o.get("--gdb_com")).and_then(|c| c.parse::<usize>().ok()).unwrap_or(2);
+    if com < 1 || com > 4 {
+        log::error!("--gdb_com={} isn't a COM port, the debugger stays off", com);
+        return;
+    }
+    let port = COM_PORTS[com-1];
+    unsafe {
+        u8::write_to_port(port+1,0x00);    // Disable all interrupts
+        u8::write_to_port(port+3,0x80);    // Enable DLAB (set baud rate divisor)
+        u8::write_to_port(port+0,0x01);    // Set divisor to 1 (lo byte) 115200 baud
+        u8::write_to_port(port+1,0x00);    //                  (hi byte)
+        u8::write_to_port(port+3,0x03);    // 8 bits, no parity, one stop bit
+        u8::write_to_port(port+2,0xC7);    // Enable FIFO, clear them, with 14-byte threshold
+        u8::write_to_port(port+4,0x0B);    // DTR, RTS, and OUT2 so ^C can raise an interrupt
+        u8::write_to_port(port+1,0x01);    // Received data interrupt
+    }
+    PORT.store(port,Ordering::SeqCst);
+    log::info!("Waiting for GDB on COM{}", com);
+    unsafe {core::arch::asm!("int3");}
+}
@@ -48,16 +48,24 @@ static ExceptionMessages: [&str; 32] = [
 ];
 
 pub static IRQ_HANDLERS: Mutex<[Option<fn()>; 0xE0]> = Mutex::new([None; 0xE0]);
+// The kernel fault that's about to become a panic, so the panic screen can show what the registers were
+pub static FAULT: Mutex<Option<(&'static str, State, u64)>> = Mutex::new(None);
 
 #[no_mangle]
 extern "C" fn x86Fault(
     index: u64,
-    regs: &State,
+    regs: &mut State,
 ) {
     if index == 0x02 {
+        if crate::arch::GDB::Park() {
+            return;
+        }
         halt!();
         loop {};
     }
+    if (index == 0x01 || index == 0x03) && regs.cs != 0x43 && crate::arch::GDB::Trap(index,regs) {
+        return;
+    }
     let cr2 = x86_64::registers::control::Cr2::read().as_u64();
     if index == 0x0e {
         if (0x7f8000000000..0x800000000000).contains(&cr2) {
@@ -84,28 +92,10 @@ extern "C" fn x86Fault(
         crate::Process::Process::SendSignal(pid,crate::Process::Signals::SIGSEGV);
         crate::Scheduler::Scheduler::Tick(CurrentHart(),regs);
     } else {
-        if index != 14 && unsafe {crate::Console::QUIET} {
-            // Page Dump
-            let ptr = regs.rip & (!0xFFF);
-            let location = regs.rip & 0xFFF;
-            let mut lock = crate::Framebuffer::MainFramebuffer.lock();
-            let width = (*lock).as_ref().unwrap().width;
-            let height = (*lock).as_ref().unwrap().height;
-            for i in 0..4096 {
-                if location == i as u64 {
-                    let color = unsafe {*((ptr as *const u8).offset(i))} as u32;
-                    if color > 127 {
-                        (*lock).as_mut().unwrap().DrawPixel((width-128)+(i as usize)%128,height-(i as usize/128),color<<16);
-                    } else {
-                        (*lock).as_mut().unwrap().DrawPixel((width-128)+(i as usize)%128,height-(i as usize/128),(color+128)<<8);
-                    }
-                } else {
-                    let color = unsafe {*((ptr as *const u8).offset(i))} as u32;
-                    (*lock).as_mut().unwrap().DrawPixel((width-128)+(i as usize)%128,height-(i as usize/128),(color<<16)|(color<<8)|color);
-                }
-            }
-            drop(lock);
-        }
+        // Let gdb have a look before going down, if it's attached
+        crate::arch::GDB::Trap(index,regs);
+        crate::Backtrace::RecordFault(regs.rip,regs.rbp);
+        *FAULT.lock() = Some((ExceptionMessages[index as usize],*regs,cr2));
         if index == 0xE {
             panic!("Unhandled AMD64 Fault: {}\nCR2=0x{:016x}\n{:?}", ExceptionMessages[index as usize], x86_64::registers::control::Cr2::read().as_u64(), regs);
         } else {
@@ -19,6 +19,24 @@ fn GetStartPageTable() -> *mut HWPageTable {
     (*Startup_PageTable.lock()).unwrap() as *mut HWPageTable
 }
 
+// Walks whatever page table is loaded right now without taking any locks, so panics and the debugger can check
+// an address before touching it
+pub fn IsMapped(addr: u64) -> bool {
+    let mut table = x86_64::registers::control::Cr3::read().0.start_address().as_u64();
+    for level in (0..4).rev() {
+        let index = (addr >> (12 + level * 9)) & 0x1FF;
+        let entry = unsafe {*((table + PHYSMEM_BEGIN + index * 8) as *const u64)};
+        if entry & 0x1 == 0 {
+            return false;
+        }
+        if level > 0 && level < 3 && entry & 0x80 != 0 { // 1 GiB or 2 MiB page
+            return true;
+        }
+        table = entry & 0x000F_FFFF_FFFF_F000;
+    }
+    true
+}
+
 pub fn AnalyzeMMAP() {
     let mmap = unsafe {crate::arch::MMAP.get_response().get()}.unwrap().mmap().unwrap();
     *Startup_PageTable.lock() = Some(x86_64::registers::control::Cr3::read().0.start_address().as_u64()+PHYSMEM_BEGIN);
@@ -79,6 +97,7 @@ pub fn AnalyzeMMAP() {
             crate::PageFrame::TotalMem.fetch_add(i.len,core::sync::atomic::Ordering::SeqCst);
         }
     }
+    crate::CrashLog::Reserve(&mut array,&mut array_index);
     Setup(array);
     let mut pt = crate::PageFrame::KernelPageTable.lock();
     unsafe {pt.page_table.index_mut(0).set_addr((*pml4).index(256).addr(),(*pml4).index(256).flags());}
@@ -5,7 +5,7 @@ use crate::Memory::PageTable;
 use crate::Process::{TaskState,TaskFloatState};
 
 #[repr(C, align(8))]
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub struct State {
     pub r15: u64,
     pub r14: u64,
@@ -141,6 +141,9 @@ impl TaskState for State {
     fn Exit(&self) {
         unsafe { crate::PageFrame::KernelPageTable.lock().Switch(); }
     }
+    fn IsKernel(&self) -> bool {
+        self.cs & 3 == 0
+    }
 }
 
 #[repr(align(16))]
@@ -51,7 +51,15 @@ fn GetTSCFreq() {
     log::debug!("UNIX Epoch timestamp on kernel startup is {}", unsafe {crate::UNIX_EPOCH});
 }
 
+// Microseconds since the TSC was calibrated
+pub fn GetMicroseconds() -> u64 {
+    if unsafe {TSC_FREQ} == 0 {
+        return 0; // The log asks before calibration
+    }
+    unsafe {(core::arch::x86_64::_rdtsc() / TSC_FREQ) - TSC_INITIAL}
+}
+
 pub fn GetTimeStamp() -> (i64,i64) {
-    let tsc = unsafe {(core::arch::x86_64::_rdtsc() / TSC_FREQ) - TSC_INITIAL};
+    let tsc = GetMicroseconds();
     return (unsafe {crate::UNIX_EPOCH+(tsc / 1000000)} as i64,((tsc % 1000000) * 1000) as i64);
 }
\ No newline at end of file
@@ -9,6 +9,7 @@ pub mod ACPI;
 pub mod Timer;
 pub mod Task;
 pub mod Syscall;
+pub mod GDB;
 
 extern crate limine;
 extern crate x86_64;
@@ -92,7 +93,7 @@ extern "C" fn _start(stack_top: u64) {
 	unsafe { IDT::Setup(); }
 	Syscall::Initialize();
 	Task::SetupFPU();
-	Memory::AnalyzeMMAP();
+	// The command line goes first, it can ask for memory to be kept away from the page frame allocator
 	if unsafe {KERNEL_FILE.get_response().get()}.is_some() {
 		let cmdstr = String::from(unsafe {KERNEL_FILE.get_response().get().unwrap().kernel_file.get()}.unwrap().cmdline.to_string().unwrap());
 		if cmdstr.len() == 0 {
@@ -101,9 +102,17 @@ extern "C" fn _start(stack_top: u64) {
 			log::info!("Kernel Command Line: \"{}\"", cmdstr.as_str());
 		}
 		crate::CommandLine::Parse(cmdstr);
+		crate::KernelLog::Configure();
 	} else {
 		log::error!("Bootloader didn't specify Kernel Command Line!");
 	}
+	Memory::AnalyzeMMAP();
+	crate::CrashLog::Initalize();
+	if unsafe {KERNEL_FILE.get_response().get()}.is_some() {
+		GDB::Initalize();
+		let kernel = unsafe {KERNEL_FILE.get_response().get().unwrap().kernel_file.get()}.unwrap();
+		crate::Backtrace::Initalize(unsafe {core::slice::from_raw_parts(kernel.base.as_ptr().unwrap(), kernel.length as usize)});
+	}
 	if unsafe {FRAMEBUFFER.get_response().get()}.is_some() {
 		let fb_tag = &unsafe {FRAMEBUFFER.get_response().get()}.unwrap().framebuffers().unwrap()[0];
 		crate::Framebuffer::Init(fb_tag.address.as_ptr().unwrap() as *mut u32,fb_tag.width as usize,fb_tag.height as usize,fb_tag.pitch as usize,fb_tag.bpp as usize);
@@ -0,0 +1,131 @@
+// Kernel backtraces for panics and faults, symbolised from the kernel ELF the bootloader hands us.
+// Walking frame pointers only works because the target spec forces them on.
+use core::sync::atomic::{AtomicU64, Ordering};
+use alloc::vec::Vec;
+use spin::Once;
+use xmas_elf::sections::SectionData;
+use xmas_elf::symbol_table::{Entry, Type};
+
+const MAX_FRAMES: usize = 32;
+
+// Start address, size, and mangled name of every function, sorted by address. This gets built at boot because
+// the heap can't be trusted by the time anyone wants to read it.
+static SYMBOLS: Once<Vec<(u64, u64, &'static str)>> = Once::new();
+
+// Where an unhandled fault happened, so the panic it turns into can start its backtrace there instead of in x86Fault
+static FAULT_RIP: AtomicU64 = AtomicU64::new(0);
+static FAULT_RBP: AtomicU64 = AtomicU64::new(0);
+
+pub fn Initalize(kernel: &'static [u8]) {
+    let elf = match xmas_elf::ElfFile::new(kernel) {
+        Ok(elf) => elf,
+        Err(e) => {
+            log::warn!("Kernel ELF couldn't be parsed, backtraces won't have symbols: {}", e);
+            return;
+        }
+    };
+    let mut symbols = Vec::new();
+    for section in elf.section_iter() {
+        if let Ok(SectionData::SymbolTable64(entries)) = section.get_data(&elf) {
+            for entry in entries.iter() {
+                if !matches!(entry.get_type(),Ok(Type::Func)) || entry.value() == 0 {
+                    continue;
+                }
+                if let Ok(name) = entry.get_name(&elf) {
+                    symbols.push((entry.value(),entry.size(),name));
+                }
+            }
+        }
+    }
+    if symbols.is_empty() {
+        log::warn!("Kernel ELF has no symbol table, backtraces won't have symbols");
+        return;
+    }
+    symbols.sort_unstable_by_key(|s| s.0);
+    log::debug!("Loaded {} kernel symbols", symbols.len());
+    SYMBOLS.call_once(|| symbols);
+}
+
+// The function an address is in, and how far into it
+pub fn Lookup(addr: u64) -> Option<(&'static str, u64)> {
+    let symbols = SYMBOLS.get()?;
+    let index = match symbols.binary_search_by_key(&addr,|s| s.0) {
+        Ok(i) => i,
+        Err(0) => return None,
+        Err(i) => i - 1,
+    };
+    let (start, size, name) = symbols[index];
+    if size != 0 && addr >= start + size {
+        return None;
+    }
+    Some((name, addr - start))
+}
+
+pub fn RecordFault(rip: u64, rbp: u64) {
+    FAULT_RIP.store(rip,Ordering::SeqCst);
+    FAULT_RBP.store(rbp,Ordering::SeqCst);
+}
+
+// The function a frame is in. Return addresses point just past the call, which can be the start of the next function over.
+pub fn FrameSymbol(n: usize, addr: u64) -> Option<(&'static str, u64)> {
+    if n == 0 {Lookup(addr)} else {Lookup(addr - 1).map(|(name, off)| (name, off + 1))}
+}
+
+fn PrintFrame(n: usize, addr: u64) {
+    match FrameSymbol(n,addr) {
+        Some((name, off)) => print!("  #{:<2} 0x{:016x} {:#}+0x{:x}\n", n, addr, rustc_demangle::demangle(name), off),
+        None => print!("  #{:<2} 0x{:016x} ???\n", n, addr),
+    }
+}
+
+// Follows the frame pointer chain starting at rbp, handing f the number and address of each frame. rip is the frame
+// on top, if there's one that isn't on the chain yet, like the instruction that faulted.
+pub fn Walk(rip: Option<u64>, rbp: u64, mut f: impl FnMut(usize, u64)) {
+    let mut n = 0;
+    if let Some(rip) = rip {
+        f(n,rip);
+        n += 1;
+    }
+    let mut rbp = rbp;
+    while n < MAX_FRAMES {
+        if rbp == 0 || rbp & 0x7 != 0 || !crate::arch::Memory::IsMapped(rbp) || !crate::arch::Memory::IsMapped(rbp + 8) {
+            break;
+        }
+        let next = unsafe {*(rbp as *const u64)};
+        let ret = unsafe {*((rbp + 8) as *const u64)};
+        if ret == 0 {
+            break;
+        }
+        f(n,ret);
+        n += 1;
+        // Stacks grow down, so anything that doesn't go up is garbage
+        if next <= rbp {
+            break;
+        }
+        rbp = next;
+    }
+}
+
+pub fn Print(rip: Option<u64>, rbp: u64) {
+    print!("Backtrace:\n");
+    Walk(rip,rbp,PrintFrame);
+}
+
+// Walks from the fault if one got recorded, otherwise from right here
+pub fn WalkPanic(f: impl FnMut(usize, u64)) {
+    let rip = FAULT_RIP.load(Ordering::SeqCst);
+    let rbp = FAULT_RBP.load(Ordering::SeqCst);
+    if rip != 0 {
+        Walk(Some(rip),rbp,f);
+        return;
+    }
+    let rbp: u64;
+    unsafe {core::arch::asm!("mov {}, rbp", out(reg) rbp);}
+    Walk(None,rbp,f);
+}
+
+// What the panic handler calls
+pub fn PrintPanic() {
+    print!("Backtrace:\n");
+    WalkPanic(PrintFrame);
+}
@@ -29,6 +29,8 @@ pub fn Parse(cmd: String) {
                 unsafe {crate::Console::QUIET = true;}
             } else if arg == "--no_color" {
                 unsafe {crate::Console::NO_COLOR = true;}
+            } else if arg == "--no_serial_log" {
+                unsafe {crate::Console::SERIAL_LOG = false;}
             }
         }
     }
@@ -1,5 +1,6 @@
 use spin::Mutex;
 use log::{Record, Metadata, Level};
+use crate::KernelLog;
 
 pub static WRITER: Mutex<Writer> = Mutex::new(Writer {cursor_x:0,cursor_y:0,text_color: 0xFFFFFF});
 
@@ -13,10 +14,18 @@ struct KernelLogger;
 
 impl log::Log for KernelLogger {
     fn enabled(&self, metadata: &Metadata) -> bool {
-        metadata.level() <= Level::Debug
+        metadata.level() <= Level::Debug && KernelLog::Enabled(KernelLog::Priority(metadata.level()),metadata.target())
     }
 
     fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let level = KernelLog::Priority(record.level());
+        KernelLog::Record(level,record.target(),*record.args());
+        if !KernelLog::Printable(level) {
+            return;
+        }
         match record.level() {
             Level::Trace => {},
             Level::Debug => crate::print!("{}:{} \x1b[37m\x1b[2mdebug\x1b[0m {}\n", record.file().unwrap(), record.line().unwrap(), record.args()),
@@ -32,14 +41,18 @@ impl log::Log for KernelLogger {
 static LOGGER: KernelLogger = KernelLogger;
 pub static mut QUIET: bool = false;
 pub static mut NO_COLOR: bool = true;
+pub static mut SERIAL_LOG: bool = true; // Whether everything printed is mirrored to COM1
 
 impl core::fmt::Write for Writer {
     #[cfg(any(target_arch="x86_64"))]
     fn write_str(&mut self, s: &str) -> core::fmt::Result {
         use limine::*;
         static mut CACHED: Option<&'static LimineTerminalResponse> = None;
-        crate::arch::UART::write_serial(s);
+        unsafe {if SERIAL_LOG {crate::arch::UART::write_serial(s);}}
         unsafe {if QUIET {return Ok(());}}
+        if crate::Drivers::Generic::VirtualTTY::ConsoleWrite(s) {
+            return Ok(());
+        }
         unsafe {
             if let Some(writer) = CACHED {
                 let terminal = writer.terminals().unwrap().first().unwrap();
@@ -60,7 +73,7 @@ impl core::fmt::Write for Writer {
     fn write_str(&mut self, s: &str) -> core::fmt::Result {
         use crate::Framebuffer::MainFramebuffer;
         use alloc::vec::Vec;
-        crate::arch::UART::write_serial(s);
+        unsafe {if SERIAL_LOG {crate::arch::UART::write_serial(s);}}
         unsafe {if QUIET {return Ok(());}}
         let mut lock = MainFramebuffer.lock();
         if lock.is_some() {
@@ -0,0 +1,236 @@
+// A crash log that survives a warm reboot, like Linux's ramoops. --crashlog=ADDRESS[,SIZE] keeps a piece of physical
+// memory away from the page frame allocator. Panics write their message and the tail of the kernel log into it, and
+// the next boot hands whatever checks out to userspace as /dev/last_crash.
+use core::fmt::Write;
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use alloc::string::String;
+use alloc::sync::Arc;
+use spin::Once;
+use crate::FS::{VFS, DevFS};
+use crate::arch::PHYSMEM_BEGIN;
+
+const MAGIC: u64 = 0x48534152_43584f46; // "FOXCRASH"
+const DEFAULT_SIZE: u64 = 0x10000;
+const LOG_RECORDS: usize = 64;
+
+#[repr(C)]
+struct Header {
+    magic: u64,
+    length: u32, // Bytes of text after the header that the checksum covers
+    checksum: u32,
+}
+
+// Physical address and size of the region, once it's been taken out of the memory map
+static REGION: Once<(u64, u64)> = Once::new();
+// Where the header is mapped, zero until the old contents have been looked at and it's safe to write
+static HEADER: AtomicU64 = AtomicU64::new(0);
+static CURSOR: AtomicUsize = AtomicUsize::new(0);
+static LAST_CRASH: Once<String> = Once::new();
+
+fn ParseNumber(s: &str) -> Option<u64> {
+    let (s, shift) = match s.as_bytes().last()? {
+        b'K' | b'k' => (&s[..s.len()-1], 10),
+        b'M' | b'm' => (&s[..s.len()-1], 20),
+        _ => (s, 0),
+    };
+    let n = match s.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex,16).ok()?,
+        None => s.parse::<u64>().ok()?,
+    };
+    n.checked_shl(shift)
+}
+
+// Carves the region out of the usable memory the page frame allocator is about to be given. It has to sit entirely
+// inside one usable entry, anywhere else and there's no telling what else is using it.
+pub fn Reserve(map: &mut [(u64, u64); 32], count: &mut usize) {
+    let option = match crate::CommandLine::OPTIONS.get().and_then(|o| o.get("--crashlog")) {
+        Some(o) => *o,
+        None => return,
+    };
+    let (start, size) = match option.split_once(',') {
+        Some((a, s)) => (ParseNumber(a), ParseNumber(s)),
+        None => (ParseNumber(option), Some(DEFAULT_SIZE)),
+    };
+    let (start, size) = match (start, size) {
+        (Some(a), Some(s)) if s as usize > core::mem::size_of::<Header>() => (a, s),
+        _ => {
+            log::warn!("Ignoring malformed --crashlog=\"{}\"", option);
+            return;
+        }
+    };
+    // Anything the allocator gets back has to stay page aligned
+    let end = (start + size + 0xfff) & !0xfff;
+    let start = start & !0xfff;
+    for i in 0..*count {
+        let (base, len) = map[i];
+        if start < base || end > base + len {
+            continue;
+        }
+        if end < base + len {
+            if *count == map.len() {
+                break;
+            }
+            map[*count] = (end, base + len - end);
+            *count += 1;
+        }
+        map[i].1 = start - base;
+        log::info!("Crash log at 0x{:016x}-0x{:016x}", start, end);
+        REGION.call_once(|| (start, end - start));
+        return;
+    }
+    log::warn!("Crash log at 0x{:016x}-0x{:016x} isn't inside usable memory, it won't be kept", start, end);
+}
+
+fn Checksum(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for b in data {
+        crc ^= *b as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {(crc >> 1) ^ 0xEDB88320} else {crc >> 1};
+        }
+    }
+    !crc
+}
+
+// Picks up whatever the last boot left behind, then clears the region so it can be written this time
+pub fn Initalize() {
+    let (start, size) = match REGION.get() {
+        Some(r) => *r,
+        None => return,
+    };
+    let header = (start + PHYSMEM_BEGIN) as *mut Header;
+    let text = (start + PHYSMEM_BEGIN) as usize + core::mem::size_of::<Header>();
+    let capacity = size as usize - core::mem::size_of::<Header>();
+    unsafe {
+        if (*header).magic == MAGIC && ((*header).length as usize) <= capacity {
+            let old = core::slice::from_raw_parts(text as *const u8,(*header).length as usize);
+            if Checksum(old) == (*header).checksum {
+                LAST_CRASH.call_once(|| String::from_utf8_lossy(old).into_owned());
+                log::warn!("The last boot crashed, its crash log is in /dev/last_crash");
+                DevFS::InstallDevice(LastCrash::new());
+            } else {
+                log::warn!("Crash log from the last boot is damaged, ignoring it");
+            }
+        }
+        header.write_volatile(Header {magic: 0, length: 0, checksum: 0});
+    }
+    HEADER.store(header as u64,Ordering::SeqCst);
+}
+
+struct RegionWriter;
+
+impl Write for RegionWriter {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let header = HEADER.load(Ordering::SeqCst);
+        let capacity = REGION.get().unwrap().1 as usize - core::mem::size_of::<Header>();
+        let cursor = CURSOR.load(Ordering::SeqCst);
+        let n = s.len().min(capacity - cursor);
+        let text = (header as usize + core::mem::size_of::<Header>() + cursor) as *mut u8;
+        unsafe {core::ptr::copy_nonoverlapping(s.as_ptr(),text,n);}
+        CURSOR.store(cursor + n,Ordering::SeqCst);
+        Ok(())
+    }
+}
+
+// Seals what's been written so far, so a reset halfway through the next part still leaves something readable
+fn Commit() {
+    let header = HEADER.load(Ordering::SeqCst) as *mut Header;
+    let length = CURSOR.load(Ordering::SeqCst);
+    unsafe {
+        let text = core::slice::from_raw_parts((header as usize + core::mem::size_of::<Header>()) as *const u8,length);
+        header.write_volatile(Header {magic: MAGIC, length: length as u32, checksum: Checksum(text)});
+        // A reset doesn't write back the caches on real hardware
+        core::arch::asm!("wbinvd");
+    }
+}
+
+fn WriteLocation(w: &mut RegionWriter, info: &PanicInfo) {
+    if let Some(m) = info.message() {
+        write!(w, "{}\n", m);
+    }
+    if let Some(l) = info.location() {
+        write!(w, "{}\n", l);
+    }
+}
+
+// Called by the panic handler once the other harts have been stopped
+pub fn Panic(info: &PanicInfo) {
+    if HEADER.load(Ordering::SeqCst) == 0 {
+        return;
+    }
+    let mut w = RegionWriter;
+    let time = crate::arch::Timer::GetMicroseconds();
+    write!(w, "Fox Kernel panic on hart 0x{} at {}.{:06}\n", crate::arch::CurrentHart(), time / 1000000, time % 1000000);
+    WriteLocation(&mut w,info);
+    Commit();
+    write!(w, "\nLast {} log records:\n", LOG_RECORDS);
+    let complete = crate::KernelLog::Tail(LOG_RECORDS,|r| {
+        write!(RegionWriter, "<{}>[{:5}.{:06}] {}: {}\n", r.level, r.time / 1000000, r.time % 1000000, r.Module(), r.Text());
+    });
+    if !complete {
+        write!(w, "(the log was locked)\n");
+    }
+    Commit();
+}
+
+// Panics while already panicking get tacked onto the end
+pub fn NestedPanic(info: &PanicInfo) {
+    if HEADER.load(Ordering::SeqCst) == 0 {
+        return;
+    }
+    let mut w = RegionWriter;
+    write!(w, "\nNested panic on hart 0x{}\n", crate::arch::CurrentHart());
+    WriteLocation(&mut w,info);
+    Commit();
+}
+
+struct LastCrash(usize);
+impl LastCrash {
+    fn new() -> Arc<Self> {
+        Arc::new(Self(DevFS::ReserveDeviceID()))
+    }
+}
+impl DevFS::Device for LastCrash {
+    fn DeviceID(&self) -> usize {
+        self.0
+    }
+    fn Inode(&self) -> Arc<dyn VFS::Inode> {
+        Arc::new(LastCrash(self.0))
+    }
+}
+impl VFS::Inode for LastCrash {
+    fn Stat(&self) -> Result<VFS::Metadata, i64> {
+        Ok(VFS::Metadata {
+            device_id: 0,
+            inode_id: i64::MAX,
+            mode: 0o0100400, // -r--------
+            nlinks: 1,
+            uid: 0,
+            gid: 0,
+            rdev: 0,
+            size: LAST_CRASH.get().map_or(0,|c| c.len()) as i64,
+            blksize: 0,
+            blocks: 0,
+
+            atime: unsafe {crate::UNIX_EPOCH as i64},
+            mtime: unsafe {crate::UNIX_EPOCH as i64},
+            ctime: unsafe {crate::UNIX_EPOCH as i64},
+            reserved1: 0,
+            reserved2: 0,
+            reserved3: 0,
+        })
+    }
+    fn GetName(&self) -> Result<&str, i64> {
+        Ok("last_crash")
+    }
+    fn Read(&self, offset: i64, buffer: &mut [u8]) -> i64 {
+        let text = LAST_CRASH.get().map_or(&[][..],|c| c.as_bytes());
+        if offset as usize >= text.len() {
+            return 0;
+        }
+        let n = buffer.len().min(text.len() - offset as usize);
+        buffer[..n].copy_from_slice(&text[offset as usize..offset as usize + n]);
+        n as i64
+    }
+}
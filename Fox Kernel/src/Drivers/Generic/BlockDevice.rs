@@ -0,0 +1,256 @@
+use crate::FS::VFS;
+use crate::FS::DevFS;
+use alloc::sync::{Arc, Weak};
+use alloc::string::String;
+use alloc::vec;
+use core::sync::atomic::{AtomicUsize,Ordering};
+use crate::Syscall::Errors;
+
+pub const IO_BLK_SIZE: usize = 0x6001;
+pub const IO_BLK_COUNT: usize = 0x6002;
+pub const IO_BLK_FLUSH: usize = 0x6003;
+
+// Every read and write gets to a driver from inside of a system call, which runs with interrupts off and can't be put
+// to sleep halfway through, so there's nobody an interrupt could wake up. Drivers leave their controller's interrupts
+// off and poll for the command to finish instead.
+pub trait BlockDevice: Send + Sync {
+    fn BlockSize(&self) -> usize;
+    fn BlockCount(&self) -> u64;
+    // Both buffers are always a multiple of BlockSize() long.
+    fn ReadBlocks(&self, lba: u64, buffer: &mut [u8]) -> Result<(),i64>;
+    fn WriteBlocks(&self, lba: u64, buffer: &[u8]) -> Result<(),i64>;
+    fn Flush(&self) -> Result<(),i64> {
+        Ok(())
+    }
+}
+
+pub struct BlockDeviceNode {
+    id: usize,
+    name: String,
+    dev: Arc<dyn BlockDevice>,
+    inode: Weak<BlockDeviceNode>,
+}
+
+impl BlockDeviceNode {
+    pub fn new(name: String, dev: Arc<dyn BlockDevice>) -> Arc<Self> {
+        Arc::new_cyclic(|inode| Self {
+            id: DevFS::ReserveDeviceID(),
+            name,
+            dev,
+            inode: inode.clone(),
+        })
+    }
+    pub fn Device(&self) -> Arc<dyn BlockDevice> {
+        self.dev.clone()
+    }
+    fn Size(&self) -> i64 {
+        (self.dev.BlockCount() * self.dev.BlockSize() as u64) as i64
+    }
+}
+
+impl DevFS::Device for BlockDeviceNode {
+    fn DeviceID(&self) -> usize {
+        self.id
+    }
+    fn Inode(&self) -> Arc<dyn VFS::Inode> {
+        self.inode.upgrade().unwrap()
+    }
+}
+
+impl VFS::Inode for BlockDeviceNode {
+    fn Stat(&self) -> Result<VFS::Metadata, i64> {
+        Ok(VFS::Metadata {
+            device_id: 0,
+            inode_id: i64::MAX,
+            mode: 0o0060660, // brw-rw----
+            nlinks: 1,
+            uid: 0,
+            gid: 0,
+            rdev: self.id as u64,
+            size: self.Size(),
+            blksize: self.dev.BlockSize() as i64,
+            blocks: self.dev.BlockCount() as i64,
+
+            atime: unsafe {crate::UNIX_EPOCH as i64},
+            mtime: unsafe {crate::UNIX_EPOCH as i64},
+            ctime: unsafe {crate::UNIX_EPOCH as i64},
+            reserved1: 0,
+            reserved2: 0,
+            reserved3: 0,
+        })
+    }
+    fn GetName(&self) -> Result<&str, i64> {
+        Ok(self.name.as_str())
+    }
+    fn Read(&self, offset: i64, buffer: &mut [u8]) -> i64 {
+        let size = self.Size();
+        if offset < 0 {
+            return -(Errors::EINVAL as i64);
+        }
+        if offset >= size || buffer.len() == 0 {
+            return 0;
+        }
+        let length = if offset + buffer.len() as i64 > size {(size - offset) as usize} else {buffer.len()};
+        let bs = self.dev.BlockSize();
+        let first = offset as usize / bs;
+        let last = (offset as usize + length).div_ceil(bs);
+        let mut data = vec![0u8; (last - first) * bs];
+        if let Err(e) = self.dev.ReadBlocks(first as u64, data.as_mut_slice()) {
+            return -e;
+        }
+        let start = offset as usize - (first * bs);
+        buffer[..length].copy_from_slice(&data[start..start+length]);
+        length as i64
+    }
+    fn Write(&self, offset: i64, buffer: &[u8]) -> i64 {
+        let size = self.Size();
+        if offset < 0 {
+            return -(Errors::EINVAL as i64);
+        }
+        if offset >= size {
+            return -(Errors::ENOSPC as i64);
+        }
+        let length = if offset + buffer.len() as i64 > size {(size - offset) as usize} else {buffer.len()};
+        let bs = self.dev.BlockSize();
+        let first = offset as usize / bs;
+        let last = (offset as usize + length).div_ceil(bs);
+        let start = offset as usize - (first * bs);
+        let mut data = vec![0u8; (last - first) * bs];
+        // Partial blocks on either end have to be read back first so we don't clobber their neighbours.
+        if start != 0 {
+            if let Err(e) = self.dev.ReadBlocks(first as u64, &mut data[0..bs]) {
+                return -e;
+            }
+        }
+        if (start + length) % bs != 0 && (last - 1 != first || start == 0) {
+            let tail = (last - first - 1) * bs;
+            if let Err(e) = self.dev.ReadBlocks((last - 1) as u64, &mut data[tail..tail+bs]) {
+                return -e;
+            }
+        }
+        data[start..start+length].copy_from_slice(&buffer[..length]);
+        if let Err(e) = self.dev.WriteBlocks(first as u64, data.as_slice()) {
+            return -e;
+        }
+        length as i64
+    }
+    fn IOCtl(&self, cmd: usize, _arg: usize) -> Result<usize, i64> {
+        match cmd {
+            IO_BLK_SIZE => Ok(self.dev.BlockSize()),
+            IO_BLK_COUNT => Ok(self.dev.BlockCount() as usize),
+            IO_BLK_FLUSH => self.dev.Flush().map(|_| 0),
+            _ => Err(Errors::EINVAL as i64),
+        }
+    }
+}
+
+static NEXT_SD: AtomicUsize = AtomicUsize::new(0);
+
+// sda, sdb, ..., sdz, sdaa, sdab, ...
+pub fn DiskName(prefix: &str, mut index: usize) -> String {
+    let mut suffix: alloc::vec::Vec<u8> = alloc::vec::Vec::new();
+    loop {
+        suffix.insert(0, b'a' + (index % 26) as u8);
+        if index < 26 {
+            break;
+        }
+        index = (index / 26) - 1;
+    }
+    let mut name = String::from(prefix);
+    name.push_str(core::str::from_utf8(suffix.as_slice()).unwrap());
+    name
+}
+
+pub fn ReserveSDName() -> String {
+    DiskName("sd",NEXT_SD.fetch_add(1, Ordering::SeqCst))
+}
+
+pub fn Register(name: String, dev: Arc<dyn BlockDevice>) -> Arc<BlockDeviceNode> {
+    log::info!("/dev/{}: {} blocks of {} bytes ({} MiB)", name, dev.BlockCount(), dev.BlockSize(), (dev.BlockCount() * dev.BlockSize() as u64) / 1024 / 1024);
+    let node = BlockDeviceNode::new(name, dev);
+    DevFS::InstallDevice(node.clone());
+    node
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::FS::VFS::Inode;
+    use alloc::vec::Vec;
+    use spin::Mutex;
+
+    const BS: usize = 16;
+    const BLOCKS: usize = 8;
+
+    struct RamDisk(Mutex<Vec<u8>>);
+
+    impl BlockDevice for RamDisk {
+        fn BlockSize(&self) -> usize {
+            BS
+        }
+        fn BlockCount(&self) -> u64 {
+            BLOCKS as u64
+        }
+        fn ReadBlocks(&self, lba: u64, buffer: &mut [u8]) -> Result<(),i64> {
+            assert_eq!(buffer.len() % BS, 0);
+            let start = lba as usize * BS;
+            buffer.copy_from_slice(&self.0.lock()[start..start+buffer.len()]);
+            Ok(())
+        }
+        fn WriteBlocks(&self, lba: u64, buffer: &[u8]) -> Result<(),i64> {
+            assert_eq!(buffer.len() % BS, 0);
+            let start = lba as usize * BS;
+            self.0.lock()[start..start+buffer.len()].copy_from_slice(buffer);
+            Ok(())
+        }
+    }
+
+    fn Pattern(seed: u8) -> Vec<u8> {
+        (0..BS*BLOCKS).map(|i| (i as u8).wrapping_mul(7).wrapping_add(seed)).collect()
+    }
+
+    fn Disk() -> (Arc<RamDisk>, Arc<BlockDeviceNode>) {
+        let disk = Arc::new(RamDisk(Mutex::new(Pattern(1))));
+        (disk.clone(), BlockDeviceNode::new(String::from("test"),disk))
+    }
+
+    #[test]
+    fn WritesLeaveTheRestOfTheirBlocksAlone() {
+        for offset in 0..BS*BLOCKS {
+            for length in 0..=3*BS {
+                let (disk, node) = Disk();
+                let data = &Pattern(0x80)[..length];
+                let written = length.min(BS*BLOCKS - offset);
+                let mut expected = Pattern(1);
+                expected[offset..offset+written].copy_from_slice(&data[..written]);
+                assert_eq!(node.Write(offset as i64,data), written as i64, "offset {} length {}", offset, length);
+                assert!(*disk.0.lock() == expected, "offset {} length {}", offset, length);
+            }
+        }
+    }
+
+    #[test]
+    fn ReadsAnyRange() {
+        let (_, node) = Disk();
+        let expected = Pattern(1);
+        for offset in 0..BS*BLOCKS {
+            for length in 0..=3*BS {
+                let mut buffer = vec![0u8; length];
+                let read = length.min(BS*BLOCKS - offset);
+                assert_eq!(node.Read(offset as i64,&mut buffer), read as i64, "offset {} length {}", offset, length);
+                assert!(buffer[..read] == expected[offset..offset+read], "offset {} length {}", offset, length);
+            }
+        }
+    }
+
+    #[test]
+    fn OutOfRange() {
+        let (disk, node) = Disk();
+        let size = (BS*BLOCKS) as i64;
+        assert_eq!(node.Read(size,&mut [0u8; 4]), 0);
+        assert_eq!(node.Write(size,&[0u8; 4]), -(Errors::ENOSPC as i64));
+        assert_eq!(node.Read(-1,&mut [0u8; 4]), -(Errors::EINVAL as i64));
+        assert_eq!(node.Write(-1,&[0u8; 4]), -(Errors::EINVAL as i64));
+        assert!(*disk.0.lock() == Pattern(1));
+    }
+}
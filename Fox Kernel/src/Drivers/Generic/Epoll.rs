@@ -0,0 +1,436 @@
+use crate::FS::VFS;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use alloc::collections::{BTreeMap, VecDeque};
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+use crate::Syscall::Errors;
+
+// These follow mlibc's sys/epoll.h, which unlike poll.h uses the same values as Linux
+pub const EPOLLIN: u32 = 0x001;
+pub const EPOLLPRI: u32 = 0x002;
+pub const EPOLLOUT: u32 = 0x004;
+pub const EPOLLERR: u32 = 0x008;
+pub const EPOLLHUP: u32 = 0x010;
+pub const EPOLLONESHOT: u32 = 1 << 30;
+pub const EPOLLET: u32 = 1 << 31;
+
+pub const EPOLL_CTL_ADD: usize = 1;
+pub const EPOLL_CTL_DEL: usize = 2;
+pub const EPOLL_CTL_MOD: usize = 3;
+
+pub const EPOLL_CLOEXEC: usize = 1;
+
+// Lets a file tell the epoll instances watching it that it might have become ready, so they only have to look at
+// what changed instead of polling everything on every wait. Clones share the same list.
+#[derive(Clone)]
+pub struct Watchers(Arc<Mutex<Vec<Weak<Watch>>>>);
+
+impl Watchers {
+    pub fn new() -> Self {
+        Self(Arc::new(Mutex::new(Vec::new())))
+    }
+
+    fn Add(&self, watch: &Arc<Watch>) {
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            let mut lock = self.0.lock();
+            lock.retain(|w| w.strong_count() > 0);
+            lock.push(Arc::downgrade(watch));
+        });
+    }
+
+    // Sets `woken` on every notification for as long as the watch it hands back is kept around, which is how a process
+    // sleeping in a system call finds out it should look again.
+    pub fn Wake(&self, woken: &Arc<AtomicBool>) -> Arc<Watch> {
+        let watch = Arc::new(Watch {id: 0, target: Target::Task(woken.clone()), queued: AtomicBool::new(false)});
+        self.Add(&watch);
+        watch
+    }
+
+    // Call this whenever what the file's Poll() returns might have changed. It only takes epoll's own locks, so it's
+    // fine to call with the file's locks held, and input devices call it from their interrupt handlers.
+    pub fn Notify(&self) {
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            let watches: Vec<Arc<Watch>> = self.0.lock().iter().filter_map(|w| w.upgrade()).collect();
+            for w in watches.iter() {
+                w.Ring();
+            }
+        });
+    }
+}
+
+enum Target {
+    Epoll(Weak<ReadyList>),
+    Task(Arc<AtomicBool>),
+}
+
+// One entry's link from a file back to the ready list of the epoll instance watching it, or to a process waiting on it
+pub struct Watch {
+    id: u64,
+    target: Target,
+    queued: AtomicBool, // Already on the ready list, so ringing it again doesn't add it twice
+}
+
+impl Watch {
+    fn Ring(&self) {
+        let ready = match &self.target {
+            Target::Epoll(ready) => ready,
+            Target::Task(woken) => {
+                woken.store(true,Ordering::SeqCst);
+                return;
+            }
+        };
+        if self.queued.swap(true,Ordering::SeqCst) {
+            return;
+        }
+        if let Some(ready) = ready.upgrade() {
+            ready.queue.lock().push_back(self.id);
+            ready.watchers.Notify();
+        }
+    }
+}
+
+struct ReadyList {
+    queue: Mutex<VecDeque<u64>>,
+    watchers: Watchers, // For epoll instances watching this one
+}
+
+struct Interest {
+    fd: i64,
+    // Weak so that watching a file doesn't keep it open, closed files just stop showing up
+    inode: Weak<dyn VFS::Inode>,
+    events: u32,
+    data: u64,
+    armed: bool, // Cleared once a one-shot entry fires, until EPOLL_CTL_MOD sets it up again
+    watch: Option<Arc<Watch>>, // None for files that can't notify us
+    seen: u32, // Files that can't notify us only get edges by comparing against what they looked like last time
+}
+
+struct Interests {
+    entries: BTreeMap<u64,Interest>,
+    polled: Vec<u64>, // Entries without a watch, these still have to be polled on every wait
+    next: u64,
+}
+
+pub struct Epoll {
+    interests: Mutex<Interests>,
+    ready: Arc<ReadyList>,
+}
+
+fn FromPoll(events: i16) -> u32 {
+    let mut ret = 0;
+    if events & VFS::POLLIN != 0 {ret |= EPOLLIN;}
+    if events & VFS::POLLPRI != 0 {ret |= EPOLLPRI;}
+    if events & VFS::POLLOUT != 0 {ret |= EPOLLOUT;}
+    if events & VFS::POLLERR != 0 {ret |= EPOLLERR;}
+    if events & VFS::POLLHUP != 0 {ret |= EPOLLHUP;}
+    ret
+}
+
+impl Interest {
+    // What's ready right now out of what this entry asked for
+    fn Current(&self) -> Option<u32> {
+        let inode = self.inode.upgrade()?;
+        Some(FromPoll(inode.Poll()) & (self.events | EPOLLERR | EPOLLHUP))
+    }
+}
+
+impl Epoll {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self {
+            interests: Mutex::new(Interests {entries: BTreeMap::new(), polled: Vec::new(), next: 0}),
+            ready: Arc::new(ReadyList {queue: Mutex::new(VecDeque::new()), watchers: Watchers::new()}),
+        })
+    }
+
+    // Whether this instance can reach `target` through the epoll instances it's watching, which keeps loops from forming
+    fn Reaches(&self, target: *const Epoll) -> bool {
+        if self as *const Epoll == target {
+            return true;
+        }
+        let lock = self.interests.lock();
+        for i in lock.entries.values() {
+            if let Some(inode) = i.inode.upgrade() {
+                if let Some(ep) = VFS::Downcast::<Epoll>(inode) {
+                    if ep.Reaches(target) {
+                        return true;
+                    }
+                }
+            }
+        }
+        false
+    }
+
+    pub fn Control(&self, op: usize, fd: i64, inode: Arc<dyn VFS::Inode>, events: u32, data: u64) -> Result<(),i32> {
+        if let Some(ep) = VFS::Downcast::<Epoll>(inode.clone()) {
+            if ep.Reaches(self as *const Epoll) {
+                return Err(Errors::ELOOP);
+            }
+        }
+        let mut lock = self.interests.lock();
+        let Interests {entries, polled, next} = &mut *lock;
+        entries.retain(|_, i| i.inode.strong_count() > 0);
+        polled.retain(|id| entries.contains_key(id));
+        let found = entries.iter().find(|(_, i)| i.fd == fd && Weak::ptr_eq(&i.inode,&Arc::downgrade(&inode))).map(|(id, _)| *id);
+        match (op, found) {
+            (EPOLL_CTL_ADD, Some(_)) => Err(Errors::EEXIST),
+            (EPOLL_CTL_ADD, None) => {
+                let id = *next;
+                *next += 1;
+                let watch = inode.Watchers().map(|watchers| {
+                    let watch = Arc::new(Watch {id, target: Target::Epoll(Arc::downgrade(&self.ready)), queued: AtomicBool::new(false)});
+                    watchers.Add(&watch);
+                    watch
+                });
+                match &watch {
+                    Some(w) => w.Ring(), // It might be ready already
+                    None => polled.push(id),
+                }
+                entries.insert(id,Interest {fd, inode: Arc::downgrade(&inode), events, data, armed: true, watch, seen: 0});
+                Ok(())
+            }
+            (EPOLL_CTL_MOD, Some(id)) => {
+                let interest = entries.get_mut(&id).unwrap();
+                interest.events = events;
+                interest.data = data;
+                interest.armed = true;
+                interest.seen = 0;
+                if let Some(w) = &interest.watch {
+                    w.Ring();
+                }
+                Ok(())
+            }
+            (EPOLL_CTL_DEL, Some(id)) => {
+                // Dropping the watch is enough to unhook it from the file, and a stale id on the ready list gets skipped
+                entries.remove(&id);
+                polled.retain(|p| *p != id);
+                Ok(())
+            }
+            (EPOLL_CTL_MOD, None) | (EPOLL_CTL_DEL, None) => Err(Errors::ENOENT),
+            _ => Err(Errors::EINVAL),
+        }
+    }
+
+    // Gathers up to `max` ready entries as (events, data). Only entries whose file rang since the last wait get looked at,
+    // so edge-triggered ones are reported once per notification. Level-triggered ones go back on the ready list to be
+    // checked again next time, and one-shot entries go quiet after they're reported.
+    pub fn Collect(&self, max: usize) -> Vec<(u32,u64)> {
+        let mut ret = Vec::new();
+        let mut lock = self.interests.lock();
+        let Interests {entries, polled, ..} = &mut *lock;
+        for id in polled.iter() {
+            if ret.len() >= max {
+                break;
+            }
+            let i = match entries.get_mut(id) {
+                Some(i) if i.armed => i,
+                _ => {continue;}
+            };
+            let current = match i.Current() {
+                Some(c) => c,
+                None => {continue;}
+            };
+            let events = if i.events & EPOLLET != 0 {current & !i.seen} else {current};
+            i.seen = current;
+            if events != 0 {
+                ret.push((events,i.data));
+                if i.events & EPOLLONESHOT != 0 {
+                    i.armed = false;
+                }
+            }
+        }
+        let mut again = Vec::new();
+        while ret.len() < max {
+            let id = match self.ready.queue.lock().pop_front() {
+                Some(id) => id,
+                None => {break;}
+            };
+            let i = match entries.get_mut(&id) {
+                Some(i) => i,
+                None => {continue;}
+            };
+            let watch = i.watch.clone().unwrap();
+            // Cleared before polling, so a notification from here on puts it right back
+            watch.queued.store(false,Ordering::SeqCst);
+            if !i.armed {
+                continue;
+            }
+            let events = i.Current().unwrap_or(0);
+            if events != 0 {
+                ret.push((events,i.data));
+                if i.events & EPOLLONESHOT != 0 {
+                    i.armed = false;
+                } else if i.events & EPOLLET == 0 {
+                    again.push(watch);
+                }
+            }
+        }
+        drop(lock);
+        for w in again.iter() {
+            w.Ring();
+        }
+        ret
+    }
+}
+
+impl VFS::Inode for Epoll {
+    fn Stat(&self) -> Result<VFS::Metadata, i64> {
+        Ok(VFS::Metadata {
+            device_id: 0,
+            inode_id: i64::MAX,
+            mode: 0o0000600, // -rw-------
+            nlinks: 1,
+            uid: 0,
+            gid: 0,
+            rdev: 0,
+            size: 0,
+            blksize: 0,
+            blocks: 0,
+
+            atime: unsafe {crate::UNIX_EPOCH as i64},
+            mtime: unsafe {crate::UNIX_EPOCH as i64},
+            ctime: unsafe {crate::UNIX_EPOCH as i64},
+            reserved1: 0,
+            reserved2: 0,
+            reserved3: 0,
+        })
+    }
+    fn GetName(&self) -> Result<&str, i64> {
+        Ok("[fox kernel epoll]")
+    }
+    fn Read(&self, _offset: i64, _buffer: &mut [u8]) -> i64 {
+        -(Errors::EINVAL as i64)
+    }
+    fn Write(&self, _offset: i64, _buffer: &[u8]) -> i64 {
+        -(Errors::EINVAL as i64)
+    }
+    fn Poll(&self) -> i16 {
+        // Looks without taking anything off the ready list, so watching an epoll instance doesn't eat its events
+        let lock = self.interests.lock();
+        let queued: Vec<u64> = self.ready.queue.lock().iter().copied().collect();
+        for id in lock.polled.iter().chain(queued.iter()) {
+            if let Some(i) = lock.entries.get(id).filter(|i| i.armed) {
+                let current = i.Current().unwrap_or(0);
+                let events = if i.watch.is_none() && i.events & EPOLLET != 0 {current & !i.seen} else {current};
+                if events != 0 {
+                    return VFS::POLLIN;
+                }
+            }
+        }
+        0
+    }
+    fn Watchers(&self) -> Option<Watchers> {
+        // Entries that have to be polled could become ready without anything ringing, so then we have to be polled too
+        if self.interests.lock().polled.is_empty() {Some(self.ready.watchers.clone())} else {None}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::sync::atomic::AtomicI16;
+
+    // A file that's only as ready as the test says, and that may or may not be able to tell epoll about it
+    struct Flag {
+        events: AtomicI16,
+        watchers: Option<Watchers>,
+    }
+
+    impl Flag {
+        fn new(notifies: bool) -> Arc<Self> {
+            Arc::new(Self {events: AtomicI16::new(0), watchers: if notifies {Some(Watchers::new())} else {None}})
+        }
+        fn Set(&self, events: i16) {
+            self.events.store(events,Ordering::SeqCst);
+            if let Some(w) = &self.watchers {
+                w.Notify();
+            }
+        }
+    }
+
+    impl VFS::Inode for Flag {
+        fn Poll(&self) -> i16 {
+            self.events.load(Ordering::SeqCst)
+        }
+        fn Watchers(&self) -> Option<Watchers> {
+            self.watchers.clone()
+        }
+    }
+
+    fn Watching(notifies: bool, events: u32) -> (Arc<Epoll>, Arc<Flag>) {
+        let ep = Epoll::new();
+        let flag = Flag::new(notifies);
+        ep.Control(EPOLL_CTL_ADD,3,flag.clone(),events,7).unwrap();
+        (ep, flag)
+    }
+
+    #[test]
+    fn LevelTriggeredReportsForAsLongAsItsReady() {
+        for notifies in [true, false] {
+            let (ep, flag) = Watching(notifies,EPOLLIN);
+            assert_eq!(ep.Collect(8), []);
+            flag.Set(VFS::POLLIN);
+            assert_eq!(ep.Collect(8), [(EPOLLIN,7)]);
+            assert_eq!(ep.Collect(8), [(EPOLLIN,7)]);
+            flag.Set(0);
+            assert_eq!(ep.Collect(8), []);
+            assert_eq!(ep.Collect(8), []);
+        }
+    }
+
+    #[test]
+    fn EdgeTriggeredReportsOncePerChange() {
+        let (ep, flag) = Watching(true,EPOLLIN | EPOLLET);
+        flag.Set(VFS::POLLIN);
+        assert_eq!(ep.Collect(8), [(EPOLLIN,7)]);
+        assert_eq!(ep.Collect(8), []);
+        flag.Set(VFS::POLLIN); // More data showing up is a new edge even though it was ready already
+        assert_eq!(ep.Collect(8), [(EPOLLIN,7)]);
+        assert_eq!(ep.Collect(8), []);
+    }
+
+    #[test]
+    fn EdgeTriggeredWithoutNotifications() {
+        // These only have what they looked like last time to go on, so staying ready isn't an edge
+        let (ep, flag) = Watching(false,EPOLLIN | EPOLLET);
+        flag.Set(VFS::POLLIN);
+        assert_eq!(ep.Collect(8), [(EPOLLIN,7)]);
+        assert_eq!(ep.Collect(8), []);
+        flag.Set(0);
+        assert_eq!(ep.Collect(8), []);
+        flag.Set(VFS::POLLIN);
+        assert_eq!(ep.Collect(8), [(EPOLLIN,7)]);
+    }
+
+    #[test]
+    fn OneShotWaitsToBeRearmed() {
+        let (ep, flag) = Watching(true,EPOLLIN | EPOLLONESHOT);
+        flag.Set(VFS::POLLIN);
+        assert_eq!(ep.Collect(8), [(EPOLLIN,7)]);
+        flag.Set(VFS::POLLIN);
+        assert_eq!(ep.Collect(8), []);
+        ep.Control(EPOLL_CTL_MOD,3,flag.clone(),EPOLLIN | EPOLLONESHOT,8).unwrap();
+        assert_eq!(ep.Collect(8), [(EPOLLIN,8)]);
+    }
+
+    #[test]
+    fn LevelTriggeredTakesTurns() {
+        let ep = Epoll::new();
+        let flags = [Flag::new(true), Flag::new(true)];
+        for (i, flag) in flags.iter().enumerate() {
+            ep.Control(EPOLL_CTL_ADD,i as i64,flag.clone(),EPOLLIN,i as u64).unwrap();
+            flag.Set(VFS::POLLIN);
+        }
+        assert_eq!(ep.Collect(1), [(EPOLLIN,0)]);
+        assert_eq!(ep.Collect(1), [(EPOLLIN,1)]);
+        assert_eq!(ep.Collect(1), [(EPOLLIN,0)]);
+    }
+
+    #[test]
+    fn ClosedFilesDropOut() {
+        let (ep, flag) = Watching(true,EPOLLIN);
+        flag.Set(VFS::POLLIN);
+        drop(flag);
+        assert_eq!(ep.Collect(8), []);
+    }
+}
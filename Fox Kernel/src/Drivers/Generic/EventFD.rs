@@ -0,0 +1,98 @@
+use crate::FS::VFS;
+use alloc::sync::Arc;
+use spin::Mutex;
+use crate::Syscall::Errors;
+use super::Epoll::Watchers;
+
+pub const EFD_SEMAPHORE: usize = 1;
+
+const MAX_COUNT: u64 = u64::MAX - 1;
+
+pub struct EventFD {
+    count: Mutex<u64>,
+    semaphore: bool, // Each read takes one off the counter instead of emptying it
+    watchers: Watchers,
+}
+
+impl EventFD {
+    pub fn new(initial: u64, flags: usize) -> Arc<Self> {
+        Arc::new(Self {
+            count: Mutex::new(initial),
+            semaphore: flags & EFD_SEMAPHORE != 0,
+            watchers: Watchers::new(),
+        })
+    }
+}
+
+impl VFS::Inode for EventFD {
+    fn Stat(&self) -> Result<VFS::Metadata, i64> {
+        Ok(VFS::Metadata {
+            device_id: 0,
+            inode_id: i64::MAX,
+            mode: 0o0000600, // -rw-------
+            nlinks: 1,
+            uid: 0,
+            gid: 0,
+            rdev: 0,
+            size: 0,
+            blksize: 0,
+            blocks: 0,
+
+            atime: unsafe {crate::UNIX_EPOCH as i64},
+            mtime: unsafe {crate::UNIX_EPOCH as i64},
+            ctime: unsafe {crate::UNIX_EPOCH as i64},
+            reserved1: 0,
+            reserved2: 0,
+            reserved3: 0,
+        })
+    }
+    fn GetName(&self) -> Result<&str, i64> {
+        Ok("[fox kernel eventfd]")
+    }
+    fn Read(&self, _offset: i64, buffer: &mut [u8]) -> i64 {
+        if buffer.len() < 8 {
+            return -(Errors::EINVAL as i64);
+        }
+        let mut count = self.count.lock();
+        if *count == 0 {
+            return -(Errors::EAGAIN as i64);
+        }
+        let value = if self.semaphore {1} else {*count};
+        *count -= value;
+        drop(count);
+        self.watchers.Notify(); // Writers might have room again
+        buffer[..8].copy_from_slice(&value.to_ne_bytes());
+        8
+    }
+    fn Write(&self, _offset: i64, buffer: &[u8]) -> i64 {
+        if buffer.len() < 8 {
+            return -(Errors::EINVAL as i64);
+        }
+        let value = u64::from_ne_bytes(buffer[..8].try_into().unwrap());
+        if value == u64::MAX {
+            return -(Errors::EINVAL as i64);
+        }
+        let mut count = self.count.lock();
+        if MAX_COUNT - *count < value {
+            return -(Errors::EAGAIN as i64); // Wait for a reader to make room
+        }
+        *count += value;
+        drop(count);
+        self.watchers.Notify();
+        8
+    }
+    fn Poll(&self) -> i16 {
+        let count = *self.count.lock();
+        let mut events = 0;
+        if count > 0 {
+            events |= VFS::POLLIN;
+        }
+        if count < MAX_COUNT {
+            events |= VFS::POLLOUT;
+        }
+        events
+    }
+    fn Watchers(&self) -> Option<Watchers> {
+        Some(self.watchers.clone())
+    }
+}
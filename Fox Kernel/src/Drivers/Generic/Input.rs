@@ -0,0 +1,564 @@
+use crate::FS::VFS;
+use crate::FS::DevFS;
+use alloc::sync::{Arc, Weak};
+use alloc::collections::{BTreeMap,VecDeque};
+use alloc::string::String;
+use alloc::format;
+use alloc::vec::Vec;
+use spin::{Mutex, Once};
+use core::sync::atomic::{AtomicUsize,Ordering};
+use crate::Syscall::Errors;
+use super::Mouse;
+use super::Epoll::Watchers;
+
+pub const EV_SYN: u16 = 0x00;
+pub const EV_KEY: u16 = 0x01;
+pub const EV_REL: u16 = 0x02;
+pub const EV_ABS: u16 = 0x03;
+
+pub const SYN_REPORT: u16 = 0;
+pub const SYN_DROPPED: u16 = 3;
+
+pub const REL_X: u16 = 0x00;
+pub const REL_Y: u16 = 0x01;
+pub const REL_WHEEL: u16 = 0x08;
+
+pub const ABS_X: u16 = 0x00;
+pub const ABS_Y: u16 = 0x01;
+pub const ABS_CNT: usize = 0x40;
+
+// Keycodes are numbered the same way Linux numbers them, which for the main block happens to be the set 1 make code
+pub const KEY_ESC: u16 = 1;
+pub const KEY_BACKSPACE: u16 = 14;
+pub const KEY_TAB: u16 = 15;
+pub const KEY_ENTER: u16 = 28;
+pub const KEY_LEFTCTRL: u16 = 29;
+pub const KEY_LEFTSHIFT: u16 = 42;
+pub const KEY_RIGHTSHIFT: u16 = 54;
+pub const KEY_LEFTALT: u16 = 56;
+pub const KEY_SPACE: u16 = 57;
+pub const KEY_CAPSLOCK: u16 = 58;
+pub const KEY_F1: u16 = 59;
+pub const KEY_F10: u16 = 68;
+pub const KEY_NUMLOCK: u16 = 69;
+pub const KEY_SCROLLLOCK: u16 = 70;
+pub const KEY_F11: u16 = 87;
+pub const KEY_F12: u16 = 88;
+pub const KEY_KPENTER: u16 = 96;
+pub const KEY_RIGHTCTRL: u16 = 97;
+pub const KEY_KPSLASH: u16 = 98;
+pub const KEY_SYSRQ: u16 = 99;
+pub const KEY_RIGHTALT: u16 = 100;
+pub const KEY_HOME: u16 = 102;
+pub const KEY_UP: u16 = 103;
+pub const KEY_PAGEUP: u16 = 104;
+pub const KEY_LEFT: u16 = 105;
+pub const KEY_RIGHT: u16 = 106;
+pub const KEY_END: u16 = 107;
+pub const KEY_DOWN: u16 = 108;
+pub const KEY_PAGEDOWN: u16 = 109;
+pub const KEY_INSERT: u16 = 110;
+pub const KEY_DELETE: u16 = 111;
+pub const KEY_MUTE: u16 = 113;
+pub const KEY_VOLUMEDOWN: u16 = 114;
+pub const KEY_VOLUMEUP: u16 = 115;
+pub const KEY_PAUSE: u16 = 119;
+pub const KEY_LEFTMETA: u16 = 125;
+pub const KEY_RIGHTMETA: u16 = 126;
+pub const KEY_COMPOSE: u16 = 127;
+
+pub const BTN_LEFT: u16 = 0x110;
+pub const BTN_RIGHT: u16 = 0x111;
+pub const BTN_MIDDLE: u16 = 0x112;
+pub const BTN_SIDE: u16 = 0x113;
+pub const BTN_EXTRA: u16 = 0x114;
+
+pub const KEY_CNT: usize = 0x300;
+pub const KEY_BYTES: usize = KEY_CNT / 8;
+pub const INPUT_NAME_MAX: usize = 64;
+
+pub const EVIOCGVERSION: usize = 0x7000;
+pub const EVIOCGNAME: usize = 0x7001; // Copies a NUL-terminated name into an INPUT_NAME_MAX byte buffer
+pub const EVIOCGTYPES: usize = 0x7002; // Bitmask of the EV_* types the device sends
+pub const EVIOCGKEYBITS: usize = 0x7003; // KEY_BYTES bitmap of keys and buttons the device has
+pub const EVIOCGRELBITS: usize = 0x7004;
+pub const EVIOCGABSBITS: usize = 0x7005;
+pub const EVIOCGKEY: usize = 0x7006; // KEY_BYTES bitmap of keys and buttons held down right now
+pub const EVIOCGABS: usize = 0x7040; // Plus the axis, fills in an AbsInfo
+
+const EV_VERSION: usize = 0x010001;
+const QUEUE_MAX: usize = 256;
+
+#[repr(C)]
+#[derive(Clone,Copy,Default)]
+pub struct InputEvent {
+    pub sec: i64,
+    pub usec: i64,
+    pub kind: u16,
+    pub code: u16,
+    pub value: i32,
+}
+
+const EVENT_SIZE: usize = core::mem::size_of::<InputEvent>();
+
+#[repr(C)]
+#[derive(Clone,Copy,Default)]
+pub struct AbsInfo {
+    pub value: i32,
+    pub minimum: i32,
+    pub maximum: i32,
+    pub fuzz: i32,
+    pub flat: i32,
+    pub resolution: i32,
+}
+
+// What a device can send, fixed when it registers
+#[derive(Clone)]
+pub struct Capabilities {
+    pub keys: [u8; KEY_BYTES],
+    pub rel: u32,
+    pub abs: u64,
+    pub absinfo: BTreeMap<u16,AbsInfo>,
+}
+
+impl Capabilities {
+    pub fn new() -> Self {
+        Self {keys: [0; KEY_BYTES], rel: 0, abs: 0, absinfo: BTreeMap::new()}
+    }
+    pub fn Key(mut self, code: u16) -> Self {
+        self.keys[code as usize / 8] |= 1 << (code % 8);
+        self
+    }
+    pub fn Keys(mut self, codes: core::ops::RangeInclusive<u16>) -> Self {
+        for code in codes {
+            self = self.Key(code);
+        }
+        self
+    }
+    pub fn Rel(mut self, axis: u16) -> Self {
+        self.rel |= 1 << axis;
+        self
+    }
+    pub fn Abs(mut self, axis: u16, minimum: i32, maximum: i32) -> Self {
+        self.abs |= 1 << axis;
+        self.absinfo.insert(axis,AbsInfo {minimum, maximum, ..Default::default()});
+        self
+    }
+    fn Types(&self) -> u32 {
+        let mut types = 1 << EV_SYN;
+        if self.keys.iter().any(|b| *b != 0) {types |= 1 << EV_KEY;}
+        if self.rel != 0 {types |= 1 << EV_REL;}
+        if self.abs != 0 {types |= 1 << EV_ABS;}
+        types
+    }
+}
+
+struct DeviceState {
+    keys: [u8; KEY_BYTES],
+    abs: [i32; ABS_CNT],
+    pending: Vec<InputEvent>, // Everything since the last SYN_REPORT, which clients only get to see all at once
+    clients: Vec<Weak<EventClient>>,
+}
+
+pub struct InputDevice {
+    node: String,
+    name: String,
+    caps: Capabilities,
+    state: Mutex<DeviceState>,
+}
+
+impl InputDevice {
+    pub fn Name(&self) -> &str {
+        self.name.as_str()
+    }
+    pub fn IsPressed(&self, code: u16) -> bool {
+        self.state.lock().keys[code as usize / 8] & (1 << (code % 8)) != 0
+    }
+    // Queues one event. Pressing a key that's already down counts as autorepeat, and gets a value of 2.
+    pub fn Report(&self, kind: u16, code: u16, value: i32) {
+        let mut state = self.state.lock();
+        let mut value = value;
+        match kind {
+            EV_KEY if (code as usize) < KEY_CNT => {
+                let (byte, bit) = (code as usize / 8, 1 << (code % 8));
+                if value != 0 {
+                    if state.keys[byte] & bit != 0 {
+                        value = 2;
+                    }
+                    state.keys[byte] |= bit;
+                } else {
+                    if state.keys[byte] & bit == 0 {
+                        return;
+                    }
+                    state.keys[byte] &= !bit;
+                }
+            }
+            EV_ABS if (code as usize) < ABS_CNT => {
+                if state.abs[code as usize] == value {
+                    return;
+                }
+                state.abs[code as usize] = value;
+            }
+            _ => {}
+        }
+        let (sec, nsec) = crate::arch::Timer::GetTimeStamp();
+        state.pending.push(InputEvent {sec, usec: nsec / 1000, kind, code, value});
+    }
+    pub fn Key(&self, code: u16, pressed: bool) {
+        self.Report(EV_KEY,code,pressed as i32);
+    }
+    // Hands everything reported since last time to whoever has the device open, and to the kernel's own handlers
+    pub fn Sync(&self) {
+        let mut state = self.state.lock();
+        if state.pending.is_empty() {
+            return;
+        }
+        let (sec, nsec) = crate::arch::Timer::GetTimeStamp();
+        state.pending.push(InputEvent {sec, usec: nsec / 1000, kind: EV_SYN, code: SYN_REPORT, value: 0});
+        let events = core::mem::take(&mut state.pending);
+        state.clients.retain(|c| c.strong_count() > 0);
+        for client in state.clients.iter().filter_map(|c| c.upgrade()) {
+            client.Deliver(events.as_slice());
+        }
+        drop(state);
+        let handlers = HANDLERS.lock().clone();
+        for event in events.iter() {
+            for handler in handlers.iter() {
+                handler(self,event);
+            }
+        }
+    }
+    // Lets go of every key and button, for devices that are about to disappear
+    pub fn ReleaseAll(&self) {
+        let keys = self.state.lock().keys;
+        for code in 0..KEY_CNT {
+            if keys[code / 8] & (1 << (code % 8)) != 0 {
+                self.Key(code as u16,false);
+            }
+        }
+        self.Sync();
+    }
+    // Reports a pointer packet from a mouse driver. `absolute` devices (tablets, touchscreens) put their position in dx and dy.
+    pub fn ReportPointer(&self, packet: &Mouse::MousePacket, absolute: bool) {
+        for (i, button) in [BTN_LEFT,BTN_RIGHT,BTN_MIDDLE,BTN_SIDE,BTN_EXTRA].iter().enumerate() {
+            let pressed = packet.buttons & (1 << i) != 0;
+            if pressed != self.IsPressed(*button) {
+                self.Key(*button,pressed);
+            }
+        }
+        if absolute {
+            self.Report(EV_ABS,ABS_X,packet.dx);
+            self.Report(EV_ABS,ABS_Y,packet.dy);
+        } else {
+            if packet.dx != 0 {self.Report(EV_REL,REL_X,packet.dx);}
+            if packet.dy != 0 {self.Report(EV_REL,REL_Y,packet.dy);}
+        }
+        if packet.wheel != 0 {
+            self.Report(EV_REL,REL_WHEEL,-packet.wheel); // Positive scrolls up here
+        }
+        self.Sync();
+    }
+}
+
+// One open of /dev/input/eventN. Every reader gets its own copy of each event.
+pub struct EventClient {
+    dev: Arc<InputDevice>,
+    queue: Mutex<VecDeque<InputEvent>>,
+    this: Weak<EventClient>,
+    watchers: Watchers,
+}
+
+impl EventClient {
+    fn Deliver(&self, events: &[InputEvent]) {
+        let mut queue = self.queue.lock();
+        // Rather than silently losing half a report, tell the reader to throw away what it has and resync with EVIOCGKEY
+        if queue.len() + events.len() > QUEUE_MAX {
+            queue.clear();
+            let (sec, nsec) = crate::arch::Timer::GetTimeStamp();
+            queue.push_back(InputEvent {sec, usec: nsec / 1000, kind: EV_SYN, code: SYN_DROPPED, value: 0});
+        } else {
+            queue.extend(events.iter().copied());
+        }
+        drop(queue);
+        self.watchers.Notify();
+    }
+}
+
+impl VFS::Inode for EventClient {
+    fn Stat(&self) -> Result<VFS::Metadata, i64> {
+        Ok(VFS::Metadata {
+            device_id: 0,
+            inode_id: i64::MAX,
+            mode: 0o0020660, // crw-rw----
+            nlinks: 1,
+            uid: 0,
+            gid: 0,
+            rdev: 0,
+            size: 0,
+            blksize: 0,
+            blocks: 0,
+
+            atime: unsafe {crate::UNIX_EPOCH as i64},
+            mtime: unsafe {crate::UNIX_EPOCH as i64},
+            ctime: unsafe {crate::UNIX_EPOCH as i64},
+            reserved1: 0,
+            reserved2: 0,
+            reserved3: 0,
+        })
+    }
+    fn GetName(&self) -> Result<&str, i64> {
+        Ok(self.dev.node.as_str())
+    }
+    fn GetParent(&self) -> Option<Arc<dyn VFS::Inode>> {
+        INPUTDIR.get().map(|d| d.clone() as Arc<dyn VFS::Inode>)
+    }
+    fn Open(&self, _mode: usize) -> Result<(), i64> {
+        let mut state = self.dev.state.lock();
+        if !state.clients.iter().any(|c| c.ptr_eq(&self.this)) {
+            state.clients.push(self.this.clone());
+        }
+        Ok(())
+    }
+    fn Close(&self) {}
+    // Only ever hands out whole events
+    fn Read(&self, _offset: i64, buffer: &mut [u8]) -> i64 {
+        if buffer.len() < EVENT_SIZE {
+            return -(Errors::EINVAL as i64);
+        }
+        let mut queue = self.queue.lock();
+        let mut i = 0;
+        while i + EVENT_SIZE <= buffer.len() {
+            let event = match queue.pop_front() {
+                Some(e) => e,
+                None => {break;}
+            };
+            let raw = unsafe {core::slice::from_raw_parts(&event as *const InputEvent as *const u8,EVENT_SIZE)};
+            buffer[i..i+EVENT_SIZE].copy_from_slice(raw);
+            i += EVENT_SIZE;
+        }
+        i as i64
+    }
+    fn Write(&self, _offset: i64, _buffer: &[u8]) -> i64 {
+        -(Errors::EBADF as i64)
+    }
+    fn IOCtl(&self, cmd: usize, arg: usize) -> Result<usize, i64> {
+        let caps = &self.dev.caps;
+        match cmd {
+            EVIOCGVERSION => Ok(EV_VERSION),
+            EVIOCGNAME => {
+                let name = self.dev.name.as_bytes();
+                let len = core::cmp::min(name.len(),INPUT_NAME_MAX-1);
+                let out = unsafe {core::slice::from_raw_parts_mut(arg as *mut u8,len+1)};
+                out[..len].copy_from_slice(&name[..len]);
+                out[len] = 0;
+                Ok(len)
+            }
+            EVIOCGTYPES => Ok(caps.Types() as usize),
+            EVIOCGKEYBITS => {
+                unsafe {core::ptr::copy(caps.keys.as_ptr(),arg as *mut u8,KEY_BYTES);}
+                Ok(KEY_BYTES)
+            }
+            EVIOCGRELBITS => Ok(caps.rel as usize),
+            EVIOCGABSBITS => Ok(caps.abs as usize),
+            EVIOCGKEY => {
+                let keys = self.dev.state.lock().keys;
+                unsafe {core::ptr::copy(keys.as_ptr(),arg as *mut u8,KEY_BYTES);}
+                Ok(KEY_BYTES)
+            }
+            _ if cmd >= EVIOCGABS && cmd < EVIOCGABS + ABS_CNT => {
+                let axis = (cmd - EVIOCGABS) as u16;
+                let mut info = *caps.absinfo.get(&axis).ok_or(Errors::EINVAL as i64)?;
+                info.value = self.dev.state.lock().abs[axis as usize];
+                unsafe {*(arg as *mut AbsInfo) = info;}
+                Ok(0)
+            }
+            _ => Err(Errors::ENOTTY as i64),
+        }
+    }
+    fn Poll(&self) -> i16 {
+        if self.queue.lock().is_empty() {0} else {VFS::POLLIN}
+    }
+    fn Watchers(&self) -> Option<Watchers> {
+        Some(self.watchers.clone())
+    }
+}
+
+fn NewClient(dev: &Arc<InputDevice>) -> Arc<EventClient> {
+    Arc::new_cyclic(|this| EventClient {
+        dev: dev.clone(),
+        queue: Mutex::new(VecDeque::new()),
+        this: this.clone(),
+        watchers: Watchers::new(),
+    })
+}
+
+static DEVICES: Mutex<Vec<Arc<InputDevice>>> = Mutex::new(Vec::new());
+static HANDLERS: Mutex<Vec<fn(&InputDevice, &InputEvent)>> = Mutex::new(Vec::new());
+static NEXT_EVENT: AtomicUsize = AtomicUsize::new(0);
+
+struct InputDir(usize);
+
+impl DevFS::Device for InputDir {
+    fn DeviceID(&self) -> usize {
+        self.0
+    }
+    fn Inode(&self) -> Arc<dyn VFS::Inode> {
+        INPUTDIR.get().expect("device not ready").clone()
+    }
+}
+
+impl VFS::Inode for InputDir {
+    fn Stat(&self) -> Result<VFS::Metadata, i64> {
+        Ok(VFS::Metadata {
+            device_id: 0,
+            inode_id: i64::MAX,
+            mode: 0o0040555, // dr-xr-xr-x
+            nlinks: 1,
+            uid: 0,
+            gid: 0,
+            rdev: 0,
+            size: 0,
+            blksize: 0,
+            blocks: 0,
+
+            atime: unsafe {crate::UNIX_EPOCH as i64},
+            mtime: unsafe {crate::UNIX_EPOCH as i64},
+            ctime: unsafe {crate::UNIX_EPOCH as i64},
+            reserved1: 0,
+            reserved2: 0,
+            reserved3: 0,
+        })
+    }
+    fn GetName(&self) -> Result<&str, i64> {
+        Ok("input")
+    }
+    fn GetParent(&self) -> Option<Arc<dyn VFS::Inode>> {
+        Some(VFS::FindMount("/dev").ok().unwrap().1.GetRootInode())
+    }
+    // Each lookup makes a fresh client, so every open gets its own queue
+    fn Lookup(&self, name: &str) -> Result<Arc<dyn VFS::Inode>, i64> {
+        let lock = DEVICES.lock();
+        match lock.iter().find(|d| d.node == name) {
+            Some(dev) => Ok(NewClient(dev)),
+            None => Err(Errors::ENOENT as i64),
+        }
+    }
+    fn ReadDir(&self, index: usize) -> Result<Option<Arc<dyn VFS::Inode>>, i64> {
+        Ok(DEVICES.lock().get(index).map(|d| NewClient(d) as Arc<dyn VFS::Inode>))
+    }
+    fn Open(&self, _mode: usize) -> Result<(), i64> {
+        Ok(())
+    }
+    fn Close(&self) {}
+}
+
+static INPUTDIR: Once<Arc<InputDir>> = Once::new();
+
+pub fn Register(name: &str, caps: Capabilities) -> Arc<InputDevice> {
+    let dev = Arc::new(InputDevice {
+        node: format!("event{}", NEXT_EVENT.fetch_add(1,Ordering::SeqCst)),
+        name: String::from(name),
+        caps,
+        state: Mutex::new(DeviceState {keys: [0; KEY_BYTES], abs: [0; ABS_CNT], pending: Vec::new(), clients: Vec::new()}),
+    });
+    log::info!("/dev/input/{}: {}", dev.node, dev.name);
+    DEVICES.lock().push(dev.clone());
+    dev
+}
+
+pub fn Unregister(dev: &Arc<InputDevice>) {
+    dev.ReleaseAll();
+    DEVICES.lock().retain(|d| !Arc::ptr_eq(d,dev));
+}
+
+// For consumers inside the kernel, like the virtual terminals. Handlers see every event from every device, and can be
+// called from an IRQ handler, so they shouldn't take long.
+pub fn AddHandler(handler: fn(&InputDevice, &InputEvent)) {
+    HANDLERS.lock().push(handler);
+}
+
+// Everything a regular PC keyboard can send
+pub fn KeyboardCapabilities() -> Capabilities {
+    Capabilities::new().Keys(KEY_ESC..=KEY_F12).Keys(KEY_KPENTER..=KEY_COMPOSE)
+}
+
+pub fn MouseCapabilities(wheel: bool) -> Capabilities {
+    let caps = Capabilities::new().Keys(BTN_LEFT..=BTN_EXTRA).Rel(REL_X).Rel(REL_Y);
+    if wheel {caps.Rel(REL_WHEEL)} else {caps}
+}
+
+// Scan code set 1 to keycode. Extended codes are 0xE0xx, and 0 means there's no key for it.
+pub fn Set1Keycode(code: u16) -> u16 {
+    if code >> 8 != 0xE0 {
+        return if code >= 1 && code <= KEY_F12 {code} else {0};
+    }
+    match code as u8 {
+        0x1C => KEY_KPENTER,
+        0x1D => KEY_RIGHTCTRL,
+        0x20 => KEY_MUTE,
+        0x2E => KEY_VOLUMEDOWN,
+        0x30 => KEY_VOLUMEUP,
+        0x35 => KEY_KPSLASH,
+        0x37 => KEY_SYSRQ,
+        0x38 => KEY_RIGHTALT,
+        0x47 => KEY_HOME,
+        0x48 => KEY_UP,
+        0x49 => KEY_PAGEUP,
+        0x4B => KEY_LEFT,
+        0x4D => KEY_RIGHT,
+        0x4F => KEY_END,
+        0x50 => KEY_DOWN,
+        0x51 => KEY_PAGEDOWN,
+        0x52 => KEY_INSERT,
+        0x53 => KEY_DELETE,
+        0x5B => KEY_LEFTMETA,
+        0x5C => KEY_RIGHTMETA,
+        0x5D => KEY_COMPOSE,
+        _ => 0,
+    }
+}
+
+// Turns a stream of set 1 bytes back into key presses, for keyboards that only speak scan codes
+#[derive(Default)]
+pub struct Set1Decoder {
+    extended: bool,
+    pause: u8, // Bytes left to swallow of the Pause sequence (E1 1D 45 E1 9D C5)
+}
+
+impl Set1Decoder {
+    pub const fn new() -> Self {
+        Self {extended: false, pause: 0}
+    }
+    pub fn Feed(&mut self, byte: u8) -> Option<(u16,bool)> {
+        // Pause sends its press and release together and never repeats, so let go of it once the sequence is over
+        if self.pause > 0 {
+            self.pause -= 1;
+            return if self.pause == 0 {Some((KEY_PAUSE,false))} else {None};
+        }
+        match byte {
+            0xE0 => {
+                self.extended = true;
+                None
+            }
+            0xE1 => {
+                self.pause = 5;
+                Some((KEY_PAUSE,true))
+            }
+            _ => {
+                let extended = core::mem::replace(&mut self.extended,false);
+                let make = (byte & 0x7F) as u16;
+                // Print Screen and friends wrap themselves in fake shift presses (E0 2A, E0 36), those aren't real keys
+                if extended && (make == 0x2A || make == 0x36) {
+                    return None;
+                }
+                let keycode = Set1Keycode(if extended {0xE000 | make} else {make});
+                if keycode == 0 {None} else {Some((keycode,byte & 0x80 == 0))}
+            }
+        }
+    }
+}
+
+pub fn Initalize() {
+    let dir = INPUTDIR.call_once(|| Arc::new(InputDir(DevFS::ReserveDeviceID())));
+    DevFS::InstallDevice(dir.clone());
+}
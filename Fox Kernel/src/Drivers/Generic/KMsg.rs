@@ -0,0 +1,140 @@
+use crate::FS::VFS;
+use crate::FS::DevFS;
+use crate::KernelLog;
+use crate::Syscall::Errors;
+use alloc::sync::Arc;
+use alloc::string::String;
+use alloc::format;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64,Ordering};
+
+const MAX_WRITE: usize = 1024;
+
+struct KMsgDev(usize);
+impl KMsgDev {
+    fn new() -> Arc<Self> {
+        Arc::new(Self(DevFS::ReserveDeviceID()))
+    }
+}
+impl DevFS::Device for KMsgDev {
+    fn DeviceID(&self) -> usize {
+        self.0
+    }
+    fn Inode(&self) -> Arc<dyn VFS::Inode> {
+        // Every open gets its own place in the log, starting at the oldest record still around
+        Arc::new(KMsg {seq: AtomicU64::new(KernelLog::LOG.lock().First())})
+    }
+}
+
+struct KMsg {
+    seq: AtomicU64,
+}
+
+// One record the way Linux's /dev/kmsg hands it out: "priority,sequence,microseconds,flags;message", then the
+// dictionary on indented lines
+fn Format(record: &KernelLog::LogRecord) -> Vec<u8> {
+    let mut out = format!("{},{},{},-;", record.level, record.seq, record.time).into_bytes();
+    for b in record.Text().bytes() {
+        if b < b' ' || b >= 0x7f || b == b'\\' {
+            out.extend_from_slice(format!("\\x{:02x}", b).as_bytes());
+        } else {
+            out.push(b);
+        }
+    }
+    out.extend_from_slice(format!("\n MODULE={}\n HART={}\n", record.Module(), record.hart).as_bytes());
+    out
+}
+
+impl VFS::Inode for KMsg {
+    fn Stat(&self) -> Result<VFS::Metadata, i64> {
+        Ok(VFS::Metadata {
+            device_id: 0,
+            inode_id: i64::MAX,
+            mode: 0o0020644, // crw-r--r--
+            nlinks: 1,
+            uid: 0,
+            gid: 0,
+            rdev: 0,
+            size: 0,
+            blksize: 0,
+            blocks: 0,
+
+            atime: unsafe {crate::UNIX_EPOCH as i64},
+            mtime: unsafe {crate::UNIX_EPOCH as i64},
+            ctime: unsafe {crate::UNIX_EPOCH as i64},
+            reserved1: 0,
+            reserved2: 0,
+            reserved3: 0,
+        })
+    }
+    fn GetName(&self) -> Result<&str, i64> {
+        Ok("kmsg")
+    }
+    // One record per read, like Linux
+    fn Read(&self, _offset: i64, buffer: &mut [u8]) -> i64 {
+        let seq = self.seq.load(Ordering::SeqCst);
+        let lock = KernelLog::LOG.lock();
+        if seq < lock.First() {
+            // We fell behind and the ring lapped us, say so once and pick up at the oldest record left
+            self.seq.store(lock.First(),Ordering::SeqCst);
+            return -(Errors::EPIPE as i64);
+        }
+        let record = match lock.Get(seq) {
+            Some(r) => *r,
+            None => return -(Errors::EAGAIN as i64),
+        };
+        drop(lock);
+        let text = Format(&record);
+        if buffer.len() < text.len() {
+            return -(Errors::EINVAL as i64);
+        }
+        buffer[..text.len()].copy_from_slice(text.as_slice());
+        self.seq.store(seq+1,Ordering::SeqCst);
+        text.len() as i64
+    }
+    fn Write(&self, _offset: i64, buffer: &[u8]) -> i64 {
+        if buffer.len() > MAX_WRITE {
+            return -(Errors::EINVAL as i64);
+        }
+        WriteCurrent(&String::from_utf8_lossy(buffer));
+        buffer.len() as i64
+    }
+    fn Seek(&self, offset: i64, whence: usize) -> Option<Result<i64, i64>> {
+        if offset != 0 {
+            return Some(Err(Errors::ESPIPE as i64));
+        }
+        let lock = KernelLog::LOG.lock();
+        match whence {
+            2 => self.seq.store(lock.Next(),Ordering::SeqCst), // SEEK_END, only new records from here on
+            3 => self.seq.store(lock.First(),Ordering::SeqCst), // SEEK_SET
+            _ => return Some(Err(Errors::EINVAL as i64)),
+        }
+        drop(lock);
+        Some(Ok(0))
+    }
+    fn Poll(&self) -> i16 {
+        let lock = KernelLog::LOG.lock();
+        let seq = self.seq.load(Ordering::SeqCst);
+        let mut events = VFS::POLLOUT;
+        if seq < lock.First() {
+            events |= VFS::POLLIN | VFS::POLLERR;
+        } else if seq < lock.Next() {
+            events |= VFS::POLLIN;
+        }
+        drop(lock);
+        events
+    }
+}
+
+// Records a userspace message under the name of the process that sent it
+pub fn WriteCurrent(msg: &str) {
+    let pid = crate::Scheduler::Scheduler::CurrentPID();
+    let lock = crate::Process::PROCESSES.lock();
+    let name = lock.get(&pid).map_or(String::from("unknown"),|p| String::from(p.name.rsplit('/').next().unwrap()));
+    drop(lock);
+    KernelLog::WriteUser(name.as_str(),msg);
+}
+
+pub fn Initalize() {
+    DevFS::InstallDevice(KMsgDev::new());
+}
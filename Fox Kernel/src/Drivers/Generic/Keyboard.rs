@@ -1,5 +1,6 @@
 use alloc::sync::Arc;
-use spin::Once;
+use alloc::collections::VecDeque;
+use spin::{Mutex, Once};
 use crate::FS::DevFS;
 use crate::FS::VFS;
 
@@ -8,6 +9,28 @@ pub trait Keyboard: Send + Sync {
     fn CanRead(&self) -> bool;
 }
 
+// Every keyboard driver feeds scan code set 1 into here, so /dev/kbd looks the same no matter where a key came from
+static SCANCODES: Mutex<VecDeque<u8>> = Mutex::new(VecDeque::new());
+
+pub fn Push(code: u8) {
+    let mut lock = SCANCODES.lock();
+    if lock.len() >= 128 {
+        lock.pop_front();
+    }
+    lock.push_back(code);
+}
+
+pub struct ScancodeQueue;
+
+impl Keyboard for ScancodeQueue {
+    fn Read(&self) -> Option<u8> {
+        SCANCODES.lock().pop_front()
+    }
+    fn CanRead(&self) -> bool {
+        !SCANCODES.lock().is_empty()
+    }
+}
+
 pub struct KeyboardDevice(usize);
 
 impl DevFS::Device for KeyboardDevice {
@@ -55,13 +78,33 @@ impl VFS::Inode for KeyboardDevice {
         }
         return buffer.len() as i64;
     }
+    fn Poll(&self) -> i16 {
+        if KEYBOARD.get().unwrap().CanRead() {VFS::POLLIN} else {0}
+    }
 }
 
 pub static KEYBOARD: Once<Arc<dyn Keyboard>> = Once::new();
 pub static KEYBOARD_DEV: Once<Arc<KeyboardDevice>> = Once::new();
 
+fn InstallDevice() {
+    let mut new = false;
+    let dev = KEYBOARD_DEV.call_once(|| {
+        new = true;
+        Arc::new(KeyboardDevice(DevFS::ReserveDeviceID()))
+    });
+    if new {
+        DevFS::InstallDevice(dev.clone());
+    }
+}
+
+// For keyboards that show up after boot, like USB ones. Makes sure there's a /dev/kbd for them to feed.
+pub fn Attach() {
+    KEYBOARD.call_once(|| Arc::new(ScancodeQueue));
+    InstallDevice();
+}
+
 pub fn Initalize() {
     if KEYBOARD.get().is_some() {
-        DevFS::InstallDevice(KEYBOARD_DEV.call_once(|| Arc::new(KeyboardDevice(DevFS::ReserveDeviceID()))).clone());
+        InstallDevice();
     }
 }
\ No newline at end of file
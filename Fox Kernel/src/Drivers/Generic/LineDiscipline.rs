@@ -0,0 +1,583 @@
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use crate::Syscall::Errors;
+use crate::Process::Signals;
+use super::PseudoTTY::{TCGETS,TCSETS,TCSETSW,TCSETSF,TCFLSH,TCXONC,TIOCGPGRP,TIOCSPGRP};
+
+pub const NCCS: usize = 19;
+
+// c_iflag
+pub const IGNBRK: u32 = 0o000001;
+pub const BRKINT: u32 = 0o000002;
+pub const IGNPAR: u32 = 0o000004;
+pub const PARMRK: u32 = 0o000010;
+pub const INPCK: u32 = 0o000020;
+pub const ISTRIP: u32 = 0o000040;
+pub const INLCR: u32 = 0o000100;
+pub const IGNCR: u32 = 0o000200;
+pub const ICRNL: u32 = 0o000400;
+pub const IXON: u32 = 0o002000;
+pub const IXOFF: u32 = 0o010000;
+pub const IUTF8: u32 = 0o040000;
+
+// c_oflag
+pub const OPOST: u32 = 0o000001;
+pub const ONLCR: u32 = 0o000004;
+pub const OCRNL: u32 = 0o000010;
+pub const ONOCR: u32 = 0o000020;
+pub const ONLRET: u32 = 0o000040;
+
+// c_cflag, the baud rate is kept in the CBAUD bits
+pub const CBAUD: u32 = 0o010017;
+pub const B0: u32 = 0o000000;
+pub const B50: u32 = 0o000001;
+pub const B75: u32 = 0o000002;
+pub const B110: u32 = 0o000003;
+pub const B134: u32 = 0o000004;
+pub const B150: u32 = 0o000005;
+pub const B200: u32 = 0o000006;
+pub const B300: u32 = 0o000007;
+pub const B600: u32 = 0o000010;
+pub const B1200: u32 = 0o000011;
+pub const B1800: u32 = 0o000012;
+pub const B2400: u32 = 0o000013;
+pub const B4800: u32 = 0o000014;
+pub const B9600: u32 = 0o000015;
+pub const B19200: u32 = 0o000016;
+pub const B38400: u32 = 0o000017;
+pub const B57600: u32 = 0o010001;
+pub const B115200: u32 = 0o010002;
+pub const CSIZE: u32 = 0o000060;
+pub const CS5: u32 = 0o000000;
+pub const CS6: u32 = 0o000020;
+pub const CS7: u32 = 0o000040;
+pub const CS8: u32 = 0o000060;
+pub const CSTOPB: u32 = 0o000100;
+pub const CREAD: u32 = 0o000200;
+pub const PARENB: u32 = 0o000400;
+pub const PARODD: u32 = 0o001000;
+pub const HUPCL: u32 = 0o002000;
+pub const CLOCAL: u32 = 0o004000;
+
+// c_lflag
+pub const ISIG: u32 = 0o000001;
+pub const ICANON: u32 = 0o000002;
+pub const ECHO: u32 = 0o000010;
+pub const ECHOE: u32 = 0o000020;
+pub const ECHOK: u32 = 0o000040;
+pub const ECHONL: u32 = 0o000100;
+pub const NOFLSH: u32 = 0o000200;
+pub const TOSTOP: u32 = 0o000400;
+pub const ECHOCTL: u32 = 0o001000;
+pub const ECHOKE: u32 = 0o004000;
+pub const IEXTEN: u32 = 0o100000;
+
+// c_cc, a character of 0 turns that function off
+pub const VINTR: usize = 0;
+pub const VQUIT: usize = 1;
+pub const VERASE: usize = 2;
+pub const VKILL: usize = 3;
+pub const VEOF: usize = 4;
+pub const VTIME: usize = 5;
+pub const VMIN: usize = 6;
+pub const VSTART: usize = 8;
+pub const VSTOP: usize = 9;
+pub const VSUSP: usize = 10;
+pub const VEOL: usize = 11;
+pub const VREPRINT: usize = 12;
+pub const VDISCARD: usize = 13;
+pub const VWERASE: usize = 14;
+pub const VLNEXT: usize = 15;
+pub const VEOL2: usize = 16;
+
+pub const TCIFLUSH: usize = 0;
+pub const TCOFLUSH: usize = 1;
+pub const TCIOFLUSH: usize = 2;
+
+const LINE_MAX: usize = 4095;
+const INPUT_MAX: usize = 4096;
+
+#[repr(C)]
+#[derive(Clone,Copy)]
+pub struct Termios {
+    pub c_iflag: u32,
+    pub c_oflag: u32,
+    pub c_cflag: u32,
+    pub c_lflag: u32,
+    pub c_line: u8,
+    pub c_cc: [u8; NCCS],
+}
+
+impl Termios {
+    pub const fn new() -> Self {
+        let mut c_cc = [0; NCCS];
+        c_cc[VINTR] = 0x03; // ^C
+        c_cc[VQUIT] = 0x1C; // ^\
+        c_cc[VERASE] = 0x7F;
+        c_cc[VKILL] = 0x15; // ^U
+        c_cc[VEOF] = 0x04; // ^D
+        c_cc[VMIN] = 1;
+        c_cc[VSTART] = 0x11; // ^Q
+        c_cc[VSTOP] = 0x13; // ^S
+        c_cc[VSUSP] = 0x1A; // ^Z
+        c_cc[VREPRINT] = 0x12; // ^R
+        c_cc[VDISCARD] = 0x0F; // ^O
+        c_cc[VWERASE] = 0x17; // ^W
+        c_cc[VLNEXT] = 0x16; // ^V
+        Self {
+            c_iflag: ICRNL | IUTF8,
+            c_oflag: OPOST | ONLCR,
+            c_cflag: B38400 | CS8 | CREAD | HUPCL,
+            c_lflag: ISIG | ICANON | ECHO | ECHOE | ECHOK | ECHOCTL | ECHOKE | IEXTEN,
+            c_line: 0,
+            c_cc,
+        }
+    }
+    fn Is(&self, c: u8, index: usize) -> bool {
+        self.c_cc[index] != 0 && self.c_cc[index] == c
+    }
+}
+
+// What Input made of some typed bytes: what to echo back, and signals for the foreground group
+pub struct Typed {
+    pub echo: Vec<u8>,
+    pgrp: i32,
+    signals: Vec<u8>,
+}
+
+impl Typed {
+    // Only call this once the line discipline is unlocked
+    pub fn Signal(&self) {
+        if self.pgrp > 0 {
+            for sig in self.signals.iter() {
+                crate::Process::Process::SignalGroup(self.pgrp,*sig);
+            }
+        }
+    }
+}
+
+// Sits between a terminal and the programs reading from it. The terminal side hands it whatever was typed and sends
+// back the echo it returns, the program side reads lines (or bytes, in raw mode) out of it.
+pub struct LineDiscipline {
+    pub termios: Termios,
+    pub pgrp: i32, // The foreground process group, which gets the signals. Nobody gets them while it's 0.
+    line: Vec<u8>, // The line being edited in canonical mode
+    ready: VecDeque<u8>, // What the program can read
+    lines: VecDeque<usize>, // Lengths of the lines in ready, in canonical mode. EOF counts as an empty line.
+    literal: bool, // The last character was VLNEXT
+    last_input: u64,
+    deadline: Option<u64>, // When a read with VTIME gives up
+}
+
+impl LineDiscipline {
+    pub const fn new() -> Self {
+        Self {
+            termios: Termios::new(),
+            pgrp: 0,
+            line: Vec::new(),
+            ready: VecDeque::new(),
+            lines: VecDeque::new(),
+            literal: false,
+            last_input: 0,
+            deadline: None,
+        }
+    }
+    fn Canonical(&self) -> bool {
+        self.termios.c_lflag & ICANON != 0
+    }
+    // Applies the output flags to what a program writes
+    pub fn Output(&self, buffer: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(buffer.len());
+        if self.termios.c_oflag & OPOST == 0 {
+            out.extend_from_slice(buffer);
+            return out;
+        }
+        for b in buffer.iter() {
+            match *b {
+                b'\n' if self.termios.c_oflag & ONLCR != 0 => out.extend_from_slice(b"\r\n"),
+                b'\r' if self.termios.c_oflag & OCRNL != 0 => out.push(b'\n'),
+                b => out.push(b),
+            }
+        }
+        out
+    }
+    fn Echo(&self, c: u8, out: &mut Vec<u8>) {
+        if self.termios.c_lflag & ECHOCTL != 0 && ((c < 0x20 && c != b'\t' && c != b'\n') || c == 0x7F) {
+            out.push(b'^');
+            out.push(c ^ 0x40);
+        } else {
+            out.push(c);
+        }
+    }
+    // Takes the last character off the line, and rubs it out on the screen too
+    fn Rubout(&mut self, out: &mut Vec<u8>) -> Option<u8> {
+        let mut c = self.line.pop()?;
+        if self.termios.c_iflag & IUTF8 != 0 {
+            while c & 0xC0 == 0x80 && !self.line.is_empty() {
+                c = self.line.pop().unwrap();
+            }
+        }
+        if self.termios.c_lflag & ECHO != 0 && self.termios.c_lflag & ECHOE != 0 {
+            let width = if self.termios.c_lflag & ECHOCTL != 0 && ((c < 0x20 && c != b'\t') || c == 0x7F) {2} else {1};
+            for _ in 0..width {
+                out.extend_from_slice(b"\x08 \x08");
+            }
+        }
+        Some(c)
+    }
+    fn Commit(&mut self) {
+        self.lines.push_back(self.line.len());
+        self.ready.extend(self.line.drain(..));
+    }
+    fn Flush(&mut self) {
+        self.line.clear();
+        self.ready.clear();
+        self.lines.clear();
+    }
+    // Handles one typed character, returning the signal it should raise if it's one of the special ones
+    fn Receive(&mut self, c: u8, out: &mut Vec<u8>) -> Option<u8> {
+        let termios = self.termios;
+        let echo = termios.c_lflag & ECHO != 0;
+        let mut c = c;
+        if termios.c_iflag & ISTRIP != 0 {
+            c &= 0x7F;
+        }
+        if self.literal {
+            self.literal = false;
+            if echo {
+                out.extend_from_slice(b"\x08\x08");
+            }
+        } else {
+            if c == b'\r' {
+                if termios.c_iflag & IGNCR != 0 {
+                    return None;
+                } else if termios.c_iflag & ICRNL != 0 {
+                    c = b'\n';
+                }
+            } else if c == b'\n' && termios.c_iflag & INLCR != 0 {
+                c = b'\r';
+            }
+            if termios.c_lflag & ISIG != 0 {
+                let sig = if termios.Is(c,VINTR) {
+                    Some(Signals::SIGINT)
+                } else if termios.Is(c,VQUIT) {
+                    Some(Signals::SIGQUIT)
+                } else if termios.Is(c,VSUSP) {
+                    Some(Signals::SIGTSTP)
+                } else {
+                    None
+                };
+                if sig.is_some() {
+                    if termios.c_lflag & NOFLSH == 0 {
+                        self.Flush();
+                    }
+                    if echo {
+                        self.Echo(c,out);
+                        out.extend(self.Output(b"\n"));
+                    }
+                    return sig;
+                }
+            }
+            if termios.c_lflag & ICANON != 0 {
+                if termios.Is(c,VERASE) {
+                    self.Rubout(out);
+                    return None;
+                }
+                if termios.Is(c,VWERASE) && termios.c_lflag & IEXTEN != 0 {
+                    while self.line.last().map_or(false, |b| *b == b' ' || *b == b'\t') {
+                        self.Rubout(out);
+                    }
+                    while self.line.last().map_or(false, |b| *b != b' ' && *b != b'\t') {
+                        self.Rubout(out);
+                    }
+                    return None;
+                }
+                if termios.Is(c,VKILL) {
+                    if echo && termios.c_lflag & ECHOKE != 0 {
+                        while self.Rubout(out).is_some() {}
+                    } else {
+                        self.line.clear();
+                        if echo && termios.c_lflag & ECHOK != 0 {
+                            self.Echo(c,out);
+                            out.extend(self.Output(b"\n"));
+                        }
+                    }
+                    return None;
+                }
+                if termios.Is(c,VEOF) {
+                    self.Commit();
+                    return None;
+                }
+                if termios.Is(c,VREPRINT) && termios.c_lflag & IEXTEN != 0 {
+                    if echo {
+                        self.Echo(c,out);
+                        out.extend(self.Output(b"\n"));
+                        out.extend(self.line.iter());
+                    }
+                    return None;
+                }
+                if termios.Is(c,VLNEXT) && termios.c_lflag & IEXTEN != 0 {
+                    self.literal = true;
+                    if echo {
+                        out.extend_from_slice(b"^\x08");
+                    }
+                    return None;
+                }
+                if c == b'\n' || termios.Is(c,VEOL) || termios.Is(c,VEOL2) {
+                    self.line.push(c);
+                    if echo || (c == b'\n' && termios.c_lflag & ECHONL != 0) {
+                        out.extend(self.Output(&[c]));
+                    }
+                    self.Commit();
+                    return None;
+                }
+            }
+        }
+        if self.Canonical() {
+            if self.line.len() >= LINE_MAX {
+                out.push(0x07);
+                return None;
+            }
+            self.line.push(c);
+        } else {
+            if self.ready.len() >= INPUT_MAX {
+                return None;
+            }
+            self.ready.push_back(c);
+        }
+        if echo {
+            if c == b'\n' {
+                out.extend(self.Output(&[c]));
+            } else {
+                self.Echo(c,out);
+            }
+        }
+        None
+    }
+    // Feeds in what was typed at the terminal. The signals that were typed are handed back rather than sent from
+    // here, since sending them takes the process table and the caller is still holding this.
+    pub fn Input(&mut self, buffer: &[u8]) -> Typed {
+        let mut echo = Vec::new();
+        let mut signals = Vec::new();
+        for b in buffer.iter() {
+            if let Some(sig) = self.Receive(*b,&mut echo) {
+                signals.push(sig);
+            }
+        }
+        self.last_input = crate::arch::Timer::GetMicroseconds();
+        Typed {echo, pgrp: self.pgrp, signals}
+    }
+    pub fn CanRead(&self) -> bool {
+        if self.Canonical() {!self.lines.is_empty()} else {!self.ready.is_empty()}
+    }
+    fn Take(&mut self, buffer: &mut [u8], len: usize) -> i64 {
+        for (i, b) in self.ready.drain(..len).enumerate() {
+            buffer[i] = b;
+        }
+        len as i64
+    }
+    // Canonical reads get a line at a time. Otherwise VMIN and VTIME decide how long to wait, with EAGAIN meaning
+    // there's more waiting to do.
+    pub fn Read(&mut self, buffer: &mut [u8]) -> i64 {
+        if self.Canonical() {
+            let len = match self.lines.pop_front() {
+                Some(len) => len,
+                None => return -(Errors::EAGAIN as i64),
+            };
+            let count = len.min(buffer.len());
+            if count < len {
+                self.lines.push_front(len-count);
+            }
+            return self.Take(buffer,count);
+        }
+        let min = (self.termios.c_cc[VMIN] as usize).min(buffer.len());
+        let time = self.termios.c_cc[VTIME] as u64 * 100000;
+        let available = self.ready.len().min(buffer.len());
+        if time == 0 {
+            if available >= min && (available > 0 || min == 0) {
+                return self.Take(buffer,available);
+            }
+            return -(Errors::EAGAIN as i64);
+        }
+        let now = crate::arch::Timer::GetMicroseconds();
+        if min == 0 {
+            // VTIME is how long to wait for anything at all
+            if available > 0 {
+                self.deadline = None;
+                return self.Take(buffer,available);
+            }
+            let deadline = *self.deadline.get_or_insert(now+time);
+            if now >= deadline {
+                self.deadline = None;
+                return 0;
+            }
+            return -(Errors::EAGAIN as i64);
+        }
+        // VTIME is how long to wait between characters, once the first one shows up
+        if available >= min || (available > 0 && now >= self.last_input+time) {
+            return self.Take(buffer,available);
+        }
+        -(Errors::EAGAIN as i64)
+    }
+    fn SetTermios(&mut self, termios: Termios) {
+        let was_canonical = self.Canonical();
+        self.termios = termios;
+        if was_canonical && !self.Canonical() {
+            // Whatever's half typed becomes readable right away
+            self.ready.extend(self.line.drain(..));
+            self.lines.clear();
+        } else if !was_canonical && self.Canonical() && !self.ready.is_empty() {
+            self.lines.push_back(self.ready.len());
+        }
+    }
+    // The termios ioctls, which every kind of terminal shares. Returns None for the ones it doesn't know about.
+    pub fn IOCtl(&mut self, cmd: usize, arg: usize) -> Option<Result<usize, i64>> {
+        match cmd {
+            TCGETS => {
+                unsafe {*(arg as *mut Termios) = self.termios;}
+            }
+            TCSETS | TCSETSW | TCSETSF => {
+                // Output is never held back, so there's nothing for TCSETSW to wait on
+                if cmd == TCSETSF {
+                    self.Flush();
+                }
+                self.SetTermios(unsafe {*(arg as *const Termios)});
+            }
+            TCFLSH => {
+                match arg {
+                    TCIFLUSH | TCIOFLUSH => self.Flush(),
+                    TCOFLUSH => {}
+                    _ => return Some(Err(Errors::EINVAL as i64)),
+                }
+            }
+            TCXONC => {}
+            TIOCGPGRP => {
+                unsafe {*(arg as *mut i32) = self.pgrp;}
+            }
+            TIOCSPGRP => {
+                let pgrp = unsafe {*(arg as *const i32)};
+                if pgrp < 0 {
+                    return Some(Err(Errors::EINVAL as i64));
+                }
+                self.pgrp = pgrp;
+            }
+            _ => return None,
+        }
+        Some(Ok(0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn Type(ldisc: &mut LineDiscipline, input: &[u8]) -> Vec<u8> {
+        ldisc.Input(input).echo
+    }
+
+    fn ReadSome(ldisc: &mut LineDiscipline, max: usize) -> Vec<u8> {
+        let mut buffer = vec![0u8; max];
+        let len = ldisc.Read(&mut buffer);
+        assert!(len >= 0, "read failed with {}", len);
+        buffer.truncate(len as usize);
+        buffer
+    }
+
+    fn ReadLine(ldisc: &mut LineDiscipline) -> Vec<u8> {
+        ReadSome(ldisc,LINE_MAX+1)
+    }
+
+    #[test]
+    fn NothingToReadUntilTheLineEnds() {
+        let mut ldisc = LineDiscipline::new();
+        assert_eq!(Type(&mut ldisc,b"ls"), b"ls");
+        assert!(!ldisc.CanRead());
+        assert_eq!(ldisc.Read(&mut [0u8; 8]), -(Errors::EAGAIN as i64));
+        assert_eq!(Type(&mut ldisc,b"\r"), b"\r\n");
+        assert_eq!(ReadLine(&mut ldisc), b"ls\n");
+        assert!(!ldisc.CanRead());
+    }
+
+    #[test]
+    fn EraseRubsOutWholeCharacters() {
+        let mut ldisc = LineDiscipline::new();
+        let echo = Type(&mut ldisc,"ab\u{e9}\x7F\x7Fc\n".as_bytes());
+        assert_eq!(echo, "ab\u{e9}\x08 \x08\x08 \x08c\r\n".as_bytes());
+        assert_eq!(ReadLine(&mut ldisc), b"ac\n");
+        // Control characters were echoed two wide, so they take two to rub out
+        assert_eq!(Type(&mut ldisc,b"x\x01\x7F\n"), b"x^A\x08 \x08\x08 \x08\r\n");
+        assert_eq!(ReadLine(&mut ldisc), b"x\n");
+        // Erasing past the start of the line does nothing
+        assert_eq!(Type(&mut ldisc,b"\x7F\x7Fy\n"), b"y\r\n");
+        assert_eq!(ReadLine(&mut ldisc), b"y\n");
+    }
+
+    #[test]
+    fn WordEraseTakesTheSpacesBeforeIt() {
+        let mut ldisc = LineDiscipline::new();
+        Type(&mut ldisc,b"one two\x17three\n");
+        assert_eq!(ReadLine(&mut ldisc), b"one three\n");
+        Type(&mut ldisc,b"one two  \x17three\n");
+        assert_eq!(ReadLine(&mut ldisc), b"one three\n");
+    }
+
+    #[test]
+    fn KillThrowsAwayTheLine() {
+        let mut ldisc = LineDiscipline::new();
+        assert_eq!(Type(&mut ldisc,b"junk\x15"), b"junk\x08 \x08\x08 \x08\x08 \x08\x08 \x08");
+        Type(&mut ldisc,b"ok\n");
+        assert_eq!(ReadLine(&mut ldisc), b"ok\n");
+        ldisc.termios.c_lflag &= !ECHOKE;
+        assert_eq!(Type(&mut ldisc,b"junk\x15"), b"junk^U\r\n");
+        Type(&mut ldisc,b"ok\n");
+        assert_eq!(ReadLine(&mut ldisc), b"ok\n");
+    }
+
+    #[test]
+    fn LiteralNextTakesTheNextCharacterAsIs() {
+        let mut ldisc = LineDiscipline::new();
+        Type(&mut ldisc,b"\x16\x15\x16\x7F\x16\x03\n");
+        assert_eq!(ReadLine(&mut ldisc), b"\x15\x7F\x03\n");
+    }
+
+    #[test]
+    fn EndOfFileHandsOverTheLineWithoutANewline() {
+        let mut ldisc = LineDiscipline::new();
+        Type(&mut ldisc,b"ab\x04");
+        assert_eq!(ReadLine(&mut ldisc), b"ab");
+        Type(&mut ldisc,b"\x04");
+        assert!(ldisc.CanRead());
+        assert_eq!(ldisc.Read(&mut [0u8; 8]), 0);
+    }
+
+    #[test]
+    fn ShortReadsGetTheRestOfTheLineNextTime() {
+        let mut ldisc = LineDiscipline::new();
+        Type(&mut ldisc,b"hello\nworld\n");
+        assert_eq!(ReadSome(&mut ldisc,2), b"he");
+        assert_eq!(ReadSome(&mut ldisc,8), b"llo\n");
+        assert_eq!(ReadSome(&mut ldisc,8), b"world\n");
+    }
+
+    #[test]
+    fn InterruptThrowsAwayTheLine() {
+        let mut ldisc = LineDiscipline::new();
+        let typed = ldisc.Input(b"half\x03");
+        assert_eq!(typed.signals, [Signals::SIGINT]);
+        assert_eq!(typed.echo, b"half^C\r\n");
+        Type(&mut ldisc,b"x\n");
+        assert_eq!(ReadLine(&mut ldisc), b"x\n");
+    }
+
+    #[test]
+    fn LeavingCanonicalModeHandsOverWhatsTyped() {
+        let mut ldisc = LineDiscipline::new();
+        Type(&mut ldisc,b"ab\x7Fc");
+        let mut termios = ldisc.termios;
+        termios.c_lflag &= !ICANON;
+        assert_eq!(ldisc.IOCtl(TCSETS,&termios as *const Termios as usize), Some(Ok(0)));
+        assert_eq!(ReadSome(&mut ldisc,8), b"ac");
+        Type(&mut ldisc,b"d\x7F");
+        assert_eq!(ReadSome(&mut ldisc,8), b"d\x7F");
+    }
+}
@@ -0,0 +1,120 @@
+use alloc::sync::Arc;
+use alloc::collections::VecDeque;
+use spin::{Mutex, Once};
+use crate::FS::DevFS;
+use crate::FS::VFS;
+use crate::Syscall::Errors;
+
+pub const BUTTON_LEFT: u32 = 1 << 0;
+pub const BUTTON_RIGHT: u32 = 1 << 1;
+pub const BUTTON_MIDDLE: u32 = 1 << 2;
+pub const BUTTON_SIDE: u32 = 1 << 3;
+pub const BUTTON_EXTRA: u32 = 1 << 4;
+
+// What a read from /dev/mouse hands back, one of these per movement. Y grows downwards, like screen coordinates.
+#[repr(C)]
+#[derive(Clone,Copy,Default)]
+pub struct MousePacket {
+    pub buttons: u32,
+    pub dx: i32,
+    pub dy: i32,
+    pub wheel: i32,
+}
+
+const PACKET_SIZE: usize = core::mem::size_of::<MousePacket>();
+
+static PACKETS: Mutex<VecDeque<MousePacket>> = Mutex::new(VecDeque::new());
+
+// Mouse drivers call this with whatever moved since their last report
+pub fn Push(packet: MousePacket) {
+    let mut lock = PACKETS.lock();
+    // Fold plain movement into the last packet if it's still sitting there, so a slow reader doesn't fall behind
+    if let Some(last) = lock.back_mut() {
+        if last.buttons == packet.buttons {
+            last.dx = last.dx.saturating_add(packet.dx);
+            last.dy = last.dy.saturating_add(packet.dy);
+            last.wheel = last.wheel.saturating_add(packet.wheel);
+            return;
+        }
+    }
+    if lock.len() >= 128 {
+        lock.pop_front();
+    }
+    lock.push_back(packet);
+}
+
+pub struct MouseDevice(usize);
+
+impl DevFS::Device for MouseDevice {
+    fn DeviceID(&self) -> usize {
+        self.0
+    }
+    fn Inode(&self) -> Arc<dyn VFS::Inode> {
+        MOUSE_DEV.get().expect("device not ready").clone()
+    }
+}
+
+impl VFS::Inode for MouseDevice {
+    fn Stat(&self) -> Result<VFS::Metadata, i64> {
+        Ok(VFS::Metadata {
+            device_id: 0,
+            inode_id: i64::MAX,
+            mode: 0o0020666, // crw-rw-rw-
+            nlinks: 1,
+            uid: 0,
+            gid: 0,
+            rdev: self.0 as u64,
+            size: 0,
+            blksize: 0,
+            blocks: 0,
+
+            atime: unsafe {crate::UNIX_EPOCH as i64},
+            mtime: unsafe {crate::UNIX_EPOCH as i64},
+            ctime: unsafe {crate::UNIX_EPOCH as i64},
+            reserved1: 0,
+            reserved2: 0,
+            reserved3: 0,
+        })
+    }
+    fn GetName(&self) -> Result<&str, i64> {
+        Ok("mouse")
+    }
+    // Only ever hands out whole packets
+    fn Read(&self, _offset: i64, buffer: &mut [u8]) -> i64 {
+        if buffer.len() < PACKET_SIZE {
+            return -(Errors::EINVAL as i64);
+        }
+        let mut lock = PACKETS.lock();
+        let mut i = 0;
+        while i + PACKET_SIZE <= buffer.len() {
+            let packet = match lock.pop_front() {
+                Some(p) => p,
+                None => {break;}
+            };
+            let raw = unsafe {core::slice::from_raw_parts(&packet as *const MousePacket as *const u8,PACKET_SIZE)};
+            buffer[i..i+PACKET_SIZE].copy_from_slice(raw);
+            i += PACKET_SIZE;
+        }
+        i as i64
+    }
+    fn Write(&self, _offset: i64, _buffer: &[u8]) -> i64 {
+        -(Errors::EBADF as i64)
+    }
+    fn Poll(&self) -> i16 {
+        if PACKETS.lock().is_empty() {0} else {VFS::POLLIN}
+    }
+}
+
+pub static MOUSE_DEV: Once<Arc<MouseDevice>> = Once::new();
+
+// Publishes /dev/mouse the first time a mouse driver finds something
+pub fn Attach() {
+    let mut new = false;
+    let dev = MOUSE_DEV.call_once(|| {
+        new = true;
+        Arc::new(MouseDevice(DevFS::ReserveDeviceID()))
+    });
+    if new {
+        let _ = DevFS::InstallDevice(dev.clone());
+    }
+}
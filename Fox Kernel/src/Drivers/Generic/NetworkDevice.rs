@@ -0,0 +1,119 @@
+use spin::Mutex;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use alloc::string::String;
+use alloc::format;
+use alloc::collections::VecDeque;
+use core::sync::atomic::{AtomicBool,AtomicU64,AtomicUsize,Ordering};
+
+// Frames that pile up past this point are dropped until the stack catches up.
+const QUEUE_LIMIT: usize = 256;
+
+pub trait NetworkDevice: Send + Sync {
+    fn MACAddress(&self) -> [u8; 6];
+    fn MTU(&self) -> usize {
+        1500
+    }
+    fn LinkUp(&self) -> bool;
+    // Hands a complete ethernet frame (without FCS) to the hardware, returns false if the transmit ring is full.
+    fn Transmit(&self, frame: &[u8]) -> bool;
+    // Acknowledges interrupts, reclaims finished transmit descriptors and passes received frames to iface.Deliver().
+    fn Poll(&self, iface: &NetworkInterface);
+}
+
+pub struct NetworkInterface {
+    pub name: String,
+    pub device: Arc<dyn NetworkDevice>,
+    rx_queue: Mutex<VecDeque<Vec<u8>>>,
+    tx_queue: Mutex<VecDeque<Vec<u8>>>,
+    link: AtomicBool,
+    pub rx_packets: AtomicU64,
+    pub tx_packets: AtomicU64,
+    pub rx_dropped: AtomicU64,
+    pub tx_dropped: AtomicU64,
+}
+
+impl NetworkInterface {
+    // Called by drivers whenever a frame comes in
+    pub fn Deliver(&self, frame: Vec<u8>) {
+        let mut rx = self.rx_queue.lock();
+        if rx.len() >= QUEUE_LIMIT {
+            self.rx_dropped.fetch_add(1,Ordering::Relaxed);
+            return;
+        }
+        self.rx_packets.fetch_add(1,Ordering::Relaxed);
+        rx.push_back(frame);
+    }
+    pub fn Receive(&self) -> Option<Vec<u8>> {
+        self.rx_queue.lock().pop_front()
+    }
+    pub fn Send(&self, frame: Vec<u8>) {
+        let mut tx = self.tx_queue.lock();
+        if tx.len() >= QUEUE_LIMIT {
+            self.tx_dropped.fetch_add(1,Ordering::Relaxed);
+            return;
+        }
+        tx.push_back(frame);
+        drop(tx);
+        self.FlushTX();
+    }
+    fn FlushTX(&self) {
+        let mut tx = self.tx_queue.lock();
+        while let Some(frame) = tx.front() {
+            if !self.device.Transmit(frame.as_slice()) {
+                break;
+            }
+            tx.pop_front();
+            self.tx_packets.fetch_add(1,Ordering::Relaxed);
+        }
+    }
+    pub fn Poll(&self) {
+        self.device.Poll(self);
+        self.FlushTX();
+        let link = self.device.LinkUp();
+        if self.link.swap(link,Ordering::SeqCst) != link {
+            log::info!("{}: Link is {}", self.name, if link {"up"} else {"down"});
+        }
+    }
+    pub fn LinkUp(&self) -> bool {
+        self.link.load(Ordering::SeqCst)
+    }
+}
+
+pub static INTERFACES: Mutex<Vec<Arc<NetworkInterface>>> = Mutex::new(Vec::new());
+static NEXT_ETH: AtomicUsize = AtomicUsize::new(0);
+
+pub fn ReserveEthName() -> String {
+    format!("eth{}", NEXT_ETH.fetch_add(1,Ordering::SeqCst))
+}
+
+pub fn Register(name: String, dev: Arc<dyn NetworkDevice>) -> Arc<NetworkInterface> {
+    let mac = dev.MACAddress();
+    log::info!("{}: MAC {:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}, MTU {}, Link {}", name, mac[0], mac[1], mac[2], mac[3], mac[4], mac[5], dev.MTU(), if dev.LinkUp() {"up"} else {"down"});
+    let iface = Arc::new(NetworkInterface {
+        name,
+        link: AtomicBool::new(dev.LinkUp()),
+        device: dev,
+        rx_queue: Mutex::new(VecDeque::new()),
+        tx_queue: Mutex::new(VecDeque::new()),
+        rx_packets: AtomicU64::new(0),
+        tx_packets: AtomicU64::new(0),
+        rx_dropped: AtomicU64::new(0),
+        tx_dropped: AtomicU64::new(0),
+    });
+    INTERFACES.lock().push(iface.clone());
+    iface
+}
+
+pub fn Find(name: &str) -> Option<Arc<NetworkInterface>> {
+    INTERFACES.lock().iter().find(|i| i.name.as_str() == name).cloned()
+}
+
+// Used by the interrupt handlers of network drivers, they don't know which interface fired.
+pub fn PollAll() {
+    if let Some(lock) = INTERFACES.try_lock() {
+        for i in lock.iter() {
+            i.Poll();
+        }
+    }
+}
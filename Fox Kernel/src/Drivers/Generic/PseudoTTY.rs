@@ -5,7 +5,10 @@ use spin::{Once,Mutex};
 use alloc::collections::{BTreeMap,VecDeque};
 use alloc::string::{String,ToString};
 use crate::Syscall::Errors;
-use core::sync::atomic::{AtomicUsize,Ordering};
+use core::sync::atomic::{AtomicBool,AtomicUsize,Ordering};
+use super::LineDiscipline::LineDiscipline;
+use super::Epoll::Watchers;
+use crate::Process::Signals;
 
 pub const TCGETS: usize = 0x4000;
 pub const TCSETS: usize = 0x4001;
@@ -34,6 +37,38 @@ pub const TIOCMBIC: usize = 0x4012;
 pub const TIOCMSET: usize = 0x4013;
 pub const TIOCGSOFTCAR: usize = 0x4014;
 pub const TIOCSSOFTCAR: usize = 0x4015;
+pub const TIOCGPTN: usize = 0x4016; // Which /dev/pts/N goes with this /dev/ptmx
+pub const TIOCSPTLCK: usize = 0x4017; // Nonzero locks the client side so it can't be opened, new ones start out locked
+pub const TIOCGPTLCK: usize = 0x4018;
+pub const TIOCGRANTPT: usize = 0x4019; // Gives the client side to the caller, with mode 0620
+
+pub const TIOCGNAME: usize = 0x4F01; // Fox Kernel specific, copies the terminal's name under /dev into a 32 byte buffer
+
+#[repr(C)]
+#[derive(Clone,Copy,PartialEq)]
+pub struct WinSize {
+    pub row: u16,
+    pub col: u16,
+    pub reserved1: u16,
+    pub reserved2: u16,
+}
+
+// Fills in a TIOCGNAME buffer
+pub fn CopyName(name: &str, arg: usize) {
+    let len = name.len().min(31);
+    unsafe {
+        core::ptr::copy(name.as_ptr(), arg as *mut u8, len);
+        *((arg+len) as *mut u8) = 0;
+    }
+}
+
+fn Credentials() -> (u32, u32) {
+    let pid = crate::Scheduler::Scheduler::CurrentPID();
+    let lock = crate::Process::PROCESSES.lock();
+    let ret = lock.get(&pid).map_or((0,0),|p| (p.ruid,p.rgid));
+    drop(lock);
+    ret
+}
 
 struct PtsDir(usize);
 impl PtsDir {
@@ -92,12 +127,9 @@ impl VFS::Inode for PtsDir {
 
     fn ReadDir(&self, index: usize) -> Result<Option<Arc<dyn VFS::Inode>>, i64> {
         let lock = PTYS.lock();
-        if index < lock.len() {
-            drop(lock);
-            return Ok(Some(PTYS.lock().get(&index).unwrap().client.clone()))
-        }
+        let ret = lock.values().nth(index).map(|pty| pty.client.clone());
         drop(lock);
-        Ok(None)
+        Ok(ret)
     }
 
     fn Open(&self, _mode: usize) -> Result<(), i64> {
@@ -113,13 +145,19 @@ pub struct PTY {
     pub client: Arc<dyn VFS::Inode>,
     pub server: Arc<dyn VFS::Inode>,
 
-    // Client read, Server write
-    pub pty_read: Mutex<VecDeque<u8>>,
-    // Client write, Server read
+    // Server write goes in, client read comes out
+    pub ldisc: Mutex<LineDiscipline>,
+    // Client write and echo, Server read
     pub pty_write: Mutex<VecDeque<u8>>,
+
+    pub locked: AtomicBool,
+    pub owner: Mutex<(u32,u32,u32)>, // uid, gid, and permissions of /dev/pts/N
+    pub winsize: Mutex<WinSize>,
+    pub watchers: Watchers, // Shared by both sides, since what one writes is what the other reads
 }
 impl PTY {
     pub fn new(index: usize) -> Arc<Self> {
+        let (uid, gid) = Credentials();
         let arc = Arc::new_cyclic(|x| {
             Self {
                 index,
@@ -127,25 +165,53 @@ impl PTY {
                 client: Arc::new(PTClient {p: x.clone()}),
                 server: Arc::new(PTServer {p: x.clone()}),
 
-                pty_read: Mutex::new(VecDeque::new()),
+                ldisc: Mutex::new(LineDiscipline::new()),
                 pty_write: Mutex::new(VecDeque::new()),
+
+                locked: AtomicBool::new(true),
+                owner: Mutex::new((uid,gid,0o620)),
+                winsize: Mutex::new(WinSize {row: 24, col: 80, reserved1: 0, reserved2: 0}),
+                watchers: Watchers::new(),
             }
         });
         return arc;
     }
+    // The ioctls both sides understand
+    fn IOCtl(&self, cmd: usize, arg: usize) -> Result<usize, i64> {
+        match cmd {
+            TIOCGWINSZ => {
+                unsafe {*(arg as *mut WinSize) = *self.winsize.lock();}
+                Ok(0)
+            }
+            TIOCSWINSZ => {
+                let new = unsafe {*(arg as *const WinSize)};
+                let mut winsize = self.winsize.lock();
+                let changed = *winsize != new;
+                *winsize = new;
+                drop(winsize);
+                if changed {
+                    let pgrp = self.ldisc.lock().pgrp;
+                    crate::Process::Process::SignalGroup(pgrp,Signals::SIGWINCH);
+                }
+                Ok(0)
+            }
+            _ => self.ldisc.lock().IOCtl(cmd,arg).unwrap_or(Err(Errors::ENOTTY as i64)),
+        }
+    }
 }
 pub struct PTClient {
     p: Weak<PTY>,
 }
 impl VFS::Inode for PTClient {
     fn Stat(&self) -> Result<VFS::Metadata, i64> {
+        let (uid, gid, mode) = self.p.upgrade().map_or((0,0,0o620),|arc| *arc.owner.lock());
         Ok(VFS::Metadata {
             device_id: 0,
             inode_id: i64::MAX,
-            mode: 0o0020666, // crw-rw-rw-
+            mode: 0o0020000 | mode as i32,
             nlinks: 1,
-            uid: 0,
-            gid: 0,
+            uid,
+            gid,
             rdev: 0,
             size: 0,
             blksize: 0,
@@ -171,43 +237,68 @@ impl VFS::Inode for PTClient {
     }
     fn Read(&self, _offset: i64, buffer: &mut [u8]) -> i64 {
         if let Some(arc) = self.p.upgrade() {
-            let mut i = 0;
-            let mut lock = arc.pty_read.lock();
-            if lock.len() == 0 {
-                drop(lock);
-                drop(arc);
-                return -(Errors::EAGAIN as i64);
-            }
-            while i < buffer.len() && lock.len() > 0 {
-                buffer[i] = lock.pop_front().unwrap();
-                i += 1;
-            }
-            drop(lock);
+            let ret = arc.ldisc.lock().Read(buffer);
             drop(arc);
-            return i as i64;
+            return ret;
         }
         return -(Errors::EPIPE as i64);
     }
     fn Write(&self, _offset: i64, buffer: &[u8]) -> i64 {
         if let Some(arc) = self.p.upgrade() {
-            let mut i = 0;
+            let out = arc.ldisc.lock().Output(buffer);
             let mut lock = arc.pty_write.lock();
-            while i < buffer.len() {
-                lock.push_back(buffer[i]);
-                i += 1;
-            }
+            lock.extend(out.iter());
             drop(lock);
+            arc.watchers.Notify();
             drop(arc);
-            return i as i64;
+            return buffer.len() as i64;
         }
         return -(Errors::EPIPE as i64);
     }
-    fn IOCtl(&self, cmd: usize, _arg: usize) -> Result<usize, i64> {
-        match cmd {
-            
-            _ => {}
+    fn IOCtl(&self, cmd: usize, arg: usize) -> Result<usize, i64> {
+        if let Some(arc) = self.p.upgrade() {
+            if cmd == TIOCGNAME {
+                CopyName(alloc::format!("pts/{}", arc.index).as_str(),arg);
+                return Ok(0);
+            }
+            return arc.IOCtl(cmd,arg);
         }
-        Ok(0)
+        Err(Errors::EPIPE as i64)
+    }
+    // Forking hands over file descriptors without opening anything, so only real opens get stopped by the lock
+    fn Open(&self, mode: usize) -> Result<(), i64> {
+        if let Some(arc) = self.p.upgrade() {
+            if mode != usize::MAX && arc.locked.load(Ordering::SeqCst) {
+                return Err(Errors::EIO as i64);
+            }
+            return Ok(());
+        }
+        Err(Errors::EIO as i64)
+    }
+    fn ChOwn(&self, uid: i32, gid: i32) -> i64 {
+        if let Some(arc) = self.p.upgrade() {
+            let mut owner = arc.owner.lock();
+            if uid >= 0 {owner.0 = uid as u32;}
+            if gid >= 0 {owner.1 = gid as u32;}
+            return 0;
+        }
+        -(Errors::EIO as i64)
+    }
+    fn ChMod(&self, mode: i32) -> i64 {
+        if let Some(arc) = self.p.upgrade() {
+            arc.owner.lock().2 = mode as u32 & 0o7777;
+            return 0;
+        }
+        -(Errors::EIO as i64)
+    }
+    fn Poll(&self) -> i16 {
+        if let Some(arc) = self.p.upgrade() {
+            return if arc.ldisc.lock().CanRead() {VFS::POLLIN | VFS::POLLOUT} else {VFS::POLLOUT};
+        }
+        VFS::POLLHUP // The server side closed /dev/ptmx
+    }
+    fn Watchers(&self) -> Option<Watchers> {
+        self.p.upgrade().map(|arc| arc.watchers.clone())
     }
 }
 pub struct PTServer {
@@ -232,19 +323,26 @@ impl VFS::Inode for PTServer {
         }
         return -(Errors::EPIPE as i64);
     }
+    // What the terminal emulator sends is what was typed, so it goes through the line discipline
     fn Write(&self, _offset: i64, buffer: &[u8]) -> i64 {
         if let Some(arc) = self.p.upgrade() {
-            let mut i = 0;
-            let mut lock = arc.pty_read.lock();
-            while i < buffer.len() {
-                lock.push_back(buffer[i]);
-                i += 1;
-            }
-            drop(lock);
-            return i as i64;
+            let typed = arc.ldisc.lock().Input(buffer);
+            typed.Signal();
+            arc.pty_write.lock().extend(typed.echo.iter());
+            arc.watchers.Notify();
+            return buffer.len() as i64;
         }
         return -(Errors::EPIPE as i64);
     }
+    fn Poll(&self) -> i16 {
+        if let Some(arc) = self.p.upgrade() {
+            return if arc.pty_write.lock().len() > 0 {VFS::POLLIN | VFS::POLLOUT} else {VFS::POLLOUT};
+        }
+        VFS::POLLHUP
+    }
+    fn Watchers(&self) -> Option<Watchers> {
+        self.p.upgrade().map(|arc| arc.watchers.clone())
+    }
 }
 struct PtmxDev(usize);
 impl PtmxDev {
@@ -323,8 +421,10 @@ impl VFS::Inode for Ptmx {
     fn GetName(&self) -> Result<&str, i64> {
         Ok("ptmx")
     }
-    fn Open(&self, _mode: usize) -> Result<(), i64> {
-        self.0.store(AddPTY(),Ordering::SeqCst);
+    fn Open(&self, mode: usize) -> Result<(), i64> {
+        if mode != usize::MAX && self.0.load(Ordering::SeqCst) == usize::MAX {
+            self.0.store(AddPTY(),Ordering::SeqCst);
+        }
         Ok(())
     }
 
@@ -337,7 +437,7 @@ impl VFS::Inode for Ptmx {
             return -(Errors::EACCES as i64);
         }
         let lock = PTYS.lock();
-        let ret = lock.get(&self.0.load(Ordering::SeqCst)).unwrap().server.Read(offset,buffer);
+        let ret = lock.get(&self.0.load(Ordering::SeqCst)).map_or(-(Errors::EIO as i64), |pty| pty.server.Read(offset,buffer));
         drop(lock);
         ret
     }
@@ -346,22 +446,58 @@ impl VFS::Inode for Ptmx {
             return -(Errors::EACCES as i64);
         }
         let lock = PTYS.lock();
-        let ret = lock.get(&self.0.load(Ordering::SeqCst)).unwrap().server.Write(offset,buffer);
+        let ret = lock.get(&self.0.load(Ordering::SeqCst)).map_or(-(Errors::EIO as i64), |pty| pty.server.Write(offset,buffer));
         drop(lock);
         ret
     }
-    fn IOCtl(&self, cmd: usize, _arg: usize) -> Result<usize, i64> {
+    fn Poll(&self) -> i16 {
+        if self.0.load(Ordering::SeqCst) == usize::MAX {
+            return VFS::POLLERR;
+        }
+        let lock = PTYS.lock();
+        let ret = lock.get(&self.0.load(Ordering::SeqCst)).map_or(VFS::POLLHUP | VFS::POLLERR, |pty| pty.server.Poll()); // Already torn down by DestroyPTY
+        drop(lock);
+        ret
+    }
+    fn Watchers(&self) -> Option<Watchers> {
+        PTYS.lock().get(&self.0.load(Ordering::SeqCst)).map(|pty| pty.watchers.clone())
+    }
+    fn IOCtl(&self, cmd: usize, arg: usize) -> Result<usize, i64> {
+        let index = self.0.load(Ordering::SeqCst);
         match cmd {
             0x4F00 => {
                 // This is a special Fox Kernel specific request that returns the index of the PTY.
-                return Ok(self.0.load(Ordering::SeqCst));
+                return Ok(index);
             }
             0x4F02 => { // TTYLOGIN
                 todo!();
             }
             _ => {}
         }
-        Ok(0)
+        let pty = match PTYS.lock().get(&index) {
+            Some(pty) => pty.clone(),
+            None => return Err(Errors::EACCES as i64),
+        };
+        match cmd {
+            TIOCGPTN => {
+                unsafe {*(arg as *mut u32) = index as u32;}
+                Ok(0)
+            }
+            TIOCSPTLCK => {
+                pty.locked.store(unsafe {*(arg as *const i32)} != 0,Ordering::SeqCst);
+                Ok(0)
+            }
+            TIOCGPTLCK => {
+                unsafe {*(arg as *mut i32) = pty.locked.load(Ordering::SeqCst) as i32;}
+                Ok(0)
+            }
+            TIOCGRANTPT => {
+                let (uid, gid) = Credentials();
+                *pty.owner.lock() = (uid,gid,0o620);
+                Ok(0)
+            }
+            _ => pty.IOCtl(cmd,arg),
+        }
     }
 }
 
@@ -385,8 +521,11 @@ pub fn AddPTY() -> usize {
 
 pub fn DestroyPTY(index: usize) {
     let mut lock = PTYS.lock();
-    lock.remove(&index);
+    let pty = lock.remove(&index);
     drop(lock);
+    if let Some(pty) = pty {
+        pty.watchers.Notify(); // The client side hangs up
+    }
 }
 
 static PTSDIR: Once<Arc<PtsDir>> = Once::new();
@@ -0,0 +1,118 @@
+use crate::FS::VFS;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use alloc::collections::BTreeMap;
+use spin::Mutex;
+use core::sync::atomic::{AtomicU64,Ordering};
+use crate::Syscall::Errors;
+use crate::Process::Signals;
+
+const SIGINFO_SIZE: usize = 128; // sizeof(struct signalfd_siginfo)
+
+// Signals covered by a signalfd are queued here for it to read instead of being delivered, bit n-1 standing for signal n like sigset_t
+static WATCHERS: Mutex<Vec<(i32,Arc<AtomicU64>)>> = Mutex::new(Vec::new());
+static PENDING: Mutex<BTreeMap<i32,u64>> = Mutex::new(BTreeMap::new());
+
+// Called when a signal is sent, returns true if a signalfd took it
+pub fn Claim(pid: i32, sig: u8) -> bool {
+    if sig == 0 || sig > 64 || sig == Signals::SIGKILL || sig == Signals::SIGSTOP || sig == Signals::SIGSEGV {
+        return false; // A fault would just happen again if its SIGSEGV got queued up instead
+    }
+    let bit = 1u64 << (sig-1);
+    if !WATCHERS.lock().iter().any(|(p, mask)| *p == pid && mask.load(Ordering::SeqCst) & bit != 0) {
+        return false;
+    }
+    *PENDING.lock().entry(pid).or_insert(0) |= bit;
+    true
+}
+
+// A signalfd reads the signals of the process that created it, which doesn't change when it's passed on through fork()
+pub struct SignalFD {
+    pid: i32,
+    mask: Arc<AtomicU64>,
+}
+
+impl SignalFD {
+    pub fn new(pid: i32, mask: u64) -> Arc<Self> {
+        let arc = Arc::new(Self {
+            pid,
+            mask: Arc::new(AtomicU64::new(0)),
+        });
+        arc.SetMask(mask);
+        WATCHERS.lock().push((pid,arc.mask.clone()));
+        arc
+    }
+
+    pub fn SetMask(&self, mask: u64) {
+        let uncatchable = (1u64 << (Signals::SIGKILL-1)) | (1u64 << (Signals::SIGSTOP-1));
+        self.mask.store(mask & !uncatchable,Ordering::SeqCst);
+    }
+}
+
+impl Drop for SignalFD {
+    fn drop(&mut self) {
+        let mut lock = WATCHERS.lock();
+        lock.retain(|(_, mask)| !Arc::ptr_eq(mask,&self.mask));
+        if !lock.iter().any(|(p, _)| *p == self.pid) {
+            // Whatever's still queued can't be read anymore
+            PENDING.lock().remove(&self.pid);
+        }
+        drop(lock);
+    }
+}
+
+impl VFS::Inode for SignalFD {
+    fn Stat(&self) -> Result<VFS::Metadata, i64> {
+        Ok(VFS::Metadata {
+            device_id: 0,
+            inode_id: i64::MAX,
+            mode: 0o0000600, // -rw-------
+            nlinks: 1,
+            uid: 0,
+            gid: 0,
+            rdev: 0,
+            size: 0,
+            blksize: 0,
+            blocks: 0,
+
+            atime: unsafe {crate::UNIX_EPOCH as i64},
+            mtime: unsafe {crate::UNIX_EPOCH as i64},
+            ctime: unsafe {crate::UNIX_EPOCH as i64},
+            reserved1: 0,
+            reserved2: 0,
+            reserved3: 0,
+        })
+    }
+    fn GetName(&self) -> Result<&str, i64> {
+        Ok("[fox kernel signalfd]")
+    }
+    fn Read(&self, _offset: i64, buffer: &mut [u8]) -> i64 {
+        if buffer.len() < SIGINFO_SIZE {
+            return -(Errors::EINVAL as i64);
+        }
+        let mask = self.mask.load(Ordering::SeqCst);
+        let mut lock = PENDING.lock();
+        let pending = match lock.get_mut(&self.pid) {
+            Some(p) => p,
+            None => {return -(Errors::EAGAIN as i64);}
+        };
+        let mut length = 0;
+        while length + SIGINFO_SIZE <= buffer.len() && *pending & mask != 0 {
+            let bit = (*pending & mask).trailing_zeros();
+            *pending &= !(1u64 << bit);
+            let info = &mut buffer[length..length+SIGINFO_SIZE];
+            info.fill(0);
+            info[0..4].copy_from_slice(&(bit+1).to_ne_bytes()); // ssi_signo
+            length += SIGINFO_SIZE;
+        }
+        drop(lock);
+        if length == 0 {
+            return -(Errors::EAGAIN as i64);
+        }
+        length as i64
+    }
+    fn Poll(&self) -> i16 {
+        let pending = PENDING.lock().get(&self.pid).copied().unwrap_or(0);
+        if pending & self.mask.load(Ordering::SeqCst) != 0 {VFS::POLLIN} else {0}
+    }
+}
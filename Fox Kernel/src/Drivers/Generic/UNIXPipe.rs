@@ -1,13 +1,30 @@
 use crate::FS::VFS;
 use alloc::collections::VecDeque;
+use alloc::vec::Vec;
 use spin::Mutex;
-use core::sync::atomic::{AtomicUsize,Ordering};
-use crate::Syscall::Errors;
+use core::sync::atomic::{AtomicBool,AtomicUsize,Ordering};
+use crate::Syscall::{Errors,OpenFlags};
 use alloc::sync::Arc;
+use super::Epoll::Watchers;
+
+const PIPE_SIZE: usize = 4096;
+const WAITER_TIMEOUT: u64 = 1000000; // Waiters that haven't retried their open() in this many microseconds are assumed to have given up
 
 pub struct Pipe {
     buffer: Mutex<VecDeque<u8>>,
     refs: AtomicUsize,
+    // Named pipes open their ends separately, so they track each side instead of using refs
+    readers: AtomicUsize,
+    writers: AtomicUsize,
+    waiting: Mutex<Vec<Waiter>>,
+    watchers: Watchers, // Shared with every end, since they all poll the same buffer
+}
+
+struct Waiter {
+    pid: i32,
+    reader: bool,
+    granted: bool, // The other side showed up, so the next retry goes through even if it's already gone again
+    last_try: u64,
 }
 
 impl VFS::Inode for Pipe {
@@ -39,6 +56,57 @@ impl VFS::Inode for Pipe {
         if self.refs.load(Ordering::SeqCst) <= 1 {
             return -Errors::EPIPE as i64;
         }
+        self.Take(buffer)
+    }
+    fn Write(&self, _offset: i64, buffer: &[u8]) -> i64 {
+        if self.refs.load(Ordering::SeqCst) <= 1 {
+            return -Errors::EPIPE as i64;
+        }
+        self.Put(buffer)
+    }
+    fn Open(&self, _mode: usize) -> Result<(), i64> {
+        self.refs.fetch_add(1,Ordering::SeqCst);
+        Ok(())
+    }
+    fn Close(&self) {
+        self.refs.fetch_sub(1,Ordering::SeqCst);
+        self.watchers.Notify();
+    }
+    fn Poll(&self) -> i16 {
+        // Both ends share this inode, so whoever's asking gets told about both directions
+        if self.refs.load(Ordering::SeqCst) <= 1 {
+            return VFS::POLLHUP | self.Ready(true,false);
+        }
+        self.Ready(true,true)
+    }
+    fn Watchers(&self) -> Option<Watchers> {
+        Some(self.watchers.clone())
+    }
+}
+
+impl Pipe {
+    pub fn new() -> (Arc<dyn VFS::Inode>, Arc<dyn VFS::Inode>) {
+        let pipe = Arc::new(Pipe::Empty(2));
+        (pipe.clone(), pipe)
+    }
+
+    // The buffer behind a FIFO, which hands out a NamedPipeEnd every time it's opened
+    pub fn Named() -> Arc<Pipe> {
+        Arc::new(Pipe::Empty(0))
+    }
+
+    fn Empty(refs: usize) -> Pipe {
+        Pipe {
+            buffer: Mutex::new(VecDeque::new()),
+            refs: AtomicUsize::new(refs),
+            readers: AtomicUsize::new(0),
+            writers: AtomicUsize::new(0),
+            waiting: Mutex::new(Vec::new()),
+            watchers: Watchers::new(),
+        }
+    }
+
+    fn Take(&self, buffer: &mut [u8]) -> i64 {
         let mut buf = self.buffer.lock();
         if buf.len() == 0 {
             drop(buf);
@@ -49,39 +117,192 @@ impl VFS::Inode for Pipe {
             buffer[i] = buf.pop_front().unwrap();
         }
         drop(buf);
+        self.watchers.Notify();
         return length as i64;
     }
-    fn Write(&self, _offset: i64, buffer: &[u8]) -> i64 {
-        if self.refs.load(Ordering::SeqCst) <= 1 {
-            return -Errors::EPIPE as i64;
-        }
+
+    fn Put(&self, buffer: &[u8]) -> i64 {
         let mut buf = self.buffer.lock();
-        if buf.len() >= 4096 {
+        if buf.len() >= PIPE_SIZE {
             drop(buf);
             return -Errors::EAGAIN as i64;
         }
-        let length = if buf.len()+buffer.len() > 4096 {buf.len()} else {buffer.len()};
+        let length = if buf.len()+buffer.len() > PIPE_SIZE {PIPE_SIZE-buf.len()} else {buffer.len()};
         for i in 0..length {
             buf.push_back(buffer[i]);
         }
         drop(buf);
+        self.watchers.Notify();
         return length as i64;
     }
-    fn Open(&self, _mode: usize) -> Result<(), i64> {
-        self.refs.fetch_add(1,Ordering::SeqCst);
+
+    fn Ready(&self, read: bool, write: bool) -> i16 {
+        let len = self.buffer.lock().len();
+        let mut events = 0;
+        if read && len > 0 {
+            events |= VFS::POLLIN;
+        }
+        if write && len < PIPE_SIZE {
+            events |= VFS::POLLOUT;
+        }
+        events
+    }
+
+    // Opening one side of a FIFO waits for the other side to show up. Since system calls can't sleep, waiting opens get EAGAIN
+    // and retry, and count as present in the meantime so the other side's open can go through. That open lets them in by
+    // counting them right away, and their next retry just picks that up.
+    fn Attach(&self, read: bool, write: bool, nonblocking: bool) -> Result<(),i64> {
+        let pid = crate::Scheduler::Scheduler::CurrentPID();
+        let now = crate::arch::Timer::GetMicroseconds();
+        let mut waiting = self.waiting.lock();
+        waiting.retain(|w| {
+            if now.saturating_sub(w.last_try) < WAITER_TIMEOUT {
+                return true;
+            }
+            if w.granted {
+                self.Detach(w.reader,!w.reader);
+            }
+            false
+        });
+        if let Some(i) = waiting.iter().position(|w| w.pid == pid && w.reader == read && w.granted && read != write) {
+            waiting.remove(i);
+            return Ok(());
+        }
+        let other = if read {&self.writers} else {&self.readers};
+        let other_waiting = waiting.iter().any(|w| w.reader != read);
+        if (read && write) || other.load(Ordering::SeqCst) > 0 || other_waiting || (read && nonblocking) {
+            // O_RDWR is both sides at once, so it never has to wait
+            waiting.retain(|w| w.pid != pid || w.reader != read);
+            self.readers.fetch_add(read as usize,Ordering::SeqCst);
+            self.writers.fetch_add(write as usize,Ordering::SeqCst);
+            for w in waiting.iter_mut().filter(|w| !w.granted && (w.reader != read || (read && write))) {
+                w.granted = true;
+                self.readers.fetch_add(w.reader as usize,Ordering::SeqCst);
+                self.writers.fetch_add(!w.reader as usize,Ordering::SeqCst);
+            }
+            self.watchers.Notify();
+            return Ok(());
+        }
+        if nonblocking {
+            return Err(Errors::ENXIO as i64);
+        }
+        match waiting.iter_mut().find(|w| w.pid == pid && w.reader == read) {
+            Some(w) => {w.last_try = now;}
+            None => {waiting.push(Waiter {pid, reader: read, granted: false, last_try: now});}
+        }
+        Err(Errors::EAGAIN as i64)
+    }
+
+    fn Detach(&self, read: bool, write: bool) {
+        if read {
+            self.readers.fetch_sub(1,Ordering::SeqCst);
+        }
+        if write {
+            self.writers.fetch_sub(1,Ordering::SeqCst);
+        }
+        self.watchers.Notify();
+    }
+}
+
+// One open() of a FIFO. Every open gets its own so that closing it knows which side of the pipe is going away.
+pub struct NamedPipeEnd {
+    node: Arc<dyn VFS::Inode>, // The FIFO in the filesystem, which answers everything that isn't I/O
+    pipe: Arc<Pipe>,
+    read: AtomicBool,
+    write: AtomicBool,
+    refs: AtomicUsize,
+}
+
+impl NamedPipeEnd {
+    pub fn new(node: Arc<dyn VFS::Inode>, pipe: Arc<Pipe>) -> Arc<Self> {
+        Arc::new(Self {
+            node,
+            pipe,
+            read: AtomicBool::new(false),
+            write: AtomicBool::new(false),
+            refs: AtomicUsize::new(0),
+        })
+    }
+}
+
+impl VFS::Inode for NamedPipeEnd {
+    fn Stat(&self) -> Result<VFS::Metadata, i64> {
+        let mut metadata = self.node.Stat()?;
+        metadata.size = self.pipe.buffer.lock().len() as i64;
+        Ok(metadata)
+    }
+    fn GetName(&self) -> Result<&str, i64> {
+        self.node.GetName()
+    }
+    fn GetParent(&self) -> Option<Arc<dyn VFS::Inode>> {
+        self.node.GetParent()
+    }
+    fn Read(&self, _offset: i64, buffer: &mut [u8]) -> i64 {
+        if !self.read.load(Ordering::SeqCst) {
+            return -Errors::EBADF as i64;
+        }
+        let result = self.pipe.Take(buffer);
+        if result == -Errors::EAGAIN as i64 && self.pipe.writers.load(Ordering::SeqCst) == 0 {
+            return 0; // Nobody's left to write anything
+        }
+        result
+    }
+    fn Write(&self, _offset: i64, buffer: &[u8]) -> i64 {
+        if !self.write.load(Ordering::SeqCst) {
+            return -Errors::EBADF as i64;
+        }
+        if self.pipe.readers.load(Ordering::SeqCst) == 0 {
+            return -Errors::EPIPE as i64;
+        }
+        self.pipe.Put(buffer)
+    }
+    fn Open(&self, mode: usize) -> Result<(), i64> {
+        if mode == usize::MAX || self.refs.load(Ordering::SeqCst) > 0 {
+            // Another descriptor sharing this end, from fork() or dup()
+            self.refs.fetch_add(1,Ordering::SeqCst);
+            return Ok(());
+        }
+        let read = mode & OpenFlags::O_ACCMODE == OpenFlags::O_RDONLY || mode & OpenFlags::O_ACCMODE == OpenFlags::O_RDWR;
+        let write = mode & OpenFlags::O_ACCMODE == OpenFlags::O_WRONLY || mode & OpenFlags::O_ACCMODE == OpenFlags::O_RDWR;
+        self.pipe.Attach(read,write,mode & OpenFlags::O_NONBLOCK != 0)?;
+        self.read.store(read,Ordering::SeqCst);
+        self.write.store(write,Ordering::SeqCst);
+        self.refs.store(1,Ordering::SeqCst);
         Ok(())
     }
     fn Close(&self) {
-        self.refs.fetch_sub(1,Ordering::SeqCst);
+        if self.refs.load(Ordering::SeqCst) > 0 && self.refs.fetch_sub(1,Ordering::SeqCst) == 1 {
+            self.pipe.Detach(self.read.load(Ordering::SeqCst),self.write.load(Ordering::SeqCst));
+        }
+    }
+    fn Poll(&self) -> i16 {
+        let read = self.read.load(Ordering::SeqCst);
+        let write = self.write.load(Ordering::SeqCst);
+        let mut events = self.pipe.Ready(read,write);
+        if read && self.pipe.writers.load(Ordering::SeqCst) == 0 {
+            events |= VFS::POLLHUP;
+        }
+        if write && self.pipe.readers.load(Ordering::SeqCst) == 0 {
+            events |= VFS::POLLERR;
+        }
+        events
+    }
+    fn Watchers(&self) -> Option<Watchers> {
+        Some(self.pipe.watchers.clone())
+    }
+    fn ChOwn(&self, uid: i32, gid: i32) -> i64 {
+        self.node.ChOwn(uid,gid)
+    }
+    fn ChMod(&self, mode: i32) -> i64 {
+        self.node.ChMod(mode)
     }
 }
 
-impl Pipe {
-    pub fn new() -> (Arc<dyn VFS::Inode>, Arc<dyn VFS::Inode>) {
-        let pipe = Arc::new(Pipe {
-            buffer: Mutex::new(VecDeque::new()),
-            refs: AtomicUsize::new(2),
-        });
-        (pipe.clone(), pipe)
+impl Drop for NamedPipeEnd {
+    fn drop(&mut self) {
+        // Descriptors are dropped without being closed when a process exits
+        if self.refs.load(Ordering::SeqCst) > 0 {
+            self.pipe.Detach(self.read.load(Ordering::SeqCst),self.write.load(Ordering::SeqCst));
+        }
     }
-}
\ No newline at end of file
+}
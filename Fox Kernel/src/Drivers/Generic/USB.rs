@@ -0,0 +1,530 @@
+use crate::FS::VFS;
+use crate::FS::DevFS;
+use alloc::sync::{Arc, Weak};
+use alloc::string::String;
+use alloc::vec::Vec;
+use alloc::vec;
+use alloc::format;
+use core::sync::atomic::{AtomicUsize,Ordering};
+use spin::Mutex;
+use crate::Syscall::Errors;
+use crate::Scheduler::Scheduler;
+use crate::Process::Process;
+
+// These line up with the xHCI default speed IDs
+pub const SPEED_FULL: u8 = 1;
+pub const SPEED_LOW: u8 = 2;
+pub const SPEED_HIGH: u8 = 3;
+pub const SPEED_SUPER: u8 = 4;
+
+pub const DESC_DEVICE: u8 = 1;
+pub const DESC_CONFIGURATION: u8 = 2;
+pub const DESC_STRING: u8 = 3;
+pub const DESC_INTERFACE: u8 = 4;
+pub const DESC_ENDPOINT: u8 = 5;
+
+pub const REQ_GET_STATUS: u8 = 0;
+pub const REQ_CLEAR_FEATURE: u8 = 1;
+pub const REQ_SET_FEATURE: u8 = 3;
+pub const REQ_GET_DESCRIPTOR: u8 = 6;
+pub const REQ_SET_CONFIGURATION: u8 = 9;
+pub const REQ_SET_INTERFACE: u8 = 11;
+
+// bmRequestType: direction | type | recipient
+pub const RT_IN: u8 = 0x80;
+pub const RT_CLASS: u8 = 0x20;
+pub const RT_INTERFACE: u8 = 0x01;
+pub const RT_ENDPOINT: u8 = 0x02;
+pub const RT_OTHER: u8 = 0x03;
+
+pub const CLASS_HID: u8 = 0x03;
+pub const CLASS_MASS_STORAGE: u8 = 0x08;
+pub const CLASS_HUB: u8 = 0x09;
+pub const CLASS_VENDOR: u8 = 0xFF;
+
+pub const XFER_CONTROL: u8 = 0;
+pub const XFER_ISOCHRONOUS: u8 = 1;
+pub const XFER_BULK: u8 = 2;
+pub const XFER_INTERRUPT: u8 = 3;
+
+const LANG_EN_US: u16 = 0x0409;
+const POLL_INTERVAL: u64 = 8000; // 8 ms
+
+#[derive(Clone,Copy)]
+pub struct SetupPacket {
+    pub request_type: u8,
+    pub request: u8,
+    pub value: u16,
+    pub index: u16,
+}
+
+#[derive(Clone,Copy,Default)]
+pub struct DeviceDescriptor {
+    pub usb_version: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub protocol: u8,
+    pub max_packet0: u8,
+    pub vendor: u16,
+    pub product: u16,
+    pub device_version: u16,
+    pub manufacturer_str: u8,
+    pub product_str: u8,
+    pub serial_str: u8,
+    pub configurations: u8,
+}
+
+impl DeviceDescriptor {
+    pub fn Parse(d: &[u8]) -> Option<Self> {
+        if d.len() < 18 || d[1] != DESC_DEVICE {
+            return None;
+        }
+        Some(Self {
+            usb_version: u16::from_le_bytes([d[2],d[3]]),
+            class: d[4],
+            subclass: d[5],
+            protocol: d[6],
+            max_packet0: d[7],
+            vendor: u16::from_le_bytes([d[8],d[9]]),
+            product: u16::from_le_bytes([d[10],d[11]]),
+            device_version: u16::from_le_bytes([d[12],d[13]]),
+            manufacturer_str: d[14],
+            product_str: d[15],
+            serial_str: d[16],
+            configurations: d[17],
+        })
+    }
+}
+
+#[derive(Clone,Copy)]
+pub struct EndpointDescriptor {
+    pub address: u8,
+    pub attributes: u8,
+    pub max_packet: u16,
+    pub interval: u8,
+}
+
+impl EndpointDescriptor {
+    pub fn TransferType(&self) -> u8 {
+        self.attributes & 0x3
+    }
+    pub fn IsIn(&self) -> bool {
+        self.address & 0x80 != 0
+    }
+}
+
+#[derive(Clone)]
+pub struct Interface {
+    pub number: u8,
+    pub alternate: u8,
+    pub class: u8,
+    pub subclass: u8,
+    pub protocol: u8,
+    pub endpoints: Vec<EndpointDescriptor>,
+    // Class-specific descriptors (HID, CS_INTERFACE, ...) in the order they showed up
+    pub extra: Vec<u8>,
+}
+
+impl Interface {
+    pub fn FindEndpoint(&self, kind: u8, input: bool) -> Option<EndpointDescriptor> {
+        self.endpoints.iter().find(|e| e.TransferType() == kind && e.IsIn() == input).copied()
+    }
+}
+
+#[derive(Clone)]
+pub struct Configuration {
+    pub value: u8,
+    pub attributes: u8,
+    pub max_power: u16, // mA
+    pub interfaces: Vec<Interface>,
+}
+
+impl Configuration {
+    pub fn Parse(raw: &[u8]) -> Option<Self> {
+        if raw.len() < 9 || raw[1] != DESC_CONFIGURATION {
+            return None;
+        }
+        let mut config = Self {
+            value: raw[5],
+            attributes: raw[7],
+            max_power: raw[8] as u16 * 2,
+            interfaces: Vec::new(),
+        };
+        let mut offset = raw[0] as usize;
+        while offset + 2 <= raw.len() {
+            let len = raw[offset] as usize;
+            if len < 2 || offset + len > raw.len() {
+                break;
+            }
+            let d = &raw[offset..offset+len];
+            match d[1] {
+                DESC_INTERFACE if len >= 9 => {
+                    config.interfaces.push(Interface {
+                        number: d[2],
+                        alternate: d[3],
+                        class: d[5],
+                        subclass: d[6],
+                        protocol: d[7],
+                        endpoints: Vec::new(),
+                        extra: Vec::new(),
+                    });
+                }
+                DESC_ENDPOINT if len >= 7 => {
+                    if let Some(iface) = config.interfaces.last_mut() {
+                        iface.endpoints.push(EndpointDescriptor {
+                            address: d[2],
+                            attributes: d[3],
+                            max_packet: u16::from_le_bytes([d[4],d[5]]),
+                            interval: d[6],
+                        });
+                    }
+                }
+                _ => {
+                    if let Some(iface) = config.interfaces.last_mut() {
+                        iface.extra.extend_from_slice(d);
+                    }
+                }
+            }
+            offset += len;
+        }
+        Some(config)
+    }
+}
+
+// What the USB core needs from a host controller. Devices are named by the slot the controller gave them when they attached.
+pub trait HostController: Send + Sync {
+    // `port` is always the root port the device sits behind, `tt` is the (hub slot, hub port) of the transaction translator
+    // in front of a low/full-speed device behind a high-speed hub.
+    fn AttachDevice(&self, port: u8, speed: u8, route: u32, tt: Option<(u8,u8)>) -> Result<u8,i64>;
+    fn DetachDevice(&self, slot: u8);
+    fn ConfigureHub(&self, slot: u8, ports: u8, think_time: u8, multi_tt: bool) -> Result<(),i64>;
+    fn ConfigureEndpoint(&self, slot: u8, endpoint: &EndpointDescriptor) -> Result<(),i64>;
+    fn ControlTransfer(&self, slot: u8, setup: SetupPacket, data: &mut [u8]) -> Result<usize,i64>;
+    fn BulkTransfer(&self, slot: u8, endpoint: u8, data: &mut [u8]) -> Result<usize,i64>;
+    fn InterruptTransfer(&self, slot: u8, endpoint: u8, data: &mut [u8]) -> Result<usize,i64>;
+    // Queues up an interrupt IN transfer without waiting for it, so drivers can check on it from their Poll
+    fn SubmitInterrupt(&self, slot: u8, endpoint: u8, length: usize) -> Result<(),i64>;
+    // None while the transfer is still outstanding
+    fn ReapInterrupt(&self, slot: u8, endpoint: u8, data: &mut [u8]) -> Option<Result<usize,i64>>;
+    // Deals with whatever happened on the root ports since the last call. Runs on the USB worker, so it's free to wait.
+    fn ServicePorts(self: Arc<Self>);
+}
+
+// A class driver looks at interfaces and hands back an InterfaceDriver for the ones it takes
+pub trait ClassDriver: Send + Sync {
+    fn Name(&self) -> &str;
+    fn Matches(&self, device: &USBDevice, interface: &Interface) -> bool;
+    fn Probe(&self, device: &Arc<USBDevice>, interface: &Interface) -> Result<Arc<dyn InterfaceDriver>,i64>;
+}
+
+pub trait InterfaceDriver: Send + Sync {
+    // Called every few milliseconds from the USB worker, so it may sleep as long as it isn't holding any locks
+    fn Poll(&self) {}
+    fn Disconnect(&self) {}
+}
+
+pub struct USBDevice {
+    pub hc: Arc<dyn HostController>,
+    pub bus: usize,
+    pub slot: u8,
+    pub port: u8,
+    pub speed: u8,
+    pub route: u32,
+    pub depth: u8,
+    pub tt: Option<(u8,u8)>,
+    pub descriptor: DeviceDescriptor,
+    pub configuration: Option<Configuration>,
+    pub manufacturer: String,
+    pub product: String,
+    id: usize,
+    name: String,
+    raw: Vec<u8>, // The device descriptor followed by every configuration descriptor, which is what reading the node gives you
+    drivers: Mutex<Vec<(u8,Arc<dyn InterfaceDriver>)>>,
+    inode: Weak<USBDevice>,
+}
+
+impl USBDevice {
+    pub fn Control(&self, request_type: u8, request: u8, value: u16, index: u16, data: &mut [u8]) -> Result<usize,i64> {
+        self.hc.ControlTransfer(self.slot,SetupPacket {request_type, request, value, index},data)
+    }
+    pub fn Bulk(&self, endpoint: u8, data: &mut [u8]) -> Result<usize,i64> {
+        self.hc.BulkTransfer(self.slot,endpoint,data)
+    }
+    pub fn Interrupt(&self, endpoint: u8, data: &mut [u8]) -> Result<usize,i64> {
+        self.hc.InterruptTransfer(self.slot,endpoint,data)
+    }
+    pub fn SubmitInterrupt(&self, endpoint: u8, length: usize) -> Result<(),i64> {
+        self.hc.SubmitInterrupt(self.slot,endpoint,length)
+    }
+    pub fn ReapInterrupt(&self, endpoint: u8, data: &mut [u8]) -> Option<Result<usize,i64>> {
+        self.hc.ReapInterrupt(self.slot,endpoint,data)
+    }
+    pub fn ClearHalt(&self, endpoint: u8) -> Result<usize,i64> {
+        self.Control(RT_ENDPOINT,REQ_CLEAR_FEATURE,0,endpoint as u16,&mut [])
+    }
+    pub fn Name(&self) -> &str {
+        self.name.as_str()
+    }
+}
+
+fn GetDescriptor(hc: &Arc<dyn HostController>, slot: u8, kind: u8, index: u8, lang: u16, data: &mut [u8]) -> Result<usize,i64> {
+    hc.ControlTransfer(slot,SetupPacket {request_type: RT_IN, request: REQ_GET_DESCRIPTOR, value: ((kind as u16) << 8) | index as u16, index: lang},data)
+}
+
+fn GetString(hc: &Arc<dyn HostController>, slot: u8, index: u8) -> String {
+    if index == 0 {
+        return String::new();
+    }
+    let mut buf = [0u8; 255];
+    let len = match GetDescriptor(hc,slot,DESC_STRING,index,LANG_EN_US,&mut buf) {
+        Ok(l) => core::cmp::min(l,buf[0] as usize),
+        Err(_) => {return String::new();}
+    };
+    if len < 2 {
+        return String::new();
+    }
+    let units: Vec<u16> = buf[2..len].chunks_exact(2).map(|c| u16::from_le_bytes([c[0],c[1]])).collect();
+    char::decode_utf16(units.iter().copied()).map(|c| c.unwrap_or('?')).collect()
+}
+
+fn SpeedName(speed: u8) -> &'static str {
+    match speed {
+        SPEED_LOW => "low-speed",
+        SPEED_FULL => "full-speed",
+        SPEED_HIGH => "high-speed",
+        _ => "SuperSpeed",
+    }
+}
+
+impl DevFS::Device for USBDevice {
+    fn DeviceID(&self) -> usize {
+        self.id
+    }
+    fn Inode(&self) -> Arc<dyn VFS::Inode> {
+        self.inode.upgrade().unwrap()
+    }
+}
+
+impl VFS::Inode for USBDevice {
+    fn Stat(&self) -> Result<VFS::Metadata, i64> {
+        Ok(VFS::Metadata {
+            device_id: 0,
+            inode_id: i64::MAX,
+            mode: 0o0020444, // cr--r--r--
+            nlinks: 1,
+            uid: 0,
+            gid: 0,
+            rdev: self.id as u64,
+            size: self.raw.len() as i64,
+            blksize: 0,
+            blocks: 0,
+
+            atime: unsafe {crate::UNIX_EPOCH as i64},
+            mtime: unsafe {crate::UNIX_EPOCH as i64},
+            ctime: unsafe {crate::UNIX_EPOCH as i64},
+            reserved1: 0,
+            reserved2: 0,
+            reserved3: 0,
+        })
+    }
+    fn GetName(&self) -> Result<&str, i64> {
+        Ok(self.name.as_str())
+    }
+    fn Read(&self, offset: i64, buffer: &mut [u8]) -> i64 {
+        if offset < 0 {
+            return -(Errors::EINVAL as i64);
+        }
+        if offset as usize >= self.raw.len() {
+            return 0;
+        }
+        let len = core::cmp::min(buffer.len(),self.raw.len() - offset as usize);
+        buffer[..len].copy_from_slice(&self.raw[offset as usize..offset as usize+len]);
+        len as i64
+    }
+    fn Write(&self, _offset: i64, _buffer: &[u8]) -> i64 {
+        -(Errors::EBADF as i64)
+    }
+}
+
+static CONTROLLERS: Mutex<Vec<Arc<dyn HostController>>> = Mutex::new(Vec::new());
+static DEVICES: Mutex<Vec<Arc<USBDevice>>> = Mutex::new(Vec::new());
+static DRIVERS: Mutex<Vec<Arc<dyn ClassDriver>>> = Mutex::new(Vec::new());
+static NEXT_BUS: AtomicUsize = AtomicUsize::new(1);
+
+pub fn ReserveBusNumber() -> usize {
+    NEXT_BUS.fetch_add(1,Ordering::SeqCst)
+}
+
+// Prefer the first configuration that isn't vendor-specific, since that's the one a class driver has a chance with
+fn ChooseConfiguration(configs: &[Configuration]) -> Option<Configuration> {
+    configs.iter().find(|c| c.interfaces.first().map_or(false,|i| i.class != CLASS_VENDOR)).or(configs.first()).cloned()
+}
+
+// Offers a device's unclaimed interfaces to a set of class drivers
+fn BindDrivers(dev: &Arc<USBDevice>, drivers: &[Arc<dyn ClassDriver>]) {
+    let config = match dev.configuration.as_ref() {
+        Some(c) => c,
+        None => {return;}
+    };
+    for iface in config.interfaces.iter().filter(|i| i.alternate == 0) {
+        if dev.drivers.lock().iter().any(|(n, _)| *n == iface.number) {
+            continue;
+        }
+        for driver in drivers.iter() {
+            if !driver.Matches(dev,iface) {
+                continue;
+            }
+            match driver.Probe(dev,iface) {
+                Ok(instance) => {
+                    log::debug!("USB: {} interface {} bound to {}", dev.name, iface.number, driver.Name());
+                    dev.drivers.lock().push((iface.number,instance));
+                    break;
+                }
+                Err(e) => {
+                    log::warn!("USB: {} couldn't take {} interface {} ({})", driver.Name(), dev.name, iface.number, e);
+                }
+            }
+        }
+    }
+}
+
+pub fn RegisterController(hc: Arc<dyn HostController>) {
+    CONTROLLERS.lock().push(hc);
+}
+
+pub fn RegisterDriver(driver: Arc<dyn ClassDriver>) {
+    DRIVERS.lock().push(driver.clone());
+    // Anything that showed up before the driver did gets a look in now
+    let devices = DEVICES.lock().clone();
+    for dev in devices.iter() {
+        BindDrivers(dev,&[driver.clone()]);
+    }
+}
+
+// Enumerates a device that just got connected to `port` (a root port), or to a hub somewhere below it if `route` isn't zero
+pub fn Attach(hc: Arc<dyn HostController>, bus: usize, port: u8, speed: u8, route: u32, depth: u8, tt: Option<(u8,u8)>) -> Result<Arc<USBDevice>,i64> {
+    let slot = hc.AttachDevice(port,speed,route,tt)?;
+    match Enumerate(hc.clone(),bus,slot,port,speed,route,depth,tt) {
+        Ok(dev) => Ok(dev),
+        Err(e) => {
+            hc.DetachDevice(slot);
+            Err(e)
+        }
+    }
+}
+
+fn Enumerate(hc: Arc<dyn HostController>, bus: usize, slot: u8, port: u8, speed: u8, route: u32, depth: u8, tt: Option<(u8,u8)>) -> Result<Arc<USBDevice>,i64> {
+    let mut raw = vec![0u8; 18];
+    if GetDescriptor(&hc,slot,DESC_DEVICE,0,0,raw.as_mut_slice())? < 18 {
+        return Err(Errors::EIO as i64);
+    }
+    let descriptor = DeviceDescriptor::Parse(raw.as_slice()).ok_or(Errors::EIO as i64)?;
+    let mut configs = Vec::new();
+    for i in 0..descriptor.configurations {
+        let mut header = [0u8; 9];
+        if GetDescriptor(&hc,slot,DESC_CONFIGURATION,i,0,&mut header)? < 9 {
+            continue;
+        }
+        let total = u16::from_le_bytes([header[2],header[3]]) as usize;
+        let mut full = vec![0u8; core::cmp::min(total,0x1000)];
+        let len = GetDescriptor(&hc,slot,DESC_CONFIGURATION,i,0,full.as_mut_slice())?;
+        full.truncate(len);
+        if let Some(config) = Configuration::Parse(full.as_slice()) {
+            configs.push(config);
+        }
+        raw.extend_from_slice(full.as_slice());
+    }
+    let configuration = ChooseConfiguration(configs.as_slice());
+    if let Some(config) = configuration.as_ref() {
+        // The controller has to know about the endpoints before the device starts using them
+        for iface in config.interfaces.iter().filter(|i| i.alternate == 0) {
+            for ep in iface.endpoints.iter() {
+                hc.ConfigureEndpoint(slot,ep)?;
+            }
+        }
+        hc.ControlTransfer(slot,SetupPacket {request_type: 0, request: REQ_SET_CONFIGURATION, value: config.value as u16, index: 0},&mut [])?;
+    }
+    let manufacturer = GetString(&hc,slot,descriptor.manufacturer_str);
+    let product = GetString(&hc,slot,descriptor.product_str);
+    let dev = Arc::new_cyclic(|inode| USBDevice {
+        hc,
+        bus,
+        slot,
+        port,
+        speed,
+        route,
+        depth,
+        tt,
+        descriptor,
+        configuration,
+        manufacturer,
+        product,
+        id: DevFS::ReserveDeviceID(),
+        name: format!("usbdev{}.{}", bus, slot),
+        raw,
+        drivers: Mutex::new(Vec::new()),
+        inode: inode.clone(),
+    });
+    log::info!("USB: New {} device {:04x}:{:04x} \"{} {}\" on bus {} port {} (route 0x{:05x})", SpeedName(speed), descriptor.vendor, descriptor.product, dev.manufacturer, dev.product, bus, port, route);
+    DEVICES.lock().push(dev.clone());
+    let _ = DevFS::InstallDevice(dev.clone());
+    let drivers = DRIVERS.lock().clone();
+    BindDrivers(&dev,drivers.as_slice());
+    Ok(dev)
+}
+
+// Tears a device down once it's been unplugged. Hub drivers detach whatever was behind them when they get disconnected.
+pub fn Detach(dev: &Arc<USBDevice>) {
+    {
+        let mut lock = DEVICES.lock();
+        match lock.iter().position(|d| Arc::ptr_eq(d,dev)) {
+            Some(pos) => {lock.remove(pos);}
+            None => {return;}
+        }
+    }
+    log::info!("USB: {} ({:04x}:{:04x}) disconnected", dev.name, dev.descriptor.vendor, dev.descriptor.product);
+    let drivers = core::mem::take(&mut *dev.drivers.lock());
+    for (_, driver) in drivers.iter() {
+        driver.Disconnect();
+    }
+    dev.hc.DetachDevice(dev.slot);
+    DevFS::RemoveDevice(dev.id);
+}
+
+// Gives every bound interface a chance to look at its interrupt endpoints
+fn PollDrivers() {
+    let devices = DEVICES.lock().clone();
+    for dev in devices.iter() {
+        let drivers = dev.drivers.lock().clone();
+        for (_, driver) in drivers.iter() {
+            driver.Poll();
+        }
+    }
+}
+
+// Enumerating a device means waiting on it, which can't be done from an interrupt handler. So host controllers just
+// note down their port changes there, and this thread comes around to deal with them, sleeping through the waits so
+// everything else on the hart still gets to run. Hubs get serviced from their Poll, so they end up here too.
+fn Worker() -> ! {
+    loop {
+        let controllers = CONTROLLERS.lock().clone();
+        for hc in controllers.into_iter() {
+            hc.ServicePorts();
+        }
+        PollDrivers();
+        Scheduler::Sleep((POLL_INTERVAL / 1000) as isize);
+    }
+}
+
+// Has to wait until init is loaded, since that needs to be PID 1
+pub fn StartWorker() {
+    if !CONTROLLERS.lock().is_empty() {
+        Process::StartKernelThread("[usb]",Worker);
+    }
+}
+
+pub fn Initalize() {
+    RegisterDriver(Arc::new(super::USBHub::HubDriver));
+    RegisterDriver(Arc::new(super::USBHID::HIDDriver));
+    RegisterDriver(Arc::new(super::USBStorage::StorageDriver));
+}
@@ -0,0 +1,547 @@
+use super::USB;
+use super::Keyboard;
+use super::Mouse;
+use super::Input;
+use alloc::sync::{Arc, Weak};
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use alloc::vec;
+use spin::Mutex;
+use crate::arch::Timer;
+use crate::Syscall::Errors;
+
+const DESC_HID: u8 = 0x21;
+const DESC_REPORT: u8 = 0x22;
+const REQ_SET_IDLE: u8 = 0x0A;
+const REQ_SET_PROTOCOL: u8 = 0x0B;
+
+const SUBCLASS_BOOT: u8 = 1;
+const PROTOCOL_KEYBOARD: u8 = 1;
+const PROTOCOL_MOUSE: u8 = 2;
+
+const PAGE_GENERIC_DESKTOP: u32 = 0x01;
+const PAGE_KEYBOARD: u32 = 0x07;
+const PAGE_BUTTON: u32 = 0x09;
+const USAGE_X: u32 = (PAGE_GENERIC_DESKTOP << 16) | 0x30;
+const USAGE_Y: u32 = (PAGE_GENERIC_DESKTOP << 16) | 0x31;
+const USAGE_WHEEL: u32 = (PAGE_GENERIC_DESKTOP << 16) | 0x38;
+const KEY_ERROR_ROLLOVER: u32 = (PAGE_KEYBOARD << 16) | 0x01;
+
+const REPEAT_DELAY: u64 = 500000;
+const REPEAT_RATE: u64 = 33000;
+
+// The report descriptors from appendix B of the HID spec. Boot protocol reports always look like this,
+// so when a device's own descriptor doesn't make sense we switch it to boot protocol and parse these instead.
+const BOOT_KEYBOARD: [u8; 63] = [
+    0x05, 0x01, 0x09, 0x06, 0xA1, 0x01, 0x05, 0x07, 0x19, 0xE0, 0x29, 0xE7, 0x15, 0x00, 0x25, 0x01,
+    0x75, 0x01, 0x95, 0x08, 0x81, 0x02, 0x95, 0x01, 0x75, 0x08, 0x81, 0x01, 0x95, 0x05, 0x75, 0x01,
+    0x05, 0x08, 0x19, 0x01, 0x29, 0x05, 0x91, 0x02, 0x95, 0x01, 0x75, 0x03, 0x91, 0x01, 0x95, 0x06,
+    0x75, 0x08, 0x15, 0x00, 0x25, 0x65, 0x05, 0x07, 0x19, 0x00, 0x29, 0x65, 0x81, 0x00, 0xC0,
+];
+const BOOT_MOUSE: [u8; 50] = [
+    0x05, 0x01, 0x09, 0x02, 0xA1, 0x01, 0x09, 0x01, 0xA1, 0x00, 0x05, 0x09, 0x19, 0x01, 0x29, 0x03,
+    0x15, 0x00, 0x25, 0x01, 0x95, 0x03, 0x75, 0x01, 0x81, 0x02, 0x95, 0x01, 0x75, 0x05, 0x81, 0x01,
+    0x05, 0x01, 0x09, 0x30, 0x09, 0x31, 0x15, 0x81, 0x25, 0x7F, 0x75, 0x08, 0x95, 0x02, 0x81, 0x06,
+    0xC0, 0xC0,
+];
+
+// HID keyboard usages to scan code set 1 make codes. 0xE0xx codes get an 0xE0 prefix, and 0 means there's nothing to send.
+const SCANCODES: [u16; 0x66] = [
+    0, 0, 0, 0, 0x1E, 0x30, 0x2E, 0x20, 0x12, 0x21, 0x22, 0x23, 0x17, 0x24, 0x25, 0x26, // 0x00: a-l
+    0x32, 0x31, 0x18, 0x19, 0x10, 0x13, 0x1F, 0x14, 0x16, 0x2F, 0x11, 0x2D, 0x15, 0x2C, 0x02, 0x03, // 0x10: m-z, 1-2
+    0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0A, 0x0B, 0x1C, 0x01, 0x0E, 0x0F, 0x39, 0x0C, 0x0D, 0x1A, // 0x20: 3-0, enter, esc, backspace, tab, space, - = [
+    0x1B, 0x2B, 0x2B, 0x27, 0x28, 0x29, 0x33, 0x34, 0x35, 0x3A, 0x3B, 0x3C, 0x3D, 0x3E, 0x3F, 0x40, // 0x30: ] \ # ; ' ` , . / caps, F1-F6
+    0x41, 0x42, 0x43, 0x44, 0x57, 0x58, 0xE037, 0x46, 0, 0xE052, 0xE047, 0xE049, 0xE053, 0xE04F, 0xE051, 0xE04D, // 0x40: F7-F12, prtsc, scroll, pause, ins, home, pgup, del, end, pgdn, right
+    0xE04B, 0xE050, 0xE048, 0x45, 0xE035, 0x37, 0x4A, 0x4E, 0xE01C, 0x4F, 0x50, 0x51, 0x4B, 0x4C, 0x4D, 0x47, // 0x50: left, down, up, numlock, keypad
+    0x48, 0x49, 0x52, 0x53, 0x56, 0xE05D, // 0x60: keypad 8 9 0 ., non-US \, menu
+];
+const MODIFIER_SCANCODES: [u16; 8] = [0x1D, 0x2A, 0x38, 0xE05B, 0xE01D, 0x36, 0xE038, 0xE05C];
+
+// One Input item out of a report descriptor
+#[derive(Clone)]
+struct Field {
+    report_id: u8,
+    offset: usize, // In bits, not counting the report ID byte
+    size: usize,
+    count: usize,
+    usages: Vec<u32>, // Usage page in the top half
+    usage_min: u32,
+    usage_max: u32,
+    logical_min: i32,
+    logical_max: i32,
+    variable: bool,
+    relative: bool,
+}
+
+impl Field {
+    // For variable fields, the usage that goes with entry `i`
+    fn Usage(&self, i: usize) -> Option<u32> {
+        if !self.usages.is_empty() {
+            return Some(self.usages[core::cmp::min(i,self.usages.len()-1)]);
+        }
+        let usage = self.usage_min + i as u32;
+        if usage <= self.usage_max && self.usage_max != 0 {Some(usage)} else {None}
+    }
+    // For array fields, the usage a value stands for
+    fn ArrayUsage(&self, value: i32) -> Option<u32> {
+        let index = value.checked_sub(self.logical_min)?;
+        if index < 0 {
+            return None;
+        }
+        if !self.usages.is_empty() {
+            return self.usages.get(index as usize).copied();
+        }
+        let usage = self.usage_min + index as u32;
+        if usage <= self.usage_max {Some(usage)} else {None}
+    }
+    fn Value(&self, report: &[u8], i: usize) -> i32 {
+        let start = self.offset + (i * self.size);
+        let mut raw: u32 = 0;
+        for bit in 0..core::cmp::min(self.size,32) {
+            let pos = start + bit;
+            if pos / 8 < report.len() && report[pos / 8] & (1 << (pos % 8)) != 0 {
+                raw |= 1 << bit;
+            }
+        }
+        if self.logical_min < 0 && self.size < 32 && raw & (1 << (self.size - 1)) != 0 {
+            (raw | (u32::MAX << self.size)) as i32
+        } else {
+            raw as i32
+        }
+    }
+}
+
+struct ReportLayout {
+    fields: Vec<Field>,
+    uses_ids: bool,
+}
+
+impl ReportLayout {
+    fn Parse(desc: &[u8]) -> Self {
+        #[derive(Clone,Copy,Default)]
+        struct Globals {
+            page: u32,
+            logical_min: i32,
+            logical_max: i32,
+            size: usize,
+            count: usize,
+            report_id: u8,
+        }
+        let mut globals = Globals::default();
+        let mut stack: Vec<Globals> = Vec::new();
+        // Local items. Usages that didn't come with their own page get the current one once the main item shows up.
+        let mut usages: Vec<(u32,bool)> = Vec::new();
+        let mut usage_min = (0u32,false);
+        let mut usage_max = (0u32,false);
+        let mut offsets: BTreeMap<u8,usize> = BTreeMap::new();
+        let mut layout = Self {fields: Vec::new(), uses_ids: false};
+        let mut i = 0;
+        while i < desc.len() {
+            let prefix = desc[i];
+            if prefix == 0xFE {
+                // Long item, nobody uses these
+                if i + 1 >= desc.len() {break;}
+                i += 3 + desc[i+1] as usize;
+                continue;
+            }
+            let size = match prefix & 0x3 {3 => 4, s => s as usize};
+            if i + 1 + size > desc.len() {
+                break;
+            }
+            let mut data: u32 = 0;
+            for b in 0..size {
+                data |= (desc[i+1+b] as u32) << (b * 8);
+            }
+            let signed = match size {
+                1 => data as u8 as i8 as i32,
+                2 => data as u16 as i16 as i32,
+                _ => data as i32,
+            };
+            let full = size == 4;
+            match (prefix >> 2) & 0x3 {
+                0 => {
+                    // Main
+                    if prefix >> 4 == 0x8 {
+                        let resolve = |(u, full): (u32,bool)| if full {u} else {(globals.page << 16) | u};
+                        let offset = offsets.entry(globals.report_id).or_insert(0);
+                        // Values get read into 32 bits, so anything wider (or empty) can't be decoded and just takes up space
+                        if data & 1 == 0 && (1..=32).contains(&globals.size) {
+                            layout.fields.push(Field {
+                                report_id: globals.report_id,
+                                offset: *offset,
+                                size: globals.size,
+                                count: globals.count,
+                                usages: usages.iter().map(|u| resolve(*u)).collect(),
+                                usage_min: resolve(usage_min),
+                                usage_max: resolve(usage_max),
+                                logical_min: globals.logical_min,
+                                logical_max: globals.logical_max,
+                                variable: data & 2 != 0,
+                                relative: data & 4 != 0,
+                            });
+                        }
+                        *offset += globals.size * globals.count;
+                    }
+                    usages.clear();
+                    usage_min = (0,false);
+                    usage_max = (0,false);
+                }
+                1 => {
+                    // Global
+                    match prefix >> 4 {
+                        0x0 => {globals.page = data;}
+                        0x1 => {globals.logical_min = signed;}
+                        0x2 => {
+                            // Only negative if the minimum is, otherwise a one byte 255 would come out as -1
+                            globals.logical_max = if globals.logical_min < 0 {signed} else {data as i32};
+                        }
+                        0x7 => {globals.size = data as usize;}
+                        0x8 => {
+                            globals.report_id = data as u8;
+                            layout.uses_ids = true;
+                        }
+                        0x9 => {globals.count = data as usize;}
+                        0xA => {stack.push(globals);}
+                        0xB => {globals = stack.pop().unwrap_or(globals);}
+                        _ => {}
+                    }
+                }
+                2 => {
+                    // Local
+                    match prefix >> 4 {
+                        0x0 => {usages.push((data,full));}
+                        0x1 => {usage_min = (data,full);}
+                        0x2 => {usage_max = (data,full);}
+                        _ => {}
+                    }
+                }
+                _ => {}
+            }
+            i += 1 + size;
+        }
+        layout
+    }
+
+    fn Fields<'a>(&'a self, report: &'a [u8]) -> (impl Iterator<Item = &'a Field>, &'a [u8]) {
+        let (id, data) = if self.uses_ids && !report.is_empty() {(report[0], &report[1..])} else {(0, report)};
+        (self.fields.iter().filter(move |f| f.report_id == id), data)
+    }
+
+    fn HasPage(&self, page: u32) -> bool {
+        self.fields.iter().any(|f| (0..f.count).any(|i| f.Usage(i).map_or(false,|u| u >> 16 == page)) || f.usage_min >> 16 == page && !f.variable)
+    }
+
+    fn IsKeyboard(&self) -> bool {
+        self.HasPage(PAGE_KEYBOARD)
+    }
+
+    fn Axis(&self, usage: u32) -> Option<&Field> {
+        self.fields.iter().find(|f| f.variable && (0..f.count).any(|i| f.Usage(i) == Some(usage)))
+    }
+
+    fn IsMouse(&self) -> bool {
+        self.Axis(USAGE_X).map_or(false,|f| f.relative) && self.Axis(USAGE_Y).map_or(false,|f| f.relative)
+    }
+
+    // Tablets, touchscreens and QEMU's usb-tablet say where the pointer is rather than how far it moved
+    fn IsTablet(&self) -> bool {
+        self.Axis(USAGE_X).map_or(false,|f| !f.relative) && self.Axis(USAGE_Y).map_or(false,|f| !f.relative)
+    }
+
+    fn HasWheel(&self) -> bool {
+        self.Axis(USAGE_WHEEL).is_some()
+    }
+
+    // Keyboard usages held down in this report, or None if the keyboard says it's lost track (too many keys at once)
+    fn Keys(&self, report: &[u8]) -> Option<Vec<u8>> {
+        let mut keys = Vec::new();
+        let (fields, data) = self.Fields(report);
+        for f in fields {
+            for i in 0..f.count {
+                let value = f.Value(data,i);
+                let usage = if f.variable {
+                    if value == 0 {continue;}
+                    f.Usage(i)
+                } else {
+                    f.ArrayUsage(value)
+                };
+                match usage {
+                    Some(KEY_ERROR_ROLLOVER) => {return None;}
+                    Some(u) if u >> 16 == PAGE_KEYBOARD && u & 0xFFFF > 3 && u & 0xFFFF <= 0xFF => {keys.push(u as u8);}
+                    _ => {}
+                }
+            }
+        }
+        Some(keys)
+    }
+
+    fn Pointer(&self, report: &[u8]) -> Mouse::MousePacket {
+        let mut packet = Mouse::MousePacket::default();
+        let (fields, data) = self.Fields(report);
+        for f in fields.filter(|f| f.variable) {
+            for i in 0..f.count {
+                let usage = match f.Usage(i) {
+                    Some(u) => u,
+                    None => {continue;}
+                };
+                let value = f.Value(data,i);
+                match usage {
+                    USAGE_X => {packet.dx = value;}
+                    USAGE_Y => {packet.dy = value;}
+                    USAGE_WHEEL => {packet.wheel = -value;} // HID scrolls up with positive values
+                    u if u >> 16 == PAGE_BUTTON && u & 0xFFFF >= 1 && u & 0xFFFF <= 32 => {
+                        if value != 0 {
+                            packet.buttons |= 1 << ((u & 0xFFFF) - 1);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+        packet
+    }
+}
+
+fn Scancode(usage: u8) -> u16 {
+    if (0xE0..=0xE7).contains(&usage) {
+        MODIFIER_SCANCODES[(usage - 0xE0) as usize]
+    } else if (usage as usize) < SCANCODES.len() {
+        SCANCODES[usage as usize]
+    } else {
+        0
+    }
+}
+
+struct KeyboardState {
+    pressed: Vec<u8>,
+    repeat: Option<(u8,u64)>, // The key that's auto-repeating and when it fires next
+}
+
+struct HIDKeyboard {
+    dev: Weak<USB::USBDevice>,
+    endpoint: u8,
+    length: usize,
+    layout: ReportLayout,
+    state: Mutex<KeyboardState>,
+    input: Arc<Input::InputDevice>,
+}
+
+impl HIDKeyboard {
+    fn EmitKey(&self, usage: u8, pressed: bool) {
+        let code = Scancode(usage);
+        // Pause has no make code of its own in set 1, only the odd E1 sequence nobody wants to fake
+        let keycode = if usage == 0x48 {Input::KEY_PAUSE} else {Input::Set1Keycode(code)};
+        if keycode != 0 {
+            self.input.Key(keycode,pressed);
+        }
+        if code == 0 {
+            return;
+        }
+        if code >> 8 == 0xE0 {
+            Keyboard::Push(0xE0);
+        }
+        Keyboard::Push((code as u8) | if pressed {0} else {0x80});
+    }
+    fn Report(&self, report: &[u8]) {
+        let keys = match self.layout.Keys(report) {
+            Some(k) => k,
+            None => {return;}
+        };
+        let mut state = self.state.lock();
+        for key in state.pressed.iter() {
+            if !keys.contains(key) {
+                self.EmitKey(*key,false);
+            }
+        }
+        for key in keys.iter() {
+            if !state.pressed.contains(key) {
+                self.EmitKey(*key,true);
+                // USB keyboards don't repeat on their own like PS/2 ones do, so that's up to us. Modifiers never repeat.
+                if *key < 0xE0 {
+                    state.repeat = Some((*key,Timer::GetMicroseconds() + REPEAT_DELAY));
+                }
+            }
+        }
+        if let Some((key, _)) = state.repeat {
+            if !keys.contains(&key) {
+                state.repeat = None;
+            }
+        }
+        state.pressed = keys;
+        self.input.Sync();
+    }
+}
+
+impl USB::InterfaceDriver for HIDKeyboard {
+    fn Poll(&self) {
+        let dev = match self.dev.upgrade() {
+            Some(d) => d,
+            None => {return;}
+        };
+        let mut report = vec![0u8; self.length];
+        match dev.ReapInterrupt(self.endpoint,report.as_mut_slice()) {
+            None => {}
+            Some(result) => {
+                if let Ok(len) = result {
+                    self.Report(&report[..len]);
+                }
+                let _ = dev.SubmitInterrupt(self.endpoint,self.length);
+            }
+        }
+        let mut state = self.state.lock();
+        if let Some((key, next)) = state.repeat {
+            let now = Timer::GetMicroseconds();
+            if now >= next {
+                self.EmitKey(key,true);
+                self.input.Sync();
+                state.repeat = Some((key,now + REPEAT_RATE));
+            }
+        }
+    }
+    fn Disconnect(&self) {
+        // Let go of everything, otherwise a key that was held while unplugging stays down forever
+        let mut state = self.state.lock();
+        for key in state.pressed.iter() {
+            self.EmitKey(*key,false);
+        }
+        state.pressed.clear();
+        state.repeat = None;
+        Input::Unregister(&self.input);
+    }
+}
+
+struct HIDMouse {
+    dev: Weak<USB::USBDevice>,
+    endpoint: u8,
+    length: usize,
+    layout: ReportLayout,
+    absolute: bool,
+    input: Arc<Input::InputDevice>,
+}
+
+impl USB::InterfaceDriver for HIDMouse {
+    fn Poll(&self) {
+        let dev = match self.dev.upgrade() {
+            Some(d) => d,
+            None => {return;}
+        };
+        let mut report = vec![0u8; self.length];
+        if let Some(result) = dev.ReapInterrupt(self.endpoint,report.as_mut_slice()) {
+            if let Ok(len) = result {
+                let packet = self.layout.Pointer(&report[..len]);
+                self.input.ReportPointer(&packet,self.absolute);
+                // /dev/mouse only knows about movement, there's nothing sensible to tell it about an absolute position
+                if !self.absolute {
+                    Mouse::Push(packet);
+                }
+            }
+            let _ = dev.SubmitInterrupt(self.endpoint,self.length);
+        }
+    }
+    fn Disconnect(&self) {
+        Input::Unregister(&self.input);
+    }
+}
+
+pub struct HIDDriver;
+
+impl USB::ClassDriver for HIDDriver {
+    fn Name(&self) -> &str {
+        "usbhid"
+    }
+    fn Matches(&self, _device: &USB::USBDevice, interface: &USB::Interface) -> bool {
+        interface.class == USB::CLASS_HID
+    }
+    fn Probe(&self, dev: &Arc<USB::USBDevice>, interface: &USB::Interface) -> Result<Arc<dyn USB::InterfaceDriver>,i64> {
+        let endpoint = interface.FindEndpoint(USB::XFER_INTERRUPT,true).ok_or(Errors::ENODEV as i64)?;
+        let index = interface.number as u16;
+        // Only report when something changes. Plenty of mice don't support this and stall, which is fine.
+        let _ = dev.Control(USB::RT_CLASS | USB::RT_INTERFACE,REQ_SET_IDLE,0,index,&mut []);
+        // The HID descriptor says how long the report descriptor is
+        let mut report_len = 0;
+        let mut pos = 0;
+        let extra = interface.extra.as_slice();
+        while pos + 2 <= extra.len() && extra[pos] >= 2 {
+            let d = &extra[pos..core::cmp::min(pos + extra[pos] as usize,extra.len())];
+            if d[1] == DESC_HID && d.len() >= 9 {
+                for n in 0..d[5] as usize {
+                    let at = 6 + (n * 3);
+                    if at + 3 <= d.len() && d[at] == DESC_REPORT {
+                        report_len = u16::from_le_bytes([d[at+1],d[at+2]]) as usize;
+                    }
+                }
+            }
+            pos += extra[pos] as usize;
+        }
+        let mut layout = None;
+        if report_len > 0 {
+            let mut desc = vec![0u8; report_len];
+            if let Ok(len) = dev.Control(USB::RT_IN | USB::RT_INTERFACE,USB::REQ_GET_DESCRIPTOR,(DESC_REPORT as u16) << 8,index,desc.as_mut_slice()) {
+                let parsed = ReportLayout::Parse(&desc[..len]);
+                if parsed.IsKeyboard() || parsed.IsMouse() || parsed.IsTablet() {
+                    layout = Some(parsed);
+                }
+            }
+        }
+        let layout = match layout {
+            Some(l) => {
+                if interface.subclass == SUBCLASS_BOOT {
+                    let _ = dev.Control(USB::RT_CLASS | USB::RT_INTERFACE,REQ_SET_PROTOCOL,1,index,&mut []);
+                }
+                l
+            }
+            None if interface.subclass == SUBCLASS_BOOT && (interface.protocol == PROTOCOL_KEYBOARD || interface.protocol == PROTOCOL_MOUSE) => {
+                dev.Control(USB::RT_CLASS | USB::RT_INTERFACE,REQ_SET_PROTOCOL,0,index,&mut [])?;
+                ReportLayout::Parse(if interface.protocol == PROTOCOL_KEYBOARD {&BOOT_KEYBOARD} else {&BOOT_MOUSE})
+            }
+            None => {return Err(Errors::ENODEV as i64);}
+        };
+        let length = core::cmp::max(endpoint.max_packet as usize,8);
+        let name = if dev.product.is_empty() {dev.Name()} else {dev.product.as_str()};
+        let driver: Arc<dyn USB::InterfaceDriver> = if layout.IsKeyboard() {
+            log::info!("USB: {} interface {} is a keyboard", dev.Name(), interface.number);
+            Keyboard::Attach();
+            Arc::new(HIDKeyboard {
+                dev: Arc::downgrade(dev),
+                endpoint: endpoint.address,
+                length,
+                layout,
+                state: Mutex::new(KeyboardState {pressed: Vec::new(), repeat: None}),
+                input: Input::Register(name,Input::KeyboardCapabilities()),
+            })
+        } else if layout.IsTablet() {
+            log::info!("USB: {} interface {} is a tablet", dev.Name(), interface.number);
+            let range = |usage: u32| layout.Axis(usage).map_or((0,0),|f| (f.logical_min,f.logical_max));
+            let ((xmin, xmax), (ymin, ymax)) = (range(USAGE_X),range(USAGE_Y));
+            let mut caps = Input::Capabilities::new().Keys(Input::BTN_LEFT..=Input::BTN_EXTRA).Abs(Input::ABS_X,xmin,xmax).Abs(Input::ABS_Y,ymin,ymax);
+            if layout.HasWheel() {
+                caps = caps.Rel(Input::REL_WHEEL);
+            }
+            Arc::new(HIDMouse {
+                dev: Arc::downgrade(dev),
+                endpoint: endpoint.address,
+                length,
+                layout,
+                absolute: true,
+                input: Input::Register(name,caps),
+            })
+        } else {
+            log::info!("USB: {} interface {} is a mouse", dev.Name(), interface.number);
+            Mouse::Attach();
+            let caps = Input::MouseCapabilities(layout.HasWheel());
+            Arc::new(HIDMouse {
+                dev: Arc::downgrade(dev),
+                endpoint: endpoint.address,
+                length,
+                layout,
+                absolute: false,
+                input: Input::Register(name,caps),
+            })
+        };
+        if let Err(e) = dev.SubmitInterrupt(endpoint.address,length) {
+            driver.Disconnect();
+            return Err(e);
+        }
+        Ok(driver)
+    }
+}
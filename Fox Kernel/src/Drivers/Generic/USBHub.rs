@@ -0,0 +1,226 @@
+use super::USB;
+use alloc::sync::{Arc, Weak};
+use alloc::collections::BTreeMap;
+use alloc::vec;
+use spin::Mutex;
+use crate::arch::Timer;
+use crate::Syscall::Errors;
+use crate::Scheduler::Scheduler;
+
+const DESC_HUB: u8 = 0x29;
+const DESC_SS_HUB: u8 = 0x2A;
+const REQ_SET_HUB_DEPTH: u8 = 12;
+
+const PORT_CONNECTION: u16 = 1 << 0;
+const PORT_ENABLE: u16 = 1 << 1;
+const PORT_LOW_SPEED: u16 = 1 << 9;
+const PORT_HIGH_SPEED: u16 = 1 << 10;
+const C_PORT_CONNECTION: u16 = 1 << 0;
+const C_PORT_RESET: u16 = 1 << 4;
+
+const FEAT_PORT_RESET: u16 = 4;
+const FEAT_PORT_POWER: u16 = 8;
+const FEAT_C_PORT_RESET: u16 = 20;
+
+// Which feature clears each bit of wPortChange. USB 2 hubs only use the first five, USB 3 ones reuse the
+// over-current and reset bits and add their own for warm resets, link state and config errors.
+const CHANGE_FEATURES: [u16; 5] = [16, 17, 18, 19, 20];
+const SS_CHANGE_FEATURES: [(u16,u16); 6] = [(0,16), (3,19), (4,20), (5,29), (6,25), (7,26)];
+
+const MAX_DEPTH: u8 = 5; // The route string only has room for five tiers
+
+pub struct HubDriver;
+
+struct Hub {
+    dev: Weak<USB::USBDevice>,
+    ports: u8,
+    endpoint: u8,
+    superspeed: bool,
+    children: Mutex<BTreeMap<u8,Arc<USB::USBDevice>>>,
+}
+
+impl Hub {
+    fn PortStatus(&self, dev: &USB::USBDevice, port: u8) -> Result<(u16,u16),i64> {
+        let mut status = [0u8; 4];
+        dev.Control(USB::RT_IN | USB::RT_CLASS | USB::RT_OTHER,USB::REQ_GET_STATUS,0,port as u16,&mut status)?;
+        Ok((u16::from_le_bytes([status[0],status[1]]),u16::from_le_bytes([status[2],status[3]])))
+    }
+    fn SetFeature(&self, dev: &USB::USBDevice, port: u8, feature: u16) -> Result<usize,i64> {
+        dev.Control(USB::RT_CLASS | USB::RT_OTHER,USB::REQ_SET_FEATURE,feature,port as u16,&mut [])
+    }
+    fn ClearFeature(&self, dev: &USB::USBDevice, port: u8, feature: u16) -> Result<usize,i64> {
+        dev.Control(USB::RT_CLASS | USB::RT_OTHER,USB::REQ_CLEAR_FEATURE,feature,port as u16,&mut [])
+    }
+    fn ClearChanges(&self, dev: &USB::USBDevice, port: u8, change: u16) {
+        if self.superspeed {
+            for (bit, feature) in SS_CHANGE_FEATURES.iter() {
+                if change & (1 << bit) != 0 {
+                    let _ = self.ClearFeature(dev,port,*feature);
+                }
+            }
+        } else {
+            for (bit, feature) in CHANGE_FEATURES.iter().enumerate() {
+                if change & (1 << bit) != 0 {
+                    let _ = self.ClearFeature(dev,port,*feature);
+                }
+            }
+        }
+    }
+    // Resets a port and returns the speed of whatever came up on it
+    fn ResetPort(&self, dev: &USB::USBDevice, port: u8) -> Option<u8> {
+        let (status, _) = self.PortStatus(dev,port).ok()?;
+        // SuperSpeed links come up enabled on their own
+        if !(self.superspeed && status & PORT_ENABLE != 0) {
+            self.SetFeature(dev,port,FEAT_PORT_RESET).ok()?;
+            let start = Timer::GetMicroseconds();
+            loop {
+                let (_, change) = self.PortStatus(dev,port).ok()?;
+                if change & C_PORT_RESET != 0 {
+                    break;
+                }
+                if Timer::GetMicroseconds() - start > 500000 {
+                    return None;
+                }
+                Scheduler::Sleep(10);
+            }
+            let _ = self.ClearFeature(dev,port,FEAT_C_PORT_RESET);
+            Scheduler::Sleep(10); // Reset recovery
+        }
+        let (status, _) = self.PortStatus(dev,port).ok()?;
+        if status & PORT_ENABLE == 0 {
+            return None;
+        }
+        Some(if self.superspeed {
+            USB::SPEED_SUPER
+        } else if status & PORT_LOW_SPEED != 0 {
+            USB::SPEED_LOW
+        } else if status & PORT_HIGH_SPEED != 0 {
+            USB::SPEED_HIGH
+        } else {
+            USB::SPEED_FULL
+        })
+    }
+    fn ServicePort(&self, dev: &Arc<USB::USBDevice>, port: u8) {
+        let (status, change) = match self.PortStatus(dev,port) {
+            Ok(s) => s,
+            Err(_) => {return;}
+        };
+        self.ClearChanges(dev,port,change);
+        let existing = self.children.lock().get(&port).cloned();
+        if let Some(child) = existing {
+            if change & C_PORT_CONNECTION == 0 && status & PORT_CONNECTION != 0 {
+                return;
+            }
+            self.children.lock().remove(&port);
+            USB::Detach(&child);
+        }
+        if status & PORT_CONNECTION == 0 {
+            return;
+        }
+        if dev.depth + 1 > MAX_DEPTH {
+            log::warn!("USB: {} port {}: Too many hubs deep, ignoring device", dev.Name(), port);
+            return;
+        }
+        Scheduler::Sleep(100); // Debounce
+        let speed = match self.ResetPort(dev,port) {
+            Some(s) => s,
+            None => {
+                log::warn!("USB: {} port {}: Couldn't reset port", dev.Name(), port);
+                return;
+            }
+        };
+        let route = dev.route | ((core::cmp::min(port,15) as u32) << (dev.depth * 4));
+        // Low and full-speed devices behind a high-speed hub go through its transaction translator
+        let tt = match speed {
+            USB::SPEED_LOW | USB::SPEED_FULL if dev.speed == USB::SPEED_HIGH => Some((dev.slot,port)),
+            USB::SPEED_LOW | USB::SPEED_FULL => dev.tt,
+            _ => None,
+        };
+        match USB::Attach(dev.hc.clone(),dev.bus,dev.port,speed,route,dev.depth + 1,tt) {
+            Ok(child) => {
+                self.children.lock().insert(port,child);
+            }
+            Err(e) => {
+                log::warn!("USB: {} port {}: Couldn't enumerate device ({})", dev.Name(), port, e);
+            }
+        }
+    }
+}
+
+impl USB::InterfaceDriver for Hub {
+    fn Poll(&self) {
+        let dev = match self.dev.upgrade() {
+            Some(d) => d,
+            None => {return;}
+        };
+        let mut bitmap = vec![0u8; (self.ports as usize / 8) + 1];
+        match dev.ReapInterrupt(self.endpoint,bitmap.as_mut_slice()) {
+            None => {return;}
+            Some(Ok(len)) => {
+                // Bit 0 is the hub itself, which we don't care about. Every other bit is a port with something new to report.
+                for port in 1..=self.ports {
+                    let byte = port as usize / 8;
+                    if byte < len && bitmap[byte] & (1 << (port % 8)) != 0 {
+                        self.ServicePort(&dev,port);
+                    }
+                }
+            }
+            Some(Err(e)) if e != Errors::EINVAL as i64 => {
+                log::warn!("USB: {} status change endpoint failed ({})", dev.Name(), e);
+            }
+            Some(Err(_)) => {}
+        }
+        let _ = dev.SubmitInterrupt(self.endpoint,bitmap.len());
+    }
+    fn Disconnect(&self) {
+        let children = core::mem::take(&mut *self.children.lock());
+        for (_, child) in children.iter() {
+            USB::Detach(child);
+        }
+    }
+}
+
+impl USB::ClassDriver for HubDriver {
+    fn Name(&self) -> &str {
+        "hub"
+    }
+    fn Matches(&self, _device: &USB::USBDevice, interface: &USB::Interface) -> bool {
+        interface.class == USB::CLASS_HUB
+    }
+    fn Probe(&self, dev: &Arc<USB::USBDevice>, interface: &USB::Interface) -> Result<Arc<dyn USB::InterfaceDriver>,i64> {
+        let endpoint = interface.FindEndpoint(USB::XFER_INTERRUPT,true).ok_or(Errors::ENODEV as i64)?;
+        let superspeed = dev.speed >= USB::SPEED_SUPER;
+        let mut desc = [0u8; 12];
+        let kind = if superspeed {DESC_SS_HUB} else {DESC_HUB};
+        if dev.Control(USB::RT_IN | USB::RT_CLASS,USB::REQ_GET_DESCRIPTOR,(kind as u16) << 8,0,&mut desc)? < 7 {
+            return Err(Errors::EIO as i64);
+        }
+        let ports = desc[2];
+        let characteristics = u16::from_le_bytes([desc[3],desc[4]]);
+        let power_good = desc[5] as isize * 2;
+        dev.hc.ConfigureHub(dev.slot,ports,((characteristics >> 5) & 0x3) as u8,dev.descriptor.protocol == 2)?;
+        if superspeed {
+            dev.Control(USB::RT_CLASS,REQ_SET_HUB_DEPTH,dev.depth as u16,0,&mut [])?;
+        }
+        log::info!("USB: {} is a {}-port hub", dev.Name(), ports);
+        let hub = Arc::new(Hub {
+            dev: Arc::downgrade(dev),
+            ports,
+            endpoint: endpoint.address,
+            superspeed,
+            children: Mutex::new(BTreeMap::new()),
+        });
+        for port in 1..=ports {
+            let _ = hub.SetFeature(dev,port,FEAT_PORT_POWER);
+        }
+        Scheduler::Sleep(core::cmp::max(power_good,100));
+        // Whatever was plugged in before the ports got power won't necessarily raise a status change, so look at every port once
+        for port in 1..=ports {
+            hub.ServicePort(dev,port);
+        }
+        if let Err(e) = dev.SubmitInterrupt(endpoint.address,(ports as usize / 8) + 1) {
+            log::warn!("USB: {} won't report hotplug events ({})", dev.Name(), e);
+        }
+        Ok(hub)
+    }
+}
@@ -0,0 +1,296 @@
+use super::USB;
+use super::BlockDevice;
+use alloc::sync::{Arc, Weak};
+use alloc::string::String;
+use alloc::vec::Vec;
+use alloc::vec;
+use spin::Mutex;
+use crate::FS::DevFS::Device;
+use crate::FS::DevFS;
+use crate::Scheduler::Scheduler;
+use crate::Syscall::Errors;
+
+const SUBCLASS_SCSI: u8 = 0x06;
+const PROTOCOL_BULK_ONLY: u8 = 0x50;
+
+const REQ_RESET: u8 = 0xFF;
+const REQ_GET_MAX_LUN: u8 = 0xFE;
+
+const CBW_SIGNATURE: u32 = 0x43425355; // "USBC"
+const CSW_SIGNATURE: u32 = 0x53425355; // "USBS"
+const CSW_PASSED: u8 = 0;
+const CSW_FAILED: u8 = 1;
+
+const SCSI_TEST_UNIT_READY: u8 = 0x00;
+const SCSI_REQUEST_SENSE: u8 = 0x03;
+const SCSI_INQUIRY: u8 = 0x12;
+const SCSI_READ_CAPACITY_10: u8 = 0x25;
+const SCSI_READ_10: u8 = 0x28;
+const SCSI_WRITE_10: u8 = 0x2A;
+const SCSI_READ_16: u8 = 0x88;
+const SCSI_WRITE_16: u8 = 0x8A;
+const SCSI_SERVICE_ACTION_IN: u8 = 0x9E;
+const SA_READ_CAPACITY_16: u8 = 0x10;
+
+const SENSE_NOT_READY: u8 = 0x2;
+const SENSE_UNIT_ATTENTION: u8 = 0x6;
+
+const MAX_COMMAND_BYTES: usize = 0x20000;
+
+// The Bulk-Only Transport for one interface. Every LUN shares it, and only one command can be in flight at a time.
+struct Transport {
+    dev: Weak<USB::USBDevice>,
+    interface: u8,
+    bulk_in: u8,
+    bulk_out: u8,
+    tag: u32,
+}
+
+impl Transport {
+    fn Device(&self) -> Result<Arc<USB::USBDevice>,i64> {
+        self.dev.upgrade().ok_or(Errors::ENODEV as i64)
+    }
+    // Reset Recovery from section 5.3.4 of the BOT spec, for when the device and us disagree about where we are
+    fn Reset(&self, dev: &USB::USBDevice) {
+        log::warn!("USB: {} mass storage reset", dev.Name());
+        let _ = dev.Control(USB::RT_CLASS | USB::RT_INTERFACE,REQ_RESET,0,self.interface as u16,&mut []);
+        let _ = dev.ClearHalt(self.bulk_in);
+        let _ = dev.ClearHalt(self.bulk_out);
+    }
+    // Sends one SCSI command block. Returns the CSW status along with how many bytes actually moved.
+    fn Command(&mut self, lun: u8, cb: &[u8], data: &mut [u8], input: bool) -> Result<(u8,usize),i64> {
+        let dev = self.Device()?;
+        self.tag = self.tag.wrapping_add(1);
+        let mut cbw = [0u8; 31];
+        cbw[0..4].copy_from_slice(&CBW_SIGNATURE.to_le_bytes());
+        cbw[4..8].copy_from_slice(&self.tag.to_le_bytes());
+        cbw[8..12].copy_from_slice(&(data.len() as u32).to_le_bytes());
+        cbw[12] = if input && data.len() > 0 {0x80} else {0};
+        cbw[13] = lun;
+        cbw[14] = cb.len() as u8;
+        cbw[15..15+cb.len()].copy_from_slice(cb);
+        if let Err(e) = dev.Bulk(self.bulk_out,&mut cbw) {
+            self.Reset(&dev);
+            return Err(e);
+        }
+        let mut transferred = 0;
+        if data.len() > 0 {
+            let endpoint = if input {self.bulk_in} else {self.bulk_out};
+            match dev.Bulk(endpoint,data) {
+                Ok(n) => {transferred = n;}
+                // A stall here just means the device stopped early, the CSW will say what went wrong
+                Err(e) if e == Errors::EPIPE as i64 => {let _ = dev.ClearHalt(endpoint);}
+                Err(e) => {
+                    self.Reset(&dev);
+                    return Err(e);
+                }
+            }
+        }
+        let mut csw = [0u8; 13];
+        let mut result = dev.Bulk(self.bulk_in,&mut csw);
+        if result == Err(Errors::EPIPE as i64) {
+            let _ = dev.ClearHalt(self.bulk_in);
+            result = dev.Bulk(self.bulk_in,&mut csw);
+        }
+        let valid = match result {
+            Ok(13) => u32::from_le_bytes([csw[0],csw[1],csw[2],csw[3]]) == CSW_SIGNATURE && u32::from_le_bytes([csw[4],csw[5],csw[6],csw[7]]) == self.tag,
+            _ => false,
+        };
+        if !valid || csw[12] > CSW_FAILED {
+            self.Reset(&dev);
+            return Err(Errors::EIO as i64);
+        }
+        let residue = u32::from_le_bytes([csw[8],csw[9],csw[10],csw[11]]) as usize;
+        Ok((csw[12],core::cmp::min(transferred,data.len() - core::cmp::min(residue,data.len()))))
+    }
+    // Returns the sense key of whatever made the last command fail
+    fn Sense(&mut self, lun: u8) -> Result<u8,i64> {
+        let mut sense = [0u8; 18];
+        let (status, _) = self.Command(lun,&[SCSI_REQUEST_SENSE,0,0,0,sense.len() as u8,0],&mut sense,true)?;
+        if status != CSW_PASSED {
+            return Err(Errors::EIO as i64);
+        }
+        Ok(sense[2] & 0xF)
+    }
+    // Like Command, except a failed command turns into an error
+    fn Execute(&mut self, lun: u8, cb: &[u8], data: &mut [u8], input: bool) -> Result<usize,i64> {
+        match self.Command(lun,cb,data,input)? {
+            (CSW_PASSED, n) => Ok(n),
+            _ => {
+                let key = self.Sense(lun).unwrap_or(0);
+                log::warn!("USB: SCSI command {:#x} on LUN {} failed (sense key {:#x})", cb[0], lun, key);
+                Err(Errors::EIO as i64)
+            }
+        }
+    }
+}
+
+struct LogicalUnit {
+    transport: Arc<Mutex<Transport>>,
+    lun: u8,
+    block_size: usize,
+    blocks: u64,
+}
+
+impl LogicalUnit {
+    fn Transfer(&self, lba: u64, buffer: &mut [u8], write: bool) -> Result<(),i64> {
+        if buffer.len() % self.block_size != 0 || lba + (buffer.len() / self.block_size) as u64 > self.blocks {
+            return Err(Errors::EINVAL as i64);
+        }
+        let per_command = core::cmp::max(MAX_COMMAND_BYTES / self.block_size,1);
+        let mut transport = self.transport.lock();
+        for (i, chunk) in buffer.chunks_mut(per_command * self.block_size).enumerate() {
+            let start = lba + (i * per_command) as u64;
+            let count = (chunk.len() / self.block_size) as u32;
+            // READ(10) and WRITE(10) only have 32 bits worth of LBA
+            let cb: Vec<u8> = if start + count as u64 > u32::MAX as u64 {
+                let mut cb = vec![if write {SCSI_WRITE_16} else {SCSI_READ_16}, 0];
+                cb.extend_from_slice(&start.to_be_bytes());
+                cb.extend_from_slice(&count.to_be_bytes());
+                cb.extend_from_slice(&[0,0]);
+                cb
+            } else {
+                let mut cb = vec![if write {SCSI_WRITE_10} else {SCSI_READ_10}, 0];
+                cb.extend_from_slice(&(start as u32).to_be_bytes());
+                cb.push(0);
+                cb.extend_from_slice(&(count as u16).to_be_bytes());
+                cb.push(0);
+                cb
+            };
+            if transport.Execute(self.lun,cb.as_slice(),chunk,!write)? != chunk.len() {
+                return Err(Errors::EIO as i64);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl BlockDevice::BlockDevice for LogicalUnit {
+    fn BlockSize(&self) -> usize {
+        self.block_size
+    }
+    fn BlockCount(&self) -> u64 {
+        self.blocks
+    }
+    fn ReadBlocks(&self, lba: u64, buffer: &mut [u8]) -> Result<(),i64> {
+        self.Transfer(lba,buffer,false)
+    }
+    fn WriteBlocks(&self, lba: u64, buffer: &[u8]) -> Result<(),i64> {
+        // Bulk transfers want a mutable buffer even going out
+        let mut data = buffer.to_vec();
+        self.Transfer(lba,data.as_mut_slice(),true)
+    }
+}
+
+struct MassStorage {
+    nodes: Vec<Arc<BlockDevice::BlockDeviceNode>>,
+}
+
+impl USB::InterfaceDriver for MassStorage {
+    fn Disconnect(&self) {
+        for node in self.nodes.iter() {
+            DevFS::RemoveDevice(node.DeviceID());
+        }
+    }
+}
+
+// Waits for a LUN to spin up or settle after reset. Returns false if there's no medium in it.
+fn WaitReady(transport: &mut Transport, lun: u8) -> Result<bool,i64> {
+    for _ in 0..20 {
+        if transport.Command(lun,&[SCSI_TEST_UNIT_READY,0,0,0,0,0],&mut [],false)?.0 == CSW_PASSED {
+            return Ok(true);
+        }
+        match transport.Sense(lun)? {
+            SENSE_UNIT_ATTENTION => {}
+            SENSE_NOT_READY => {Scheduler::Sleep(100);} // Nobody else can see the transport yet, so holding it is fine
+            _ => {return Ok(false);}
+        }
+    }
+    Ok(false)
+}
+
+fn ProbeLUN(transport: &Arc<Mutex<Transport>>, dev: &USB::USBDevice, lun: u8) -> Result<Option<LogicalUnit>,i64> {
+    let mut lock = transport.lock();
+    let mut inquiry = [0u8; 36];
+    lock.Execute(lun,&[SCSI_INQUIRY,0,0,0,inquiry.len() as u8,0],&mut inquiry,true)?;
+    // Direct-access, CD/DVD, optical and simplified direct-access devices can all be read in blocks
+    let kind = inquiry[0] & 0x1F;
+    if inquiry[0] >> 5 != 0 || ![0x00,0x05,0x07,0x0E].contains(&kind) {
+        return Ok(None);
+    }
+    let vendor = String::from_utf8_lossy(&inquiry[8..16]);
+    let product = String::from_utf8_lossy(&inquiry[16..32]);
+    log::info!("USB: {} LUN {}: {} {}", dev.Name(), lun, vendor.trim(), product.trim());
+    if !WaitReady(&mut lock,lun)? {
+        log::info!("USB: {} LUN {}: No medium", dev.Name(), lun);
+        return Ok(None);
+    }
+    let mut capacity = [0u8; 8];
+    lock.Execute(lun,&[SCSI_READ_CAPACITY_10,0,0,0,0,0,0,0,0,0],&mut capacity,true)?;
+    let mut last = u32::from_be_bytes([capacity[0],capacity[1],capacity[2],capacity[3]]) as u64;
+    let mut block_size = u32::from_be_bytes([capacity[4],capacity[5],capacity[6],capacity[7]]) as usize;
+    // Anything past 2 TiB doesn't fit, so the device tells us to ask again with READ CAPACITY(16)
+    if last == u32::MAX as u64 {
+        let mut capacity = [0u8; 32];
+        let mut cb = [0u8; 16];
+        cb[0] = SCSI_SERVICE_ACTION_IN;
+        cb[1] = SA_READ_CAPACITY_16;
+        cb[13] = capacity.len() as u8;
+        lock.Execute(lun,&cb,&mut capacity,true)?;
+        last = u64::from_be_bytes(capacity[0..8].try_into().unwrap());
+        block_size = u32::from_be_bytes(capacity[8..12].try_into().unwrap()) as usize;
+    }
+    if block_size == 0 {
+        return Err(Errors::EIO as i64);
+    }
+    drop(lock);
+    Ok(Some(LogicalUnit {
+        transport: transport.clone(),
+        lun,
+        block_size,
+        blocks: last + 1,
+    }))
+}
+
+pub struct StorageDriver;
+
+impl USB::ClassDriver for StorageDriver {
+    fn Name(&self) -> &str {
+        "usb-storage"
+    }
+    fn Matches(&self, _device: &USB::USBDevice, interface: &USB::Interface) -> bool {
+        interface.class == USB::CLASS_MASS_STORAGE && interface.subclass == SUBCLASS_SCSI && interface.protocol == PROTOCOL_BULK_ONLY
+    }
+    fn Probe(&self, dev: &Arc<USB::USBDevice>, interface: &USB::Interface) -> Result<Arc<dyn USB::InterfaceDriver>,i64> {
+        let bulk_in = interface.FindEndpoint(USB::XFER_BULK,true).ok_or(Errors::ENODEV as i64)?;
+        let bulk_out = interface.FindEndpoint(USB::XFER_BULK,false).ok_or(Errors::ENODEV as i64)?;
+        // Devices with a single LUN are allowed to stall this
+        let mut max_lun = [0u8; 1];
+        if dev.Control(USB::RT_IN | USB::RT_CLASS | USB::RT_INTERFACE,REQ_GET_MAX_LUN,0,interface.number as u16,&mut max_lun).is_err() {
+            max_lun[0] = 0;
+        }
+        let transport = Arc::new(Mutex::new(Transport {
+            dev: Arc::downgrade(dev),
+            interface: interface.number,
+            bulk_in: bulk_in.address,
+            bulk_out: bulk_out.address,
+            tag: 0,
+        }));
+        let mut nodes = Vec::new();
+        for lun in 0..=core::cmp::min(max_lun[0],15) {
+            match ProbeLUN(&transport,dev,lun) {
+                Ok(Some(unit)) => {
+                    nodes.push(BlockDevice::Register(BlockDevice::ReserveSDName(),Arc::new(unit)));
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    log::warn!("USB: {} LUN {}: Couldn't probe ({})", dev.Name(), lun, e);
+                }
+            }
+        }
+        if nodes.is_empty() {
+            return Err(Errors::ENODEV as i64);
+        }
+        Ok(Arc::new(MassStorage {nodes}))
+    }
+}
@@ -0,0 +1,935 @@
+use crate::FS::VFS;
+use crate::FS::DevFS;
+use crate::Framebuffer::MainFramebuffer;
+use alloc::sync::Arc;
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::format;
+use alloc::vec;
+use alloc::vec::Vec;
+use spin::{Mutex, Once};
+use core::sync::atomic::{AtomicBool,AtomicUsize,Ordering};
+use crate::Syscall::Errors;
+use super::Input::{self, InputDevice, InputEvent};
+use super::LineDiscipline::LineDiscipline;
+use super::Epoll::Watchers;
+use super::PseudoTTY::{self, WinSize, TIOCGWINSZ, TIOCGNAME};
+
+pub const VT_COUNT: usize = 6;
+const SCROLLBACK: usize = 500; // Lines kept above the screen for Shift+PgUp
+
+pub const KDSETMODE: usize = 0x4B3A; // KD_GRAPHICS stops the console drawing over whatever's using /dev/fb0
+pub const KDGETMODE: usize = 0x4B3B;
+pub const KD_TEXT: usize = 0;
+pub const KD_GRAPHICS: usize = 1;
+
+pub const KDGKBMAP: usize = 0x4B80; // Copies out the current KeyMap
+pub const KDSKBMAP: usize = 0x4B81; // Replaces it with the KeyMap passed in
+pub const KDSKBLAYOUT: usize = 0x4B82; // Switches to a built-in layout, named by a NUL-terminated string
+
+pub const VT_GETSTATE: usize = 0x5603;
+pub const VT_ACTIVATE: usize = 0x5606;
+
+// Tango, which is what the kernel log colours were picked from
+const PALETTE: [u32; 16] = [
+    0x000000, 0xCC0000, 0x4E9A06, 0xC4A000, 0x3465A4, 0x75507B, 0x06989A, 0xD3D7CF,
+    0x555753, 0xEF2929, 0x8AE234, 0xFCE94F, 0x729FCF, 0xAD7FA8, 0x34E2E2, 0xEEEEEC,
+];
+const DEFAULT_FG: u8 = 7;
+const DEFAULT_BG: u8 = 0;
+
+#[repr(C)]
+pub struct VTState {
+    pub v_active: u16,
+    pub v_signal: u16,
+    pub v_state: u16, // Bit n set if ttyn exists
+}
+
+// Foreground colour in the low nibble, background in the high one
+#[derive(Clone,Copy,PartialEq)]
+struct Cell {
+    ch: u8,
+    attr: u8,
+}
+
+const BLANK: Cell = Cell {ch: b' ', attr: (DEFAULT_BG << 4) | DEFAULT_FG};
+
+#[derive(PartialEq)]
+enum ParseState {
+    Normal,
+    Escape,
+    CSI,
+}
+
+struct Screen {
+    cols: usize,
+    rows: usize,
+    lines: VecDeque<Vec<Cell>>, // The scrollback, and then the rows on screen at the end
+    x: usize,
+    y: usize,
+    saved: (usize,usize),
+    fg: u8,
+    bg: u8,
+    bold: bool,
+    dim: bool,
+    reverse: bool,
+    cursor_visible: bool,
+    wrap_pending: bool, // Writing in the last column leaves the cursor there until the next character shows up
+    state: ParseState,
+    params: Vec<usize>,
+    private: bool,
+    utf8: u32,
+    utf8_left: u8,
+    view: usize, // How many lines back into the scrollback we're looking
+    mode: usize,
+    dirty: Vec<bool>,
+    scrolled: usize, // Lines the screen moved up since it was last drawn
+}
+
+impl Screen {
+    fn new(cols: usize, rows: usize) -> Self {
+        let mut lines = VecDeque::new();
+        for _ in 0..rows {
+            lines.push_back(vec![BLANK; cols]);
+        }
+        Self {
+            cols, rows, lines,
+            x: 0, y: 0, saved: (0,0),
+            fg: DEFAULT_FG, bg: DEFAULT_BG, bold: false, dim: false, reverse: false,
+            cursor_visible: true, wrap_pending: false,
+            state: ParseState::Normal, params: Vec::new(), private: false,
+            utf8: 0, utf8_left: 0,
+            view: 0, mode: KD_TEXT,
+            dirty: vec![true; rows], scrolled: 0,
+        }
+    }
+    fn Attr(&self) -> u8 {
+        let mut fg = if self.dim {8} else {self.fg};
+        if self.bold && fg < 8 {
+            fg += 8;
+        }
+        if self.reverse {(fg << 4) | self.bg} else {(self.bg << 4) | fg}
+    }
+    fn Blank(&self) -> Cell {
+        Cell {ch: b' ', attr: (self.bg << 4) | DEFAULT_FG}
+    }
+    fn Row(&mut self, y: usize) -> &mut Vec<Cell> {
+        let index = self.lines.len()-self.rows+y;
+        self.dirty[y] = true;
+        &mut self.lines[index]
+    }
+    fn MarkAll(&mut self) {
+        self.dirty.iter_mut().for_each(|d| *d = true);
+        self.scrolled = 0;
+    }
+    fn ResetAttr(&mut self) {
+        self.fg = DEFAULT_FG;
+        self.bg = DEFAULT_BG;
+        self.bold = false;
+        self.dim = false;
+        self.reverse = false;
+    }
+    fn ScrollUp(&mut self) {
+        let blank = self.Blank();
+        self.lines.push_back(vec![blank; self.cols]);
+        if self.lines.len() > self.rows+SCROLLBACK {
+            self.lines.pop_front();
+        } else if self.view > 0 {
+            self.view += 1; // Keep looking at the same lines
+        }
+        self.dirty.remove(0);
+        self.dirty.push(true);
+        self.scrolled += 1;
+    }
+    fn LineFeed(&mut self) {
+        if self.y == self.rows-1 {
+            self.ScrollUp();
+        } else {
+            self.y += 1;
+        }
+    }
+    fn ReverseIndex(&mut self) {
+        if self.y > 0 {
+            self.y -= 1;
+            return;
+        }
+        let top = self.lines.len()-self.rows;
+        let blank = self.Blank();
+        self.lines.remove(self.lines.len()-1);
+        self.lines.insert(top,vec![blank; self.cols]);
+        self.MarkAll();
+    }
+    fn Put(&mut self, ch: u8) {
+        if self.wrap_pending {
+            self.wrap_pending = false;
+            self.x = 0;
+            self.LineFeed();
+        }
+        let cell = Cell {ch, attr: self.Attr()};
+        let x = self.x;
+        self.Row(self.y)[x] = cell;
+        if self.x == self.cols-1 {
+            self.wrap_pending = true;
+        } else {
+            self.x += 1;
+        }
+    }
+    // Characters past Latin-1 aren't in the font
+    fn Decode(&mut self, b: u8) {
+        if self.utf8_left > 0 {
+            if b & 0xC0 == 0x80 {
+                self.utf8 = (self.utf8 << 6) | (b & 0x3F) as u32;
+                self.utf8_left -= 1;
+                if self.utf8_left == 0 {
+                    self.Put(if self.utf8 < 0x100 {self.utf8 as u8} else {b'?'});
+                }
+                return;
+            }
+            self.utf8_left = 0;
+            self.Put(b'?');
+        }
+        match b {
+            0x00..=0x7F => self.Put(b),
+            0xC0..=0xDF => {self.utf8 = (b & 0x1F) as u32; self.utf8_left = 1;}
+            0xE0..=0xEF => {self.utf8 = (b & 0x0F) as u32; self.utf8_left = 2;}
+            0xF0..=0xF7 => {self.utf8 = (b & 0x07) as u32; self.utf8_left = 3;}
+            _ => self.Put(b), // Not UTF-8, so take it as Latin-1
+        }
+    }
+    fn Param(&self, index: usize, default: usize) -> usize {
+        match self.params.get(index) {
+            Some(0) | None => default,
+            Some(val) => *val,
+        }
+    }
+    fn Erase(&mut self, y: usize, from: usize, to: usize) {
+        let blank = self.Blank();
+        let cols = self.cols;
+        self.Row(y)[from.min(cols)..to.min(cols)].fill(blank);
+    }
+    fn SGR(&mut self) {
+        let mut i = 0;
+        while i < self.params.len() {
+            match self.params[i] {
+                0 => self.ResetAttr(),
+                1 => self.bold = true,
+                2 => self.dim = true,
+                7 => self.reverse = true,
+                22 => {self.bold = false; self.dim = false;}
+                27 => self.reverse = false,
+                n @ 30..=37 => self.fg = (n-30) as u8,
+                39 => self.fg = DEFAULT_FG,
+                n @ 40..=47 => self.bg = (n-40) as u8,
+                49 => self.bg = DEFAULT_BG,
+                n @ 90..=97 => self.fg = (n-90+8) as u8,
+                n @ 100..=107 => self.bg = (n-100+8) as u8,
+                n @ (38 | 48) => {
+                    // Only the 16 colours we actually have out of the 256 colour palette, and nothing for 24-bit colour
+                    if self.Param(i+1,0) == 5 {
+                        let color = self.Param(i+2,0);
+                        if color < 16 {
+                            if n == 38 {self.fg = color as u8;} else {self.bg = color as u8;}
+                        }
+                        i += 2;
+                    } else if self.Param(i+1,0) == 2 {
+                        i += 4;
+                    }
+                }
+                _ => {}
+            }
+            i += 1;
+        }
+    }
+    fn Dispatch(&mut self, cmd: u8) {
+        let n = self.Param(0,1);
+        self.wrap_pending = false;
+        match cmd {
+            b'A' => self.y = self.y.saturating_sub(n),
+            b'B' => self.y += n,
+            b'C' => self.x += n,
+            b'D' => self.x = self.x.saturating_sub(n),
+            b'E' => {self.y += n; self.x = 0;}
+            b'F' => {self.y = self.y.saturating_sub(n); self.x = 0;}
+            b'G' => self.x = n-1,
+            b'd' => self.y = n-1,
+            b'H' | b'f' => {
+                self.y = n-1;
+                self.x = self.Param(1,1)-1;
+            }
+            b'J' => {
+                let (x, y, rows) = (self.x, self.y, self.rows);
+                match self.Param(0,0) {
+                    0 => {
+                        self.Erase(y,x,usize::MAX);
+                        (y+1..rows).for_each(|r| self.Erase(r,0,usize::MAX));
+                    }
+                    1 => {
+                        (0..y).for_each(|r| self.Erase(r,0,usize::MAX));
+                        self.Erase(y,0,x+1);
+                    }
+                    _ => (0..rows).for_each(|r| self.Erase(r,0,usize::MAX)),
+                }
+            }
+            b'K' => {
+                let (x, y) = (self.x, self.y);
+                match self.Param(0,0) {
+                    0 => self.Erase(y,x,usize::MAX),
+                    1 => self.Erase(y,0,x+1),
+                    _ => self.Erase(y,0,usize::MAX),
+                }
+            }
+            b'X' => {
+                let (x, y) = (self.x, self.y);
+                self.Erase(y,x,x+n);
+            }
+            b'P' | b'@' => {
+                let (x, y, cols) = (self.x, self.y, self.cols);
+                let n = n.min(cols-x);
+                let blank = self.Blank();
+                let row = self.Row(y);
+                if cmd == b'P' {
+                    row[x..].rotate_left(n);
+                    row[cols-n..].fill(blank);
+                } else {
+                    row[x..].rotate_right(n);
+                    row[x..x+n].fill(blank);
+                }
+            }
+            b'L' | b'M' => {
+                let (y, rows, cols) = (self.y, self.rows, self.cols);
+                let top = self.lines.len()-rows;
+                let blank = self.Blank();
+                for _ in 0..n.min(rows-y) {
+                    if cmd == b'L' {
+                        self.lines.remove(self.lines.len()-1);
+                        self.lines.insert(top+y,vec![blank; cols]);
+                    } else {
+                        self.lines.remove(top+y);
+                        self.lines.push_back(vec![blank; cols]);
+                    }
+                }
+                (y..rows).for_each(|r| self.dirty[r] = true);
+            }
+            b'm' => self.SGR(),
+            b'h' | b'l' => {
+                if self.private && self.Param(0,0) == 25 {
+                    self.cursor_visible = cmd == b'h';
+                }
+            }
+            b's' => self.saved = (self.x,self.y),
+            b'u' => (self.x,self.y) = self.saved,
+            _ => {}
+        }
+        self.x = self.x.min(self.cols-1);
+        self.y = self.y.min(self.rows-1);
+    }
+    fn Reset(&mut self) {
+        self.ResetAttr();
+        (0..self.rows).for_each(|r| self.Erase(r,0,usize::MAX));
+        self.x = 0;
+        self.y = 0;
+        self.cursor_visible = true;
+        self.wrap_pending = false;
+    }
+    fn Feed(&mut self, b: u8) {
+        match self.state {
+            ParseState::Normal => match b {
+                0x1B => self.state = ParseState::Escape,
+                b'\n' | 0x0B | 0x0C => {
+                    self.wrap_pending = false;
+                    self.LineFeed();
+                }
+                b'\r' => {
+                    self.wrap_pending = false;
+                    self.x = 0;
+                }
+                0x08 => {
+                    self.wrap_pending = false;
+                    self.x = self.x.saturating_sub(1);
+                }
+                b'\t' => {
+                    self.wrap_pending = false;
+                    self.x = ((self.x/8+1)*8).min(self.cols-1);
+                }
+                0x00..=0x1F | 0x7F => {}
+                _ => self.Decode(b),
+            }
+            ParseState::Escape => {
+                self.state = ParseState::Normal;
+                match b {
+                    b'[' => {
+                        self.params.clear();
+                        self.params.push(0);
+                        self.private = false;
+                        self.state = ParseState::CSI;
+                    }
+                    b'7' => self.saved = (self.x,self.y),
+                    b'8' => {(self.x,self.y) = self.saved; self.wrap_pending = false;}
+                    b'c' => self.Reset(),
+                    b'D' => self.LineFeed(),
+                    b'E' => {self.x = 0; self.LineFeed();}
+                    b'M' => self.ReverseIndex(),
+                    _ => {}
+                }
+            }
+            ParseState::CSI => match b {
+                b'0'..=b'9' => {
+                    let last = self.params.last_mut().unwrap();
+                    *last = last.saturating_mul(10).saturating_add((b-b'0') as usize).min(9999);
+                }
+                b';' => self.params.push(0),
+                b'?' => self.private = true,
+                0x1B => self.state = ParseState::Escape,
+                0x40..=0x7E => {
+                    self.state = ParseState::Normal;
+                    self.Dispatch(b);
+                }
+                _ => {}
+            }
+        }
+    }
+    fn Write(&mut self, buffer: &[u8]) {
+        self.dirty[self.y] = true; // Where the cursor was drawn
+        buffer.iter().for_each(|b| self.Feed(*b));
+        self.dirty[self.y] = true;
+    }
+    // Moves the view back (positive) or forward (negative) through the scrollback
+    fn ScrollView(&mut self, lines: isize) {
+        let max = self.lines.len()-self.rows;
+        let view = (self.view as isize + lines).clamp(0,max as isize) as usize;
+        if view != self.view {
+            self.view = view;
+            self.MarkAll();
+        }
+    }
+    // Draws what's changed since last time
+    fn Render(&mut self, disp: &mut Display) {
+        if !disp.claimed {
+            disp.Clear();
+            disp.claimed = true;
+            self.MarkAll();
+        }
+        if self.scrolled >= self.rows || (self.scrolled > 0 && self.view != 0) {
+            self.MarkAll();
+        } else if self.scrolled > 0 {
+            disp.Scroll(self.scrolled,self.rows);
+        }
+        self.scrolled = 0;
+        let top = self.lines.len()-self.rows-self.view;
+        for y in 0..self.rows {
+            if !self.dirty[y] {
+                continue;
+            }
+            self.dirty[y] = false;
+            for (x, cell) in self.lines[top+y].iter().enumerate() {
+                let cursor = self.cursor_visible && self.view == 0 && x == self.x && y == self.y;
+                disp.DrawCell(x,y,*cell,cursor);
+            }
+        }
+    }
+}
+
+// The framebuffer the terminals share, only ever showing the active one
+struct Display {
+    base: u64,
+    width: usize,
+    height: usize,
+    stride: usize,
+    claimed: bool, // Whether the boot screen has been cleared off yet
+}
+
+impl Display {
+    fn DrawCell(&mut self, x: usize, y: usize, cell: Cell, cursor: bool) {
+        let glyph = crate::Framebuffer::Glyph(cell.ch);
+        let fg = PALETTE[(cell.attr & 0xF) as usize];
+        let bg = PALETTE[(cell.attr >> 4) as usize];
+        for (i, bits) in glyph.iter().enumerate() {
+            let bits = if cursor && i >= 14 {0xFF} else {*bits}; // An underline for the cursor
+            let line = (self.base + ((y*16+i)*self.stride + x*8*4) as u64) as *mut u32;
+            for j in 0..8 {
+                unsafe {line.add(j).write_volatile(if bits & (0x80 >> j) != 0 {fg} else {bg});}
+            }
+        }
+    }
+    fn Scroll(&mut self, lines: usize, rows: usize) {
+        let offset = lines*16*self.stride;
+        unsafe {core::ptr::copy((self.base as usize + offset) as *const u8, self.base as *mut u8, (rows-lines)*16*self.stride);}
+    }
+    fn Clear(&mut self) {
+        for y in 0..self.height {
+            let line = (self.base + (y*self.stride) as u64) as *mut u32;
+            for x in 0..self.width {
+                unsafe {line.add(x).write_volatile(PALETTE[DEFAULT_BG as usize]);}
+            }
+        }
+    }
+}
+
+static DISPLAY: Mutex<Option<Display>> = Mutex::new(None);
+static VTS: Once<Vec<Arc<VirtualTerminal>>> = Once::new();
+static ACTIVE: AtomicUsize = AtomicUsize::new(0);
+
+pub struct VirtualTerminal {
+    id: usize,
+    index: usize,
+    name: String,
+    screen: Mutex<Screen>,
+    ldisc: Mutex<LineDiscipline>,
+    redraw: AtomicBool, // Set when the whole screen needs drawing again, for whoever gets to the screen lock next
+    watchers: Watchers,
+}
+
+impl VirtualTerminal {
+    // Keyboard interrupts can land while the kernel is printing, so nothing here waits on a lock. Drawing that
+    // gets skipped leaves the rows dirty and happens on the next write instead.
+    fn Refresh(&self, screen: &mut Screen) {
+        if self.redraw.swap(false,Ordering::SeqCst) {
+            screen.MarkAll();
+        }
+        if ACTIVE.load(Ordering::SeqCst) != self.index || screen.mode != KD_TEXT {
+            return;
+        }
+        if let Some(mut disp) = DISPLAY.try_lock() {
+            if let Some(disp) = disp.as_mut() {
+                screen.Render(disp);
+            }
+        }
+    }
+    fn Redraw(&self) {
+        self.redraw.store(true,Ordering::SeqCst);
+        if let Some(mut screen) = self.screen.try_lock() {
+            self.Refresh(&mut screen);
+        }
+    }
+    fn Output(&self, screen: &mut Screen, buffer: &[u8]) {
+        screen.Write(buffer);
+        if screen.view == 0 {
+            self.Refresh(screen);
+        }
+    }
+    fn Type(&self, bytes: &[u8]) {
+        let typed = self.ldisc.lock().Input(bytes);
+        typed.Signal();
+        self.watchers.Notify();
+        if let Some(mut screen) = self.screen.try_lock() {
+            if screen.view != 0 {
+                screen.view = 0;
+                self.redraw.store(true,Ordering::SeqCst);
+            }
+            self.Output(&mut screen,typed.echo.as_slice());
+        }
+    }
+}
+
+impl DevFS::Device for VirtualTerminal {
+    fn DeviceID(&self) -> usize {
+        self.id
+    }
+    fn Inode(&self) -> Arc<dyn VFS::Inode> {
+        VTS.get().expect("device not ready")[self.index].clone()
+    }
+}
+
+impl VFS::Inode for VirtualTerminal {
+    fn Stat(&self) -> Result<VFS::Metadata, i64> {
+        Ok(VFS::Metadata {
+            device_id: 0,
+            inode_id: i64::MAX,
+            mode: 0o0020666, // crw-rw-rw-
+            nlinks: 1,
+            uid: 0,
+            gid: 0,
+            rdev: 0,
+            size: 0,
+            blksize: 0,
+            blocks: 0,
+
+            atime: unsafe {crate::UNIX_EPOCH as i64},
+            mtime: unsafe {crate::UNIX_EPOCH as i64},
+            ctime: unsafe {crate::UNIX_EPOCH as i64},
+            reserved1: 0,
+            reserved2: 0,
+            reserved3: 0,
+        })
+    }
+    fn GetName(&self) -> Result<&str, i64> {
+        Ok(self.name.as_str())
+    }
+    fn Read(&self, _offset: i64, buffer: &mut [u8]) -> i64 {
+        self.ldisc.lock().Read(buffer)
+    }
+    fn Write(&self, _offset: i64, buffer: &[u8]) -> i64 {
+        let out = self.ldisc.lock().Output(buffer);
+        let mut screen = self.screen.lock();
+        self.Output(&mut screen,out.as_slice());
+        buffer.len() as i64
+    }
+    fn IOCtl(&self, cmd: usize, arg: usize) -> Result<usize, i64> {
+        if let Some(ret) = self.ldisc.lock().IOCtl(cmd,arg) {
+            return ret;
+        }
+        match cmd {
+            TIOCGNAME => {
+                PseudoTTY::CopyName(self.name.as_str(),arg);
+                Ok(0)
+            }
+            TIOCGWINSZ => {
+                let screen = self.screen.lock();
+                unsafe {*(arg as *mut WinSize) = WinSize {row: screen.rows as u16, col: screen.cols as u16, reserved1: 0, reserved2: 0};}
+                Ok(0)
+            }
+            KDSETMODE => {
+                if arg != KD_TEXT && arg != KD_GRAPHICS {
+                    return Err(Errors::EINVAL as i64);
+                }
+                self.screen.lock().mode = arg;
+                if arg == KD_TEXT {
+                    self.Redraw();
+                }
+                Ok(0)
+            }
+            KDGETMODE => Ok(self.screen.lock().mode),
+            KDGKBMAP => {
+                unsafe {*(arg as *mut KeyMap) = *KEYMAP.lock();}
+                Ok(0)
+            }
+            KDSKBMAP => {
+                *KEYMAP.lock() = unsafe {*(arg as *const KeyMap)};
+                Ok(0)
+            }
+            KDSKBLAYOUT => {
+                let mut name = String::new();
+                let ptr = arg as *const u8;
+                for i in 0..64 {
+                    let b = unsafe {*ptr.add(i)};
+                    if b == 0 {
+                        break;
+                    }
+                    name.push(b as char);
+                }
+                let layout = LAYOUTS.iter().find(|(n, _)| *n == name.as_str()).ok_or(Errors::EINVAL as i64)?;
+                *KEYMAP.lock() = KeyMap::Build(&layout.1);
+                Ok(0)
+            }
+            VT_GETSTATE => {
+                let state = VTState {
+                    v_active: ACTIVE.load(Ordering::SeqCst) as u16 + 1,
+                    v_signal: 0,
+                    v_state: (((1 << VT_COUNT) - 1) << 1) as u16,
+                };
+                unsafe {*(arg as *mut VTState) = state;}
+                Ok(0)
+            }
+            VT_ACTIVATE => {
+                if arg < 1 || arg > VT_COUNT {
+                    return Err(Errors::ENXIO as i64);
+                }
+                Switch(arg-1);
+                Ok(0)
+            }
+            _ => Err(Errors::ENOTTY as i64),
+        }
+    }
+    fn Poll(&self) -> i16 {
+        if self.ldisc.lock().CanRead() {VFS::POLLIN | VFS::POLLOUT} else {VFS::POLLOUT}
+    }
+    fn Watchers(&self) -> Option<Watchers> {
+        Some(self.watchers.clone())
+    }
+}
+
+// What each key types, by keycode, as Unicode. Zero means the key doesn't type anything on that level.
+#[repr(C)]
+#[derive(Clone,Copy)]
+pub struct KeyMap {
+    pub plain: [u32; 128],
+    pub shift: [u32; 128],
+    pub altgr: [u32; 128],
+}
+
+// Keycodes of the first key in each row of a layout: the number row, the top, home and bottom rows, the key above
+// Enter, and the extra key next to left shift that 105 key keyboards have
+const ROW_START: [usize; 6] = [2, 16, 30, 43, 44, 86];
+
+type Layout = [[&'static str; 6]; 3];
+
+const LAYOUTS: [(&str, Layout); 5] = [
+    ("en_US_104qwerty", [
+        ["1234567890-=", "qwertyuiop[]", "asdfghjkl;'`", "\\", "zxcvbnm,./", "\\"],
+        ["!@#$%^&*()_+", "QWERTYUIOP{}", "ASDFGHJKL:\"~", "|", "ZXCVBNM<>?", "|"],
+        ["", "", "", "", "", ""],
+    ]),
+    ("en_GB_105qwerty", [
+        ["1234567890-=", "qwertyuiop[]", "asdfghjkl;'`", "#", "zxcvbnm,./", "\\"],
+        ["!\"£$%^&*()_+", "QWERTYUIOP{}", "ASDFGHJKL:@¬", "~", "ZXCVBNM<>?", "|"],
+        ["\0\0\0€", "", "\0\0\0\0\0\0\0\0\0\0\0¦", "", "", ""],
+    ]),
+    ("generic_104dvorak", [
+        ["1234567890[]", "',.pyfgcrl/=", "aoeuidhtns-`", "\\", ";qjkxbmwvz", "\\"],
+        ["!@#$%^&*(){}", "\"<>PYFGCRL?+", "AOEUIDHTNS_~", "|", ":QJKXBMWVZ", "|"],
+        ["", "", "", "", "", ""],
+    ]),
+    ("de_DE_105qwertz", [
+        ["1234567890ß´", "qwertzuiopü+", "asdfghjklöä^", "#", "yxcvbnm,.-", "<"],
+        ["!\"§$%&/()=?`", "QWERTZUIOPÜ*", "ASDFGHJKLÖÄ°", "'", "YXCVBNM;:_", ">"],
+        ["\0²³\0\0\0{[]}\\", "@\0€\0\0\0\0\0\0\0\0~", "", "", "\0\0\0\0\0\0µ", "|"],
+    ]),
+    ("fr_FR_105azerty", [
+        ["&é\"'(-è_çà)=", "azertyuiop^$", "qsdfghjklmù²", "*", "wxcvbn,;:!", "<"],
+        ["1234567890°+", "AZERTYUIOP¨£", "QSDFGHJKLM%", "µ", "WXCVBN?./§", ">"],
+        ["\0~#{[|`\\^@]}", "\0\0€\0\0\0\0\0\0\0\0¤", "", "", "", ""],
+    ]),
+];
+
+impl KeyMap {
+    const fn empty() -> Self {
+        Self {plain: [0; 128], shift: [0; 128], altgr: [0; 128]}
+    }
+    fn Build(layout: &Layout) -> Self {
+        let mut map = Self::empty();
+        for (level, rows) in [&mut map.plain, &mut map.shift, &mut map.altgr].into_iter().zip(layout.iter()) {
+            for (start, row) in ROW_START.iter().zip(rows.iter()) {
+                for (i, ch) in row.chars().enumerate() {
+                    level[start+i] = ch as u32;
+                }
+            }
+            level[Input::KEY_SPACE as usize] = b' ' as u32;
+        }
+        map
+    }
+}
+
+static KEYMAP: Mutex<KeyMap> = Mutex::new(KeyMap::empty());
+
+struct Modifiers {
+    lshift: bool,
+    rshift: bool,
+    lctrl: bool,
+    rctrl: bool,
+    alt: bool,
+    altgr: bool,
+    caps: bool,
+    num: bool,
+}
+
+impl Modifiers {
+    fn Shift(&self) -> bool {
+        self.lshift || self.rshift
+    }
+    fn Ctrl(&self) -> bool {
+        self.lctrl || self.rctrl
+    }
+}
+
+static MODIFIERS: Mutex<Modifiers> = Mutex::new(Modifiers {
+    lshift: false, rshift: false, lctrl: false, rctrl: false, alt: false, altgr: false, caps: false, num: true,
+});
+
+// The keypad types digits with Num Lock on, and moves the cursor like the keys it doubles as with it off
+const KEYPAD: [(u16, u8, &[u8]); 15] = [
+    (71, b'7', b"\x1b[H"), (72, b'8', b"\x1b[A"), (73, b'9', b"\x1b[5~"), (74, b'-', b"-"),
+    (75, b'4', b"\x1b[D"), (76, b'5', b""), (77, b'6', b"\x1b[C"), (78, b'+', b"+"),
+    (79, b'1', b"\x1b[F"), (80, b'2', b"\x1b[B"), (81, b'3', b"\x1b[6~"),
+    (82, b'0', b"\x1b[2~"), (83, b'.', b"\x1b[3~"), (55, b'*', b"*"), (Input::KEY_KPSLASH, b'/', b"/"),
+];
+
+const FUNCTION_KEYS: [&[u8]; 12] = [
+    b"\x1bOP", b"\x1bOQ", b"\x1bOR", b"\x1bOS", b"\x1b[15~", b"\x1b[17~",
+    b"\x1b[18~", b"\x1b[19~", b"\x1b[20~", b"\x1b[21~", b"\x1b[23~", b"\x1b[24~",
+];
+
+fn FunctionKey(code: u16) -> Option<usize> {
+    match code {
+        Input::KEY_F1..=Input::KEY_F10 => Some((code-Input::KEY_F1) as usize),
+        Input::KEY_F11 => Some(10),
+        Input::KEY_F12 => Some(11),
+        _ => None,
+    }
+}
+
+fn Special(code: u16, shift: bool) -> Option<&'static [u8]> {
+    Some(match code {
+        Input::KEY_ENTER | Input::KEY_KPENTER => b"\r",
+        Input::KEY_BACKSPACE => b"\x7f",
+        Input::KEY_TAB => if shift {b"\x1b[Z"} else {b"\t"},
+        Input::KEY_ESC => b"\x1b",
+        Input::KEY_UP => b"\x1b[A",
+        Input::KEY_DOWN => b"\x1b[B",
+        Input::KEY_RIGHT => b"\x1b[C",
+        Input::KEY_LEFT => b"\x1b[D",
+        Input::KEY_HOME => b"\x1b[H",
+        Input::KEY_END => b"\x1b[F",
+        Input::KEY_INSERT => b"\x1b[2~",
+        Input::KEY_DELETE => b"\x1b[3~",
+        Input::KEY_PAGEUP => b"\x1b[5~",
+        Input::KEY_PAGEDOWN => b"\x1b[6~",
+        _ => return FunctionKey(code).map(|f| FUNCTION_KEYS[f]),
+    })
+}
+
+// Turns a key press into the bytes it types, going by the keymap and whatever modifiers are held
+fn Translate(code: u16, mods: &Modifiers) -> Vec<u8> {
+    let mut out = Vec::new();
+    if let Some((_, digit, nav)) = KEYPAD.iter().find(|(c, _, _)| *c == code) {
+        if mods.num && !mods.Shift() {
+            out.push(*digit);
+        } else {
+            out.extend_from_slice(nav);
+        }
+        return out;
+    }
+    if let Some(seq) = Special(code, mods.Shift()) {
+        if mods.alt && seq.len() == 1 {
+            out.push(0x1B);
+        }
+        out.extend_from_slice(seq);
+        return out;
+    }
+    if code as usize >= 128 {
+        return out;
+    }
+    let map = KEYMAP.lock();
+    let plain = char::from_u32(map.plain[code as usize]).unwrap_or('\0');
+    let mut shift = mods.Shift();
+    if mods.caps && plain.is_alphabetic() {
+        shift = !shift;
+    }
+    let ch = if mods.altgr {
+        map.altgr[code as usize]
+    } else if shift {
+        map.shift[code as usize]
+    } else {
+        map.plain[code as usize]
+    };
+    drop(map);
+    let mut ch = match char::from_u32(ch) {
+        Some(ch) if ch != '\0' => ch,
+        _ => return out,
+    };
+    if mods.Ctrl() {
+        ch = match ch.to_ascii_uppercase() {
+            c @ '@'..='_' => ((c as u8) & 0x1F) as char,
+            '?' => '\x7f',
+            ' ' => '\0',
+            _ => ch,
+        };
+    }
+    if mods.alt {
+        out.push(0x1B);
+    }
+    let mut buf = [0; 4];
+    out.extend_from_slice(ch.encode_utf8(&mut buf).as_bytes());
+    out
+}
+
+fn HandleEvent(_dev: &InputDevice, event: &InputEvent) {
+    if event.kind != Input::EV_KEY {
+        return;
+    }
+    let pressed = event.value != 0;
+    let mut mods = MODIFIERS.lock();
+    match event.code {
+        Input::KEY_LEFTSHIFT => {mods.lshift = pressed; return;}
+        Input::KEY_RIGHTSHIFT => {mods.rshift = pressed; return;}
+        Input::KEY_LEFTCTRL => {mods.lctrl = pressed; return;}
+        Input::KEY_RIGHTCTRL => {mods.rctrl = pressed; return;}
+        Input::KEY_LEFTALT => {mods.alt = pressed; return;}
+        Input::KEY_RIGHTALT => {mods.altgr = pressed; return;}
+        Input::KEY_CAPSLOCK => {if event.value == 1 {mods.caps = !mods.caps;} return;}
+        Input::KEY_NUMLOCK => {if event.value == 1 {mods.num = !mods.num;} return;}
+        _ => {}
+    }
+    if !pressed {
+        return;
+    }
+    let vt = match VTS.get() {
+        Some(vts) => vts[ACTIVE.load(Ordering::SeqCst)].clone(),
+        None => return,
+    };
+    if mods.alt {
+        if let Some(f) = FunctionKey(event.code).filter(|f| *f < VT_COUNT) {
+            drop(mods);
+            Switch(f);
+            return;
+        }
+    }
+    if mods.Ctrl() && mods.alt && (event.code == Input::KEY_DELETE || event.code == 83) {
+        drop(mods);
+        if event.value == 1 && !super::SignalFD::Claim(1,crate::Process::Signals::SIGINT) {
+            log::warn!("Ctrl+Alt+Del pressed, but init isn't listening for it");
+        }
+        return;
+    }
+    if mods.Shift() && (event.code == Input::KEY_PAGEUP || event.code == Input::KEY_PAGEDOWN) {
+        drop(mods);
+        if let Some(mut screen) = vt.screen.try_lock() {
+            let half = (screen.rows/2) as isize;
+            screen.ScrollView(if event.code == Input::KEY_PAGEUP {half} else {-half});
+            vt.Refresh(&mut screen);
+        }
+        return;
+    }
+    let bytes = Translate(event.code,&mods);
+    drop(mods);
+    if !bytes.is_empty() {
+        vt.Type(bytes.as_slice());
+    }
+}
+
+// Brings another terminal up on the screen
+pub fn Switch(index: usize) {
+    let vts = match VTS.get() {
+        Some(vts) => vts,
+        None => return,
+    };
+    if index >= vts.len() || ACTIVE.swap(index,Ordering::SeqCst) == index {
+        return;
+    }
+    vts[index].Redraw();
+}
+
+// Where the kernel's own messages go once the terminals are up, which is tty1. Returns false before that.
+pub fn ConsoleWrite(s: &str) -> bool {
+    let vt = match VTS.get() {
+        Some(vts) => &vts[0],
+        None => return false,
+    };
+    // Printing while this terminal is locked would deadlock, so the message only makes it to the serial port
+    if let Some(mut screen) = vt.screen.try_lock() {
+        let mut out = Vec::with_capacity(s.len());
+        for b in s.bytes() {
+            if b == b'\n' {
+                out.push(b'\r');
+            }
+            out.push(b);
+        }
+        vt.Output(&mut screen,out.as_slice());
+    }
+    true
+}
+
+pub fn Initalize() {
+    let (cols, rows) = match MainFramebuffer.lock().as_ref() {
+        Some(fb) if fb.bpp == 32 => {
+            *DISPLAY.lock() = Some(Display {base: fb.pointer, width: fb.width, height: fb.height, stride: fb.stride, claimed: false});
+            (fb.width/8, fb.height/16)
+        }
+        _ => (80, 25),
+    };
+    *KEYMAP.lock() = KeyMap::Build(&LAYOUTS[0].1);
+    VTS.call_once(|| {
+        (0..VT_COUNT).map(|i| Arc::new(VirtualTerminal {
+            id: DevFS::ReserveDeviceID(),
+            index: i,
+            name: format!("tty{}",i+1),
+            screen: Mutex::new(Screen::new(cols,rows)),
+            ldisc: Mutex::new(LineDiscipline::new()),
+            redraw: AtomicBool::new(false),
+            watchers: Watchers::new(),
+        })).collect()
+    });
+    for vt in VTS.get().unwrap().iter() {
+        let _ = DevFS::InstallDevice(vt.clone());
+    }
+    Input::AddHandler(HandleEvent);
+}
@@ -1,12 +1,31 @@
 pub mod UNIXStreamDevs;
+pub mod KMsg;
 pub mod PseudoTTY;
+pub mod LineDiscipline;
 pub mod Keyboard;
 pub mod Framebuffer;
 pub mod UNIXPipe;
+pub mod Epoll;
+pub mod EventFD;
+pub mod TimerFD;
+pub mod SignalFD;
+pub mod BlockDevice;
+pub mod NetworkDevice;
+pub mod USB;
+pub mod USBHub;
+pub mod USBHID;
+pub mod USBStorage;
+pub mod Mouse;
+pub mod Input;
+pub mod VirtualTTY;
 
 pub fn Initalize() {
     UNIXStreamDevs::Initalize();
+    KMsg::Initalize();
     PseudoTTY::Initalize();
     Keyboard::Initalize();
+    Input::Initalize();
     Framebuffer::Initalize();
+    VirtualTTY::Initalize();
+    USB::Initalize();
 }
\ No newline at end of file
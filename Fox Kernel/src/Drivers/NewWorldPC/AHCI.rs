@@ -0,0 +1,392 @@
+use spin::Mutex;
+use alloc::vec::Vec;
+use alloc::string::String;
+use alloc::sync::Arc;
+use crate::arch::PHYSMEM_BEGIN;
+use crate::arch::Timer;
+use crate::Drivers::Arch::PCI;
+use crate::Drivers::Generic::BlockDevice;
+use crate::Syscall::Errors;
+
+// Generic Host Control
+const HBA_CAP: u64 = 0x00;
+const HBA_GHC: u64 = 0x04;
+const HBA_IS: u64 = 0x08;
+const HBA_PI: u64 = 0x0C;
+const HBA_VS: u64 = 0x10;
+const HBA_CAP2: u64 = 0x24;
+const HBA_BOHC: u64 = 0x28;
+
+// Port Registers (offset from 0x100 + (port * 0x80))
+const PORT_CLB: u64 = 0x00;
+const PORT_CLBU: u64 = 0x04;
+const PORT_FB: u64 = 0x08;
+const PORT_FBU: u64 = 0x0C;
+const PORT_IS: u64 = 0x10;
+const PORT_CMD: u64 = 0x18;
+const PORT_TFD: u64 = 0x20;
+const PORT_SIG: u64 = 0x24;
+const PORT_SSTS: u64 = 0x28;
+const PORT_SERR: u64 = 0x30;
+const PORT_SACT: u64 = 0x34;
+const PORT_CI: u64 = 0x38;
+
+const GHC_HR: u32 = 1 << 0;
+const GHC_AE: u32 = 1 << 31;
+
+const CMD_ST: u32 = 1 << 0;
+const CMD_SUD: u32 = 1 << 1;
+const CMD_FRE: u32 = 1 << 4;
+const CMD_FR: u32 = 1 << 14;
+const CMD_CR: u32 = 1 << 15;
+
+const TFD_ERR: u32 = 1 << 0;
+const TFD_DRQ: u32 = 1 << 3;
+const TFD_BSY: u32 = 1 << 7;
+
+// TFES | HBFS | HBDS | IFS | INFS | OFS
+const IS_ERROR: u32 = (1 << 30) | (1 << 29) | (1 << 28) | (1 << 27) | (1 << 26) | (1 << 24);
+
+const SIG_ATA: u32 = 0x00000101;
+const SIG_ATAPI: u32 = 0xEB140101;
+
+const FIS_TYPE_REG_H2D: u8 = 0x27;
+
+const ATA_CMD_READ_DMA_EXT: u8 = 0x25;
+const ATA_CMD_WRITE_DMA_EXT: u8 = 0x35;
+const ATA_CMD_FLUSH_CACHE_EXT: u8 = 0xEA;
+const ATA_CMD_IDENTIFY: u8 = 0xEC;
+
+// Every command table gets its own page: 0x80 bytes of FIS/ATAPI area followed by the PRDT.
+const CMD_TABLE_SIZE: u64 = 0x1000;
+const PRDT_OFFSET: u64 = 0x80;
+// We only ever hand the HBA a single physically contiguous bounce buffer per command.
+const MAX_TRANSFER: usize = 0x20000;
+const COMMAND_TIMEOUT: u64 = 5000000; // 5 seconds
+
+struct AHCIPort {
+    base: u64, // Virtual address of the port's register block
+    slots: usize,
+    cmd_list: u64, // Command List (0x000-0x3FF) + Received FIS (0x400-0x4FF)
+    tables: Vec<u64>,
+    bounce: u64,
+}
+
+// Ports only get dropped when setting them up didn't work out
+impl Drop for AHCIPort {
+    fn drop(&mut self) {
+        // An HBA that won't let go of the port could still write into these, so they're better off leaked
+        if !self.Stop() {
+            return;
+        }
+        if self.cmd_list != 0 {
+            crate::PageFrame::Free(self.cmd_list as *mut u8,0x1000);
+        }
+        for table in self.tables.iter() {
+            crate::PageFrame::Free(*table as *mut u8,CMD_TABLE_SIZE);
+        }
+        if self.bounce != 0 {
+            crate::PageFrame::Free(self.bounce as *mut u8,MAX_TRANSFER as u64);
+        }
+    }
+}
+
+impl AHCIPort {
+    fn Read(&self, reg: u64) -> u32 {
+        unsafe {((self.base+reg) as *const u32).read_volatile()}
+    }
+    fn Write(&self, reg: u64, val: u32) {
+        unsafe {((self.base+reg) as *mut u32).write_volatile(val);}
+    }
+    fn Stop(&self) -> bool {
+        self.Write(PORT_CMD,self.Read(PORT_CMD) & !CMD_ST);
+        if !WaitFor(|| self.Read(PORT_CMD) & CMD_CR == 0, 500000) {return false;}
+        self.Write(PORT_CMD,self.Read(PORT_CMD) & !CMD_FRE);
+        WaitFor(|| self.Read(PORT_CMD) & CMD_FR == 0, 500000)
+    }
+    fn Start(&self) -> bool {
+        self.Write(PORT_CMD,self.Read(PORT_CMD) | CMD_FRE);
+        if !WaitFor(|| self.Read(PORT_TFD) & (TFD_BSY | TFD_DRQ) == 0, 1000000) {return false;}
+        self.Write(PORT_CMD,self.Read(PORT_CMD) | CMD_ST);
+        true
+    }
+    fn FreeSlot(&self) -> Option<usize> {
+        let busy = self.Read(PORT_SACT) | self.Read(PORT_CI);
+        for i in 0..self.slots {
+            if busy & (1 << i) == 0 {
+                return Some(i);
+            }
+        }
+        None
+    }
+    // Builds a command in a free slot, issues it and waits for the HBA to finish with it.
+    // `length` bytes of the bounce buffer are used for the data phase (if any).
+    fn Issue(&self, command: u8, lba: u64, count: u16, length: usize, write: bool) -> Result<(),i64> {
+        let slot = match self.FreeSlot() {
+            Some(s) => s,
+            None => {return Err(Errors::EBUSY as i64);}
+        };
+        let table = self.tables[slot];
+        unsafe {
+            core::ptr::write_bytes(table as *mut u8,0,PRDT_OFFSET as usize + 0x10);
+            let fis = table as *mut u8;
+            fis.offset(0).write_volatile(FIS_TYPE_REG_H2D);
+            fis.offset(1).write_volatile(0x80); // Command, not Control
+            fis.offset(2).write_volatile(command);
+            fis.offset(4).write_volatile(lba as u8);
+            fis.offset(5).write_volatile((lba >> 8) as u8);
+            fis.offset(6).write_volatile((lba >> 16) as u8);
+            fis.offset(7).write_volatile(1 << 6); // LBA mode
+            fis.offset(8).write_volatile((lba >> 24) as u8);
+            fis.offset(9).write_volatile((lba >> 32) as u8);
+            fis.offset(10).write_volatile((lba >> 40) as u8);
+            fis.offset(12).write_volatile(count as u8);
+            fis.offset(13).write_volatile((count >> 8) as u8);
+            let prdt = (table+PRDT_OFFSET) as *mut u32;
+            if length > 0 {
+                let phys = self.bounce-PHYSMEM_BEGIN;
+                prdt.offset(0).write_volatile(phys as u32);
+                prdt.offset(1).write_volatile((phys >> 32) as u32);
+                prdt.offset(3).write_volatile(((length-1) as u32) | (1 << 31));
+            }
+            let header = (self.cmd_list + (slot as u64 * 32)) as *mut u32;
+            let phys = table-PHYSMEM_BEGIN;
+            header.offset(0).write_volatile(5 | (if write {1 << 6} else {0}) | (if length > 0 {1 << 16} else {0}));
+            header.offset(1).write_volatile(0);
+            header.offset(2).write_volatile(phys as u32);
+            header.offset(3).write_volatile((phys >> 32) as u32);
+        }
+        self.Write(PORT_CI,1 << slot);
+        let start = Timer::GetMicroseconds();
+        loop {
+            // No interrupts here, see BlockDevice for why
+            let is = self.Read(PORT_IS);
+            if is != 0 {
+                self.Write(PORT_IS,is);
+            }
+            if is & IS_ERROR != 0 || self.Read(PORT_TFD) & TFD_ERR != 0 {
+                log::error!("AHCI: Command 0x{:02x} failed (IS: 0x{:08x} TFD: 0x{:08x})", command, is, self.Read(PORT_TFD));
+                self.Recover();
+                return Err(Errors::EIO as i64);
+            }
+            if self.Read(PORT_CI) & (1 << slot) == 0 {
+                return Ok(());
+            }
+            if Timer::GetMicroseconds() - start > COMMAND_TIMEOUT {
+                log::error!("AHCI: Command 0x{:02x} timed out", command);
+                self.Recover();
+                return Err(Errors::EIO as i64);
+            }
+            core::hint::spin_loop();
+        }
+    }
+    // Clears out the error state by restarting the command engine.
+    fn Recover(&self) {
+        self.Stop();
+        self.Write(PORT_SERR,0xFFFFFFFF);
+        self.Write(PORT_IS,0xFFFFFFFF);
+        self.Start();
+    }
+}
+
+pub struct AHCIDisk {
+    port: Mutex<AHCIPort>,
+    sector_size: usize,
+    sectors: u64,
+}
+
+impl AHCIDisk {
+    fn Transfer(&self, lba: u64, buffer: *mut u8, len: usize, write: bool) -> Result<(),i64> {
+        if len % self.sector_size != 0 {
+            return Err(Errors::EINVAL as i64);
+        }
+        if lba + (len / self.sector_size) as u64 > self.sectors {
+            return Err(Errors::EINVAL as i64);
+        }
+        let port = self.port.lock();
+        let mut done = 0;
+        while done < len {
+            let size = if len - done > MAX_TRANSFER {MAX_TRANSFER} else {len - done};
+            let cur_lba = lba + (done / self.sector_size) as u64;
+            let count = (size / self.sector_size) as u16;
+            unsafe {
+                if write {
+                    core::ptr::copy(buffer.add(done),port.bounce as *mut u8,size);
+                    port.Issue(ATA_CMD_WRITE_DMA_EXT,cur_lba,count,size,true)?;
+                } else {
+                    port.Issue(ATA_CMD_READ_DMA_EXT,cur_lba,count,size,false)?;
+                    core::ptr::copy(port.bounce as *const u8,buffer.add(done),size);
+                }
+            }
+            done += size;
+        }
+        Ok(())
+    }
+}
+
+impl BlockDevice::BlockDevice for AHCIDisk {
+    fn BlockSize(&self) -> usize {
+        self.sector_size
+    }
+    fn BlockCount(&self) -> u64 {
+        self.sectors
+    }
+    fn ReadBlocks(&self, lba: u64, buffer: &mut [u8]) -> Result<(),i64> {
+        self.Transfer(lba,buffer.as_mut_ptr(),buffer.len(),false)
+    }
+    fn WriteBlocks(&self, lba: u64, buffer: &[u8]) -> Result<(),i64> {
+        self.Transfer(lba,buffer.as_ptr() as *mut u8,buffer.len(),true)
+    }
+    fn Flush(&self) -> Result<(),i64> {
+        let port = self.port.lock();
+        port.Issue(ATA_CMD_FLUSH_CACHE_EXT,0,0,0,false)
+    }
+}
+
+fn HBARead(base: u64, reg: u64) -> u32 {
+    unsafe {((base+reg) as *const u32).read_volatile()}
+}
+
+fn HBAWrite(base: u64, reg: u64, val: u32) {
+    unsafe {((base+reg) as *mut u32).write_volatile(val);}
+}
+
+fn WaitFor<F: Fn() -> bool>(cond: F, timeout: u64) -> bool {
+    let start = Timer::GetMicroseconds();
+    while !cond() {
+        if Timer::GetMicroseconds() - start > timeout {
+            return cond();
+        }
+        core::hint::spin_loop();
+    }
+    true
+}
+
+fn IdentifyString(data: &[u16], start: usize, end: usize) -> String {
+    let mut s = String::new();
+    for w in data[start..end].iter() {
+        s.push((w >> 8) as u8 as char);
+        s.push((w & 0xFF) as u8 as char);
+    }
+    String::from(s.trim())
+}
+
+fn SetupPort(base: u64, index: usize, slots: usize, staggered: bool) -> Option<Arc<AHCIDisk>> {
+    let mut port = AHCIPort {
+        base: base + 0x100 + (index as u64 * 0x80),
+        slots,
+        cmd_list: 0,
+        tables: Vec::new(),
+        bounce: 0,
+    };
+    if !port.Stop() {
+        log::warn!("AHCI: Port {} refused to stop", index);
+        return None;
+    }
+    // If anything past here fails, dropping the port (or the disk holding it) gives the memory back
+    port.cmd_list = crate::PageFrame::Allocate(0x1000)? as u64;
+    for _ in 0..slots {
+        port.tables.push(crate::PageFrame::Allocate(CMD_TABLE_SIZE)? as u64);
+    }
+    port.bounce = crate::PageFrame::Allocate(MAX_TRANSFER as u64)? as u64;
+    let clb = port.cmd_list-PHYSMEM_BEGIN;
+    let fb = clb+0x400;
+    port.Write(PORT_CLB,clb as u32);
+    port.Write(PORT_CLBU,(clb >> 32) as u32);
+    port.Write(PORT_FB,fb as u32);
+    port.Write(PORT_FBU,(fb >> 32) as u32);
+    port.Write(PORT_SERR,0xFFFFFFFF);
+    port.Write(PORT_IS,0xFFFFFFFF);
+    if staggered {
+        port.Write(PORT_CMD,port.Read(PORT_CMD) | CMD_SUD);
+    }
+    // Wait for the PHY to come back up after the HBA reset
+    if !WaitFor(|| port.Read(PORT_SSTS) & 0xF == 3, 100000) {
+        return None;
+    }
+    let sig = port.Read(PORT_SIG);
+    if sig == SIG_ATAPI {
+        log::debug!("AHCI: Port {} has an ATAPI device, which isn't supported yet", index);
+        return None;
+    } else if sig != SIG_ATA {
+        log::debug!("AHCI: Port {} has an unsupported device (signature 0x{:08x})", index, sig);
+        return None;
+    }
+    if !port.Start() {
+        log::warn!("AHCI: Port {} has a device that never became ready", index);
+        return None;
+    }
+    let mut disk = AHCIDisk {
+        port: Mutex::new(port),
+        sector_size: 512,
+        sectors: 0,
+    };
+    let mut identify = [0u16; 256];
+    {
+        let port = disk.port.lock();
+        if port.Issue(ATA_CMD_IDENTIFY,0,0,512,false).is_err() {
+            log::warn!("AHCI: Port {} didn't respond to IDENTIFY DEVICE", index);
+            return None;
+        }
+        unsafe {core::ptr::copy(port.bounce as *const u16,identify.as_mut_ptr(),256);}
+    }
+    if identify[83] & (1 << 10) == 0 {
+        log::warn!("AHCI: Port {} has a drive without LBA48 support", index);
+        return None;
+    }
+    disk.sectors = (identify[100] as u64) | ((identify[101] as u64) << 16) | ((identify[102] as u64) << 32) | ((identify[103] as u64) << 48);
+    if identify[106] & 0xC000 == 0x4000 && identify[106] & (1 << 12) != 0 {
+        disk.sector_size = (((identify[117] as u32) | ((identify[118] as u32) << 16)) * 2) as usize;
+    }
+    log::info!("AHCI: Port {}: \"{}\" (Firmware: {})", index, IdentifyString(&identify,27,47), IdentifyString(&identify,23,27));
+    Some(Arc::new(disk))
+}
+
+pub fn Initalize() {
+    let lock = PCI::PCI_DEVICES.lock();
+    for i in lock.iter() {
+        if i.class == 0x1 && i.subclass == 0x6 && i.progif == 0x1 {
+            let abar = PCI::ReadBAR(i.bus,i.slot,i.func,5);
+            if !PCI::BARIsMapped(abar) {
+                log::warn!("AHCI: ABAR 0x{:016x} is outside of the direct map, skipping controller", abar);
+                continue;
+            }
+            let base = abar+PHYSMEM_BEGIN;
+            let vs = HBARead(base,HBA_VS);
+            log::debug!("Starting up AHCI {}.{}.{} Controller", vs >> 16, (vs >> 8) & 0xFF, vs & 0xFF);
+            // BIOS/OS Handoff
+            if HBARead(base,HBA_CAP2) & 1 != 0 {
+                HBAWrite(base,HBA_BOHC,HBARead(base,HBA_BOHC) | (1 << 1));
+                if !WaitFor(|| HBARead(base,HBA_BOHC) & 1 == 0, 2000000) {
+                    log::error!("AHCI Firmware handoff failed (Firmware bug?)");
+                    continue;
+                }
+            }
+            HBAWrite(base,HBA_GHC,GHC_AE);
+            HBAWrite(base,HBA_GHC,GHC_AE | GHC_HR);
+            if !WaitFor(|| HBARead(base,HBA_GHC) & GHC_HR == 0, 1000000) {
+                log::error!("AHCI: HBA reset timed out");
+                continue;
+            }
+            HBAWrite(base,HBA_GHC,GHC_AE);
+            let cap = HBARead(base,HBA_CAP);
+            let slots = (((cap >> 8) & 0x1F) + 1) as usize;
+            if cap & (1 << 31) == 0 {
+                log::warn!("AHCI: Controller doesn't support 64-bit DMA");
+            }
+            let pi = HBARead(base,HBA_PI);
+            let mut disks = Vec::new();
+            for p in 0..32 {
+                if pi & (1 << p) != 0 {
+                    if let Some(disk) = SetupPort(base,p,slots,cap & (1 << 27) != 0) {
+                        disks.push(disk);
+                    }
+                }
+            }
+            HBAWrite(base,HBA_IS,0xFFFFFFFF);
+            for d in disks {
+                BlockDevice::Register(BlockDevice::ReserveSDName(),d);
+            }
+        }
+    }
+}
@@ -0,0 +1,253 @@
+use spin::Mutex;
+use alloc::sync::Arc;
+use crate::arch::PHYSMEM_BEGIN;
+use crate::Drivers::Arch::PCI;
+use crate::Drivers::Generic::NetworkDevice;
+
+const REG_CTRL: u64 = 0x0000;
+const REG_STATUS: u64 = 0x0008;
+const REG_EERD: u64 = 0x0014;
+const REG_ICR: u64 = 0x00C0;
+const REG_IMS: u64 = 0x00D0;
+const REG_IMC: u64 = 0x00D8;
+const REG_RCTL: u64 = 0x0100;
+const REG_TCTL: u64 = 0x0400;
+const REG_TIPG: u64 = 0x0410;
+const REG_RDBAL: u64 = 0x2800;
+const REG_RDBAH: u64 = 0x2804;
+const REG_RDLEN: u64 = 0x2808;
+const REG_RDH: u64 = 0x2810;
+const REG_RDT: u64 = 0x2818;
+const REG_TDBAL: u64 = 0x3800;
+const REG_TDBAH: u64 = 0x3804;
+const REG_TDLEN: u64 = 0x3808;
+const REG_TDH: u64 = 0x3810;
+const REG_TDT: u64 = 0x3818;
+const REG_MTA: u64 = 0x5200;
+const REG_RAL: u64 = 0x5400;
+const REG_RAH: u64 = 0x5404;
+
+const CTRL_ASDE: u32 = 1 << 5;
+const CTRL_SLU: u32 = 1 << 6;
+const CTRL_RST: u32 = 1 << 26;
+const STATUS_LU: u32 = 1 << 1;
+
+const RCTL_EN: u32 = 1 << 1;
+const RCTL_BAM: u32 = 1 << 15;
+const RCTL_SECRC: u32 = 1 << 26;
+
+const TCTL_EN: u32 = 1 << 1;
+const TCTL_PSP: u32 = 1 << 3;
+const TCTL_CT: u32 = 0x0F << 4;
+const TCTL_COLD: u32 = 0x40 << 12;
+
+// TXDW | LSC | RXDMT0 | RXO | RXT0
+const INT_MASK: u32 = (1 << 0) | (1 << 2) | (1 << 4) | (1 << 6) | (1 << 7);
+
+const TX_CMD_EOP: u8 = 1 << 0;
+const TX_CMD_IFCS: u8 = 1 << 1;
+const TX_CMD_RS: u8 = 1 << 3;
+const DESC_DD: u8 = 1 << 0;
+const RX_STATUS_EOP: u8 = 1 << 1;
+
+const RX_DESCS: usize = 32;
+const TX_DESCS: usize = 32;
+const BUFFER_SIZE: usize = 2048;
+
+struct E1000Rings {
+    rx: u64, // Virtual addresses of the descriptor rings and their buffers
+    tx: u64,
+    rx_buffers: u64,
+    tx_buffers: u64,
+    rx_next: usize,
+    tx_next: usize,
+    tx_clean: usize,
+}
+
+pub struct E1000 {
+    base: u64,
+    rings: Mutex<E1000Rings>,
+    mac: [u8; 6],
+}
+
+impl E1000 {
+    fn Read(&self, reg: u64) -> u32 {
+        unsafe {((self.base+reg) as *const u32).read_volatile()}
+    }
+    fn Write(&self, reg: u64, val: u32) {
+        unsafe {((self.base+reg) as *mut u32).write_volatile(val);}
+    }
+    fn ReadEEPROM(&self, addr: u8) -> u16 {
+        self.Write(REG_EERD,1 | ((addr as u32) << 8));
+        for _ in 0..1000 {
+            let val = self.Read(REG_EERD);
+            if val & (1 << 4) != 0 {
+                return (val >> 16) as u16;
+            }
+            crate::arch::Timer::Sleep(1);
+        }
+        0
+    }
+}
+
+impl NetworkDevice::NetworkDevice for E1000 {
+    fn MACAddress(&self) -> [u8; 6] {
+        self.mac
+    }
+    fn LinkUp(&self) -> bool {
+        self.Read(REG_STATUS) & STATUS_LU != 0
+    }
+    fn Transmit(&self, frame: &[u8]) -> bool {
+        if frame.len() > BUFFER_SIZE {
+            return true; // Drop it, it'll never fit
+        }
+        let mut rings = match self.rings.try_lock() {
+            Some(r) => r,
+            None => {return false;}
+        };
+        let next = (rings.tx_next + 1) % TX_DESCS;
+        if next == rings.tx_clean {
+            return false;
+        }
+        let index = rings.tx_next;
+        let buffer = rings.tx_buffers + (index * BUFFER_SIZE) as u64;
+        let desc = (rings.tx + (index as u64 * 16)) as *mut u8;
+        unsafe {
+            core::ptr::copy(frame.as_ptr(),buffer as *mut u8,frame.len());
+            (desc as *mut u64).write_volatile(buffer-PHYSMEM_BEGIN);
+            (desc.offset(8) as *mut u16).write_volatile(frame.len() as u16);
+            desc.offset(10).write_volatile(0);
+            desc.offset(11).write_volatile(TX_CMD_EOP | TX_CMD_IFCS | TX_CMD_RS);
+            desc.offset(12).write_volatile(0);
+        }
+        rings.tx_next = next;
+        self.Write(REG_TDT,next as u32);
+        true
+    }
+    fn Poll(&self, iface: &NetworkDevice::NetworkInterface) {
+        self.Read(REG_ICR);
+        let mut rings = match self.rings.try_lock() {
+            Some(r) => r,
+            None => {return;}
+        };
+        while rings.tx_clean != rings.tx_next {
+            let desc = (rings.tx + (rings.tx_clean as u64 * 16)) as *const u8;
+            if unsafe {desc.offset(12).read_volatile()} & DESC_DD == 0 {break;}
+            rings.tx_clean = (rings.tx_clean + 1) % TX_DESCS;
+        }
+        loop {
+            let index = rings.rx_next;
+            let desc = (rings.rx + (index as u64 * 16)) as *mut u8;
+            let status = unsafe {desc.offset(12).read_volatile()};
+            if status & DESC_DD == 0 {break;}
+            let len = unsafe {(desc.offset(8) as *const u16).read_volatile()} as usize;
+            let errors = unsafe {desc.offset(13).read_volatile()};
+            // We don't enable long packets, so anything without EOP set is garbage
+            if status & RX_STATUS_EOP != 0 && errors == 0 && len > 0 {
+                let buffer = rings.rx_buffers + (index * BUFFER_SIZE) as u64;
+                iface.Deliver(unsafe {core::slice::from_raw_parts(buffer as *const u8,len)}.to_vec());
+            }
+            unsafe {
+                desc.offset(12).write_volatile(0);
+                desc.offset(13).write_volatile(0);
+            }
+            rings.rx_next = (index + 1) % RX_DESCS;
+            self.Write(REG_RDT,index as u32);
+        }
+    }
+}
+
+fn Handle() {
+    NetworkDevice::PollAll();
+}
+
+fn Setup(base: u64) -> Option<Arc<E1000>> {
+    let rx = crate::PageFrame::Allocate(0x1000)? as u64;
+    let tx = crate::PageFrame::Allocate(0x1000)? as u64;
+    let rx_buffers = crate::PageFrame::Allocate((RX_DESCS*BUFFER_SIZE) as u64)? as u64;
+    let tx_buffers = crate::PageFrame::Allocate((TX_DESCS*BUFFER_SIZE) as u64)? as u64;
+    let mut nic = E1000 {
+        base,
+        rings: Mutex::new(E1000Rings {
+            rx,
+            tx,
+            rx_buffers,
+            tx_buffers,
+            rx_next: 0,
+            tx_next: 0,
+            tx_clean: 0,
+        }),
+        mac: [0; 6],
+    };
+    // Reset the controller
+    nic.Write(REG_IMC,0xFFFFFFFF);
+    nic.Write(REG_CTRL,nic.Read(REG_CTRL) | CTRL_RST);
+    crate::arch::Timer::Sleep(10);
+    while nic.Read(REG_CTRL) & CTRL_RST != 0 {core::hint::spin_loop();}
+    nic.Write(REG_IMC,0xFFFFFFFF);
+    nic.Read(REG_ICR);
+    nic.Write(REG_CTRL,nic.Read(REG_CTRL) | CTRL_SLU | CTRL_ASDE);
+    // Firmware usually loads the MAC into the first receive address register for us
+    let ral = nic.Read(REG_RAL);
+    let rah = nic.Read(REG_RAH);
+    if rah & (1 << 31) != 0 {
+        nic.mac = [ral as u8, (ral >> 8) as u8, (ral >> 16) as u8, (ral >> 24) as u8, rah as u8, (rah >> 8) as u8];
+    } else {
+        for i in 0..3 {
+            let word = nic.ReadEEPROM(i);
+            nic.mac[(i*2) as usize] = word as u8;
+            nic.mac[(i*2+1) as usize] = (word >> 8) as u8;
+        }
+        let mac = nic.mac;
+        nic.Write(REG_RAL,(mac[0] as u32) | ((mac[1] as u32) << 8) | ((mac[2] as u32) << 16) | ((mac[3] as u32) << 24));
+        nic.Write(REG_RAH,(mac[4] as u32) | ((mac[5] as u32) << 8) | (1 << 31));
+    }
+    for i in 0..128 {
+        nic.Write(REG_MTA + (i * 4),0);
+    }
+    for i in 0..RX_DESCS {
+        let desc = (rx + (i as u64 * 16)) as *mut u64;
+        unsafe {desc.write_volatile(rx_buffers + (i * BUFFER_SIZE) as u64 - PHYSMEM_BEGIN);}
+    }
+    nic.Write(REG_RDBAL,(rx-PHYSMEM_BEGIN) as u32);
+    nic.Write(REG_RDBAH,((rx-PHYSMEM_BEGIN) >> 32) as u32);
+    nic.Write(REG_RDLEN,(RX_DESCS * 16) as u32);
+    nic.Write(REG_RDH,0);
+    nic.Write(REG_RDT,(RX_DESCS - 1) as u32);
+    nic.Write(REG_RCTL,RCTL_EN | RCTL_BAM | RCTL_SECRC); // BSIZE = 2048
+    nic.Write(REG_TDBAL,(tx-PHYSMEM_BEGIN) as u32);
+    nic.Write(REG_TDBAH,((tx-PHYSMEM_BEGIN) >> 32) as u32);
+    nic.Write(REG_TDLEN,(TX_DESCS * 16) as u32);
+    nic.Write(REG_TDH,0);
+    nic.Write(REG_TDT,0);
+    nic.Write(REG_TCTL,TCTL_EN | TCTL_PSP | TCTL_CT | TCTL_COLD);
+    nic.Write(REG_TIPG,0x0060200A);
+    nic.Write(REG_IMS,INT_MASK);
+    Some(Arc::new(nic))
+}
+
+pub fn Initalize() {
+    let lock = PCI::PCI_DEVICES.lock();
+    for i in lock.iter() {
+        // 82540EM, which is what QEMU emulates for "-device e1000"
+        if i.vendor == 0x8086 && i.device == 0x100E {
+            let bar = PCI::ReadBAR(i.bus,i.slot,i.func,0);
+            if !PCI::BARIsMapped(bar) {
+                log::warn!("e1000: BAR0 0x{:016x} is outside of the direct map, skipping controller", bar);
+                continue;
+            }
+            log::debug!("Starting up Intel e1000 Network Controller");
+            let nic = match Setup(bar+PHYSMEM_BEGIN) {
+                Some(n) => n,
+                None => {continue;}
+            };
+            match i.irq {
+                PCI::IRQ::Msi(irq) | PCI::IRQ::Msix(irq) | PCI::IRQ::Irql(irq) if irq != u8::MAX => {
+                    crate::arch::IDT::IRQ_HANDLERS.lock()[(irq-0x20) as usize] = Some(Handle);
+                }
+                _ => {}
+            }
+            NetworkDevice::Register(NetworkDevice::ReserveEthName(),nic);
+        }
+    }
+}
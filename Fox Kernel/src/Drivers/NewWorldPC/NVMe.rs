@@ -0,0 +1,464 @@
+use spin::Mutex;
+use alloc::vec::Vec;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::format;
+use core::sync::atomic::{AtomicUsize,Ordering};
+use crate::arch::PHYSMEM_BEGIN;
+use crate::arch::Timer;
+use crate::Drivers::Arch::PCI;
+use crate::Drivers::Generic::BlockDevice;
+use crate::Syscall::Errors;
+
+const NVME_CAP: u64 = 0x00;
+const NVME_VS: u64 = 0x08;
+const NVME_CC: u64 = 0x14;
+const NVME_CSTS: u64 = 0x1C;
+const NVME_AQA: u64 = 0x24;
+const NVME_ASQ: u64 = 0x28;
+const NVME_ACQ: u64 = 0x30;
+const NVME_DOORBELLS: u64 = 0x1000;
+
+const CC_EN: u32 = 1 << 0;
+const CC_IOSQES: u32 = 6 << 16; // 64 byte submission entries
+const CC_IOCQES: u32 = 4 << 20; // 16 byte completion entries
+const CSTS_RDY: u32 = 1 << 0;
+const CSTS_CFS: u32 = 1 << 1;
+
+const ADMIN_CREATE_SQ: u8 = 0x01;
+const ADMIN_DELETE_CQ: u8 = 0x04;
+const ADMIN_CREATE_CQ: u8 = 0x05;
+const ADMIN_IDENTIFY: u8 = 0x06;
+const ADMIN_SET_FEATURES: u8 = 0x09;
+
+const IO_FLUSH: u8 = 0x00;
+const IO_WRITE: u8 = 0x01;
+const IO_READ: u8 = 0x02;
+
+const FEATURE_NUM_QUEUES: u32 = 0x07;
+
+const QUEUE_ENTRIES: u16 = 64;
+const MAX_TRANSFER: usize = 0x20000;
+const COMMAND_TIMEOUT: u64 = 5000000; // 5 seconds
+
+struct NVMeQueue {
+    id: u16,
+    sq: u64,
+    cq: u64,
+    size: u16,
+    sq_tail: u16,
+    cq_head: u16,
+    phase: bool,
+    sq_doorbell: u64,
+    cq_doorbell: u64,
+    cid: u16,
+    bounce: u64,
+    bounce_size: usize,
+    prp_list: u64,
+}
+
+// Queues only get dropped when the controller can't get at them anymore, see SetupGuard
+impl Drop for NVMeQueue {
+    fn drop(&mut self) {
+        if self.sq != 0 {
+            crate::PageFrame::Free(self.sq as *mut u8,0x1000);
+        }
+        if self.cq != 0 {
+            crate::PageFrame::Free(self.cq as *mut u8,0x1000);
+        }
+        if self.bounce != 0 {
+            crate::PageFrame::Free(self.bounce as *mut u8,self.bounce_size as u64);
+        }
+        if self.prp_list != 0 {
+            crate::PageFrame::Free(self.prp_list as *mut u8,0x1000);
+        }
+    }
+}
+
+impl NVMeQueue {
+    fn new(base: u64, dstrd: u64, id: u16, size: u16, bounce_size: usize) -> Option<Self> {
+        let mut queue = Self {
+            id,
+            sq: 0,
+            cq: 0,
+            size,
+            sq_tail: 0,
+            cq_head: 0,
+            phase: true,
+            sq_doorbell: base + NVME_DOORBELLS + ((2 * id as u64) * (4 << dstrd)),
+            cq_doorbell: base + NVME_DOORBELLS + ((2 * id as u64 + 1) * (4 << dstrd)),
+            cid: 0,
+            bounce: 0,
+            bounce_size,
+            prp_list: 0,
+        };
+        // If any of these fail, dropping the queue gives back the ones that didn't
+        queue.sq = crate::PageFrame::Allocate(0x1000)? as u64;
+        queue.cq = crate::PageFrame::Allocate(0x1000)? as u64;
+        queue.bounce = crate::PageFrame::Allocate(bounce_size as u64)? as u64;
+        queue.prp_list = crate::PageFrame::Allocate(0x1000)? as u64;
+        Some(queue)
+    }
+    // For a queue the controller might still write into, which is better off never being reused
+    fn Leak(&mut self) {
+        self.sq = 0;
+        self.cq = 0;
+        self.bounce = 0;
+        self.prp_list = 0;
+    }
+    // Points PRP1/PRP2 at the first `length` bytes of the bounce buffer.
+    fn SetupPRPs(&self, cmd: &mut [u32; 16], length: usize) {
+        let phys = self.bounce-PHYSMEM_BEGIN;
+        cmd[6] = phys as u32;
+        cmd[7] = (phys >> 32) as u32;
+        if length > 0x2000 {
+            let list = self.prp_list as *mut u64;
+            for i in 1..length.div_ceil(0x1000) {
+                unsafe {list.add(i-1).write_volatile(phys + (i as u64 * 0x1000));}
+            }
+            let list_phys = self.prp_list-PHYSMEM_BEGIN;
+            cmd[8] = list_phys as u32;
+            cmd[9] = (list_phys >> 32) as u32;
+        } else if length > 0x1000 {
+            cmd[8] = (phys + 0x1000) as u32;
+            cmd[9] = ((phys + 0x1000) >> 32) as u32;
+        }
+    }
+    fn NextCompletion(&mut self) -> Option<[u32; 4]> {
+        let entry = (self.cq + (self.cq_head as u64 * 16)) as *const u32;
+        let status = unsafe {entry.offset(3).read_volatile()};
+        if ((status >> 16) & 1 != 0) != self.phase {
+            return None;
+        }
+        let mut result = [0u32; 4];
+        for i in 0..4 {
+            result[i] = unsafe {entry.offset(i as isize).read_volatile()};
+        }
+        self.cq_head += 1;
+        if self.cq_head == self.size {
+            self.cq_head = 0;
+            self.phase = !self.phase;
+        }
+        unsafe {(self.cq_doorbell as *mut u32).write_volatile(self.cq_head as u32);}
+        Some(result)
+    }
+    fn Submit(&mut self, mut cmd: [u32; 16]) -> Result<u32,i64> {
+        self.cid = self.cid.wrapping_add(1);
+        let cid = self.cid;
+        cmd[0] = (cmd[0] & 0xFFFF) | ((cid as u32) << 16);
+        let entry = (self.sq + (self.sq_tail as u64 * 64)) as *mut u32;
+        for i in 0..16 {
+            unsafe {entry.offset(i as isize).write_volatile(cmd[i]);}
+        }
+        self.sq_tail = (self.sq_tail + 1) % self.size;
+        unsafe {(self.sq_doorbell as *mut u32).write_volatile(self.sq_tail as u32);}
+        let start = Timer::GetMicroseconds();
+        loop {
+            // No interrupts here, see BlockDevice for why. Anything that isn't ours is left over from a command we gave up on.
+            if let Some(c) = self.NextCompletion() {
+                if (c[3] & 0xFFFF) as u16 != cid {
+                    continue;
+                }
+                let status = (c[3] >> 17) & 0x7FFF;
+                if status != 0 {
+                    log::error!("NVMe: Queue {} command 0x{:02x} failed (SCT: {} SC: 0x{:02x})", self.id, cmd[0] & 0xFF, (status >> 8) & 0x7, status & 0xFF);
+                    return Err(Errors::EIO as i64);
+                }
+                return Ok(c[0]);
+            }
+            if Timer::GetMicroseconds() - start > COMMAND_TIMEOUT {
+                log::error!("NVMe: Queue {} command 0x{:02x} timed out", self.id, cmd[0] & 0xFF);
+                return Err(Errors::EIO as i64);
+            }
+            core::hint::spin_loop();
+        }
+    }
+}
+
+pub struct NVMeController {
+    admin: Mutex<NVMeQueue>,
+    io: Vec<Mutex<NVMeQueue>>,
+    hart_queue: [usize; 64],
+    max_transfer: usize,
+}
+
+impl NVMeController {
+    fn Queue(&self) -> &Mutex<NVMeQueue> {
+        &self.io[self.hart_queue[crate::arch::CurrentHart() as usize]]
+    }
+    fn Identify(&self, cns: u32, nsid: u32, out: &mut [u8; 4096]) -> Result<(),i64> {
+        let mut admin = self.admin.lock();
+        let mut cmd = [0u32; 16];
+        cmd[0] = ADMIN_IDENTIFY as u32;
+        cmd[1] = nsid;
+        cmd[10] = cns;
+        admin.SetupPRPs(&mut cmd,0x1000);
+        admin.Submit(cmd)?;
+        unsafe {core::ptr::copy(admin.bounce as *const u8,out.as_mut_ptr(),0x1000);}
+        Ok(())
+    }
+}
+
+pub struct NVMeNamespace {
+    controller: Arc<NVMeController>,
+    nsid: u32,
+    sector_size: usize,
+    sectors: u64,
+}
+
+impl NVMeNamespace {
+    fn Transfer(&self, lba: u64, buffer: *mut u8, len: usize, write: bool) -> Result<(),i64> {
+        if len % self.sector_size != 0 {
+            return Err(Errors::EINVAL as i64);
+        }
+        if lba + (len / self.sector_size) as u64 > self.sectors {
+            return Err(Errors::EINVAL as i64);
+        }
+        let mut queue = self.controller.Queue().lock();
+        let max = self.controller.max_transfer;
+        let mut done = 0;
+        while done < len {
+            let size = if len - done > max {max} else {len - done};
+            let cur_lba = lba + (done / self.sector_size) as u64;
+            let mut cmd = [0u32; 16];
+            cmd[0] = (if write {IO_WRITE} else {IO_READ}) as u32;
+            cmd[1] = self.nsid;
+            cmd[10] = cur_lba as u32;
+            cmd[11] = (cur_lba >> 32) as u32;
+            cmd[12] = ((size / self.sector_size) - 1) as u32;
+            queue.SetupPRPs(&mut cmd,size);
+            unsafe {
+                if write {
+                    core::ptr::copy(buffer.add(done),queue.bounce as *mut u8,size);
+                    queue.Submit(cmd)?;
+                } else {
+                    queue.Submit(cmd)?;
+                    core::ptr::copy(queue.bounce as *const u8,buffer.add(done),size);
+                }
+            }
+            done += size;
+        }
+        Ok(())
+    }
+}
+
+impl BlockDevice::BlockDevice for NVMeNamespace {
+    fn BlockSize(&self) -> usize {
+        self.sector_size
+    }
+    fn BlockCount(&self) -> u64 {
+        self.sectors
+    }
+    fn ReadBlocks(&self, lba: u64, buffer: &mut [u8]) -> Result<(),i64> {
+        self.Transfer(lba,buffer.as_mut_ptr(),buffer.len(),false)
+    }
+    fn WriteBlocks(&self, lba: u64, buffer: &[u8]) -> Result<(),i64> {
+        self.Transfer(lba,buffer.as_ptr() as *mut u8,buffer.len(),true)
+    }
+    fn Flush(&self) -> Result<(),i64> {
+        let mut cmd = [0u32; 16];
+        cmd[0] = IO_FLUSH as u32;
+        cmd[1] = self.nsid;
+        self.controller.Queue().lock().Submit(cmd).map(|_| ())
+    }
+}
+
+static NEXT_CONTROLLER: AtomicUsize = AtomicUsize::new(0);
+
+fn WaitFor<F: Fn() -> bool>(cond: F, timeout: u64) -> bool {
+    let start = Timer::GetMicroseconds();
+    while !cond() {
+        if Timer::GetMicroseconds() - start > timeout {
+            return cond();
+        }
+        core::hint::spin_loop();
+    }
+    true
+}
+
+fn IdentifyString(data: &[u8]) -> String {
+    String::from(String::from_utf8_lossy(data).trim())
+}
+
+fn Disable(base: u64, timeout: u64) -> bool {
+    let cc = (base+NVME_CC) as *mut u32;
+    unsafe {cc.write_volatile(cc.read_volatile() & !CC_EN);}
+    WaitFor(|| unsafe {((base+NVME_CSTS) as *const u32).read_volatile()} & CSTS_RDY == 0, timeout)
+}
+
+// Holds on to a controller while it's being set up. If that falls through, the controller gets turned back off before
+// its queues are given back, since it could still be writing into them otherwise.
+struct SetupGuard {
+    base: u64,
+    timeout: u64,
+    controller: Option<NVMeController>,
+}
+
+impl Drop for SetupGuard {
+    fn drop(&mut self) {
+        if let Some(mut controller) = self.controller.take() {
+            if !Disable(self.base,self.timeout) {
+                log::error!("NVMe: Controller refused to disable, leaking its queues");
+                controller.admin.get_mut().Leak();
+                for queue in controller.io.iter_mut() {
+                    queue.get_mut().Leak();
+                }
+            }
+        }
+    }
+}
+
+fn SetupController(base: u64) -> Option<Arc<NVMeController>> {
+    let read32 = |reg: u64| unsafe {((base+reg) as *const u32).read_volatile()};
+    let write32 = |reg: u64, val: u32| unsafe {((base+reg) as *mut u32).write_volatile(val)};
+    let write64 = |reg: u64, val: u64| unsafe {((base+reg) as *mut u64).write_volatile(val)};
+    let cap = unsafe {((base+NVME_CAP) as *const u64).read_volatile()};
+    let mqes = ((cap & 0xFFFF) + 1) as u16;
+    let dstrd = (cap >> 32) & 0xF;
+    let timeout = ((cap >> 24) & 0xFF) * 500000;
+    let mpsmin = ((cap >> 48) & 0xF) as u32;
+    let size = if mqes < QUEUE_ENTRIES {mqes} else {QUEUE_ENTRIES};
+    // Reset the controller
+    if !Disable(base,timeout) {
+        log::error!("NVMe: Controller refused to disable");
+        return None;
+    }
+    let admin = NVMeQueue::new(base,dstrd,0,size,0x1000)?;
+    write32(NVME_AQA,((size as u32 - 1) << 16) | (size as u32 - 1));
+    write64(NVME_ASQ,admin.sq-PHYSMEM_BEGIN);
+    write64(NVME_ACQ,admin.cq-PHYSMEM_BEGIN);
+    let mut setup = SetupGuard {
+        base,
+        timeout,
+        controller: Some(NVMeController {
+            admin: Mutex::new(admin),
+            io: Vec::new(),
+            hart_queue: [0; 64],
+            max_transfer: MAX_TRANSFER,
+        }),
+    };
+    let controller = setup.controller.as_mut().unwrap();
+    write32(NVME_CC,CC_EN | CC_IOSQES | CC_IOCQES);
+    if !WaitFor(|| read32(NVME_CSTS) & (CSTS_RDY | CSTS_CFS) != 0, timeout) || read32(NVME_CSTS) & CSTS_CFS != 0 {
+        log::error!("NVMe: Controller failed to become ready");
+        return None;
+    }
+    let mut identify = [0u8; 4096];
+    if controller.Identify(1,0,&mut identify).is_err() {
+        log::error!("NVMe: Controller didn't respond to IDENTIFY");
+        return None;
+    }
+    log::info!("NVMe: \"{}\" (Serial: {} Firmware: {})", IdentifyString(&identify[24..64]), IdentifyString(&identify[4..24]), IdentifyString(&identify[64..72]));
+    // MDTS is a power of two in units of the smallest page size, and one big enough to not fit in a usize means no limit
+    let mdts = identify[77] as u32;
+    if mdts != 0 {
+        if let Some(limit) = 1usize.checked_shl(12 + mpsmin + mdts) {
+            controller.max_transfer = core::cmp::min(limit,MAX_TRANSFER);
+        }
+    }
+    // Ask for one I/O queue pair per hart
+    let mut harts: Vec<u32> = Vec::new();
+    for i in 0..64 {
+        if unsafe {crate::arch::GDT::HARTS[i].is_some()} {
+            harts.push(i as u32);
+        }
+    }
+    let mut queues = harts.len();
+    let mut cmd = [0u32; 16];
+    cmd[0] = ADMIN_SET_FEATURES as u32;
+    cmd[10] = FEATURE_NUM_QUEUES;
+    cmd[11] = ((queues as u32 - 1) << 16) | (queues as u32 - 1);
+    match controller.admin.lock().Submit(cmd) {
+        Ok(granted) => {
+            let sq = ((granted & 0xFFFF) + 1) as usize;
+            let cq = ((granted >> 16) + 1) as usize;
+            if sq < queues {queues = sq;}
+            if cq < queues {queues = cq;}
+        }
+        Err(_) => {
+            queues = 1;
+        }
+    }
+    // Getting fewer queues than we asked for is fine, the harts just end up sharing them
+    for i in 0..queues {
+        let qid = (i + 1) as u16;
+        let mut queue = match NVMeQueue::new(base,dstrd,qid,size,MAX_TRANSFER) {
+            Some(q) => q,
+            None => {break;}
+        };
+        let mut cmd = [0u32; 16];
+        cmd[0] = ADMIN_CREATE_CQ as u32;
+        cmd[6] = (queue.cq-PHYSMEM_BEGIN) as u32;
+        cmd[7] = ((queue.cq-PHYSMEM_BEGIN) >> 32) as u32;
+        cmd[10] = ((size as u32 - 1) << 16) | qid as u32;
+        cmd[11] = 0b1; // Physically Contiguous, and no interrupts
+        if controller.admin.lock().Submit(cmd).is_err() {break;}
+        let mut cmd = [0u32; 16];
+        cmd[0] = ADMIN_CREATE_SQ as u32;
+        cmd[6] = (queue.sq-PHYSMEM_BEGIN) as u32;
+        cmd[7] = ((queue.sq-PHYSMEM_BEGIN) >> 32) as u32;
+        cmd[10] = ((size as u32 - 1) << 16) | qid as u32;
+        cmd[11] = ((qid as u32) << 16) | 0b1; // Completion Queue | Physically Contiguous
+        if controller.admin.lock().Submit(cmd).is_err() {
+            let mut cmd = [0u32; 16];
+            cmd[0] = ADMIN_DELETE_CQ as u32;
+            cmd[10] = qid as u32;
+            if controller.admin.lock().Submit(cmd).is_err() {
+                queue.Leak();
+            }
+            break;
+        }
+        controller.io.push(Mutex::new(queue));
+    }
+    if controller.io.len() == 0 {
+        log::error!("NVMe: Couldn't create any I/O queues");
+        return None;
+    }
+    for (i, hart) in harts.iter().enumerate() {
+        controller.hart_queue[*hart as usize] = i % controller.io.len();
+    }
+    log::debug!("NVMe: Using {} I/O queue pair(s) with {} entries each", controller.io.len(), size);
+    Some(Arc::new(setup.controller.take().unwrap()))
+}
+
+pub fn Initalize() {
+    let lock = PCI::PCI_DEVICES.lock();
+    for i in lock.iter() {
+        if i.class == 0x1 && i.subclass == 0x8 && i.progif == 0x2 {
+            let bar = PCI::ReadBAR(i.bus,i.slot,i.func,0);
+            if !PCI::BARIsMapped(bar) {
+                log::warn!("NVMe: BAR0 0x{:016x} is outside of the direct map, skipping controller", bar);
+                continue;
+            }
+            let base = bar+PHYSMEM_BEGIN;
+            let vs = unsafe {((base+NVME_VS) as *const u32).read_volatile()};
+            log::debug!("Starting up NVMe {}.{}.{} Controller", vs >> 16, (vs >> 8) & 0xFF, vs & 0xFF);
+            let controller = match SetupController(base) {
+                Some(c) => c,
+                None => {continue;}
+            };
+            let index = NEXT_CONTROLLER.fetch_add(1,Ordering::SeqCst);
+            let mut list = [0u8; 4096];
+            if controller.Identify(2,0,&mut list).is_err() {
+                log::error!("NVMe: Couldn't get the active namespace list");
+                continue;
+            }
+            for n in 0..1024 {
+                let nsid = u32::from_le_bytes([list[n*4],list[n*4+1],list[n*4+2],list[n*4+3]]);
+                if nsid == 0 {break;}
+                let mut ns = [0u8; 4096];
+                if controller.Identify(0,nsid,&mut ns).is_err() {continue;}
+                let sectors = u64::from_le_bytes(ns[0..8].try_into().unwrap());
+                if sectors == 0 {continue;}
+                let format = (ns[26] & 0xF) as usize;
+                let lbads = ns[128 + (format * 4) + 2];
+                BlockDevice::Register(format!("nvme{}n{}", index, nsid),Arc::new(NVMeNamespace {
+                    controller: controller.clone(),
+                    nsid,
+                    sector_size: 1 << lbads,
+                    sectors,
+                }));
+            }
+        }
+    }
+}
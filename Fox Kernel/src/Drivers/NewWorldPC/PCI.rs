@@ -330,6 +330,21 @@ pub fn SearchCapability(bus: u8, slot: u8, func: u8, cap: u8) -> Result<u8,bool>
     return Err(false);
 }
 
+// Same as SearchCapability, but returns every instance of the capability (Vendor-specific capabilities can show up multiple times)
+pub fn Capabilities(bus: u8, slot: u8, func: u8, cap: u8) -> Vec<u8> {
+    let mut caps: Vec<u8> = Vec::new();
+    if ReadU8(bus,slot,func,PCI_STATUS) & 0x10 == 0x10 {
+        let mut cap_off = ReadU8(bus,slot,func,0x34);
+        while cap_off != 0 {
+            if ReadU8(bus,slot,func,cap_off as u16) == cap {
+                caps.push(cap_off);
+            }
+            cap_off = ReadU8(bus,slot,func,(cap_off+1) as u16);
+        }
+    }
+    caps
+}
+
 pub fn EnableMSI(bus: u8, slot: u8, func: u8, off: u8) -> u8 {
     let msg_ctl = ((ReadU16(bus,slot,func,(off+2) as u16) & (!0x70)) & (!0x100)) | 0x1;
     let mut irqlock = IRQS_FREE.lock();
@@ -382,6 +397,15 @@ pub fn EnableMSIX(bus: u8, slot: u8, func: u8, off: u8) -> u8 {
     return irql;
 }
 
+// Limine only puts usable memory and the first 4 GiB into the direct map
+pub fn BARIsMapped(base: u64) -> bool {
+    let flock = crate::PageFrame::FRAME_ALLOC.lock();
+    let mut highest_address = flock.0.cursor_back().current().unwrap().1 as u64 - PHYSMEM_BEGIN;
+    drop(flock);
+    if highest_address < 0x100000000 {highest_address = 0x100000000;}
+    base < highest_address
+}
+
 pub enum IRQ {
     None,
     Irql(u8),
@@ -0,0 +1,327 @@
+use alloc::vec::Vec;
+use core::sync::atomic::{fence,Ordering};
+use crate::arch::PHYSMEM_BEGIN;
+use crate::Drivers::Arch::PCI;
+
+pub const VIRTIO_VENDOR: u16 = 0x1af4;
+
+const CAP_VENDOR: u8 = 0x09;
+const CAP_COMMON_CFG: u8 = 1;
+const CAP_NOTIFY_CFG: u8 = 2;
+const CAP_ISR_CFG: u8 = 3;
+const CAP_DEVICE_CFG: u8 = 4;
+
+// Common Configuration
+const COMMON_DFSELECT: u64 = 0x00;
+const COMMON_DF: u64 = 0x04;
+const COMMON_GFSELECT: u64 = 0x08;
+const COMMON_GF: u64 = 0x0C;
+const COMMON_MSIX: u64 = 0x10;
+const COMMON_NUMQ: u64 = 0x12;
+const COMMON_STATUS: u64 = 0x14;
+const COMMON_CFGGENERATION: u64 = 0x15;
+const COMMON_Q_SELECT: u64 = 0x16;
+const COMMON_Q_SIZE: u64 = 0x18;
+const COMMON_Q_MSIX: u64 = 0x1A;
+const COMMON_Q_ENABLE: u64 = 0x1C;
+const COMMON_Q_NOFF: u64 = 0x1E;
+const COMMON_Q_DESC: u64 = 0x20;
+const COMMON_Q_AVAIL: u64 = 0x28;
+const COMMON_Q_USED: u64 = 0x30;
+
+pub const STATUS_ACKNOWLEDGE: u8 = 1;
+pub const STATUS_DRIVER: u8 = 2;
+pub const STATUS_DRIVER_OK: u8 = 4;
+pub const STATUS_FEATURES_OK: u8 = 8;
+pub const STATUS_FAILED: u8 = 128;
+
+pub const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+pub const VIRTQ_DESC_F_NEXT: u16 = 1;
+pub const VIRTQ_DESC_F_WRITE: u16 = 2;
+
+pub const NO_VECTOR: u16 = 0xFFFF;
+
+// Keeps every part of the split virtqueue inside of a single page
+const MAX_QUEUE_SIZE: u16 = 256;
+
+pub struct VirtQueue {
+    pub index: u16,
+    size: u16,
+    desc: u64,
+    avail: u64,
+    used: u64,
+    free: Vec<u16>,
+    last_used: u16,
+    notify: u64,
+}
+
+impl VirtQueue {
+    pub fn Size(&self) -> u16 {
+        self.size
+    }
+    // Chains the given (physical address, length, device writable) buffers together and makes them available to the device.
+    // Returns the head descriptor index, which is what shows up in the used ring once the device is done.
+    pub fn Submit(&mut self, buffers: &[(u64,u32,bool)]) -> Option<u16> {
+        if buffers.len() == 0 || self.free.len() < buffers.len() {
+            return None;
+        }
+        let mut ids: Vec<u16> = Vec::new();
+        for _ in 0..buffers.len() {
+            ids.push(self.free.pop().unwrap());
+        }
+        for (i, buf) in buffers.iter().enumerate() {
+            let desc = (self.desc + (ids[i] as u64 * 16)) as *mut u8;
+            let mut flags: u16 = 0;
+            if buf.2 {flags |= VIRTQ_DESC_F_WRITE;}
+            if i+1 < buffers.len() {flags |= VIRTQ_DESC_F_NEXT;}
+            unsafe {
+                (desc as *mut u64).write_volatile(buf.0);
+                (desc.offset(8) as *mut u32).write_volatile(buf.1);
+                (desc.offset(12) as *mut u16).write_volatile(flags);
+                (desc.offset(14) as *mut u16).write_volatile(if i+1 < buffers.len() {ids[i+1]} else {0});
+            }
+        }
+        unsafe {
+            let idx = ((self.avail+2) as *const u16).read_volatile();
+            ((self.avail + 4 + ((idx % self.size) as u64 * 2)) as *mut u16).write_volatile(ids[0]);
+            fence(Ordering::SeqCst);
+            ((self.avail+2) as *mut u16).write_volatile(idx.wrapping_add(1));
+        }
+        fence(Ordering::SeqCst);
+        Some(ids[0])
+    }
+    pub fn Notify(&self) {
+        unsafe {(self.notify as *mut u16).write_volatile(self.index);}
+    }
+    // Pops the next used chain (head descriptor index, bytes written by the device) and releases its descriptors.
+    pub fn PollUsed(&mut self) -> Option<(u16,u32)> {
+        fence(Ordering::SeqCst);
+        let idx = unsafe {((self.used+2) as *const u16).read_volatile()};
+        if idx == self.last_used {
+            return None;
+        }
+        let elem = self.used + 4 + ((self.last_used % self.size) as u64 * 8);
+        let id = unsafe {(elem as *const u32).read_volatile()} as u16;
+        let len = unsafe {((elem+4) as *const u32).read_volatile()};
+        self.last_used = self.last_used.wrapping_add(1);
+        let mut cur = id;
+        loop {
+            let desc = (self.desc + (cur as u64 * 16)) as *const u8;
+            let flags = unsafe {(desc.offset(12) as *const u16).read_volatile()};
+            let next = unsafe {(desc.offset(14) as *const u16).read_volatile()};
+            self.free.push(cur);
+            if flags & VIRTQ_DESC_F_NEXT == 0 {break;}
+            cur = next;
+        }
+        Some((id,len))
+    }
+}
+
+pub struct VirtIODevice {
+    pub bus: u8,
+    pub slot: u8,
+    pub func: u8,
+    common: u64,
+    notify: u64,
+    notify_mult: u32,
+    isr: u64,
+    device: Option<u64>, // Not every device type has its own configuration
+    msix: bool,
+}
+
+fn CapabilityAddress(dev: &PCI::PCIDevice, cap: u8) -> Option<u64> {
+    let bar = PCI::ReadU8(dev.bus,dev.slot,dev.func,(cap+4) as u16);
+    if bar > 5 {return None;}
+    let offset = PCI::ReadU32(dev.bus,dev.slot,dev.func,(cap+8) as u16) as u64;
+    let base = PCI::ReadBAR(dev.bus,dev.slot,dev.func,bar);
+    if !PCI::BARIsMapped(base) {
+        log::warn!("VirtIO: BAR{} 0x{:016x} is outside of the direct map", bar, base);
+        return None;
+    }
+    Some(base+offset+PHYSMEM_BEGIN)
+}
+
+impl VirtIODevice {
+    pub fn new(dev: &PCI::PCIDevice) -> Option<Self> {
+        let mut common = None;
+        let mut notify = None;
+        let mut notify_mult = 0;
+        let mut isr = None;
+        let mut device = None;
+        for cap in PCI::Capabilities(dev.bus,dev.slot,dev.func,CAP_VENDOR) {
+            // The first capability of each type is the one that we're supposed to use
+            match PCI::ReadU8(dev.bus,dev.slot,dev.func,(cap+3) as u16) {
+                CAP_COMMON_CFG if common.is_none() => {common = CapabilityAddress(dev,cap);}
+                CAP_NOTIFY_CFG if notify.is_none() => {
+                    notify = CapabilityAddress(dev,cap);
+                    notify_mult = PCI::ReadU32(dev.bus,dev.slot,dev.func,(cap+16) as u16);
+                }
+                CAP_ISR_CFG if isr.is_none() => {isr = CapabilityAddress(dev,cap);}
+                CAP_DEVICE_CFG if device.is_none() => {device = CapabilityAddress(dev,cap);}
+                _ => {}
+            }
+        }
+        if common.is_none() || notify.is_none() || isr.is_none() {
+            log::warn!("VirtIO: Device 0x{:04x} at {:02x}:{:02x}.{} is missing modern PCI capabilities (legacy-only devices aren't supported)", dev.device, dev.bus, dev.slot, dev.func);
+            return None;
+        }
+        Some(Self {
+            bus: dev.bus,
+            slot: dev.slot,
+            func: dev.func,
+            common: common.unwrap(),
+            notify: notify.unwrap(),
+            notify_mult,
+            isr: isr.unwrap(),
+            device,
+            msix: if let PCI::IRQ::Msix(_) = dev.irq {true} else {false},
+        })
+    }
+    fn Read8(&self, reg: u64) -> u8 {
+        unsafe {((self.common+reg) as *const u8).read_volatile()}
+    }
+    fn Read16(&self, reg: u64) -> u16 {
+        unsafe {((self.common+reg) as *const u16).read_volatile()}
+    }
+    fn Read32(&self, reg: u64) -> u32 {
+        unsafe {((self.common+reg) as *const u32).read_volatile()}
+    }
+    fn Write8(&self, reg: u64, val: u8) {
+        unsafe {((self.common+reg) as *mut u8).write_volatile(val);}
+    }
+    fn Write16(&self, reg: u64, val: u16) {
+        unsafe {((self.common+reg) as *mut u16).write_volatile(val);}
+    }
+    fn Write32(&self, reg: u64, val: u32) {
+        unsafe {((self.common+reg) as *mut u32).write_volatile(val);}
+    }
+    fn Write64(&self, reg: u64, val: u64) {
+        self.Write32(reg,val as u32);
+        self.Write32(reg+4,(val >> 32) as u32);
+    }
+    pub fn Status(&self) -> u8 {
+        self.Read8(COMMON_STATUS)
+    }
+    pub fn SetStatus(&self, status: u8) {
+        self.Write8(COMMON_STATUS,self.Status() | status);
+    }
+    pub fn Fail(&self) {
+        self.SetStatus(STATUS_FAILED);
+    }
+    pub fn Reset(&self) -> bool {
+        self.Write8(COMMON_STATUS,0);
+        for _ in 0..1000 {
+            if self.Status() == 0 {return true;}
+            crate::arch::Timer::Sleep(1);
+        }
+        false
+    }
+    // Resets the device, then offers it the subset of `wanted` that it supports (VIRTIO_F_VERSION_1 is required).
+    // Returns the negotiated feature set.
+    pub fn Negotiate(&self, wanted: u64) -> Option<u64> {
+        if !self.Reset() {
+            log::error!("VirtIO: Device never finished resetting");
+            return None;
+        }
+        self.SetStatus(STATUS_ACKNOWLEDGE);
+        self.SetStatus(STATUS_DRIVER);
+        self.Write32(COMMON_DFSELECT,0);
+        let mut features = self.Read32(COMMON_DF) as u64;
+        self.Write32(COMMON_DFSELECT,1);
+        features |= (self.Read32(COMMON_DF) as u64) << 32;
+        if features & VIRTIO_F_VERSION_1 == 0 {
+            log::error!("VirtIO: Device doesn't support VIRTIO_F_VERSION_1");
+            self.Fail();
+            return None;
+        }
+        let accepted = features & (wanted | VIRTIO_F_VERSION_1);
+        self.Write32(COMMON_GFSELECT,0);
+        self.Write32(COMMON_GF,accepted as u32);
+        self.Write32(COMMON_GFSELECT,1);
+        self.Write32(COMMON_GF,(accepted >> 32) as u32);
+        self.SetStatus(STATUS_FEATURES_OK);
+        if self.Status() & STATUS_FEATURES_OK == 0 {
+            log::error!("VirtIO: Device rejected our feature set (0x{:016x})", accepted);
+            self.Fail();
+            return None;
+        }
+        if self.msix {
+            self.Write16(COMMON_MSIX,NO_VECTOR);
+        }
+        Some(accepted)
+    }
+    pub fn NumQueues(&self) -> u16 {
+        self.Read16(COMMON_NUMQ)
+    }
+    // Sets up the split virtqueue at `index`, routing its interrupts to MSI-X table entry `vector` (if MSI-X is in use).
+    pub fn SetupQueue(&self, index: u16, vector: u16) -> Option<VirtQueue> {
+        self.Write16(COMMON_Q_SELECT,index);
+        let mut size = self.Read16(COMMON_Q_SIZE);
+        if size == 0 {
+            return None;
+        }
+        if size > MAX_QUEUE_SIZE {
+            size = MAX_QUEUE_SIZE;
+            self.Write16(COMMON_Q_SIZE,size);
+        }
+        let desc = crate::PageFrame::Allocate(0x1000)? as u64;
+        let avail = crate::PageFrame::Allocate(0x1000)? as u64;
+        let used = crate::PageFrame::Allocate(0x1000)? as u64;
+        self.Write64(COMMON_Q_DESC,desc-PHYSMEM_BEGIN);
+        self.Write64(COMMON_Q_AVAIL,avail-PHYSMEM_BEGIN);
+        self.Write64(COMMON_Q_USED,used-PHYSMEM_BEGIN);
+        if self.msix {
+            self.Write16(COMMON_Q_MSIX,vector);
+            if self.Read16(COMMON_Q_MSIX) != vector {
+                log::warn!("VirtIO: Device couldn't assign an MSI-X vector to queue {}", index);
+            }
+        }
+        let notify_off = self.Read16(COMMON_Q_NOFF) as u64;
+        self.Write16(COMMON_Q_ENABLE,1);
+        let mut free: Vec<u16> = Vec::new();
+        for i in (0..size).rev() {
+            free.push(i);
+        }
+        Some(VirtQueue {
+            index,
+            size,
+            desc,
+            avail,
+            used,
+            free,
+            last_used: 0,
+            notify: self.notify + (notify_off * self.notify_mult as u64),
+        })
+    }
+    pub fn DriverOK(&self) {
+        self.SetStatus(STATUS_DRIVER_OK);
+    }
+    // Reading the ISR status acknowledges the interrupt, it's only used when we're not on MSI-X.
+    pub fn ReadISR(&self) -> u8 {
+        unsafe {(self.isr as *const u8).read_volatile()}
+    }
+    pub fn HasConfig(&self) -> bool {
+        self.device.is_some()
+    }
+    // These all read as zero if the device didn't give us a configuration structure
+    pub fn ConfigRead8(&self, off: u64) -> u8 {
+        self.device.map_or(0,|d| unsafe {((d+off) as *const u8).read_volatile()})
+    }
+    pub fn ConfigRead16(&self, off: u64) -> u16 {
+        self.device.map_or(0,|d| unsafe {((d+off) as *const u16).read_volatile()})
+    }
+    pub fn ConfigRead32(&self, off: u64) -> u32 {
+        self.device.map_or(0,|d| unsafe {((d+off) as *const u32).read_volatile()})
+    }
+    pub fn ConfigRead64(&self, off: u64) -> u64 {
+        // 64-bit fields aren't guaranteed to be read atomically by the device, so we have to read them twice
+        loop {
+            let gen = self.Read8(COMMON_CFGGENERATION);
+            let val = (self.ConfigRead32(off) as u64) | ((self.ConfigRead32(off+4) as u64) << 32);
+            if gen == self.Read8(COMMON_CFGGENERATION) {
+                return val;
+            }
+        }
+    }
+}
@@ -0,0 +1,217 @@
+use spin::Mutex;
+use alloc::vec::Vec;
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicUsize,Ordering};
+use crate::arch::PHYSMEM_BEGIN;
+use crate::arch::Timer;
+use crate::Drivers::Arch::PCI;
+use crate::Drivers::Arch::VirtIO;
+use crate::Drivers::Generic::BlockDevice;
+use crate::Syscall::Errors;
+
+const VIRTIO_BLK_F_RO: u64 = 1 << 5;
+const VIRTIO_BLK_F_BLK_SIZE: u64 = 1 << 6;
+const VIRTIO_BLK_F_FLUSH: u64 = 1 << 9;
+
+const CONFIG_CAPACITY: u64 = 0x00;
+const CONFIG_BLK_SIZE: u64 = 0x14;
+
+const VIRTIO_BLK_T_IN: u32 = 0;
+const VIRTIO_BLK_T_OUT: u32 = 1;
+const VIRTIO_BLK_T_FLUSH: u32 = 4;
+
+const VIRTIO_BLK_S_OK: u8 = 0;
+
+const MAX_TRANSFER: usize = 0x20000;
+const REQUEST_TIMEOUT: u64 = 5000000; // 5 seconds
+
+struct VirtIOBlockQueue {
+    queue: VirtIO::VirtQueue,
+    request: u64, // Request header at 0x000, status byte at 0x100
+    bounce: u64,
+}
+
+impl VirtIOBlockQueue {
+    fn Request(&mut self, kind: u32, sector: u64, length: usize) -> Result<(),i64> {
+        unsafe {
+            (self.request as *mut u32).write_volatile(kind);
+            ((self.request+4) as *mut u32).write_volatile(0);
+            ((self.request+8) as *mut u64).write_volatile(sector);
+            ((self.request+0x100) as *mut u8).write_volatile(0xFF);
+        }
+        let header = self.request-PHYSMEM_BEGIN;
+        let data = self.bounce-PHYSMEM_BEGIN;
+        let status = header+0x100;
+        let head = if length > 0 {
+            self.queue.Submit(&[(header,16,false),(data,length as u32,kind == VIRTIO_BLK_T_IN),(status,1,true)])
+        } else {
+            self.queue.Submit(&[(header,16,false),(status,1,true)])
+        };
+        let head = match head {
+            Some(h) => h,
+            None => {return Err(Errors::EBUSY as i64);}
+        };
+        self.queue.Notify();
+        let start = Timer::GetMicroseconds();
+        loop {
+            // The interrupt handler can't run while we hold the queue, spin on the used ring instead.
+            if let Some((id, _)) = self.queue.PollUsed() {
+                if id != head {
+                    continue;
+                }
+                let result = unsafe {((self.request+0x100) as *const u8).read_volatile()};
+                if result != VIRTIO_BLK_S_OK {
+                    log::error!("VirtIO Block: Request type {} at sector {} failed with status {}", kind, sector, result);
+                    return Err(Errors::EIO as i64);
+                }
+                return Ok(());
+            }
+            if Timer::GetMicroseconds() - start > REQUEST_TIMEOUT {
+                log::error!("VirtIO Block: Request type {} at sector {} timed out", kind, sector);
+                return Err(Errors::EIO as i64);
+            }
+            core::hint::spin_loop();
+        }
+    }
+}
+
+pub struct VirtIOBlock {
+    dev: VirtIO::VirtIODevice,
+    queue: Mutex<VirtIOBlockQueue>,
+    features: u64,
+    block_size: usize,
+    sectors: u64, // Always in 512 byte units, regardless of block_size
+}
+
+impl VirtIOBlock {
+    fn Transfer(&self, lba: u64, buffer: *mut u8, len: usize, write: bool) -> Result<(),i64> {
+        if len % self.block_size != 0 {
+            return Err(Errors::EINVAL as i64);
+        }
+        if lba + (len / self.block_size) as u64 > self.BlockCountInternal() {
+            return Err(Errors::EINVAL as i64);
+        }
+        if write && self.features & VIRTIO_BLK_F_RO != 0 {
+            return Err(Errors::EROFS as i64);
+        }
+        let mut queue = self.queue.lock();
+        let mut done = 0;
+        while done < len {
+            let size = if len - done > MAX_TRANSFER {MAX_TRANSFER} else {len - done};
+            let sector = (lba * (self.block_size as u64 / 512)) + (done / 512) as u64;
+            unsafe {
+                if write {
+                    core::ptr::copy(buffer.add(done),queue.bounce as *mut u8,size);
+                    queue.Request(VIRTIO_BLK_T_OUT,sector,size)?;
+                } else {
+                    queue.Request(VIRTIO_BLK_T_IN,sector,size)?;
+                    core::ptr::copy(queue.bounce as *const u8,buffer.add(done),size);
+                }
+            }
+            done += size;
+        }
+        Ok(())
+    }
+    fn BlockCountInternal(&self) -> u64 {
+        self.sectors / (self.block_size as u64 / 512)
+    }
+}
+
+impl BlockDevice::BlockDevice for VirtIOBlock {
+    fn BlockSize(&self) -> usize {
+        self.block_size
+    }
+    fn BlockCount(&self) -> u64 {
+        self.BlockCountInternal()
+    }
+    fn ReadBlocks(&self, lba: u64, buffer: &mut [u8]) -> Result<(),i64> {
+        self.Transfer(lba,buffer.as_mut_ptr(),buffer.len(),false)
+    }
+    fn WriteBlocks(&self, lba: u64, buffer: &[u8]) -> Result<(),i64> {
+        self.Transfer(lba,buffer.as_ptr() as *mut u8,buffer.len(),true)
+    }
+    fn Flush(&self) -> Result<(),i64> {
+        if self.features & VIRTIO_BLK_F_FLUSH == 0 {
+            return Ok(());
+        }
+        self.queue.lock().Request(VIRTIO_BLK_T_FLUSH,0,0)
+    }
+}
+
+static DISKS: Mutex<Vec<Arc<VirtIOBlock>>> = Mutex::new(Vec::new());
+static NEXT_DISK: AtomicUsize = AtomicUsize::new(0);
+
+fn Handle() {
+    // Only requests that timed out can end up here
+    let lock = DISKS.lock();
+    for d in lock.iter() {
+        d.dev.ReadISR();
+        if let Some(mut q) = d.queue.try_lock() {
+            while q.queue.PollUsed().is_some() {}
+        }
+    }
+}
+
+fn Setup(i: &PCI::PCIDevice) -> Option<Arc<VirtIOBlock>> {
+    let dev = VirtIO::VirtIODevice::new(i)?;
+    if !dev.HasConfig() {
+        log::warn!("VirtIO Block: Device at {:02x}:{:02x}.{} has no configuration structure, so there's no way to know its capacity", i.bus, i.slot, i.func);
+        return None;
+    }
+    let features = dev.Negotiate(VIRTIO_BLK_F_RO | VIRTIO_BLK_F_BLK_SIZE | VIRTIO_BLK_F_FLUSH)?;
+    let queue = match dev.SetupQueue(0,0) {
+        Some(q) => q,
+        None => {
+            log::error!("VirtIO Block: Couldn't set up the request queue");
+            dev.Fail();
+            return None;
+        }
+    };
+    let request = crate::PageFrame::Allocate(0x1000)? as u64;
+    let bounce = crate::PageFrame::Allocate(MAX_TRANSFER as u64)? as u64;
+    let sectors = dev.ConfigRead64(CONFIG_CAPACITY);
+    let mut block_size = 512;
+    if features & VIRTIO_BLK_F_BLK_SIZE != 0 {
+        let size = dev.ConfigRead32(CONFIG_BLK_SIZE) as usize;
+        if size >= 512 && size.is_power_of_two() && size <= MAX_TRANSFER {
+            block_size = size;
+        }
+    }
+    dev.DriverOK();
+    Some(Arc::new(VirtIOBlock {
+        dev,
+        queue: Mutex::new(VirtIOBlockQueue {
+            queue,
+            request,
+            bounce,
+        }),
+        features,
+        block_size,
+        sectors,
+    }))
+}
+
+pub fn Initalize() {
+    let lock = PCI::PCI_DEVICES.lock();
+    for i in lock.iter() {
+        // 0x1001 is the transitional device ID, which still has the modern capabilities on any recent QEMU
+        if i.vendor == VirtIO::VIRTIO_VENDOR && (i.device == 0x1042 || i.device == 0x1001) {
+            log::debug!("Starting up VirtIO Block Device");
+            let disk = match Setup(i) {
+                Some(d) => d,
+                None => {continue;}
+            };
+            DISKS.lock().push(disk.clone());
+            match i.irq {
+                PCI::IRQ::Msi(irq) | PCI::IRQ::Msix(irq) | PCI::IRQ::Irql(irq) if irq != u8::MAX => {
+                    crate::arch::IDT::IRQ_HANDLERS.lock()[(irq-0x20) as usize] = Some(Handle);
+                }
+                _ => {}
+            }
+            if disk.features & VIRTIO_BLK_F_RO != 0 {
+                log::info!("VirtIO Block: Device is read-only");
+            }
+            BlockDevice::Register(BlockDevice::DiskName("vd",NEXT_DISK.fetch_add(1,Ordering::SeqCst)),disk);
+        }
+    }
+}
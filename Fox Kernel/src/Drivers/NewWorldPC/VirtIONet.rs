@@ -0,0 +1,194 @@
+use spin::Mutex;
+use alloc::vec::Vec;
+use alloc::sync::Arc;
+use crate::arch::PHYSMEM_BEGIN;
+use crate::Drivers::Arch::PCI;
+use crate::Drivers::Arch::VirtIO;
+use crate::Drivers::Generic::NetworkDevice;
+
+const VIRTIO_NET_F_MTU: u64 = 1 << 3;
+const VIRTIO_NET_F_MAC: u64 = 1 << 5;
+const VIRTIO_NET_F_STATUS: u64 = 1 << 16;
+
+const CONFIG_MAC: u64 = 0x00;
+const CONFIG_STATUS: u64 = 0x06;
+const CONFIG_MTU: u64 = 0x0A;
+
+const VIRTIO_NET_S_LINK_UP: u16 = 1;
+
+const RECEIVEQ: u16 = 0;
+const TRANSMITQ: u16 = 1;
+
+// struct virtio_net_hdr, including num_buffers since we're always using VIRTIO_F_VERSION_1
+const NET_HDR_SIZE: usize = 12;
+const BUFFER_SIZE: usize = 2048;
+const BUFFERS_PER_QUEUE: usize = 64;
+
+struct VirtIONetQueues {
+    rx: VirtIO::VirtQueue,
+    tx: VirtIO::VirtQueue,
+    rx_inflight: Vec<u64>, // Indexed by descriptor ID
+    tx_inflight: Vec<u64>,
+    tx_free: Vec<u64>,
+}
+
+impl VirtIONetQueues {
+    fn PostRX(&mut self, buffer: u64) {
+        if let Some(id) = self.rx.Submit(&[(buffer-PHYSMEM_BEGIN,BUFFER_SIZE as u32,true)]) {
+            self.rx_inflight[id as usize] = buffer;
+        }
+    }
+}
+
+pub struct VirtIONet {
+    dev: VirtIO::VirtIODevice,
+    queues: Mutex<VirtIONetQueues>,
+    features: u64,
+    mac: [u8; 6],
+    mtu: usize,
+}
+
+impl NetworkDevice::NetworkDevice for VirtIONet {
+    fn MACAddress(&self) -> [u8; 6] {
+        self.mac
+    }
+    fn MTU(&self) -> usize {
+        self.mtu
+    }
+    fn LinkUp(&self) -> bool {
+        if self.features & VIRTIO_NET_F_STATUS == 0 {
+            return true;
+        }
+        self.dev.ConfigRead16(CONFIG_STATUS) & VIRTIO_NET_S_LINK_UP != 0
+    }
+    fn Transmit(&self, frame: &[u8]) -> bool {
+        if frame.len() + NET_HDR_SIZE > BUFFER_SIZE {
+            return true; // Drop it, it'll never fit
+        }
+        let mut q = match self.queues.try_lock() {
+            Some(q) => q,
+            None => {return false;}
+        };
+        let buffer = match q.tx_free.pop() {
+            Some(b) => b,
+            None => {return false;}
+        };
+        unsafe {
+            core::ptr::write_bytes(buffer as *mut u8,0,NET_HDR_SIZE);
+            core::ptr::copy(frame.as_ptr(),(buffer as *mut u8).add(NET_HDR_SIZE),frame.len());
+        }
+        match q.tx.Submit(&[(buffer-PHYSMEM_BEGIN,(frame.len()+NET_HDR_SIZE) as u32,false)]) {
+            Some(id) => {
+                q.tx_inflight[id as usize] = buffer;
+                q.tx.Notify();
+                true
+            }
+            None => {
+                q.tx_free.push(buffer);
+                false
+            }
+        }
+    }
+    fn Poll(&self, iface: &NetworkDevice::NetworkInterface) {
+        self.dev.ReadISR();
+        let mut q = match self.queues.try_lock() {
+            Some(q) => q,
+            None => {return;}
+        };
+        while let Some((id, _)) = q.tx.PollUsed() {
+            let buffer = q.tx_inflight[id as usize];
+            q.tx_free.push(buffer);
+        }
+        let mut posted = false;
+        while let Some((id, len)) = q.rx.PollUsed() {
+            let buffer = q.rx_inflight[id as usize];
+            let len = len as usize;
+            if len > NET_HDR_SIZE {
+                let frame = unsafe {core::slice::from_raw_parts((buffer as *const u8).add(NET_HDR_SIZE),len-NET_HDR_SIZE)};
+                iface.Deliver(frame.to_vec());
+            }
+            q.PostRX(buffer);
+            posted = true;
+        }
+        if posted {
+            q.rx.Notify();
+        }
+    }
+}
+
+fn Handle() {
+    NetworkDevice::PollAll();
+}
+
+fn Setup(i: &PCI::PCIDevice) -> Option<Arc<VirtIONet>> {
+    let dev = VirtIO::VirtIODevice::new(i)?;
+    let features = dev.Negotiate(VIRTIO_NET_F_MTU | VIRTIO_NET_F_MAC | VIRTIO_NET_F_STATUS)?;
+    let rx = dev.SetupQueue(RECEIVEQ,0);
+    let tx = dev.SetupQueue(TRANSMITQ,0);
+    if rx.is_none() || tx.is_none() {
+        log::error!("VirtIO Net: Couldn't set up the receive/transmit queues");
+        dev.Fail();
+        return None;
+    }
+    let rx = rx.unwrap();
+    let tx = tx.unwrap();
+    let mut queues = VirtIONetQueues {
+        rx_inflight: alloc::vec![0; rx.Size() as usize],
+        tx_inflight: alloc::vec![0; tx.Size() as usize],
+        tx_free: Vec::new(),
+        rx,
+        tx,
+    };
+    let rx_pool = crate::PageFrame::Allocate((BUFFER_SIZE*BUFFERS_PER_QUEUE) as u64)? as u64;
+    let tx_pool = crate::PageFrame::Allocate((BUFFER_SIZE*BUFFERS_PER_QUEUE) as u64)? as u64;
+    for b in 0..BUFFERS_PER_QUEUE {
+        queues.PostRX(rx_pool + (b * BUFFER_SIZE) as u64);
+        queues.tx_free.push(tx_pool + (b * BUFFER_SIZE) as u64);
+    }
+    let mut mac = [0u8; 6];
+    // Both of these live in the device configuration, so they're only any good if the device actually has one
+    if features & VIRTIO_NET_F_MAC != 0 && dev.HasConfig() {
+        for b in 0..6 {
+            mac[b] = dev.ConfigRead8(CONFIG_MAC + b as u64);
+        }
+    } else {
+        // Locally administered address derived from the PCI location
+        mac = [0x02,0x00,0x00,i.bus,i.slot,i.func];
+    }
+    let mut mtu = 1500;
+    if features & VIRTIO_NET_F_MTU != 0 && dev.HasConfig() {
+        let m = dev.ConfigRead16(CONFIG_MTU) as usize;
+        if m >= 68 && m + 14 + NET_HDR_SIZE <= BUFFER_SIZE {
+            mtu = m;
+        }
+    }
+    dev.DriverOK();
+    queues.rx.Notify();
+    Some(Arc::new(VirtIONet {
+        dev,
+        queues: Mutex::new(queues),
+        features,
+        mac,
+        mtu,
+    }))
+}
+
+pub fn Initalize() {
+    let lock = PCI::PCI_DEVICES.lock();
+    for i in lock.iter() {
+        if i.vendor == VirtIO::VIRTIO_VENDOR && (i.device == 0x1041 || i.device == 0x1000) {
+            log::debug!("Starting up VirtIO Network Device");
+            let nic = match Setup(i) {
+                Some(n) => n,
+                None => {continue;}
+            };
+            match i.irq {
+                PCI::IRQ::Msi(irq) | PCI::IRQ::Msix(irq) | PCI::IRQ::Irql(irq) if irq != u8::MAX => {
+                    crate::arch::IDT::IRQ_HANDLERS.lock()[(irq-0x20) as usize] = Some(Handle);
+                }
+                _ => {}
+            }
+            NetworkDevice::Register(NetworkDevice::ReserveEthName(),nic);
+        }
+    }
+}
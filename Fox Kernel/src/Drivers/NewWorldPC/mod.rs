@@ -3,14 +3,27 @@ pub mod PCI;
 pub mod PS2HID;
 #[path = "../OldWorldPC/ATA.rs"]
 pub mod ATA;
+#[path = "../OldWorldPC/Serial.rs"]
+pub mod Serial;
 pub mod AHCI;
+pub mod NVMe;
+pub mod VirtIO;
+pub mod VirtIOBlock;
+pub mod VirtIONet;
+pub mod E1000;
 pub mod xHCI;
 pub mod LimineTTY;
 
 pub fn Initalize() {
     PCI::Initalize();
     PS2HID::Initalize();
+    Serial::Initalize();
     ATA::Initalize();
+    AHCI::Initalize();
+    NVMe::Initalize();
+    VirtIOBlock::Initalize();
+    VirtIONet::Initalize();
+    E1000::Initalize();
     if !crate::CommandLine::FLAGS.get().unwrap().contains("--no_xhci") {xHCI::Initalize();}
     LimineTTY::Initalize();
 }
\ No newline at end of file
@@ -2,6 +2,15 @@ use core::num::NonZeroUsize;
 use xhci::accessor::Mapper;
 use core::sync::atomic::{AtomicUsize,Ordering};
 use spin::Mutex;
+use alloc::vec::Vec;
+use alloc::sync::Arc;
+use alloc::collections::BTreeMap;
+use crate::arch::PHYSMEM_BEGIN;
+use crate::arch::Timer;
+use crate::Scheduler::Scheduler;
+use crate::Drivers::Arch::PCI;
+use crate::Syscall::Errors;
+use crate::Drivers::Generic::USB::{self, SetupPacket};
 
 #[derive(Clone)]
 struct MemoryMapper;
@@ -14,24 +23,334 @@ impl Mapper for MemoryMapper {
     }
 }
 
+// Capability registers
+const CAP_CAPLENGTH: u64 = 0x00;
+const CAP_HCSPARAMS1: u64 = 0x04;
+const CAP_HCSPARAMS2: u64 = 0x08;
+const CAP_HCCPARAMS1: u64 = 0x10;
+const CAP_DBOFF: u64 = 0x14;
+const CAP_RTSOFF: u64 = 0x18;
+
+// Operational registers
+const OP_USBCMD: u64 = 0x00;
+const OP_USBSTS: u64 = 0x04;
+const OP_PAGESIZE: u64 = 0x08;
+const OP_CRCR: u64 = 0x18;
+const OP_DCBAAP: u64 = 0x30;
+const OP_CONFIG: u64 = 0x38;
+const OP_PORTSC: u64 = 0x400;
+
+// Interrupter 0, relative to the runtime registers
+const IR_IMAN: u64 = 0x20;
+const IR_IMOD: u64 = 0x24;
+const IR_ERSTSZ: u64 = 0x28;
+const IR_ERSTBA: u64 = 0x30;
+const IR_ERDP: u64 = 0x38;
+
+const USBCMD_RS: u32 = 1 << 0;
+const USBCMD_HCRST: u32 = 1 << 1;
+const USBCMD_INTE: u32 = 1 << 2;
+const USBSTS_HCH: u32 = 1 << 0;
+const USBSTS_EINT: u32 = 1 << 3;
+const USBSTS_CNR: u32 = 1 << 11;
+const IMAN_IP: u32 = 1 << 0;
+const IMAN_IE: u32 = 1 << 1;
+const ERDP_EHB: u64 = 1 << 3;
+
+const PORTSC_CCS: u32 = 1 << 0;
+const PORTSC_PED: u32 = 1 << 1;
+const PORTSC_PR: u32 = 1 << 4;
+const PORTSC_PP: u32 = 1 << 9;
+const PORTSC_CSC: u32 = 1 << 17;
+const PORTSC_PRC: u32 = 1 << 21;
+// PED and the change bits are cleared by writing 1, so they have to be masked off whenever PORTSC is rewritten
+const PORTSC_RW1C: u32 = PORTSC_PED | (0x7F << 17);
+
+const TRB_NORMAL: u32 = 1;
+const TRB_SETUP: u32 = 2;
+const TRB_DATA: u32 = 3;
+const TRB_STATUS: u32 = 4;
+const TRB_LINK: u32 = 6;
+const TRB_ENABLE_SLOT: u32 = 9;
+const TRB_DISABLE_SLOT: u32 = 10;
+const TRB_ADDRESS_DEVICE: u32 = 11;
+const TRB_CONFIGURE_ENDPOINT: u32 = 12;
+const TRB_EVALUATE_CONTEXT: u32 = 13;
+const TRB_RESET_ENDPOINT: u32 = 14;
+const TRB_SET_TR_DEQUEUE: u32 = 16;
+const TRB_TRANSFER_EVENT: u32 = 32;
+const TRB_COMMAND_COMPLETION: u32 = 33;
+const TRB_PORT_STATUS_CHANGE: u32 = 34;
+
+const TRB_CYCLE: u32 = 1 << 0;
+const TRB_TOGGLE_CYCLE: u32 = 1 << 1;
+const TRB_ISP: u32 = 1 << 2;
+const TRB_CHAIN: u32 = 1 << 4;
+const TRB_IOC: u32 = 1 << 5;
+const TRB_IDT: u32 = 1 << 6;
+const TRB_DIR_IN: u32 = 1 << 16;
+
+const CC_SUCCESS: u32 = 1;
+const CC_BABBLE: u32 = 3;
+const CC_STALL: u32 = 6;
+const CC_SHORT_PACKET: u32 = 13;
+
+// Endpoint Type field of the endpoint context. OUT endpoints use the USB transfer type as is, IN ones add 4.
+const EP_BULK_OUT: u8 = 2;
+const EP_INTERRUPT_OUT: u8 = 3;
+const EP_CONTROL: u8 = 4;
+const EP_BULK_IN: u8 = 6;
+const EP_INTERRUPT_IN: u8 = 7;
+
+const RING_TRBS: usize = 256; // One page worth, the last one is the link back to the start
+const MAX_TRANSFER: usize = 0x10000;
+const COMMAND_TIMEOUT: u64 = 5000000; // 5 seconds
+const TRANSFER_TIMEOUT: u64 = 5000000;
+
+// Transfer completions are filed by (slot, DCI). Slot IDs start at 1, so (0,0) is free to use for the command ring.
+const COMMAND_KEY: (u8,u8) = (0,0);
+
+struct Ring {
+    trbs: u64,
+    index: usize,
+    cycle: bool,
+}
+
+impl Ring {
+    fn new() -> Option<Self> {
+        let trbs = crate::PageFrame::Allocate(0x1000)? as u64;
+        let phys = trbs-PHYSMEM_BEGIN;
+        let link = (trbs + ((RING_TRBS as u64 - 1) * 16)) as *mut u32;
+        unsafe {
+            link.write_volatile(phys as u32);
+            link.offset(1).write_volatile((phys >> 32) as u32);
+            link.offset(3).write_volatile((TRB_LINK << 10) | TRB_TOGGLE_CYCLE);
+        }
+        Some(Self {trbs, index: 0, cycle: true})
+    }
+    fn Phys(&self) -> u64 {
+        self.trbs-PHYSMEM_BEGIN
+    }
+    // Where the next TRB will go, along with the cycle state the controller should expect there
+    fn Dequeue(&self) -> u64 {
+        (self.Phys() + (self.index as u64 * 16)) | (self.cycle as u64)
+    }
+    // Queues a TRB and returns its physical address. The cycle bit is written last so the controller never sees a half-written TRB.
+    fn Push(&mut self, trb: [u32; 4]) -> u64 {
+        let phys = self.Phys() + (self.index as u64 * 16);
+        let entry = (self.trbs + (self.index as u64 * 16)) as *mut u32;
+        unsafe {
+            entry.write_volatile(trb[0]);
+            entry.offset(1).write_volatile(trb[1]);
+            entry.offset(2).write_volatile(trb[2]);
+            entry.offset(3).write_volatile((trb[3] & !TRB_CYCLE) | (self.cycle as u32));
+        }
+        self.index += 1;
+        if self.index == RING_TRBS - 1 {
+            // Hand the link TRB over too, keeping the chain going if we're in the middle of a TD
+            let link = (self.trbs + (self.index as u64 * 16)) as *mut u32;
+            unsafe {link.offset(3).write_volatile((TRB_LINK << 10) | TRB_TOGGLE_CYCLE | (trb[3] & TRB_CHAIN) | (self.cycle as u32));}
+            self.index = 0;
+            self.cycle = !self.cycle;
+        }
+        phys
+    }
+}
+
+impl Drop for Ring {
+    fn drop(&mut self) {
+        crate::PageFrame::Free(self.trbs as *mut u8,0x1000);
+    }
+}
+
+struct EventRing {
+    trbs: u64,
+    erst: u64,
+    runtime: u64,
+    index: usize,
+    cycle: bool,
+    completions: BTreeMap<(u8,u8),Vec<[u32; 4]>>,
+    port_changes: Vec<u8>,
+}
+
+impl EventRing {
+    fn new(runtime: u64) -> Option<Self> {
+        let trbs = crate::PageFrame::Allocate(0x1000)? as u64;
+        let erst = crate::PageFrame::Allocate(0x1000)? as u64;
+        unsafe {
+            (erst as *mut u64).write_volatile(trbs-PHYSMEM_BEGIN);
+            ((erst + 8) as *mut u32).write_volatile(RING_TRBS as u32);
+        }
+        Some(Self {trbs, erst, runtime, index: 0, cycle: true, completions: BTreeMap::new(), port_changes: Vec::new()})
+    }
+    fn Next(&mut self) -> Option<[u32; 4]> {
+        let entry = (self.trbs + (self.index as u64 * 16)) as *const u32;
+        let control = unsafe {entry.offset(3).read_volatile()};
+        if ((control & TRB_CYCLE) != 0) != self.cycle {
+            return None;
+        }
+        let mut trb = [0u32; 4];
+        for i in 0..4 {
+            trb[i] = unsafe {entry.offset(i as isize).read_volatile()};
+        }
+        self.index += 1;
+        if self.index == RING_TRBS {
+            self.index = 0;
+            self.cycle = !self.cycle;
+        }
+        Some(trb)
+    }
+    // Sorts everything the controller has posted into completions and port changes, then tells it how far we got
+    fn Drain(&mut self) {
+        let mut any = false;
+        while let Some(trb) = self.Next() {
+            any = true;
+            match (trb[3] >> 10) & 0x3F {
+                TRB_COMMAND_COMPLETION => {
+                    self.completions.entry(COMMAND_KEY).or_insert_with(Vec::new).push(trb);
+                }
+                TRB_TRANSFER_EVENT => {
+                    let key = ((trb[3] >> 24) as u8, ((trb[3] >> 16) & 0x1F) as u8);
+                    self.completions.entry(key).or_insert_with(Vec::new).push(trb);
+                }
+                TRB_PORT_STATUS_CHANGE => {
+                    let port = (trb[0] >> 24) as u8;
+                    if !self.port_changes.contains(&port) {
+                        self.port_changes.push(port);
+                    }
+                }
+                _ => {}
+            }
+        }
+        if any {
+            let dequeue = (self.trbs-PHYSMEM_BEGIN) + (self.index as u64 * 16);
+            unsafe {((self.runtime + IR_ERDP) as *mut u64).write_volatile(dequeue | ERDP_EHB);}
+        }
+    }
+    // Pulls out the events for one TD, which ends either at the TRB we asked to be interrupted on or at the first one that didn't succeed.
+    fn Take(&mut self, key: (u8,u8), last: u64) -> Option<Vec<[u32; 4]>> {
+        let list = self.completions.get_mut(&key)?;
+        let pos = list.iter().position(|t| {
+            let ptr = (t[0] as u64) | ((t[1] as u64) << 32);
+            ptr == last || (t[2] >> 24) != CC_SUCCESS
+        })?;
+        let events: Vec<[u32; 4]> = list.drain(..=pos).collect();
+        Some(events)
+    }
+}
+
+struct Endpoint {
+    ring: Ring,
+    bounce: u64,
+    max_packet: u16,
+    pending: Option<(u64,usize)>, // Last TRB and length of an interrupt transfer nobody's waiting on
+}
+
+impl Endpoint {
+    fn new(max_packet: u16) -> Option<Self> {
+        Some(Self {
+            ring: Ring::new()?,
+            max_packet,
+            pending: None,
+            bounce: crate::PageFrame::Allocate(MAX_TRANSFER as u64)? as u64,
+        })
+    }
+}
+
+impl Drop for Endpoint {
+    fn drop(&mut self) {
+        crate::PageFrame::Free(self.bounce as *mut u8,MAX_TRANSFER as u64);
+    }
+}
+
+pub struct Slot {
+    pub id: u8,
+    pub port: u8,
+    pub speed: u8,
+    pub route: u32,
+    output: u64,
+    input: Mutex<u64>,
+    endpoints: Mutex<BTreeMap<u8,Arc<Mutex<Endpoint>>>>,
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        crate::PageFrame::Free(self.output as *mut u8,0x1000);
+        crate::PageFrame::Free(*self.input.get_mut() as *mut u8,0x1000);
+    }
+}
 
 pub struct xHCI_Device {
     regs: xhci::Registers<MemoryMapper>,
     caps: Option<xhci::extended_capabilities::List<MemoryMapper>>,
     _irql: u16,
+    base: u64,
+    op: u64,
+    runtime: u64,
+    doorbells: u64,
+    max_slots: u8,
+    max_ports: u8,
+    context_size: u64,
+    dcbaa: u64,
+    bus: usize,
+    commands: Mutex<Option<Ring>>,
+    events: Mutex<Option<EventRing>>,
+    slots: Mutex<BTreeMap<u8,Arc<Slot>>>,
+    ports: Mutex<BTreeMap<u8,Arc<USB::USBDevice>>>,
+}
+
+// DCI (Device Context Index) of an endpoint address, EP0 is always 1
+pub fn EndpointIndex(address: u8) -> u8 {
+    let num = address & 0xF;
+    if num == 0 {1} else {(num * 2) + ((address >> 7) & 1)}
 }
 
 impl xHCI_Device {
     pub fn new(base: usize, irql: u16) -> Self {
         let r: xhci::Registers<MemoryMapper> = unsafe {xhci::Registers::new(base,MemoryMapper)};
         let hc1 = r.capability.hccparams1.read_volatile();
+        let virt = base as u64+PHYSMEM_BEGIN;
+        let caplength = unsafe {((virt+CAP_CAPLENGTH) as *const u8).read_volatile()} as u64;
+        let hcs1 = unsafe {((virt+CAP_HCSPARAMS1) as *const u32).read_volatile()};
+        let hcc1 = unsafe {((virt+CAP_HCCPARAMS1) as *const u32).read_volatile()};
         Self {
             regs: r,
             caps: unsafe {xhci::extended_capabilities::List::new(base, hc1, MemoryMapper)},
             _irql: irql,
+            base: virt,
+            op: virt+caplength,
+            runtime: virt + (unsafe {((virt+CAP_RTSOFF) as *const u32).read_volatile()} & !0x1F) as u64,
+            doorbells: virt + (unsafe {((virt+CAP_DBOFF) as *const u32).read_volatile()} & !0x3) as u64,
+            max_slots: (hcs1 & 0xFF) as u8,
+            max_ports: (hcs1 >> 24) as u8,
+            context_size: if hcc1 & (1 << 2) != 0 {64} else {32},
+            dcbaa: 0,
+            bus: 0,
+            commands: Mutex::new(None),
+            events: Mutex::new(None),
+            slots: Mutex::new(BTreeMap::new()),
+            ports: Mutex::new(BTreeMap::new()),
         }
     }
-    pub fn Init(&mut self) {
+    fn ReadOp(&self, reg: u64) -> u32 {
+        unsafe {((self.op+reg) as *const u32).read_volatile()}
+    }
+    fn WriteOp(&self, reg: u64, val: u32) {
+        unsafe {((self.op+reg) as *mut u32).write_volatile(val);}
+    }
+    fn WriteOp64(&self, reg: u64, val: u64) {
+        unsafe {((self.op+reg) as *mut u64).write_volatile(val);}
+    }
+    fn ReadPort(&self, port: u8) -> u32 {
+        self.ReadOp(OP_PORTSC + ((port as u64 - 1) * 0x10))
+    }
+    fn WritePort(&self, port: u8, val: u32) {
+        self.WriteOp(OP_PORTSC + ((port as u64 - 1) * 0x10), val);
+    }
+    fn Doorbell(&self, slot: u8, target: u8) {
+        unsafe {((self.doorbells + (slot as u64 * 4)) as *mut u32).write_volatile(target as u32);}
+    }
+    pub fn Init(&mut self) -> bool {
         // Release Control from Firmware (if it didn't already do that for us)
         if let Some(caplist) = self.caps.as_mut() {
             for i in caplist {
@@ -48,7 +367,7 @@ impl xHCI_Device {
                                 }
                                 if c.usblegsup.read_volatile().hc_bios_owned_semaphore() {
                                     log::error!("xHCI Firmware handoff failed (Firmware bug?)");
-                                    return;
+                                    return false;
                                 }
                             } else {
                                 log::debug!("Firmware already handed off xHCI to OS");
@@ -65,35 +384,651 @@ impl xHCI_Device {
             u.clear_run_stop();
         });
         while !self.regs.operational.usbsts.read_volatile().hc_halted() {core::hint::spin_loop();}
+
+        // Reset it, so that whatever the firmware left behind is gone
+        self.WriteOp(OP_USBCMD,self.ReadOp(OP_USBCMD) | USBCMD_HCRST);
+        if !WaitFor(|| self.ReadOp(OP_USBCMD) & USBCMD_HCRST == 0 && self.ReadOp(OP_USBSTS) & USBSTS_CNR == 0, 1000000) {
+            log::error!("xHCI: Controller didn't come out of reset");
+            return false;
+        }
+        if self.ReadOp(OP_PAGESIZE) & 1 == 0 {
+            log::error!("xHCI: Controller doesn't support 4 KiB pages");
+            return false;
+        }
+        self.WriteOp(OP_CONFIG,(self.ReadOp(OP_CONFIG) & !0xFF) | self.max_slots as u32);
+
+        // Device Context Base Address Array, entry 0 points at the scratchpad buffers if the controller wants any
+        let dcbaa = match crate::PageFrame::Allocate(0x1000) {
+            Some(d) => d as u64,
+            None => {return false;}
+        };
+        self.dcbaa = dcbaa;
+        let hcs2 = unsafe {((self.base+CAP_HCSPARAMS2) as *const u32).read_volatile()};
+        let scratchpads = (((hcs2 >> 21) & 0x1F) << 5) | ((hcs2 >> 27) & 0x1F);
+        if scratchpads > 0 {
+            let array = match crate::PageFrame::Allocate(0x1000) {
+                Some(a) => a as u64,
+                None => {return false;}
+            };
+            for i in 0..scratchpads as u64 {
+                let page = match crate::PageFrame::Allocate(0x1000) {
+                    Some(p) => p as u64,
+                    None => {return false;}
+                };
+                unsafe {((array + (i * 8)) as *mut u64).write_volatile(page-PHYSMEM_BEGIN);}
+            }
+            unsafe {(dcbaa as *mut u64).write_volatile(array-PHYSMEM_BEGIN);}
+        }
+        self.WriteOp64(OP_DCBAAP,dcbaa-PHYSMEM_BEGIN);
+
+        let commands = match Ring::new() {
+            Some(r) => r,
+            None => {return false;}
+        };
+        self.WriteOp64(OP_CRCR,commands.Phys() | 1);
+        *self.commands.get_mut() = Some(commands);
+
+        // Event ring on interrupter 0, which is the one tied to the first MSI/MSI-X vector
+        let events = match EventRing::new(self.runtime) {
+            Some(e) => e,
+            None => {return false;}
+        };
+        unsafe {
+            ((self.runtime+IR_ERSTSZ) as *mut u32).write_volatile(1);
+            ((self.runtime+IR_ERDP) as *mut u64).write_volatile(events.trbs-PHYSMEM_BEGIN);
+            ((self.runtime+IR_ERSTBA) as *mut u64).write_volatile(events.erst-PHYSMEM_BEGIN);
+            ((self.runtime+IR_IMOD) as *mut u32).write_volatile(4000); // 1 ms between interrupts at most
+            ((self.runtime+IR_IMAN) as *mut u32).write_volatile(IMAN_IE | IMAN_IP);
+        }
+        *self.events.get_mut() = Some(events);
+
+        self.WriteOp(OP_USBSTS,USBSTS_EINT);
+        self.WriteOp(OP_USBCMD,self.ReadOp(OP_USBCMD) | USBCMD_INTE | USBCMD_RS);
+        if !WaitFor(|| self.ReadOp(OP_USBSTS) & USBSTS_HCH == 0, 100000) {
+            log::error!("xHCI: Controller didn't start running");
+            return false;
+        }
+        for port in 1..=self.max_ports {
+            let portsc = self.ReadPort(port);
+            if portsc & PORTSC_PP == 0 {
+                self.WritePort(port,(portsc & !PORTSC_RW1C) | PORTSC_PP);
+            }
+        }
+        log::debug!("xHCI: Controller running with {} slots, {} ports and {} scratchpad buffers", self.max_slots, self.max_ports, scratchpads);
+        true
+    }
+
+    // Waits for the events that finish a TD. The interrupt handler normally files them for us, but if interrupts are off
+    // (during startup, inside a system call or on the USB worker) nobody else is going to, so we drain the event ring
+    // ourselves. This spins with the hart to itself, but only for as long as the controller takes to answer.
+    fn WaitForEvents(&self, key: (u8,u8), last: u64, timeout: u64) -> Option<Vec<[u32; 4]>> {
+        let start = Timer::GetMicroseconds();
+        loop {
+            let result = self.CheckEvents(key,last);
+            if result.is_some() {
+                return result;
+            }
+            if Timer::GetMicroseconds() - start > timeout {
+                return None;
+            }
+            core::hint::spin_loop();
+        }
+    }
+    fn CheckEvents(&self, key: (u8,u8), last: u64) -> Option<Vec<[u32; 4]>> {
+        let polling = !x86_64::instructions::interrupts::are_enabled();
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            let mut lock = self.events.lock();
+            let events = lock.as_mut().unwrap();
+            if polling {
+                events.Drain();
+            }
+            events.Take(key,last)
+        })
+    }
+    // Forgets any events left over from a TD we gave up on earlier
+    fn Discard(&self, key: (u8,u8)) {
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            if let Some(events) = self.events.lock().as_mut() {
+                events.completions.remove(&key);
+            }
+        });
+    }
+
+    fn Command(&self, trb: [u32; 4]) -> Result<[u32; 4],i64> {
+        let mut lock = self.commands.lock();
+        let ring = lock.as_mut().ok_or(Errors::ENODEV as i64)?;
+        self.Discard(COMMAND_KEY);
+        let ptr = ring.Push(trb);
+        self.Doorbell(0,0);
+        let events = match self.WaitForEvents(COMMAND_KEY,ptr,COMMAND_TIMEOUT) {
+            Some(e) => e,
+            None => {
+                log::error!("xHCI: Command {} timed out", (trb[3] >> 10) & 0x3F);
+                return Err(Errors::ETIMEDOUT as i64);
+            }
+        };
+        let event = events[events.len()-1];
+        let code = event[2] >> 24;
+        if code != CC_SUCCESS {
+            log::error!("xHCI: Command {} failed (Completion Code {})", (trb[3] >> 10) & 0x3F, code);
+            return Err(Errors::EIO as i64);
+        }
+        Ok(event)
+    }
+
+    fn InputContext(&self, input: u64, index: u64) -> *mut u32 {
+        (input + (index * self.context_size)) as *mut u32
+    }
+
+    // Sets up a slot for a device that just showed up, either on a root port or (with a non-zero route string) behind a hub.
+    // The parent is the slot and port of the high-speed hub in front of a low/full-speed device, which it needs for split transactions.
+    fn EnableSlot(&self, port: u8, speed: u8, route: u32, parent: Option<(u8,u8)>) -> Result<Arc<Slot>,i64> {
+        let mps = match speed {
+            USB::SPEED_LOW | USB::SPEED_FULL => 8,
+            USB::SPEED_HIGH => 64,
+            _ => 512,
+        };
+        let ep0 = Endpoint::new(mps).ok_or(Errors::ENOMEM as i64)?;
+        let output = crate::PageFrame::Allocate(0x1000).ok_or(Errors::ENOMEM as i64)? as u64;
+        let input = match crate::PageFrame::Allocate(0x1000) {
+            Some(i) => i as u64,
+            None => {
+                crate::PageFrame::Free(output as *mut u8,0x1000);
+                return Err(Errors::ENOMEM as i64);
+            }
+        };
+        let id = match self.Command([0,0,0,TRB_ENABLE_SLOT << 10]) {
+            Ok(event) => (event[3] >> 24) as u8,
+            Err(e) => {
+                crate::PageFrame::Free(output as *mut u8,0x1000);
+                crate::PageFrame::Free(input as *mut u8,0x1000);
+                return Err(e);
+            }
+        };
+        unsafe {((self.dcbaa + (id as u64 * 8)) as *mut u64).write_volatile(output-PHYSMEM_BEGIN);}
+        unsafe {
+            let control = self.InputContext(input,0);
+            control.offset(1).write_volatile(0b11); // Add the slot and EP0
+            let slot = self.InputContext(input,1);
+            slot.write_volatile((1 << 27) | ((speed as u32) << 20) | (route & 0xFFFFF));
+            slot.offset(1).write_volatile((port as u32) << 16);
+            if let Some((hub, hub_port)) = parent {
+                slot.offset(2).write_volatile(((hub_port as u32) << 8) | hub as u32);
+            }
+            let ep = self.InputContext(input,2);
+            ep.offset(1).write_volatile(((mps as u32) << 16) | ((EP_CONTROL as u32) << 3) | (3 << 1));
+            let dequeue = ep0.ring.Dequeue();
+            ep.offset(2).write_volatile(dequeue as u32);
+            ep.offset(3).write_volatile((dequeue >> 32) as u32);
+            ep.offset(4).write_volatile(8); // Average TRB Length
+        }
+        let slot = Arc::new(Slot {
+            id,
+            port,
+            speed,
+            route,
+            output,
+            input: Mutex::new(input),
+            endpoints: Mutex::new(BTreeMap::new()),
+        });
+        slot.endpoints.lock().insert(1,Arc::new(Mutex::new(ep0)));
+        if let Err(e) = self.Command([(input-PHYSMEM_BEGIN) as u32,((input-PHYSMEM_BEGIN) >> 32) as u32,0,(TRB_ADDRESS_DEVICE << 10) | ((id as u32) << 24)]) {
+            let _ = self.Command([0,0,0,(TRB_DISABLE_SLOT << 10) | ((id as u32) << 24)]);
+            unsafe {((self.dcbaa + (id as u64 * 8)) as *mut u64).write_volatile(0);}
+            return Err(e);
+        }
+        self.slots.lock().insert(id,slot.clone());
+        // Full speed devices can have an EP0 packet size anywhere from 8 to 64, the first 8 bytes of the device descriptor tell us which
+        if speed == USB::SPEED_FULL {
+            let mut header = [0u8; 8];
+            if let Err(e) = self.Control(&slot,SetupPacket {request_type: USB::RT_IN, request: USB::REQ_GET_DESCRIPTOR, value: (USB::DESC_DEVICE as u16) << 8, index: 0},&mut header) {
+                self.DisableSlot(&slot);
+                return Err(e);
+            }
+            if header[7] != 0 && header[7] != 8 {
+                let input = *slot.input.lock();
+                unsafe {
+                    let control = self.InputContext(input,0);
+                    control.write_volatile(0);
+                    control.offset(1).write_volatile(1 << 1);
+                    let ep = self.InputContext(input,2);
+                    ep.offset(1).write_volatile((ep.offset(1).read_volatile() & 0xFFFF) | ((header[7] as u32) << 16));
+                }
+                if let Err(e) = self.Command([(input-PHYSMEM_BEGIN) as u32,((input-PHYSMEM_BEGIN) >> 32) as u32,0,(TRB_EVALUATE_CONTEXT << 10) | ((id as u32) << 24)]) {
+                    self.DisableSlot(&slot);
+                    return Err(e);
+                }
+                if let Some(ep) = slot.endpoints.lock().get(&1) {
+                    ep.lock().max_packet = header[7] as u16;
+                }
+            }
+        }
+        Ok(slot)
+    }
+
+    fn DisableSlot(&self, slot: &Arc<Slot>) {
+        if self.slots.lock().remove(&slot.id).is_none() {
+            return;
+        }
+        let _ = self.Command([0,0,0,(TRB_DISABLE_SLOT << 10) | ((slot.id as u32) << 24)]);
+        unsafe {((self.dcbaa + (slot.id as u64 * 8)) as *mut u64).write_volatile(0);}
+        self.Discard((slot.id,0));
+        for dci in 1..32 {
+            self.Discard((slot.id,dci));
+        }
+    }
+
+    fn GetSlot(&self, id: u8) -> Option<Arc<Slot>> {
+        self.slots.lock().get(&id).cloned()
+    }
+
+    // Adds an endpoint from a configuration descriptor to the device's slot. `interval` is the raw bInterval.
+    fn AddEndpoint(&self, slot: &Arc<Slot>, address: u8, kind: u8, max_packet: u16, interval: u8) -> Result<(),i64> {
+        let dci = EndpointIndex(address);
+        let endpoint = Endpoint::new(max_packet & 0x7FF).ok_or(Errors::ENOMEM as i64)?;
+        // xHCI wants the interval as an exponent of 125 us microframes
+        let interval = match (kind, slot.speed) {
+            (EP_INTERRUPT_IN, USB::SPEED_LOW) | (EP_INTERRUPT_IN, USB::SPEED_FULL) | (EP_INTERRUPT_OUT, USB::SPEED_LOW) | (EP_INTERRUPT_OUT, USB::SPEED_FULL) => {
+                let frames = core::cmp::max(interval as u32,1) * 8;
+                (31 - frames.leading_zeros()).clamp(3,10)
+            }
+            (EP_BULK_IN, _) | (EP_BULK_OUT, _) => 0,
+            _ => (core::cmp::max(interval,1) as u32 - 1).min(15),
+        };
+        let input = *slot.input.lock();
+        unsafe {
+            core::ptr::write_bytes(input as *mut u8,0,(self.context_size * 33) as usize);
+            let control = self.InputContext(input,0);
+            control.offset(1).write_volatile((1 << dci) | 1);
+            // Copy the slot context over and bump Context Entries if this is now the highest endpoint
+            let slot_ctx = self.InputContext(input,1);
+            let out = slot.output as *const u32;
+            for i in 0..4 {
+                slot_ctx.offset(i).write_volatile(out.offset(i).read_volatile());
+            }
+            let entries = (slot_ctx.read_volatile() >> 27).max(dci as u32);
+            slot_ctx.write_volatile((slot_ctx.read_volatile() & !(0x1F << 27)) | (entries << 27));
+            slot_ctx.offset(3).write_volatile(0);
+            let ep = self.InputContext(input,dci as u64 + 1);
+            ep.write_volatile(interval << 16);
+            ep.offset(1).write_volatile(((max_packet as u32 & 0x7FF) << 16) | ((kind as u32) << 3) | (3 << 1));
+            let dequeue = endpoint.ring.Dequeue();
+            ep.offset(2).write_volatile(dequeue as u32);
+            ep.offset(3).write_volatile((dequeue >> 32) as u32);
+            let average = if kind == EP_INTERRUPT_IN || kind == EP_INTERRUPT_OUT {max_packet as u32} else {3072};
+            let esit = if kind == EP_INTERRUPT_IN || kind == EP_INTERRUPT_OUT {max_packet as u32} else {0};
+            ep.offset(4).write_volatile((esit << 16) | average);
+        }
+        self.Command([(input-PHYSMEM_BEGIN) as u32,((input-PHYSMEM_BEGIN) >> 32) as u32,0,(TRB_CONFIGURE_ENDPOINT << 10) | ((slot.id as u32) << 24)])?;
+        slot.endpoints.lock().insert(dci,Arc::new(Mutex::new(endpoint)));
+        Ok(())
+    }
+
+    // Gets a halted endpoint going again after a stall, pointing it past whatever was left on its ring
+    fn ResetEndpoint(&self, slot: &Arc<Slot>, dci: u8, endpoint: &Endpoint) {
+        let _ = self.Command([0,0,0,(TRB_RESET_ENDPOINT << 10) | ((dci as u32) << 16) | ((slot.id as u32) << 24)]);
+        let dequeue = endpoint.ring.Dequeue();
+        let _ = self.Command([dequeue as u32,(dequeue >> 32) as u32,0,(TRB_SET_TR_DEQUEUE << 10) | ((dci as u32) << 16) | ((slot.id as u32) << 24)]);
+    }
+
+    fn Finish(&self, slot: &Arc<Slot>, dci: u8, endpoint: &Endpoint, events: Option<Vec<[u32; 4]>>) -> Result<[u32; 4],i64> {
+        let events = match events {
+            Some(e) => e,
+            None => {
+                log::error!("xHCI: Transfer on slot {} endpoint {} timed out", slot.id, dci);
+                self.ResetEndpoint(slot,dci,endpoint);
+                return Err(Errors::ETIMEDOUT as i64);
+            }
+        };
+        let event = events[events.len()-1];
+        match event[2] >> 24 {
+            CC_SUCCESS | CC_SHORT_PACKET => Ok(event),
+            CC_STALL => {
+                self.ResetEndpoint(slot,dci,endpoint);
+                Err(Errors::EPIPE as i64)
+            }
+            code => {
+                log::warn!("xHCI: Transfer on slot {} endpoint {} failed (Completion Code {})", slot.id, dci, code);
+                if code == CC_BABBLE {
+                    self.ResetEndpoint(slot,dci,endpoint);
+                }
+                Err(Errors::EIO as i64)
+            }
+        }
+    }
+
+    // Runs a control transfer on EP0. The direction comes from bit 7 of request_type, and the data stage is however long `data` is.
+    fn Control(&self, slot: &Arc<Slot>, setup: SetupPacket, data: &mut [u8]) -> Result<usize,i64> {
+        if data.len() > 0x1000 {
+            return Err(Errors::EINVAL as i64);
+        }
+        let endpoint = slot.endpoints.lock().get(&1).cloned().ok_or(Errors::ENODEV as i64)?;
+        let mut ep = endpoint.lock();
+        let input = setup.request_type & 0x80 != 0;
+        let length = data.len();
+        if !input {
+            unsafe {core::ptr::copy(data.as_ptr(),ep.bounce as *mut u8,length);}
+        }
+        let transfer_type = if length == 0 {0} else if input {3} else {2};
+        let setup_trb = [
+            (setup.request_type as u32) | ((setup.request as u32) << 8) | ((setup.value as u32) << 16),
+            (setup.index as u32) | ((length as u32) << 16),
+            8,
+            (TRB_SETUP << 10) | TRB_IDT | (transfer_type << 16),
+        ];
+        self.Discard((slot.id,1));
+        ep.ring.Push(setup_trb);
+        let mut data_trb = 0;
+        if length > 0 {
+            let phys = ep.bounce-PHYSMEM_BEGIN;
+            data_trb = ep.ring.Push([phys as u32,(phys >> 32) as u32,length as u32,(TRB_DATA << 10) | TRB_ISP | if input {TRB_DIR_IN} else {0}]);
+        }
+        // The status stage goes the opposite way from the data
+        let status_dir = if input && length > 0 {0} else {TRB_DIR_IN};
+        let last = ep.ring.Push([0,0,0,(TRB_STATUS << 10) | TRB_IOC | status_dir]);
+        self.Doorbell(slot.id,1);
+        let events = self.WaitForEvents((slot.id,1),last,TRANSFER_TIMEOUT);
+        let short = events.as_ref().and_then(|e| e.iter().find(|t| (t[0] as u64 | ((t[1] as u64) << 32)) == data_trb && (t[2] >> 24) == CC_SHORT_PACKET).copied());
+        self.Finish(slot,1,&ep,events)?;
+        let transferred = match short {
+            Some(t) => length - core::cmp::min((t[2] & 0xFFFFFF) as usize,length),
+            None => length,
+        };
+        if input {
+            unsafe {core::ptr::copy(ep.bounce as *const u8,data.as_mut_ptr(),transferred);}
+        }
+        Ok(transferred)
+    }
+
+    // Bulk and interrupt transfers look the same on the ring, the endpoint context is what tells them apart
+    fn NormalTransfer(&self, slot: &Arc<Slot>, address: u8, data: &mut [u8]) -> Result<usize,i64> {
+        let dci = EndpointIndex(address);
+        let endpoint = slot.endpoints.lock().get(&dci).cloned().ok_or(Errors::ENODEV as i64)?;
+        let mut ep = endpoint.lock();
+        if ep.pending.is_some() {
+            return Err(Errors::EBUSY as i64);
+        }
+        let input = address & 0x80 != 0;
+        let mut done = 0;
+        while done < data.len() || (data.len() == 0 && done == 0) {
+            let length = core::cmp::min(data.len() - done,MAX_TRANSFER);
+            if !input {
+                unsafe {core::ptr::copy(data.as_ptr().add(done),ep.bounce as *mut u8,length);}
+            }
+            self.Discard((slot.id,dci));
+            // One TRB per page, so none of them cross a 64 KiB boundary
+            let phys = ep.bounce-PHYSMEM_BEGIN;
+            let mut trbs: Vec<(u64,usize)> = Vec::new();
+            let mut offset = 0;
+            loop {
+                let size = core::cmp::min(length - offset,0x1000);
+                // TD Size is how many packets are still left after this TRB
+                let remaining = (length - offset - size).div_ceil(core::cmp::max(ep.max_packet,1) as usize) as u32;
+                let last = offset + size >= length;
+                let flags = (if last {TRB_IOC} else {TRB_CHAIN}) | (if input {TRB_ISP} else {0});
+                let ptr = ep.ring.Push([(phys + offset as u64) as u32,((phys + offset as u64) >> 32) as u32,(size as u32) | (remaining.min(31) << 17),(TRB_NORMAL << 10) | flags]);
+                trbs.push((ptr,offset + size));
+                offset += size;
+                if last {break;}
+            }
+            self.Doorbell(slot.id,dci);
+            let events = self.WaitForEvents((slot.id,dci),trbs[trbs.len()-1].0,TRANSFER_TIMEOUT);
+            let event = self.Finish(slot,dci,&ep,events)?;
+            // A short packet ends the TD early, so work out how far into it we got
+            let ptr = event[0] as u64 | ((event[1] as u64) << 32);
+            let end = trbs.iter().find(|t| t.0 == ptr).map_or(length,|t| t.1);
+            let transferred = end - core::cmp::min((event[2] & 0xFFFFFF) as usize,end);
+            if input {
+                unsafe {core::ptr::copy(ep.bounce as *const u8,data.as_mut_ptr().add(done),transferred);}
+            }
+            done += transferred;
+            if transferred < length || length == 0 {
+                break;
+            }
+        }
+        Ok(done)
+    }
+
+    fn Submit(&self, slot: &Arc<Slot>, address: u8, length: usize) -> Result<(),i64> {
+        let dci = EndpointIndex(address);
+        let endpoint = slot.endpoints.lock().get(&dci).cloned().ok_or(Errors::ENODEV as i64)?;
+        let mut ep = endpoint.lock();
+        if ep.pending.is_some() {
+            return Err(Errors::EBUSY as i64);
+        }
+        if length > 0x1000 {
+            return Err(Errors::EINVAL as i64);
+        }
+        self.Discard((slot.id,dci));
+        let phys = ep.bounce-PHYSMEM_BEGIN;
+        let flags = TRB_IOC | if address & 0x80 != 0 {TRB_ISP} else {0};
+        let ptr = ep.ring.Push([phys as u32,(phys >> 32) as u32,length as u32,(TRB_NORMAL << 10) | flags]);
+        ep.pending = Some((ptr,length));
+        self.Doorbell(slot.id,dci);
+        Ok(())
+    }
+
+    fn Reap(&self, slot: &Arc<Slot>, address: u8, data: &mut [u8]) -> Option<Result<usize,i64>> {
+        let dci = EndpointIndex(address);
+        let endpoint = match slot.endpoints.lock().get(&dci).cloned() {
+            Some(e) => e,
+            None => {return Some(Err(Errors::ENODEV as i64));}
+        };
+        let mut ep = endpoint.lock();
+        let (ptr, length) = match ep.pending {
+            Some(p) => p,
+            None => {return Some(Err(Errors::EINVAL as i64));}
+        };
+        let events = self.CheckEvents((slot.id,dci),ptr)?;
+        ep.pending = None;
+        let event = match self.Finish(slot,dci,&ep,Some(events)) {
+            Ok(e) => e,
+            Err(e) => {return Some(Err(e));}
+        };
+        let transferred = core::cmp::min(length - core::cmp::min((event[2] & 0xFFFFFF) as usize,length),data.len());
+        unsafe {core::ptr::copy(ep.bounce as *const u8,data.as_mut_ptr(),transferred);}
+        Some(Ok(transferred))
+    }
+
+    fn ResetPort(&self, port: u8) -> bool {
+        let portsc = self.ReadPort(port);
+        // USB 3 ports train the link on their own and come up already enabled, USB 2 ones need a reset first
+        if portsc & PORTSC_PED != 0 {
+            return true;
+        }
+        self.WritePort(port,(portsc & !PORTSC_RW1C) | PORTSC_PR);
+        if !WaitFor(|| self.ReadPort(port) & PORTSC_PRC != 0, 500000) {
+            return false;
+        }
+        let portsc = self.ReadPort(port);
+        self.WritePort(port,(portsc & !PORTSC_RW1C) | PORTSC_PRC);
+        Scheduler::Sleep(10); // Reset recovery
+        self.ReadPort(port) & PORTSC_PED != 0
+    }
+
+    // Brings a root port in line with whatever is (or isn't) plugged into it now
+    fn ServicePort(self: &Arc<Self>, port: u8) {
+        if port == 0 || port > self.max_ports {
+            return;
+        }
+        let portsc = self.ReadPort(port);
+        self.WritePort(port,(portsc & !PORTSC_RW1C) | (portsc & PORTSC_RW1C & !PORTSC_PED));
+        let existing = self.ports.lock().get(&port).cloned();
+        if let Some(dev) = existing {
+            // Anything that was here before is gone, even if something got plugged back in already
+            if portsc & PORTSC_CSC != 0 || portsc & PORTSC_CCS == 0 {
+                self.ports.lock().remove(&port);
+                USB::Detach(&dev);
+            } else {
+                return;
+            }
+        }
+        if portsc & PORTSC_CCS == 0 {
+            return;
+        }
+        Scheduler::Sleep(100); // Give the device time to settle after being plugged in
+        if !self.ResetPort(port) {
+            log::warn!("xHCI: Couldn't reset port {}", port);
+            return;
+        }
+        let speed = ((self.ReadPort(port) >> 10) & 0xF) as u8;
+        match USB::Attach(self.clone(),self.bus,port,speed,0,0,None) {
+            Ok(dev) => {
+                self.ports.lock().insert(port,dev);
+            }
+            Err(e) => {
+                log::warn!("xHCI: Couldn't enumerate the device on port {} ({})", port, e);
+            }
+        }
+    }
+
+    fn PendingPorts(&self) -> Vec<u8> {
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            match self.events.lock().as_mut() {
+                Some(events) => core::mem::take(&mut events.port_changes),
+                None => Vec::new(),
+            }
+        })
     }
 }
 
-pub static XHCI_CONTROLLER: Mutex<Option<xHCI_Device>> = Mutex::new(None);
+impl USB::HostController for xHCI_Device {
+    fn AttachDevice(&self, port: u8, speed: u8, route: u32, tt: Option<(u8,u8)>) -> Result<u8,i64> {
+        self.EnableSlot(port,speed,route,tt).map(|s| s.id)
+    }
+    fn DetachDevice(&self, slot: u8) {
+        if let Some(s) = self.GetSlot(slot) {
+            self.DisableSlot(&s);
+        }
+    }
+    fn ConfigureHub(&self, slot: u8, ports: u8, think_time: u8, multi_tt: bool) -> Result<(),i64> {
+        let slot = self.GetSlot(slot).ok_or(Errors::ENODEV as i64)?;
+        let input = *slot.input.lock();
+        unsafe {
+            core::ptr::write_bytes(input as *mut u8,0,(self.context_size * 33) as usize);
+            self.InputContext(input,0).offset(1).write_volatile(1);
+            let slot_ctx = self.InputContext(input,1);
+            let out = slot.output as *const u32;
+            for i in 0..4 {
+                slot_ctx.offset(i).write_volatile(out.offset(i).read_volatile());
+            }
+            slot_ctx.write_volatile(slot_ctx.read_volatile() | (1 << 26) | if multi_tt {1 << 25} else {0});
+            slot_ctx.offset(1).write_volatile((slot_ctx.offset(1).read_volatile() & 0x00FFFFFF) | ((ports as u32) << 24));
+            slot_ctx.offset(2).write_volatile((slot_ctx.offset(2).read_volatile() & !(0x3 << 16)) | ((think_time as u32 & 0x3) << 16));
+            slot_ctx.offset(3).write_volatile(0);
+        }
+        self.Command([(input-PHYSMEM_BEGIN) as u32,((input-PHYSMEM_BEGIN) >> 32) as u32,0,(TRB_CONFIGURE_ENDPOINT << 10) | ((slot.id as u32) << 24)])?;
+        Ok(())
+    }
+    fn ConfigureEndpoint(&self, slot: u8, endpoint: &USB::EndpointDescriptor) -> Result<(),i64> {
+        let slot = self.GetSlot(slot).ok_or(Errors::ENODEV as i64)?;
+        let kind = match endpoint.TransferType() {
+            USB::XFER_CONTROL => EP_CONTROL,
+            t => t + if endpoint.IsIn() {4} else {0},
+        };
+        self.AddEndpoint(&slot,endpoint.address,kind,endpoint.max_packet,endpoint.interval)
+    }
+    fn ControlTransfer(&self, slot: u8, setup: SetupPacket, data: &mut [u8]) -> Result<usize,i64> {
+        let slot = self.GetSlot(slot).ok_or(Errors::ENODEV as i64)?;
+        self.Control(&slot,setup,data)
+    }
+    fn BulkTransfer(&self, slot: u8, endpoint: u8, data: &mut [u8]) -> Result<usize,i64> {
+        let slot = self.GetSlot(slot).ok_or(Errors::ENODEV as i64)?;
+        self.NormalTransfer(&slot,endpoint,data)
+    }
+    // Only completes once the device has something to say, so `data` should be one report long
+    fn InterruptTransfer(&self, slot: u8, endpoint: u8, data: &mut [u8]) -> Result<usize,i64> {
+        let slot = self.GetSlot(slot).ok_or(Errors::ENODEV as i64)?;
+        self.NormalTransfer(&slot,endpoint,data)
+    }
+    fn SubmitInterrupt(&self, slot: u8, endpoint: u8, length: usize) -> Result<(),i64> {
+        let slot = self.GetSlot(slot).ok_or(Errors::ENODEV as i64)?;
+        self.Submit(&slot,endpoint,length)
+    }
+    fn ReapInterrupt(&self, slot: u8, endpoint: u8, data: &mut [u8]) -> Option<Result<usize,i64>> {
+        match self.GetSlot(slot) {
+            Some(s) => self.Reap(&s,endpoint,data),
+            None => Some(Err(Errors::ENODEV as i64)),
+        }
+    }
+    // Picks up the port changes the interrupt handler noticed
+    fn ServicePorts(self: Arc<Self>) {
+        for port in self.PendingPorts() {
+            self.ServicePort(port);
+        }
+    }
+}
+
+pub static CONTROLLERS: Mutex<Vec<Arc<xHCI_Device>>> = Mutex::new(Vec::new());
 static XHCI_BASE: AtomicUsize = AtomicUsize::new(0);
 
+fn Handle() {
+    let lock = CONTROLLERS.lock();
+    for c in lock.iter() {
+        let sts = c.ReadOp(OP_USBSTS);
+        if sts & USBSTS_EINT != 0 {
+            c.WriteOp(OP_USBSTS,USBSTS_EINT);
+        }
+        unsafe {
+            let iman = (c.runtime+IR_IMAN) as *mut u32;
+            iman.write_volatile(iman.read_volatile() | IMAN_IP);
+        }
+        if let Some(events) = c.events.lock().as_mut() {
+            events.Drain();
+        }
+    }
+}
+
+// Lets the hart go while waiting when we're on the USB worker, so this can't be used with a lock held
+fn WaitFor<F: Fn() -> bool>(cond: F, timeout: u64) -> bool {
+    let start = Timer::GetMicroseconds();
+    while !cond() {
+        if Timer::GetMicroseconds() - start > timeout {
+            return cond();
+        }
+        Scheduler::Yield();
+    }
+    true
+}
+
 pub fn Initalize() {
     let flock = crate::PageFrame::FRAME_ALLOC.lock();
     let mut highest_address = flock.0.cursor_back().current().unwrap().1 as u64;
     if highest_address < 0x100000000 {highest_address = 0x100000000;}
     drop(flock);
-    let lock = crate::Drivers::Arch::PCI::PCI_DEVICES.lock();
+    let lock = PCI::PCI_DEVICES.lock();
     for i in lock.iter() {
         if i.class == 0xc && i.subclass == 0x3 && i.progif == 0x30 {
-            let base = crate::Drivers::Arch::PCI::ReadBAR(i.bus,i.slot,i.func,0);
+            let base = PCI::ReadBAR(i.bus,i.slot,i.func,0);
             XHCI_BASE.store(base as usize,Ordering::SeqCst);
             if base > highest_address {
                 return;
             }
             log::debug!("Starting up xHCI Controller");
-            if let crate::Drivers::Arch::PCI::IRQ::Msix(irq) = i.irq {
-                *XHCI_CONTROLLER.lock() = Some(xHCI_Device::new(base as usize,irq as u16));
-                XHCI_CONTROLLER.lock().as_mut().unwrap().Init();
-            } else if let crate::Drivers::Arch::PCI::IRQ::Msi(irq) = i.irq {
-                *XHCI_CONTROLLER.lock() = Some(xHCI_Device::new(base as usize,irq as u16));
-                XHCI_CONTROLLER.lock().as_mut().unwrap().Init();
-            } else {
-                log::warn!("xHCI Controller doesn't support MSI or MSI-X, cannot startup!");
+            let irq = match i.irq {
+                PCI::IRQ::Msix(irq) | PCI::IRQ::Msi(irq) if irq != u8::MAX => irq,
+                _ => {
+                    log::warn!("xHCI Controller doesn't support MSI or MSI-X, cannot startup!");
+                    continue;
+                }
+            };
+            let mut controller = xHCI_Device::new(base as usize,irq as u16);
+            if !controller.Init() {
+                continue;
             }
+            controller.bus = USB::ReserveBusNumber();
+            let controller = Arc::new(controller);
+            CONTROLLERS.lock().push(controller.clone());
+            USB::RegisterController(controller.clone());
+            crate::arch::IDT::IRQ_HANDLERS.lock()[(irq-0x20) as usize] = Some(Handle);
+            // Devices that were already plugged in at boot don't generate a port change, so go looking for them
+            for port in 1..=controller.max_ports {
+                if controller.ReadPort(port) & PORTSC_CCS != 0 {
+                    controller.ServicePort(port);
+                }
+            }
+            // The initial scan has already handled the changes that queued up while it ran
+            controller.PendingPorts();
         }
     }
-}
\ No newline at end of file
+}
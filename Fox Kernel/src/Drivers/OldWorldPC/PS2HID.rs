@@ -1,34 +1,15 @@
-use crate::Drivers::Generic::Keyboard;
-
-pub struct PS2KBD;
-
-impl Keyboard::Keyboard for PS2KBD {
-    fn Read(&self) -> Option<u8> {
-        PS2Keyboard::BUFFER.lock().pop_front()
-    }
-    fn CanRead(&self) -> bool {
-        let buf = PS2Keyboard::BUFFER.lock();
-        if buf.len() == 0 {
-            drop(buf);
-            return false;
-        }
-        drop(buf);
-        return true;
-    }
-}
-
 pub mod PS2Keyboard {
     use x86_64::structures::port::{PortRead,PortWrite};
-    use spin::Mutex;
-    use alloc::collections::VecDeque;
-    use super::PS2KBD;
     use alloc::sync::Arc;
+    use spin::{Mutex, Once};
     use crate::Drivers::Generic::Keyboard;
+    use crate::Drivers::Generic::Input;
+
+    static INPUT: Once<Arc<Input::InputDevice>> = Once::new();
+    static DECODER: Mutex<Input::Set1Decoder> = Mutex::new(Input::Set1Decoder::new());
+
     // PS/2 Keyboard Command Port: 0x64
     // PS/2 Keyboard Data Port: 0x60
-    lazy_static::lazy_static! {
-        pub static ref BUFFER: Mutex<VecDeque<u8>> = Mutex::new(VecDeque::new());
-    }
     pub fn Initalize() {
         unsafe {
             log::debug!("Initalizing PS/2 Keyboard");
@@ -48,7 +29,8 @@ pub mod PS2Keyboard {
             SendCommand(0xf4);
             SendCommand(0xf0);
             SendCommand(1);
-            Keyboard::KEYBOARD.call_once(|| Arc::new(PS2KBD));
+            Keyboard::KEYBOARD.call_once(|| Arc::new(Keyboard::ScancodeQueue));
+            INPUT.call_once(|| Input::Register("PS/2 Keyboard",Input::KeyboardCapabilities()));
             let mut lock = crate::arch::IDT::IRQ_HANDLERS.lock();
             lock[0x1] = Some(Handle);
             drop(lock);
@@ -89,26 +71,180 @@ pub mod PS2Keyboard {
     }
     pub fn Handle() {
         unsafe {
-            while u8::read_from_port(0x64) & 0x1 == 0x1 {
-                let mut lock = BUFFER.lock();
+            loop {
+                let status = u8::read_from_port(0x64);
+                // Bytes from the mouse show up on the same port, those are PS2Mouse's to take
+                if status & 0x1 == 0 || status & 0x20 != 0 {
+                    break;
+                }
                 let val = u8::read_from_port(0x60);
                 if val == 0x00 || val == 0xEE || val >= 0xFA {
                     continue;
                 }
-                if lock.len() >= 128 {
-                    lock.pop_front();
+                Keyboard::Push(val);
+                if let Some((keycode, pressed)) = DECODER.lock().Feed(val) {
+                    if let Some(input) = INPUT.get() {
+                        input.Key(keycode,pressed);
+                        input.Sync();
+                    }
                 }
-                lock.push_back(val);
-                drop(lock);
             }
         }
     }
 }
 
 pub mod PS2Mouse {
+    use x86_64::structures::port::{PortRead,PortWrite};
+    use alloc::sync::Arc;
+    use spin::{Mutex, Once};
+    use crate::Drivers::Generic::Mouse;
+    use crate::Drivers::Generic::Input;
 
+    const ACK: u8 = 0xFA;
+    const RESEND: u8 = 0xFE;
+
+    // Bytes of the packet we're in the middle of, how many there are so far, and how long packets are (4 with a scroll wheel)
+    static PACKET: Mutex<([u8; 4],usize,usize)> = Mutex::new(([0; 4],0,3));
+    static mut DEVICE_ID: u8 = 0;
+    static INPUT: Once<Arc<Input::InputDevice>> = Once::new();
+
+    fn WaitWrite() -> bool {
+        for _ in 0..100000 {
+            if unsafe {u8::read_from_port(0x64)} & 0x2 == 0 {
+                return true;
+            }
+        }
+        false
+    }
+    fn WaitRead() -> bool {
+        for _ in 0..100000 {
+            if unsafe {u8::read_from_port(0x64)} & 0x1 != 0 {
+                return true;
+            }
+        }
+        false
+    }
+    fn Controller(cmd: u8) {
+        WaitWrite();
+        unsafe {u8::write_to_port(0x64,cmd);}
+    }
+    fn Read() -> Option<u8> {
+        if WaitRead() {Some(unsafe {u8::read_from_port(0x60)})} else {None}
+    }
+    // Sends a byte to the mouse rather than the keyboard and waits for it to be acknowledged
+    fn Write(byte: u8) -> bool {
+        for _ in 0..3 {
+            Controller(0xD4);
+            WaitWrite();
+            unsafe {u8::write_to_port(0x60,byte);}
+            match Read() {
+                Some(ACK) => {return true;}
+                Some(RESEND) => {}
+                _ => {return false;}
+            }
+        }
+        false
+    }
+    fn SetSampleRate(rate: u8) -> bool {
+        Write(0xF3) && Write(rate)
+    }
+    fn GetID() -> Option<u8> {
+        if !Write(0xF2) {
+            return None;
+        }
+        Read()
+    }
+    pub fn Initalize() {
+        log::debug!("Initalizing PS/2 Mouse");
+        Controller(0xA8);
+        // Turn on IRQ12 and the auxiliary clock in the controller configuration byte
+        Controller(0x20);
+        let config = match Read() {
+            Some(c) => c,
+            None => {return;}
+        };
+        Controller(0x60);
+        WaitWrite();
+        unsafe {u8::write_to_port(0x60,(config | 0x2) & !0x20);}
+        if !Write(0xF6) {
+            log::debug!("No PS/2 mouse");
+            return;
+        }
+        // The magic sample rate sequences that unlock the IntelliMouse scroll wheel (ID 3) and then the extra two buttons (ID 4)
+        let mut id = 0;
+        if SetSampleRate(200) && SetSampleRate(100) && SetSampleRate(80) {
+            id = GetID().unwrap_or(0);
+            if id == 3 && SetSampleRate(200) && SetSampleRate(200) && SetSampleRate(80) {
+                id = GetID().unwrap_or(3);
+            }
+        }
+        SetSampleRate(100);
+        unsafe {DEVICE_ID = id;}
+        PACKET.lock().2 = if id == 3 || id == 4 {4} else {3};
+        if !Write(0xF4) {
+            log::warn!("PS/2 mouse won't enable data reporting");
+            return;
+        }
+        log::info!("PS/2 mouse (ID {})", id);
+        let name = match id {
+            3 => "PS/2 IntelliMouse",
+            4 => "PS/2 IntelliMouse Explorer",
+            _ => "PS/2 Mouse",
+        };
+        INPUT.call_once(|| Input::Register(name,Input::MouseCapabilities(id == 3 || id == 4)));
+        Mouse::Attach();
+        let mut lock = crate::arch::IDT::IRQ_HANDLERS.lock();
+        lock[12] = Some(Handle);
+        drop(lock);
+    }
+    fn Decode(bytes: &[u8]) {
+        let flags = bytes[0];
+        // Overflowed packets don't have anything useful in them
+        if flags & 0xC0 != 0 {
+            return;
+        }
+        let mut packet = Mouse::MousePacket {
+            buttons: (flags & 0x7) as u32,
+            dx: bytes[1] as i32 - (((flags as i32) << 4) & 0x100),
+            dy: -(bytes[2] as i32 - (((flags as i32) << 3) & 0x100)), // PS/2 counts upwards
+            wheel: 0,
+        };
+        if bytes.len() == 4 {
+            if unsafe {DEVICE_ID} == 4 {
+                packet.wheel = ((bytes[3] << 4) as i8 >> 4) as i32;
+                if bytes[3] & 0x10 != 0 {packet.buttons |= Mouse::BUTTON_SIDE;}
+                if bytes[3] & 0x20 != 0 {packet.buttons |= Mouse::BUTTON_EXTRA;}
+            } else {
+                packet.wheel = bytes[3] as i8 as i32;
+            }
+        }
+        if let Some(input) = INPUT.get() {
+            input.ReportPointer(&packet,false);
+        }
+        Mouse::Push(packet);
+    }
+    pub fn Handle() {
+        let mut lock = PACKET.lock();
+        unsafe {
+            while u8::read_from_port(0x64) & 0x21 == 0x21 {
+                let val = u8::read_from_port(0x60);
+                let (ref mut bytes, ref mut index, size) = *lock;
+                // Bit 3 of the first byte is always set, which is how we get back in step after dropping a byte
+                if *index == 0 && val & 0x8 == 0 {
+                    continue;
+                }
+                bytes[*index] = val;
+                *index += 1;
+                if *index == size {
+                    *index = 0;
+                    Decode(&bytes[..size]);
+                }
+            }
+        }
+    }
 }
 
 pub fn Initalize() {
     PS2Keyboard::Initalize();
+    PS2Mouse::Initalize();
 }
\ No newline at end of file
@@ -0,0 +1,344 @@
+// 16550 UARTs on the standard PC COM ports, which show up as /dev/ttyS0 through /dev/ttyS3
+use x86_64::structures::port::{PortRead,PortWrite};
+use crate::FS::VFS;
+use crate::FS::DevFS;
+use crate::Syscall::Errors;
+use crate::Process::Signals;
+use crate::Drivers::Generic::LineDiscipline::*;
+use crate::Drivers::Generic::Epoll::Watchers;
+use crate::Drivers::Generic::PseudoTTY::{self, WinSize, TCSETS, TCSETSW, TCSETSF, TCSBRK, TIOCGWINSZ, TIOCSWINSZ, TIOCGNAME};
+use alloc::sync::Arc;
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::format;
+use alloc::vec::Vec;
+use spin::{Mutex, Once};
+
+const COM_PORTS: [(u16, usize); 4] = [(0x3F8, 4), (0x2F8, 3), (0x3E8, 4), (0x2E8, 3)]; // I/O base and IRQ
+const CLOCK: u32 = 115200; // The 1.8432 MHz crystal divided by 16
+const FIFO_SIZE: usize = 16;
+const RX_MAX: usize = 4096;
+
+// Registers, as offsets from the I/O base
+const DATA: u16 = 0; // Divisor low byte while DLAB is set
+const IER: u16 = 1; // Divisor high byte while DLAB is set
+const IIR: u16 = 2; // FCR when written
+const LCR: u16 = 3;
+const MCR: u16 = 4;
+const LSR: u16 = 5;
+const MSR: u16 = 6;
+const SCRATCH: u16 = 7;
+
+const IER_RX: u8 = 0x01;
+const IER_LINE: u8 = 0x04;
+const LCR_BREAK: u8 = 0x40;
+const LCR_DLAB: u8 = 0x80;
+const MCR_DTR: u8 = 0x01;
+const MCR_RTS: u8 = 0x02;
+const MCR_OUT2: u8 = 0x08; // Gates the interrupt line on PC hardware
+const LSR_DATA: u8 = 0x01;
+const LSR_THRE: u8 = 0x20;
+
+// Indexed by the low bits of CBAUD, B57600 and B115200 are the only extended ones a 16550 can do
+const SPEEDS: [u32; 16] = [0, 50, 75, 110, 134, 150, 200, 300, 600, 1200, 1800, 2400, 4800, 9600, 19200, 38400];
+
+fn Speed(cbaud: u32) -> Option<u32> {
+    match cbaud {
+        B57600 => Some(57600),
+        B115200 => Some(115200),
+        b if b < 16 => Some(SPEEDS[b as usize]),
+        _ => None,
+    }
+}
+
+static PORTS: Once<Vec<Arc<SerialPort>>> = Once::new();
+
+pub struct SerialPort {
+    id: usize,
+    index: usize,
+    name: String,
+    base: u16,
+    fifo: bool, // 8250s and 16450s only hold one byte at a time
+    rx: Mutex<VecDeque<u8>>, // Filled by the interrupt handler, emptied into the line discipline
+    ldisc: Mutex<LineDiscipline>,
+    winsize: Mutex<WinSize>,
+    watchers: Watchers,
+}
+
+impl SerialPort {
+    fn Probe(base: u16) -> bool {
+        unsafe {
+            u8::write_to_port(base+SCRATCH,0x5A);
+            if u8::read_from_port(base+SCRATCH) != 0x5A {
+                return false;
+            }
+            u8::write_to_port(base+SCRATCH,0xA5);
+            u8::read_from_port(base+SCRATCH) == 0xA5
+        }
+    }
+    fn Setup(&self) {
+        unsafe {
+            u8::write_to_port(self.base+IER,0x00);
+            self.Configure(&self.ldisc.lock().termios);
+            u8::write_to_port(self.base+IIR,0xC7); // Enable FIFO, clear them, with 14-byte threshold
+            // Throw away anything that was sitting around from before
+            u8::read_from_port(self.base+LSR);
+            u8::read_from_port(self.base+MSR);
+            while u8::read_from_port(self.base+LSR) & LSR_DATA != 0 {
+                u8::read_from_port(self.base+DATA);
+            }
+            u8::read_from_port(self.base+IIR);
+            u8::write_to_port(self.base+IER,IER_RX | IER_LINE);
+        }
+    }
+    // Programs the line to match c_cflag. The speed has already been checked by the time this gets called.
+    fn Configure(&self, termios: &Termios) {
+        let cflag = termios.c_cflag;
+        let speed = Speed(cflag & CBAUD).unwrap_or(CLOCK);
+        unsafe {
+            if speed == 0 { // B0 hangs up the line
+                u8::write_to_port(self.base+MCR,MCR_OUT2);
+                return;
+            }
+            let divisor = (CLOCK / speed).max(1) as u16;
+            let mut lcr = ((cflag & CSIZE) >> 4) as u8;
+            if cflag & CSTOPB != 0 {
+                lcr |= 0x04;
+            }
+            if cflag & PARENB != 0 {
+                lcr |= 0x08;
+                if cflag & PARODD == 0 {
+                    lcr |= 0x10;
+                }
+            }
+            u8::write_to_port(self.base+LCR,lcr | LCR_DLAB);
+            u8::write_to_port(self.base+DATA,(divisor & 0xFF) as u8);
+            u8::write_to_port(self.base+IER,(divisor >> 8) as u8);
+            u8::write_to_port(self.base+LCR,lcr);
+            u8::write_to_port(self.base+MCR,MCR_DTR | MCR_RTS | MCR_OUT2);
+        }
+    }
+    fn Transmit(&self, bytes: &[u8]) {
+        for chunk in bytes.chunks(if self.fifo {FIFO_SIZE} else {1}) {
+            unsafe {
+                while u8::read_from_port(self.base+LSR) & LSR_THRE == 0 {core::hint::spin_loop()}
+                for &b in chunk {
+                    u8::write_to_port(self.base+DATA,b);
+                }
+            }
+        }
+    }
+    fn Break(&self, micros: u64) {
+        unsafe {
+            let lcr = u8::read_from_port(self.base+LCR);
+            u8::write_to_port(self.base+LCR,lcr | LCR_BREAK);
+            let end = crate::arch::Timer::GetMicroseconds() + micros;
+            while crate::arch::Timer::GetMicroseconds() < end {core::hint::spin_loop()}
+            u8::write_to_port(self.base+LCR,lcr);
+        }
+    }
+    // Hands whatever the interrupt handler picked up to the line discipline, and sends back the echo.
+    // Any signals it raised come back to be sent once the line discipline is unlocked.
+    fn Pump(&self, ldisc: &mut LineDiscipline) -> Option<Typed> {
+        let bytes: Vec<u8> = self.rx.lock().drain(..).collect();
+        if bytes.is_empty() || ldisc.termios.c_cflag & CREAD == 0 {
+            return None;
+        }
+        let typed = ldisc.Input(bytes.as_slice());
+        self.Transmit(typed.echo.as_slice());
+        Some(typed)
+    }
+    fn Service(&self) {
+        // Bounded so a port that's stuck asserting something can't hang the interrupt
+        for _ in 0..16 {
+            let iir = unsafe {u8::read_from_port(self.base+IIR)};
+            if iir & 0x01 != 0 {
+                return;
+            }
+            match (iir >> 1) & 0x7 {
+                0 => {unsafe {u8::read_from_port(self.base+MSR);}}
+                3 => {unsafe {u8::read_from_port(self.base+LSR);}} // Overrun, parity, framing, or break
+                2 | 6 => self.Receive(), // Data ready, or the FIFO timed out under the threshold
+                _ => {}
+            }
+        }
+    }
+    fn Receive(&self) {
+        let mut rx = self.rx.lock();
+        unsafe {
+            while u8::read_from_port(self.base+LSR) & LSR_DATA != 0 {
+                let b = u8::read_from_port(self.base+DATA);
+                if rx.len() < RX_MAX {
+                    rx.push_back(b);
+                }
+            }
+        }
+        drop(rx);
+        self.watchers.Notify();
+        // Whoever has the line discipline locked will pick the bytes up when they're done with it
+        if let Some(mut ldisc) = self.ldisc.try_lock() {
+            let typed = self.Pump(&mut ldisc);
+            drop(ldisc);
+            if let Some(typed) = typed {
+                typed.Signal();
+            }
+        }
+    }
+}
+
+impl DevFS::Device for SerialPort {
+    fn DeviceID(&self) -> usize {
+        self.id
+    }
+    fn Inode(&self) -> Arc<dyn VFS::Inode> {
+        PORTS.get().expect("device not ready")[self.index].clone()
+    }
+}
+
+impl VFS::Inode for SerialPort {
+    fn Stat(&self) -> Result<VFS::Metadata, i64> {
+        Ok(VFS::Metadata {
+            device_id: 0,
+            inode_id: i64::MAX,
+            mode: 0o0020660, // crw-rw----
+            nlinks: 1,
+            uid: 0,
+            gid: 0,
+            rdev: 0,
+            size: 0,
+            blksize: 0,
+            blocks: 0,
+
+            atime: unsafe {crate::UNIX_EPOCH as i64},
+            mtime: unsafe {crate::UNIX_EPOCH as i64},
+            ctime: unsafe {crate::UNIX_EPOCH as i64},
+            reserved1: 0,
+            reserved2: 0,
+            reserved3: 0,
+        })
+    }
+    fn GetName(&self) -> Result<&str, i64> {
+        Ok(self.name.as_str())
+    }
+    fn Read(&self, _offset: i64, buffer: &mut [u8]) -> i64 {
+        let typed = self.Pump(&mut self.ldisc.lock());
+        if let Some(typed) = typed {
+            typed.Signal();
+        }
+        self.ldisc.lock().Read(buffer)
+    }
+    fn Write(&self, _offset: i64, buffer: &[u8]) -> i64 {
+        let out = self.ldisc.lock().Output(buffer);
+        self.Transmit(out.as_slice());
+        buffer.len() as i64
+    }
+    fn IOCtl(&self, cmd: usize, arg: usize) -> Result<usize, i64> {
+        match cmd {
+            TCSETS | TCSETSW | TCSETSF => {
+                let termios = unsafe {*(arg as *const Termios)};
+                if Speed(termios.c_cflag & CBAUD).is_none() {
+                    return Err(Errors::EINVAL as i64);
+                }
+                let mut ldisc = self.ldisc.lock();
+                let ret = ldisc.IOCtl(cmd,arg).unwrap_or(Err(Errors::ENOTTY as i64))?;
+                self.Configure(&ldisc.termios);
+                Ok(ret)
+            }
+            TCSBRK => {
+                // Zero asks for a break, anything else is a drain, which is already done since output isn't buffered
+                if arg == 0 {
+                    self.Break(250000);
+                }
+                Ok(0)
+            }
+            TIOCGWINSZ => {
+                unsafe {*(arg as *mut WinSize) = *self.winsize.lock();}
+                Ok(0)
+            }
+            TIOCSWINSZ => {
+                // Nothing on the other end of the wire tells us its size, so this is whatever stty was told
+                let new = unsafe {*(arg as *const WinSize)};
+                let mut winsize = self.winsize.lock();
+                let changed = *winsize != new;
+                *winsize = new;
+                drop(winsize);
+                if changed {
+                    let pgrp = self.ldisc.lock().pgrp;
+                    crate::Process::Process::SignalGroup(pgrp,Signals::SIGWINCH);
+                }
+                Ok(0)
+            }
+            TIOCGNAME => {
+                PseudoTTY::CopyName(self.name.as_str(),arg);
+                Ok(0)
+            }
+            _ => self.ldisc.lock().IOCtl(cmd,arg).unwrap_or(Err(Errors::ENOTTY as i64)),
+        }
+    }
+    // Doesn't pump, since poll shouldn't be what raises signals. Bytes the interrupt handler couldn't hand over
+    // count as readable, and the read that follows will feed them through.
+    fn Poll(&self) -> i16 {
+        if self.ldisc.lock().CanRead() || !self.rx.lock().is_empty() {VFS::POLLIN | VFS::POLLOUT} else {VFS::POLLOUT}
+    }
+    fn Watchers(&self) -> Option<Watchers> {
+        Some(self.watchers.clone())
+    }
+}
+
+// COM1 and COM3 share IRQ 4, COM2 and COM4 share IRQ 3, so every port gets checked either way. The debugger's
+// port isn't one of ours, but its interrupts come through here too.
+pub fn Handle() {
+    crate::arch::GDB::Interrupt();
+    if let Some(ports) = PORTS.get() {
+        for port in ports.iter() {
+            port.Service();
+        }
+    }
+}
+
+pub fn Initalize() {
+    let mut found = Vec::new();
+    for (i, &(base, irq)) in COM_PORTS.iter().enumerate() {
+        if !SerialPort::Probe(base) || crate::arch::GDB::Port() == Some(base) {
+            continue;
+        }
+        // Everything the kernel logs goes out COM1 as well, so it can't be a terminal at the same time
+        if i == 0 && unsafe {crate::Console::SERIAL_LOG} {
+            log::info!("ttyS0: Left to the kernel log, boot with --no_serial_log to use it as a terminal");
+            continue;
+        }
+        let fifo = unsafe {
+            u8::write_to_port(base+IIR,0x01);
+            u8::read_from_port(base+IIR) & 0xC0 == 0xC0
+        };
+        log::info!("ttyS{}: {} at 0x{:x}, IRQ {}", i, if fifo {"16550A"} else {"8250/16450"}, base, irq);
+        found.push((i, base, fifo));
+    }
+    PORTS.call_once(|| {
+        found.iter().enumerate().map(|(index, &(i, base, fifo))| {
+            let mut ldisc = LineDiscipline::new();
+            // Serial consoles don't have modem lines hooked up, and 115200 is what the other end usually expects
+            ldisc.termios.c_cflag = (ldisc.termios.c_cflag & !CBAUD) | B115200 | CLOCAL;
+            Arc::new(SerialPort {
+                id: DevFS::ReserveDeviceID(),
+                index,
+                name: format!("ttyS{}",i),
+                base,
+                fifo,
+                rx: Mutex::new(VecDeque::new()),
+                ldisc: Mutex::new(ldisc),
+                winsize: Mutex::new(WinSize {row: 24, col: 80, reserved1: 0, reserved2: 0}),
+                watchers: Watchers::new(),
+            })
+        }).collect()
+    });
+    let ports = PORTS.get().unwrap();
+    for port in ports.iter() {
+        port.Setup();
+        let _ = DevFS::InstallDevice(port.clone());
+    }
+    let mut lock = crate::arch::IDT::IRQ_HANDLERS.lock();
+    lock[3] = Some(Handle);
+    lock[4] = Some(Handle);
+    drop(lock);
+}
@@ -102,6 +102,15 @@ pub fn InstallDevice(dev: Arc<dyn Device>) -> Result<(),i64> {
     Ok(())
 }
 
+// For devices that can go away, like anything that's hot-pluggable
+pub fn RemoveDevice(id: usize) {
+    let mut devices = DEVICES.lock();
+    if let Some(pos) = devices.iter().position(|d| d.DeviceID() == id) {
+        debug!("Removing device \"{}\" with ID #{}", devices[pos].Inode().GetName().unwrap_or("?"), id);
+        devices.remove(pos);
+    }
+}
+
 pub fn ReserveDeviceID() -> usize {
     NEXT_DEVICE.fetch_add(1, Ordering::SeqCst)
 }
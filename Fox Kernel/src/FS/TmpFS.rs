@@ -5,6 +5,7 @@ use spin::Mutex;
 use core::sync::atomic::{Ordering,AtomicI32,AtomicU32,AtomicI64};
 use crate::Syscall::Errors;
 use alloc::sync::{Arc,Weak};
+use crate::Drivers::Generic::UNIXPipe;
 
 static NEXT_INODEID: AtomicI64 = AtomicI64::new(2);
 
@@ -13,8 +14,9 @@ pub struct TMPInode {
     name: String,
     inode: Weak<dyn VFS::Inode>,
     parent: Option<Arc<dyn VFS::Inode>>,
-    children: Mutex<Vec<Arc<dyn VFS::Inode>>>,
+    children: Mutex<Vec<Arc<TMPInode>>>,
     content: Mutex<Vec<u8>>,
+    fifo: Option<Arc<UNIXPipe::Pipe>>, // Shared by everyone who opens this, if it's a FIFO
     pub ctime: AtomicI64,
     pub mtime: AtomicI64,
     pub uid: AtomicU32,
@@ -32,6 +34,7 @@ impl TMPInode {
             inode: inode.clone(),
             children: Mutex::new(Vec::new()),
             content: Mutex::new(Vec::new()),
+            fifo: if mode & 0o0170000 == VFS::FTYPE_FIFO as i32 {Some(UNIXPipe::Pipe::Named())} else {None},
             ctime: AtomicI64::new(ts),
             mtime: AtomicI64::new(ts),
             uid: AtomicU32::new(0),
@@ -143,6 +146,9 @@ impl VFS::Inode for TMPInode {
             let children = self.children.lock();
             for i in children.iter() {
                 if i.GetName()? == name {
+                    if let Some(pipe) = &i.fifo {
+                        return Ok(UNIXPipe::NamedPipeEnd::new(i.clone(),pipe.clone()));
+                    }
                     return Ok(i.clone())
                 }
             }
@@ -12,8 +12,16 @@ const FTYPE_CSPL: u64 = 0o0020000; /* character special */
 const FTYPE_BSPL: u64 = 0o0060000; /* block special */
 const FTYPE_REG:  u64 = 0o0100000; /* regular */
 const FTYPE_SLNK: u64 = 0o0120000; /* symbolic link */
-const FTYPE_SOCK: u64 = 0o0140000; /* socket */
-const FTYPE_FIFO: u64 = 0o0010000; /* fifo */
+pub const FTYPE_SOCK: u64 = 0o0140000; /* socket */
+pub const FTYPE_FIFO: u64 = 0o0010000; /* fifo */
+
+// Readiness bits for Inode::Poll, these follow mlibc's poll.h
+pub const POLLIN: i16 = 0x01;
+pub const POLLOUT: i16 = 0x02;
+pub const POLLPRI: i16 = 0x04;
+pub const POLLHUP: i16 = 0x08;
+pub const POLLERR: i16 = 0x10;
+pub const POLLNVAL: i16 = 0x40;
 
 #[repr(C)]
 pub struct Metadata {
@@ -84,6 +92,9 @@ pub trait Inode: Any + Send + Sync {
         Err(Errors::ENOSYS as i64)
     }
 
+    // Called with the open flags whenever a file is opened, and with usize::MAX whenever an existing descriptor is
+    // duplicated (dup, dup2, fork and SCM_RIGHTS). The duplicate shares whatever state the first open set up, so
+    // nodes that create something per open (like /dev/ptmx) must not do it again for usize::MAX.
     fn Open(&self, _mode: usize) -> Result<(), i64> {
         Ok(())
     }
@@ -97,6 +108,24 @@ pub trait Inode: Any + Send + Sync {
     fn ChMod(&self, _mode: i32) -> i64 {
         Errors::ENOSYS as i64
     }
+
+    fn Seek(&self, _offset: i64, _whence: usize) -> Option<Result<i64, i64>> { // For files where the offset isn't a byte position, None leaves it to lseek
+        None
+    }
+
+    fn Poll(&self) -> i16 { // Files that never block are always ready
+        POLLIN | POLLOUT
+    }
+
+    fn Watchers(&self) -> Option<crate::Drivers::Generic::Epoll::Watchers> { // Files that can tell epoll when they might have become ready
+        None
+    }
+}
+
+// Gets at the type behind a file, for the system calls that only work on one kind of file (sockets, epoll, ...)
+pub fn Downcast<T: Inode>(inode: Arc<dyn Inode>) -> Option<Arc<T>> {
+    let any: Arc<dyn Any + Send + Sync> = inode;
+    any.downcast::<T>().ok()
 }
 
 pub trait Filesystem: Send + Sync {
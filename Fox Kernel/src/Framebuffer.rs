@@ -5,6 +5,11 @@ const FoxScript: &[u8; 4096] = include_bytes!("FoxScript");
 
 pub static MainFramebuffer: Mutex<Option<Framebuffer>> = Mutex::new(None);
 
+// The 8x16 bitmap for a character, one byte per row with the leftmost pixel in the top bit
+pub fn Glyph(sym: u8) -> &'static [u8] {
+    &FoxScript[(sym as usize*16)..(sym as usize*16)+16]
+}
+
 pub struct Framebuffer {
     pub pointer: u64,
     pub width: usize,
@@ -116,4 +121,184 @@ pub fn Progress(num: u8) {
             }
         }
     }
-}
\ No newline at end of file
+}
+// Colours for the panic screen, --panic_colors=BACKGROUND,TEXT,ACCENT,DIM in hex. Any left out keep their default.
+struct PanicColors {
+    background: u32,
+    text: u32,
+    accent: u32,
+    dim: u32,
+}
+
+fn GetPanicColors() -> PanicColors {
+    let mut colors = [0x2B0A0A, 0xF2F2F2, 0xE05050, 0x9A8A8A];
+    if let Some(option) = crate::CommandLine::OPTIONS.get().and_then(|o| o.get("--panic_colors")) {
+        for (i, c) in option.split(',').take(4).enumerate() {
+            if let Ok(c) = u32::from_str_radix(c.trim_start_matches("0x").trim_start_matches('#'),16) {
+                colors[i] = c & 0xFFFFFF;
+            }
+        }
+    }
+    PanicColors {background: colors[0], text: colors[1], accent: colors[2], dim: colors[3]}
+}
+
+// Writes into a grid of character cells, wrapping at the right edge and dropping whatever falls off the bottom
+struct TextBox<'a> {
+    fb: &'a mut Framebuffer,
+    x: usize,
+    y: usize,
+    cols: usize,
+    rows: usize,
+    col: usize,
+    row: usize,
+    color: u32,
+    wrap: bool, // Lines that don't wrap get cut off instead
+}
+
+impl TextBox<'_> {
+    fn NewLine(&mut self) {
+        self.col = 0;
+        self.row += 1;
+    }
+    fn Section(&mut self, title: &str, color: u32) {
+        if self.col != 0 {
+            self.NewLine();
+        }
+        self.NewLine();
+        let old = self.color;
+        self.color = color;
+        core::fmt::Write::write_str(self,title);
+        self.NewLine();
+        self.color = old;
+    }
+    fn RowsLeft(&self) -> usize {
+        self.rows.saturating_sub(self.row + if self.col != 0 {1} else {0})
+    }
+}
+
+impl core::fmt::Write for TextBox<'_> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        for b in s.bytes() {
+            if b == b'\n' {
+                self.NewLine();
+                continue;
+            }
+            if self.col >= self.cols {
+                if !self.wrap {
+                    continue;
+                }
+                self.NewLine();
+            }
+            if self.row < self.rows {
+                self.fb.DrawSymbol(self.x+self.col*8,self.y+self.row*16,if (32..127).contains(&b) {b} else {b'?'},self.color,1);
+            }
+            self.col += 1;
+        }
+        Ok(())
+    }
+}
+
+// Takes over the screen once a panic has been printed. Nothing in here allocates or waits on a lock, the hart that
+// panicked might be holding any of them.
+pub fn PanicScreen(info: &core::panic::PanicInfo) {
+    use core::fmt::Write;
+    let mut lock = match MainFramebuffer.try_lock() {
+        Some(l) => l,
+        None => return,
+    };
+    let fb = match lock.as_mut() {
+        Some(fb) if fb.bpp == 32 => fb,
+        _ => return,
+    };
+    let colors = GetPanicColors();
+    fb.Clear(colors.background);
+    fb.DrawRect(0,0,fb.width,40,colors.accent);
+    fb.DrawString(16,4,"Fox Kernel Panic",colors.background,2);
+    let (width, height) = (fb.width, fb.height);
+    let mut w = TextBox {fb, x: 16, y: 56, cols: (width-32)/8, rows: (height-72)/16, col: 0, row: 0, color: colors.text, wrap: true};
+
+    let pid = crate::Scheduler::SCHEDULERS.try_lock().and_then(|s| s.get(&crate::arch::CurrentHart()).map(|s| s.current_proc_id.load(core::sync::atomic::Ordering::SeqCst)));
+    w.color = colors.dim;
+    write!(w, "hart 0x{}", crate::arch::CurrentHart());
+    match pid {
+        Some(pid) if pid > 0 => {
+            write!(w, ", process #{}", pid);
+            if let Some(procs) = crate::Process::PROCESSES.try_lock() {
+                if let Some(p) = procs.get(&pid) {
+                    write!(w, " ({})", p.name);
+                }
+            }
+        }
+        _ => {write!(w, ", no process");}
+    }
+    w.NewLine();
+    w.NewLine();
+    w.color = colors.text;
+    if let Some(m) = info.message() {
+        write!(w, "{}\n", m);
+    }
+    w.color = colors.dim;
+    if let Some(l) = info.location() {
+        write!(w, "{}\n", l);
+    }
+
+    w.Section("Registers",colors.accent);
+    w.color = colors.text;
+    if let Some((name, regs, cr2)) = crate::arch::IDT::FAULT.try_lock().and_then(|f| *f) {
+        write!(w, "{} (error code 0x{:x})\n", name, regs.err_code);
+        let list = [
+            ("RAX", regs.rax), ("RBX", regs.rbx), ("RCX", regs.rcx), ("RDX", regs.rdx),
+            ("RSI", regs.rsi), ("RDI", regs.rdi), ("RBP", regs.rbp), ("RSP", regs.rsp),
+            ("R8 ", regs.r8), ("R9 ", regs.r9), ("R10", regs.r10), ("R11", regs.r11),
+            ("R12", regs.r12), ("R13", regs.r13), ("R14", regs.r14), ("R15", regs.r15),
+            ("RIP", regs.rip), ("RFL", regs.rflags), ("CS ", regs.cs), ("SS ", regs.ss),
+            ("CR2", cr2), ("CR3", x86_64::registers::control::Cr3::read().0.start_address().as_u64()),
+        ];
+        let per_row = ((w.cols + 2) / 24).max(1);
+        for (i, (name, value)) in list.iter().enumerate() {
+            write!(w, "{}=0x{:016x}  ", name, value);
+            if i % per_row == per_row - 1 {
+                w.NewLine();
+            }
+        }
+    } else {
+        write!(w, "Not a fault, CR2=0x{:016x}\n", x86_64::registers::control::Cr2::read().as_u64());
+    }
+
+    w.Section("Backtrace",colors.accent);
+    w.color = colors.text;
+    w.wrap = false;
+    crate::Backtrace::WalkPanic(|n, addr| {
+        if w.RowsLeft() < 4 {
+            return; // Leave some room for the log
+        }
+        match crate::Backtrace::FrameSymbol(n,addr) {
+            Some((name, off)) => write!(w, "#{:<2} 0x{:016x} {:#}+0x{:x}", n, addr, rustc_demangle::demangle(name), off),
+            None => write!(w, "#{:<2} 0x{:016x} ???", n, addr),
+        };
+        w.NewLine();
+    });
+
+    w.Section("Kernel Log",colors.accent);
+    let count = w.RowsLeft();
+    w.color = colors.dim;
+    let complete = crate::KernelLog::Tail(count,|r| {
+        write!(w, "[{:5}.{:06}] {}: ", r.time / 1000000, r.time % 1000000, r.Module());
+        for line in r.Text().split('\n') {
+            write!(w, "{} ", line);
+        }
+        w.NewLine();
+    });
+    if !complete {
+        write!(w, "(the log is locked)");
+    }
+}
+
+pub fn ShutdownScreen() {
+    let mut lock = MainFramebuffer.lock();
+    if let Some(fb) = lock.as_mut() {
+        fb.Clear(0x000000);
+        let msg = "It is now safe to turn off your computer";
+        fb.DrawString((fb.width/2)-(msg.len()*8),fb.height/2-16,msg,0xFFFFFF,2);
+    }
+}
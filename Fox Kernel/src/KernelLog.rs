@@ -0,0 +1,221 @@
+// The kernel log. Every record is kept in a fixed ring whether or not it made it to the console, so boot logs can be
+// read back out of /dev/kmsg even when QUIET swallowed them. Nothing here allocates once it's running, since the panic
+// paths want to read the tail of it.
+use core::fmt::Write;
+use core::sync::atomic::{AtomicU8, Ordering};
+use alloc::vec::Vec;
+use spin::{Mutex, Once};
+
+pub const RECORDS: usize = 1024;
+const MODULE_LEN: usize = 48;
+const TEXT_LEN: usize = 200;
+
+// syslog priorities
+pub const LOG_EMERG: u8 = 0;
+pub const LOG_ALERT: u8 = 1;
+pub const LOG_CRIT: u8 = 2;
+pub const LOG_ERR: u8 = 3;
+pub const LOG_WARNING: u8 = 4;
+pub const LOG_NOTICE: u8 = 5;
+pub const LOG_INFO: u8 = 6;
+pub const LOG_DEBUG: u8 = 7;
+
+const LEVEL_NAMES: [&str; 8] = ["emerg","alert","crit","err","warn","notice","info","debug"];
+
+#[derive(Clone, Copy)]
+pub struct LogRecord {
+    pub seq: u64,
+    pub time: u64, // Microseconds since the TSC was calibrated
+    pub level: u8,
+    pub hart: u32,
+    module: [u8; MODULE_LEN],
+    module_len: u8,
+    text: [u8; TEXT_LEN],
+    text_len: u8,
+}
+
+impl LogRecord {
+    const EMPTY: LogRecord = LogRecord {seq: 0, time: 0, level: 0, hart: 0, module: [0; MODULE_LEN], module_len: 0, text: [0; TEXT_LEN], text_len: 0};
+
+    pub fn Module(&self) -> &str {
+        core::str::from_utf8(&self.module[..self.module_len as usize]).unwrap_or("")
+    }
+    pub fn Text(&self) -> &str {
+        core::str::from_utf8(&self.text[..self.text_len as usize]).unwrap_or("")
+    }
+}
+
+// Formats into a fixed buffer, cutting off whatever doesn't fit without splitting a character
+struct Truncate<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl Write for Truncate<'_> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let mut n = s.len().min(self.buf.len() - self.len);
+        while !s.is_char_boundary(n) {
+            n -= 1;
+        }
+        self.buf[self.len..self.len+n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        Ok(())
+    }
+}
+
+pub struct LogBuffer {
+    records: [LogRecord; RECORDS],
+    next: u64,
+}
+
+impl LogBuffer {
+    // Sequence number of the oldest record that hasn't been written over yet
+    pub fn First(&self) -> u64 {
+        self.next.saturating_sub(RECORDS as u64)
+    }
+    pub fn Next(&self) -> u64 {
+        self.next
+    }
+    pub fn Get(&self, seq: u64) -> Option<&LogRecord> {
+        if seq < self.First() || seq >= self.next {
+            return None;
+        }
+        Some(&self.records[(seq % RECORDS as u64) as usize])
+    }
+}
+
+pub static LOG: Mutex<LogBuffer> = Mutex::new(LogBuffer {records: [LogRecord::EMPTY; RECORDS], next: 0});
+
+// Records at or below this get printed, --loglevel
+static CONSOLE_LEVEL: AtomicU8 = AtomicU8::new(LOG_DEBUG);
+// Module path prefixes and the most verbose level recorded for them at all, --logfilter
+static FILTERS: Once<Vec<(&'static str, u8)>> = Once::new();
+
+pub fn Priority(level: log::Level) -> u8 {
+    match level {
+        log::Level::Error => LOG_ERR,
+        log::Level::Warn => LOG_WARNING,
+        log::Level::Info => LOG_INFO,
+        log::Level::Debug | log::Level::Trace => LOG_DEBUG,
+    }
+}
+
+// Takes either the number or the name
+pub fn ParseLevel(s: &str) -> Option<u8> {
+    if let Ok(n) = s.parse::<u8>() {
+        return if n <= LOG_DEBUG {Some(n)} else {None};
+    }
+    match s {
+        "error" => Some(LOG_ERR),
+        "warning" => Some(LOG_WARNING),
+        _ => LEVEL_NAMES.iter().position(|n| *n == s).map(|n| n as u8),
+    }
+}
+
+// Module paths come in as foxkernel::Drivers::Generic::USB, filters are written without the crate
+fn StripCrate(module: &str) -> &str {
+    module.split_once("::").map_or(module,|m| m.1)
+}
+
+// Has to run after the command line is parsed, everything before that is recorded and printed
+pub fn Configure() {
+    let options = crate::CommandLine::OPTIONS.get().unwrap();
+    if let Some(level) = options.get("--loglevel") {
+        match ParseLevel(level) {
+            Some(l) => CONSOLE_LEVEL.store(l,Ordering::SeqCst),
+            None => log::warn!("Unknown log level \"{}\"", level),
+        }
+    }
+    let mut filters = Vec::new();
+    if let Some(list) = options.get("--logfilter") {
+        for filter in list.split(',').filter(|f| !f.is_empty()) {
+            match filter.rsplit_once(':').and_then(|(module, level)| Some((module, ParseLevel(level)?))) {
+                Some(f) => filters.push(f),
+                None => log::warn!("Ignoring malformed log filter \"{}\"", filter),
+            }
+        }
+    }
+    FILTERS.call_once(|| filters);
+}
+
+// Whether a record from this module at this level gets recorded. The longest matching filter wins.
+pub fn Enabled(level: u8, module: &str) -> bool {
+    let module = StripCrate(module);
+    let mut best: Option<(usize, u8)> = None;
+    for (prefix, max) in FILTERS.get().map_or(&[][..],|f| f.as_slice()) {
+        let matches = module == *prefix || (module.starts_with(prefix) && module[prefix.len()..].starts_with("::"));
+        if matches && best.map_or(true,|b| prefix.len() > b.0) {
+            best = Some((prefix.len(),*max));
+        }
+    }
+    level <= best.map_or(LOG_DEBUG,|b| b.1)
+}
+
+pub fn Printable(level: u8) -> bool {
+    level <= CONSOLE_LEVEL.load(Ordering::Relaxed)
+}
+
+// GS isn't set up until the GDT is, and the allocator logs before that
+fn Hart() -> u32 {
+    if x86_64::registers::model_specific::KernelGsBase::read().as_u64() == 0 {0} else {crate::arch::CurrentHart()}
+}
+
+pub fn Record(level: u8, module: &str, args: core::fmt::Arguments) -> u64 {
+    let time = crate::arch::Timer::GetMicroseconds();
+    let hart = Hart();
+    // Interrupt handlers log too, and one landing while we hold this would never get it
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut lock = LOG.lock();
+        let seq = lock.next;
+        let record = &mut lock.records[(seq % RECORDS as u64) as usize];
+        record.seq = seq;
+        record.time = time;
+        record.level = level;
+        record.hart = hart;
+        let mut module_buf = Truncate {buf: &mut record.module, len: 0};
+        module_buf.write_str(StripCrate(module));
+        record.module_len = module_buf.len as u8;
+        let mut text_buf = Truncate {buf: &mut record.text, len: 0};
+        text_buf.write_fmt(args);
+        record.text_len = text_buf.len as u8;
+        lock.next += 1;
+        seq
+    })
+}
+
+// A message from userspace, through /dev/kmsg or foxkernel_log. A leading "<N>" picks the priority like it does on Linux,
+// the facility bits above it are ignored.
+pub fn WriteUser(name: &str, msg: &str) {
+    let mut level = LOG_WARNING;
+    let mut text = msg;
+    if let Some(rest) = msg.strip_prefix('<') {
+        if let Some((prio, rest)) = rest.split_once('>') {
+            if let Ok(prio) = prio.parse::<u32>() {
+                level = (prio & 7) as u8;
+                text = rest;
+            }
+        }
+    }
+    let text = text.strip_suffix('\n').unwrap_or(text);
+    if text.is_empty() {
+        return;
+    }
+    Record(level,name,format_args!("{}",text));
+    if Printable(level) {
+        crate::print!("{}: {}\n", name, text);
+    }
+}
+
+// Runs f over the newest count records, oldest first. Gives up instead of spinning if the log is locked, since this
+// runs when something has already gone wrong and the hart holding it may never let go.
+pub fn Tail(count: usize, mut f: impl FnMut(&LogRecord)) -> bool {
+    let lock = match LOG.try_lock() {
+        Some(l) => l,
+        None => return false,
+    };
+    let start = lock.Next().saturating_sub(count as u64).max(lock.First());
+    for seq in start..lock.Next() {
+        f(lock.Get(seq).unwrap());
+    }
+    true
+}
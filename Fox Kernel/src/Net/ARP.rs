@@ -0,0 +1,136 @@
+use spin::Mutex;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use alloc::collections::BTreeMap;
+use super::IPInterface;
+
+const ARP_REQUEST: u16 = 1;
+const ARP_REPLY: u16 = 2;
+
+const ENTRY_LIFETIME: u64 = 300000; // 5 minutes
+const RETRY_INTERVAL: u64 = 1000;
+const RESOLVE_TIMEOUT: u64 = 3000;
+const PENDING_LIMIT: usize = 16;
+
+struct ARPEntry {
+    ipif: Arc<IPInterface>,
+    mac: Option<[u8; 6]>,
+    updated: u64,
+    requested: u64,
+    pending: Vec<Vec<u8>>, // IPv4 packets waiting for this entry to resolve
+}
+
+static CACHE: Mutex<BTreeMap<u32,ARPEntry>> = Mutex::new(BTreeMap::new());
+
+fn SendPacket(ipif: &IPInterface, op: u16, dst_mac: [u8; 6], target_mac: [u8; 6], target: u32) {
+    let mut packet = Vec::with_capacity(28);
+    packet.extend_from_slice(&1u16.to_be_bytes()); // Ethernet
+    packet.extend_from_slice(&super::ETHERTYPE_IPV4.to_be_bytes());
+    packet.push(6);
+    packet.push(4);
+    packet.extend_from_slice(&op.to_be_bytes());
+    packet.extend_from_slice(&ipif.iface.device.MACAddress());
+    packet.extend_from_slice(&ipif.address.to_be_bytes());
+    packet.extend_from_slice(&target_mac);
+    packet.extend_from_slice(&target.to_be_bytes());
+    super::SendFrame(ipif,dst_mac,super::ETHERTYPE_ARP,packet.as_slice());
+}
+
+// Sends an IPv4 packet to nexthop, holding onto it until the address resolves if we don't know the MAC yet.
+pub fn Resolve(ipif: &Arc<IPInterface>, nexthop: u32, packet: Vec<u8>) {
+    if ipif.loopback {
+        super::SendFrame(ipif,[0; 6],super::ETHERTYPE_IPV4,packet.as_slice());
+        return;
+    }
+    if nexthop == u32::MAX || nexthop == ipif.Broadcast() {
+        super::SendFrame(ipif,super::BROADCAST_MAC,super::ETHERTYPE_IPV4,packet.as_slice());
+        return;
+    }
+    let now = super::Now();
+    let mut cache = CACHE.lock();
+    if let Some(entry) = cache.get_mut(&nexthop) {
+        if let Some(mac) = entry.mac {
+            drop(cache);
+            super::SendFrame(ipif,mac,super::ETHERTYPE_IPV4,packet.as_slice());
+            return;
+        }
+        if entry.pending.len() < PENDING_LIMIT {
+            entry.pending.push(packet);
+        }
+        return;
+    }
+    cache.insert(nexthop,ARPEntry {
+        ipif: ipif.clone(),
+        mac: None,
+        updated: now,
+        requested: now,
+        pending: alloc::vec![packet],
+    });
+    drop(cache);
+    SendPacket(ipif,ARP_REQUEST,super::BROADCAST_MAC,[0; 6],nexthop);
+}
+
+pub fn Receive(ipif: &Arc<IPInterface>, packet: &[u8]) {
+    if ipif.loopback || packet.len() < 28 {
+        return;
+    }
+    let htype = u16::from_be_bytes([packet[0],packet[1]]);
+    let ptype = u16::from_be_bytes([packet[2],packet[3]]);
+    if htype != 1 || ptype != super::ETHERTYPE_IPV4 || packet[4] != 6 || packet[5] != 4 {
+        return;
+    }
+    let op = u16::from_be_bytes([packet[6],packet[7]]);
+    let mut sender_mac = [0u8; 6];
+    sender_mac.copy_from_slice(&packet[8..14]);
+    let sender = u32::from_be_bytes([packet[14],packet[15],packet[16],packet[17]]);
+    let target = u32::from_be_bytes([packet[24],packet[25],packet[26],packet[27]]);
+    let for_us = target == ipif.address;
+    // RFC 826: refresh the entry if we already have one, only create it when the packet was aimed at us
+    let mut flush = Vec::new();
+    let mut cache = CACHE.lock();
+    if let Some(entry) = cache.get_mut(&sender) {
+        entry.mac = Some(sender_mac);
+        entry.updated = super::Now();
+        flush = core::mem::take(&mut entry.pending);
+    } else if for_us && sender != 0 {
+        cache.insert(sender,ARPEntry {
+            ipif: ipif.clone(),
+            mac: Some(sender_mac),
+            updated: super::Now(),
+            requested: 0,
+            pending: Vec::new(),
+        });
+    }
+    drop(cache);
+    for p in flush {
+        super::SendFrame(ipif,sender_mac,super::ETHERTYPE_IPV4,p.as_slice());
+    }
+    if for_us && op == ARP_REQUEST {
+        SendPacket(ipif,ARP_REPLY,sender_mac,sender_mac,sender);
+    }
+}
+
+pub fn Timer(now: u64) {
+    let mut retry = Vec::new();
+    let mut cache = CACHE.lock();
+    cache.retain(|addr, entry| {
+        match entry.mac {
+            Some(_) => now.saturating_sub(entry.updated) < ENTRY_LIFETIME,
+            None if now.saturating_sub(entry.updated) >= RESOLVE_TIMEOUT => {
+                log::debug!("{}: Couldn't resolve {}, dropping {} packet(s)", entry.ipif.iface.name, super::FormatAddress(*addr), entry.pending.len());
+                false
+            }
+            None => {
+                if now.saturating_sub(entry.requested) >= RETRY_INTERVAL {
+                    entry.requested = now;
+                    retry.push((entry.ipif.clone(),*addr));
+                }
+                true
+            }
+        }
+    });
+    drop(cache);
+    for (ipif, addr) in retry {
+        SendPacket(&ipif,ARP_REQUEST,super::BROADCAST_MAC,[0; 6],addr);
+    }
+}
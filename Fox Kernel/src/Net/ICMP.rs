@@ -0,0 +1,58 @@
+use alloc::vec::Vec;
+use super::IPv4;
+
+const ECHO_REPLY: u8 = 0;
+const DEST_UNREACHABLE: u8 = 3;
+const ECHO_REQUEST: u8 = 8;
+
+pub const CODE_PORT_UNREACHABLE: u8 = 3;
+
+pub fn Receive(src: u32, dst: u32, packet: &[u8]) {
+    if packet.len() < 8 || super::Checksum(packet) != 0 {
+        return;
+    }
+    match packet[0] {
+        ECHO_REQUEST => {
+            if super::IsBroadcast(dst) {
+                return;
+            }
+            let mut reply = packet.to_vec();
+            reply[0] = ECHO_REPLY;
+            reply[2] = 0;
+            reply[3] = 0;
+            let checksum = super::Checksum(reply.as_slice());
+            reply[2..4].copy_from_slice(&checksum.to_be_bytes());
+            let _ = IPv4::Send(dst,src,IPv4::PROTO_ICMP,reply.as_slice());
+        }
+        DEST_UNREACHABLE => {
+            // The offending packet's IP header plus at least 8 bytes of its payload are quoted after ours
+            let quoted = &packet[8..];
+            if quoted.len() < IPv4::HEADER_SIZE + 8 {
+                return;
+            }
+            let ihl = ((quoted[0] & 0xF) as usize) * 4;
+            if quoted.len() < ihl + 8 || quoted[9] != IPv4::PROTO_UDP {
+                return;
+            }
+            let orig_dst = u32::from_be_bytes([quoted[16],quoted[17],quoted[18],quoted[19]]);
+            let sport = u16::from_be_bytes([quoted[ihl],quoted[ihl+1]]);
+            let dport = u16::from_be_bytes([quoted[ihl+2],quoted[ihl+3]]);
+            super::UDP::Unreachable(sport,orig_dst,dport);
+        }
+        _ => {}
+    }
+}
+
+// Tells src that the packet we just got from them had nowhere to go. ip_packet is the whole packet, header included.
+pub fn DestinationUnreachable(src: u32, dst: u32, code: u8, ip_packet: &[u8]) {
+    if super::IsBroadcast(dst) {
+        return;
+    }
+    let quote = if ip_packet.len() > 548 {&ip_packet[..548]} else {ip_packet};
+    let mut packet = Vec::with_capacity(8 + quote.len());
+    packet.extend_from_slice(&[DEST_UNREACHABLE,code,0,0,0,0,0,0]);
+    packet.extend_from_slice(quote);
+    let checksum = super::Checksum(packet.as_slice());
+    packet[2..4].copy_from_slice(&checksum.to_be_bytes());
+    let _ = IPv4::Send(dst,src,IPv4::PROTO_ICMP,packet.as_slice());
+}
@@ -0,0 +1,88 @@
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU16,Ordering};
+use crate::Syscall::Errors;
+use super::IPInterface;
+
+pub const PROTO_ICMP: u8 = 1;
+pub const PROTO_TCP: u8 = 6;
+pub const PROTO_UDP: u8 = 17;
+
+pub const HEADER_SIZE: usize = 20;
+const DEFAULT_TTL: u8 = 64;
+const FLAG_DF: u16 = 0x4000;
+const FLAG_MF: u16 = 0x2000;
+
+static NEXT_ID: AtomicU16 = AtomicU16::new(1);
+
+// The address we'd use as the source when talking to dst, None if there's no route to it.
+pub fn SourceAddress(dst: u32) -> Option<u32> {
+    let (ipif, _) = super::Route(dst)?;
+    if ipif.loopback {
+        return Some(if dst >> 24 == 127 {dst} else {super::IP_INTERFACES.lock().iter().find(|i| i.address == dst).map_or(ipif.address,|i| i.address)});
+    }
+    Some(ipif.address)
+}
+
+// Largest payload that fits in a single packet towards dst, since we never fragment.
+pub fn MaxPayload(dst: u32) -> Option<usize> {
+    super::Route(dst).map(|(ipif, _)| ipif.MTU() - HEADER_SIZE)
+}
+
+pub fn Send(src: u32, dst: u32, protocol: u8, payload: &[u8]) -> Result<(),i32> {
+    let (ipif, nexthop) = match super::Route(dst) {
+        Some(r) => r,
+        None => {return Err(if super::IP_INTERFACES.lock().iter().any(|i| !i.loopback) {Errors::EHOSTUNREACH} else {Errors::ENETUNREACH});}
+    };
+    if HEADER_SIZE + payload.len() > ipif.MTU() {
+        return Err(Errors::EMSGSIZE);
+    }
+    let src = if src == 0 {ipif.address} else {src};
+    let mut packet = Vec::with_capacity(HEADER_SIZE + payload.len());
+    packet.push(0x45);
+    packet.push(0);
+    packet.extend_from_slice(&((HEADER_SIZE + payload.len()) as u16).to_be_bytes());
+    packet.extend_from_slice(&NEXT_ID.fetch_add(1,Ordering::Relaxed).to_be_bytes());
+    packet.extend_from_slice(&FLAG_DF.to_be_bytes());
+    packet.push(DEFAULT_TTL);
+    packet.push(protocol);
+    packet.extend_from_slice(&[0,0]);
+    packet.extend_from_slice(&src.to_be_bytes());
+    packet.extend_from_slice(&dst.to_be_bytes());
+    let checksum = super::Checksum(&packet[..HEADER_SIZE]);
+    packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+    packet.extend_from_slice(payload);
+    super::ARP::Resolve(&ipif,nexthop,packet);
+    Ok(())
+}
+
+pub fn Receive(ipif: &Arc<IPInterface>, packet: &[u8]) {
+    if packet.len() < HEADER_SIZE || packet[0] >> 4 != 4 {
+        return;
+    }
+    let ihl = ((packet[0] & 0xF) as usize) * 4;
+    let total = u16::from_be_bytes([packet[2],packet[3]]) as usize;
+    if ihl < HEADER_SIZE || total < ihl || total > packet.len() {
+        return;
+    }
+    if super::Checksum(&packet[..ihl]) != 0 {
+        return;
+    }
+    let fragment = u16::from_be_bytes([packet[6],packet[7]]);
+    if fragment & FLAG_MF != 0 || fragment & 0x1FFF != 0 {
+        // Reassembly isn't supported, and everything we send has DF set so peers shouldn't need it.
+        return;
+    }
+    let src = u32::from_be_bytes([packet[12],packet[13],packet[14],packet[15]]);
+    let dst = u32::from_be_bytes([packet[16],packet[17],packet[18],packet[19]]);
+    if dst != ipif.address && !super::IsLocal(dst) && !super::IsBroadcast(dst) {
+        return;
+    }
+    let payload = &packet[ihl..total];
+    match packet[9] {
+        PROTO_ICMP => super::ICMP::Receive(src,dst,payload),
+        PROTO_UDP => super::UDP::Receive(src,dst,payload,&packet[..total]),
+        PROTO_TCP => super::TCP::Receive(src,dst,payload),
+        _ => {}
+    }
+}
@@ -0,0 +1,46 @@
+use spin::Mutex;
+use alloc::vec::Vec;
+use alloc::collections::VecDeque;
+use crate::Drivers::Generic::NetworkDevice;
+
+const LOOPBACK_MTU: usize = 16384;
+const LOOPBACK_QUEUE: usize = 512;
+
+// A fake NIC that hands everything it transmits straight back to the receive side on the next poll.
+pub struct LoopbackDevice {
+    queue: Mutex<VecDeque<Vec<u8>>>,
+}
+
+impl LoopbackDevice {
+    pub fn new() -> Self {
+        Self {
+            queue: Mutex::new(VecDeque::new()),
+        }
+    }
+}
+
+impl NetworkDevice::NetworkDevice for LoopbackDevice {
+    fn MACAddress(&self) -> [u8; 6] {
+        [0; 6]
+    }
+    fn MTU(&self) -> usize {
+        LOOPBACK_MTU
+    }
+    fn LinkUp(&self) -> bool {
+        true
+    }
+    fn Transmit(&self, frame: &[u8]) -> bool {
+        let mut queue = self.queue.lock();
+        if queue.len() >= LOOPBACK_QUEUE {
+            return false;
+        }
+        queue.push_back(frame.to_vec());
+        true
+    }
+    fn Poll(&self, iface: &NetworkDevice::NetworkInterface) {
+        let frames: Vec<Vec<u8>> = self.queue.lock().drain(..).collect();
+        for frame in frames {
+            iface.Deliver(frame);
+        }
+    }
+}
@@ -0,0 +1,473 @@
+use spin::Mutex;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool,Ordering};
+use crate::FS::VFS;
+use crate::Syscall::Errors;
+use crate::Drivers::Generic::Epoll::Watchers;
+use super::{TCP,UDP,UNIX};
+
+// These follow mlibc's ABI, not Linux's
+pub const AF_INET: usize = 1;
+pub const AF_UNIX: usize = 4;
+pub const SOCK_DGRAM: usize = 1;
+pub const SOCK_STREAM: usize = 4;
+pub const SOCK_NONBLOCK: usize = 0x10000;
+pub const SOCK_CLOEXEC: usize = 0x20000;
+
+pub const SOL_SOCKET: usize = 1;
+pub const SO_BROADCAST: usize = 2;
+pub const SO_ERROR: usize = 5;
+pub const SO_KEEPALIVE: usize = 6;
+pub const SO_RCVBUF: usize = 9;
+pub const SO_REUSEADDR: usize = 12;
+pub const SO_SNDBUF: usize = 13;
+pub const SO_TYPE: usize = 16;
+pub const SO_PEERCRED: usize = 18;
+pub const SO_PASSCRED: usize = 20;
+pub const IPPROTO_TCP: usize = 6;
+pub const IPPROTO_UDP: usize = 17;
+pub const TCP_NODELAY: usize = 1;
+
+pub const SHUT_RD: usize = 1;
+pub const SHUT_RDWR: usize = 2;
+pub const SHUT_WR: usize = 3;
+
+pub const SCM_RIGHTS: usize = 1;
+
+pub const MSG_CTRUNC: usize = 0x1;
+pub const MSG_PEEK: usize = 0x20;
+pub const MSG_TRUNC: usize = 0x40;
+
+const FIONREAD: usize = 0x541B;
+const FIONBIO: usize = 0x5421;
+
+const SOCKADDR_IN_SIZE: usize = 16;
+const MIN_BUFFER: usize = 2048;
+const MAX_BUFFER: usize = 1024 * 1024;
+const MAX_BACKLOG: usize = 128;
+
+pub enum Protocol {
+    TCP(Arc<Mutex<TCP::TCB>>),
+    UDP(Arc<Mutex<UDP::UDPSocket>>),
+    UNIX(Arc<Mutex<UNIX::UNIXSocket>>),
+}
+
+pub struct Socket {
+    pub protocol: Protocol,
+    nonblocking: AtomicBool,
+    connecting: AtomicBool,
+}
+
+pub fn ParseSockAddr(addr: &[u8]) -> Result<(u32,u16),i32> {
+    if addr.len() < 8 {
+        return Err(Errors::EINVAL);
+    }
+    if u16::from_ne_bytes([addr[0],addr[1]]) as usize != AF_INET {
+        return Err(Errors::EAFNOSUPPORT);
+    }
+    Ok((u32::from_be_bytes([addr[4],addr[5],addr[6],addr[7]]),u16::from_be_bytes([addr[2],addr[3]])))
+}
+
+pub fn EncodeSockAddr(addr: u32, port: u16) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(SOCKADDR_IN_SIZE);
+    buf.extend_from_slice(&(AF_INET as u16).to_ne_bytes());
+    buf.extend_from_slice(&port.to_be_bytes());
+    buf.extend_from_slice(&addr.to_be_bytes());
+    buf.resize(SOCKADDR_IN_SIZE,0);
+    buf
+}
+
+impl Socket {
+    // cred is who UNIX sockets report as their owner through SO_PEERCRED
+    pub fn new(domain: usize, kind: usize, protocol: usize, cred: UNIX::Credentials) -> Result<Arc<Socket>,i32> {
+        let proto = match (domain, kind & !(SOCK_NONBLOCK | SOCK_CLOEXEC)) {
+            (AF_INET, SOCK_STREAM) if protocol == 0 || protocol == IPPROTO_TCP => Protocol::TCP(Arc::new(Mutex::new(TCP::TCB::new()))),
+            (AF_INET, SOCK_DGRAM) if protocol == 0 || protocol == IPPROTO_UDP => Protocol::UDP(Arc::new(Mutex::new(UDP::UDPSocket::new()))),
+            (AF_UNIX, SOCK_STREAM) | (AF_UNIX, SOCK_DGRAM) if protocol == 0 => Protocol::UNIX(Arc::new(Mutex::new(UNIX::UNIXSocket::new(kind & !(SOCK_NONBLOCK | SOCK_CLOEXEC) == SOCK_STREAM,cred)))),
+            (AF_INET, SOCK_STREAM) | (AF_INET, SOCK_DGRAM) | (AF_UNIX, SOCK_STREAM) | (AF_UNIX, SOCK_DGRAM) => {return Err(Errors::EPROTONOSUPPORT);}
+            (AF_INET, _) | (AF_UNIX, _) => {return Err(Errors::EPROTOTYPE);}
+            _ => {return Err(Errors::EAFNOSUPPORT);}
+        };
+        Ok(Socket::FromProtocol(proto,kind & SOCK_NONBLOCK != 0))
+    }
+
+    // socketpair(), which only makes sense for UNIX sockets
+    pub fn Pair(domain: usize, kind: usize, cred: UNIX::Credentials) -> Result<(Arc<Socket>,Arc<Socket>),i32> {
+        if domain != AF_UNIX {
+            return Err(if domain == AF_INET {Errors::EOPNOTSUPP} else {Errors::EAFNOSUPPORT});
+        }
+        let stream = match kind & !(SOCK_NONBLOCK | SOCK_CLOEXEC) {
+            SOCK_STREAM => true,
+            SOCK_DGRAM => false,
+            _ => {return Err(Errors::EPROTOTYPE);}
+        };
+        let (a, b) = UNIX::Pair(stream,cred);
+        let nonblocking = kind & SOCK_NONBLOCK != 0;
+        Ok((Socket::FromProtocol(Protocol::UNIX(a),nonblocking),Socket::FromProtocol(Protocol::UNIX(b),nonblocking)))
+    }
+
+    fn FromProtocol(protocol: Protocol, nonblocking: bool) -> Arc<Socket> {
+        Arc::new(Socket {
+            protocol,
+            nonblocking: AtomicBool::new(nonblocking),
+            connecting: AtomicBool::new(false),
+        })
+    }
+
+    pub fn Bind(&self, addr: &[u8], caller: &UNIX::Caller) -> Result<(),i32> {
+        if let Protocol::UNIX(arc) = &self.protocol {
+            return UNIX::Bind(arc,&mut arc.lock(),caller,UNIX::ParsePath(addr)?);
+        }
+        let (addr, port) = ParseSockAddr(addr)?;
+        match &self.protocol {
+            Protocol::TCP(arc) => TCP::Bind(arc,&mut arc.lock(),addr,port),
+            Protocol::UDP(arc) => UDP::Bind(arc,&mut arc.lock(),addr,port),
+            Protocol::UNIX(_) => unreachable!(),
+        }
+    }
+
+    pub fn Listen(&self, backlog: usize) -> Result<(),i32> {
+        let backlog = backlog.clamp(1,MAX_BACKLOG);
+        match &self.protocol {
+            Protocol::TCP(arc) => TCP::Listen(arc,&mut arc.lock(),backlog),
+            Protocol::UDP(_) => Err(Errors::EOPNOTSUPP),
+            Protocol::UNIX(arc) => UNIX::Listen(&mut arc.lock(),backlog),
+        }
+    }
+
+    pub fn Accept(&self) -> Result<(Arc<Socket>,Vec<u8>),i32> {
+        super::Poll();
+        match &self.protocol {
+            Protocol::TCP(arc) => {
+                let mut tcb = arc.lock();
+                if tcb.state != TCP::TCPState::Listen {
+                    return Err(Errors::EINVAL);
+                }
+                match tcb.backlog.pop_front() {
+                    Some(child) => {
+                        drop(tcb);
+                        let remote = child.lock().remote;
+                        Ok((Socket::FromProtocol(Protocol::TCP(child),false),EncodeSockAddr(remote.0,remote.1)))
+                    }
+                    None => Err(Errors::EAGAIN),
+                }
+            }
+            Protocol::UDP(_) => Err(Errors::EOPNOTSUPP),
+            Protocol::UNIX(arc) => {
+                let child = UNIX::Accept(&mut arc.lock())?;
+                let sock = Socket::FromProtocol(Protocol::UNIX(child),false);
+                let addr = sock.PeerAddress().unwrap_or_else(|_| UNIX::EncodePath(None));
+                Ok((sock,addr))
+            }
+        }
+    }
+
+    pub fn NonBlocking(&self) -> bool {
+        self.nonblocking.load(Ordering::SeqCst)
+    }
+
+    // Blocking callers get EAGAIN until the handshake finishes and wait in the system call for it, nonblocking ones
+    // get EINPROGRESS once and then poll with SO_ERROR or another connect().
+    pub fn Connect(&self, addr: &[u8], caller: &UNIX::Caller) -> Result<(),i32> {
+        if let Protocol::UNIX(arc) = &self.protocol {
+            return UNIX::Connect(arc,caller,UNIX::ParsePath(addr)?);
+        }
+        let (addr, port) = ParseSockAddr(addr)?;
+        let result = match &self.protocol {
+            Protocol::TCP(arc) => {
+                let mut tcb = arc.lock();
+                if self.connecting.load(Ordering::SeqCst) {
+                    match tcb.state {
+                        TCP::TCPState::SynSent | TCP::TCPState::SynReceived => {
+                            return Err(if self.nonblocking.load(Ordering::SeqCst) {Errors::EALREADY} else {Errors::EAGAIN});
+                        }
+                        _ => {
+                            self.connecting.store(false,Ordering::SeqCst);
+                            return match tcb.error.take() {
+                                Some(e) => Err(e),
+                                None => Ok(()),
+                            };
+                        }
+                    }
+                }
+                TCP::Connect(arc,&mut tcb,addr,port)?;
+                self.connecting.store(true,Ordering::SeqCst);
+                Err(if self.nonblocking.load(Ordering::SeqCst) {Errors::EINPROGRESS} else {Errors::EAGAIN})
+            }
+            Protocol::UDP(arc) => {
+                let mut sock = arc.lock();
+                if sock.local.1 == 0 {
+                    UDP::Bind(arc,&mut sock,0,0)?;
+                }
+                sock.remote = if addr == 0 && port == 0 {None} else {Some((addr,port))};
+                Ok(())
+            }
+            Protocol::UNIX(_) => unreachable!(),
+        };
+        super::Poll();
+        result
+    }
+
+    // Only UNIX sockets can carry descriptors, and only they need the caller to resolve a destination address
+    pub fn SendTo(&self, data: &[u8], addr: Option<&[u8]>, caller: Option<&UNIX::Caller>, fds: Vec<UNIX::InFlight>) -> Result<usize,i32> {
+        if let Protocol::UNIX(arc) = &self.protocol {
+            let target = match (addr, caller) {
+                (Some(a), Some(c)) => Some(UNIX::Lookup(c,UNIX::ParsePath(a)?)?),
+                (Some(_), None) => {return Err(Errors::EINVAL);}
+                (None, _) => None,
+            };
+            return UNIX::Send(arc,data,fds,target);
+        }
+        if !fds.is_empty() {
+            return Err(Errors::EOPNOTSUPP);
+        }
+        let result = match &self.protocol {
+            Protocol::TCP(arc) => {
+                if addr.is_some() {
+                    return Err(Errors::EISCONN);
+                }
+                TCP::Send(&mut arc.lock(),data)
+            }
+            Protocol::UDP(arc) => {
+                let mut sock = arc.lock();
+                if let Some(e) = sock.error.take() {
+                    return Err(e);
+                }
+                let (dst, port) = match addr {
+                    Some(a) => ParseSockAddr(a)?,
+                    None => match sock.remote {
+                        Some(r) => r,
+                        None => {return Err(Errors::EDESTADDRREQ);}
+                    }
+                };
+                if sock.local.1 == 0 {
+                    UDP::Bind(arc,&mut sock,0,0)?;
+                }
+                UDP::SendTo(&sock,dst,port,data)
+            }
+            Protocol::UNIX(_) => unreachable!(),
+        };
+        super::Poll();
+        result
+    }
+
+    // Returns the number of bytes received along with the sender's address, for protocols that have one per message.
+    // Any descriptors that came along are dropped, recvmsg() is the only way to get at those.
+    pub fn RecvFrom(&self, buffer: &mut [u8], flags: usize) -> Result<(usize,Option<Vec<u8>>),i32> {
+        self.RecvMsg(buffer,flags).map(|(len, addr, _, _)| (len,addr))
+    }
+
+    // Like RecvFrom, but also hands back passed descriptors and the MSG_* flags recvmsg() reports
+    pub fn RecvMsg(&self, buffer: &mut [u8], flags: usize) -> Result<(usize,Option<Vec<u8>>,Vec<UNIX::InFlight>,usize),i32> {
+        super::Poll();
+        let peek = flags & MSG_PEEK != 0;
+        match &self.protocol {
+            Protocol::TCP(arc) => TCP::Recv(&mut arc.lock(),buffer,peek).map(|len| (len,None,Vec::new(),0)),
+            Protocol::UNIX(arc) => {
+                let mut sock = arc.lock();
+                let received = UNIX::Recv(&mut sock,buffer,peek)?;
+                let addr = if sock.stream {None} else {Some(UNIX::EncodePath(received.from.as_deref()))};
+                drop(sock);
+                if !peek {
+                    UNIX::NotifySenders(arc);
+                }
+                Ok((received.length,addr,received.fds,if received.truncated {MSG_TRUNC} else {0}))
+            }
+            Protocol::UDP(arc) => {
+                let mut sock = arc.lock();
+                if let Some(e) = sock.error.take() {
+                    return Err(e);
+                }
+                let datagram = match sock.queue.front() {
+                    Some(d) => d,
+                    None => {return Err(Errors::EAGAIN);}
+                };
+                // Whatever doesn't fit is discarded with the rest of the datagram
+                let length = core::cmp::min(buffer.len(),datagram.data.len());
+                buffer[..length].copy_from_slice(&datagram.data[..length]);
+                let addr = EncodeSockAddr(datagram.src,datagram.port);
+                let flags = if length < datagram.data.len() {MSG_TRUNC} else {0};
+                if !peek {
+                    let datagram = sock.queue.pop_front().unwrap();
+                    sock.queued -= datagram.data.len();
+                }
+                Ok((length,Some(addr),Vec::new(),flags))
+            }
+        }
+    }
+
+    pub fn Shutdown(&self, how: usize) -> Result<(),i32> {
+        let (read, write) = match how {
+            SHUT_RD => (true,false),
+            SHUT_WR => (false,true),
+            SHUT_RDWR => (true,true),
+            _ => {return Err(Errors::EINVAL);}
+        };
+        let result = match &self.protocol {
+            Protocol::TCP(arc) => TCP::Shutdown(&mut arc.lock(),read,write),
+            Protocol::UDP(arc) => {
+                if arc.lock().remote.is_none() {Err(Errors::ENOTCONN)} else {Ok(())}
+            }
+            Protocol::UNIX(arc) => UNIX::Shutdown(arc,read,write),
+        };
+        super::Poll();
+        result
+    }
+
+    pub fn SetOption(&self, level: usize, name: usize, value: &[u8]) -> Result<(),i32> {
+        if value.len() < 4 {
+            return Err(Errors::EINVAL);
+        }
+        let val = i32::from_ne_bytes([value[0],value[1],value[2],value[3]]);
+        let size = (val.max(0) as usize * 2).clamp(MIN_BUFFER,MAX_BUFFER); // Linux doubles these too, for bookkeeping overhead
+        match (&self.protocol, level, name) {
+            (Protocol::TCP(arc), SOL_SOCKET, SO_REUSEADDR) => {arc.lock().reuse_addr = val != 0;}
+            (Protocol::TCP(arc), SOL_SOCKET, SO_RCVBUF) => {arc.lock().recv_limit = size;}
+            (Protocol::TCP(arc), SOL_SOCKET, SO_SNDBUF) => {arc.lock().send_limit = size;}
+            (Protocol::TCP(_), SOL_SOCKET, SO_KEEPALIVE) | (Protocol::TCP(_), IPPROTO_TCP, TCP_NODELAY) => {} // Segments always go out right away
+            (Protocol::UDP(_), SOL_SOCKET, SO_REUSEADDR) => {}
+            (Protocol::UDP(arc), SOL_SOCKET, SO_BROADCAST) => {arc.lock().broadcast = val != 0;}
+            (Protocol::UDP(arc), SOL_SOCKET, SO_RCVBUF) => {arc.lock().recv_buffer = size;}
+            (Protocol::UDP(_), SOL_SOCKET, SO_SNDBUF) => {}
+            (Protocol::UNIX(arc), SOL_SOCKET, SO_RCVBUF) => {arc.lock().recv_limit = size;}
+            (Protocol::UNIX(_), SOL_SOCKET, SO_SNDBUF) | (Protocol::UNIX(_), SOL_SOCKET, SO_REUSEADDR) | (Protocol::UNIX(_), SOL_SOCKET, SO_PASSCRED) => {}
+            _ => {return Err(Errors::ENOPROTOOPT);}
+        }
+        Ok(())
+    }
+
+    pub fn GetOption(&self, level: usize, name: usize) -> Result<Vec<u8>,i32> {
+        if let (Protocol::UNIX(arc), SOL_SOCKET, SO_PEERCRED) = (&self.protocol, level, name) {
+            // Linux hands out an invalid ucred rather than an error when there's no peer
+            let cred = arc.lock().peer_cred.unwrap_or(UNIX::Credentials {pid: 0, uid: u32::MAX, gid: u32::MAX});
+            return Ok(cred.Encode());
+        }
+        let val = match (&self.protocol, level, name) {
+            (Protocol::TCP(_), SOL_SOCKET, SO_TYPE) => SOCK_STREAM as i32,
+            (Protocol::UDP(_), SOL_SOCKET, SO_TYPE) => SOCK_DGRAM as i32,
+            (Protocol::TCP(arc), SOL_SOCKET, SO_ERROR) => arc.lock().error.take().unwrap_or(0),
+            (Protocol::UDP(arc), SOL_SOCKET, SO_ERROR) => arc.lock().error.take().unwrap_or(0),
+            (Protocol::TCP(arc), SOL_SOCKET, SO_REUSEADDR) => arc.lock().reuse_addr as i32,
+            (Protocol::TCP(arc), SOL_SOCKET, SO_RCVBUF) => arc.lock().recv_limit as i32,
+            (Protocol::TCP(arc), SOL_SOCKET, SO_SNDBUF) => arc.lock().send_limit as i32,
+            (Protocol::TCP(_), SOL_SOCKET, SO_KEEPALIVE) => 0,
+            (Protocol::TCP(_), IPPROTO_TCP, TCP_NODELAY) => 1,
+            (Protocol::UDP(arc), SOL_SOCKET, SO_BROADCAST) => arc.lock().broadcast as i32,
+            (Protocol::UDP(arc), SOL_SOCKET, SO_RCVBUF) => arc.lock().recv_buffer as i32,
+            (Protocol::UDP(_), SOL_SOCKET, SO_SNDBUF) => UDP::DEFAULT_RECV_BUFFER as i32,
+            (Protocol::UNIX(arc), SOL_SOCKET, SO_TYPE) => if arc.lock().stream {SOCK_STREAM as i32} else {SOCK_DGRAM as i32},
+            (Protocol::UNIX(_), SOL_SOCKET, SO_ERROR) | (Protocol::UNIX(_), SOL_SOCKET, SO_PASSCRED) => 0,
+            (Protocol::UNIX(arc), SOL_SOCKET, SO_RCVBUF) => arc.lock().recv_limit as i32,
+            (Protocol::UNIX(_), SOL_SOCKET, SO_SNDBUF) => UNIX::DEFAULT_BUFFER as i32,
+            _ => {return Err(Errors::ENOPROTOOPT);}
+        };
+        Ok(val.to_ne_bytes().to_vec())
+    }
+
+    pub fn LocalAddress(&self) -> Vec<u8> {
+        let (addr, port) = match &self.protocol {
+            Protocol::TCP(arc) => arc.lock().local,
+            Protocol::UDP(arc) => arc.lock().local,
+            Protocol::UNIX(arc) => {return UNIX::EncodePath(arc.lock().path.as_deref());}
+        };
+        EncodeSockAddr(addr,port)
+    }
+
+    pub fn PeerAddress(&self) -> Result<Vec<u8>,i32> {
+        let remote = match &self.protocol {
+            Protocol::TCP(arc) => {
+                let tcb = arc.lock();
+                if !tcb.connected || tcb.state == TCP::TCPState::Closed {None} else {Some(tcb.remote)}
+            }
+            Protocol::UDP(arc) => arc.lock().remote,
+            Protocol::UNIX(arc) => {return UNIX::PeerPath(arc).map(|p| UNIX::EncodePath(p.as_deref()));}
+        };
+        match remote {
+            Some((addr, port)) => Ok(EncodeSockAddr(addr,port)),
+            None => Err(Errors::ENOTCONN),
+        }
+    }
+}
+
+impl VFS::Inode for Socket {
+    fn Stat(&self) -> Result<VFS::Metadata, i64> {
+        Ok(VFS::Metadata {
+            device_id: 0,
+            inode_id: i64::MAX,
+            mode: 0o0140777, // srwxrwxrwx
+            nlinks: 1,
+            uid: 0,
+            gid: 0,
+            rdev: 0,
+            size: 0,
+            blksize: 0,
+            blocks: 0,
+
+            atime: unsafe {crate::UNIX_EPOCH as i64},
+            mtime: unsafe {crate::UNIX_EPOCH as i64},
+            ctime: unsafe {crate::UNIX_EPOCH as i64},
+            reserved1: 0,
+            reserved2: 0,
+            reserved3: 0,
+        })
+    }
+    fn GetName(&self) -> Result<&str, i64> {
+        Ok("[fox kernel socket]")
+    }
+    fn Read(&self, _offset: i64, buffer: &mut [u8]) -> i64 {
+        match self.RecvFrom(buffer,0) {
+            Ok((len, _)) => len as i64,
+            Err(e) => -(e as i64),
+        }
+    }
+    fn Write(&self, _offset: i64, buffer: &[u8]) -> i64 {
+        match self.SendTo(buffer,None,None,Vec::new()) {
+            Ok(len) => len as i64,
+            Err(e) => -(e as i64),
+        }
+    }
+    fn IOCtl(&self, cmd: usize, arg: usize) -> Result<usize, i64> {
+        match cmd {
+            FIONREAD => {
+                let available = match &self.protocol {
+                    Protocol::TCP(arc) => TCP::Available(&arc.lock()),
+                    Protocol::UDP(arc) => arc.lock().queue.front().map_or(0,|d| d.data.len()),
+                    Protocol::UNIX(arc) => UNIX::Available(&arc.lock()),
+                };
+                unsafe {*(arg as *mut i32) = available as i32;}
+                Ok(0)
+            }
+            FIONBIO => {
+                self.nonblocking.store(unsafe {*(arg as *const i32)} != 0,Ordering::SeqCst);
+                Ok(0)
+            }
+            _ => Err(Errors::ENOTTY as i64),
+        }
+    }
+    fn Poll(&self) -> i16 {
+        match &self.protocol {
+            Protocol::TCP(arc) => TCP::Poll(&arc.lock()),
+            Protocol::UDP(arc) => UDP::Poll(&arc.lock()),
+            Protocol::UNIX(arc) => UNIX::Poll(arc),
+        }
+    }
+    fn Watchers(&self) -> Option<Watchers> {
+        Some(match &self.protocol {
+            Protocol::TCP(arc) => arc.lock().watchers.clone(),
+            Protocol::UDP(arc) => arc.lock().watchers.clone(),
+            Protocol::UNIX(arc) => arc.lock().watchers.clone(),
+        })
+    }
+}
+
+impl Drop for Socket {
+    fn drop(&mut self) {
+        // UDP ports free themselves once the port map's weak reference can't be upgraded anymore
+        match &self.protocol {
+            Protocol::TCP(arc) => TCP::Close(arc),
+            Protocol::UNIX(arc) => UNIX::Close(arc),
+            Protocol::UDP(_) => {}
+        }
+    }
+}
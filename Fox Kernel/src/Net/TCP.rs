@@ -0,0 +1,1139 @@
+use spin::Mutex;
+use alloc::sync::{Arc,Weak};
+use alloc::vec::Vec;
+use alloc::collections::VecDeque;
+use core::sync::atomic::{AtomicU16,AtomicU32,AtomicUsize,Ordering};
+use crate::Syscall::Errors;
+use crate::FS::VFS;
+use crate::Drivers::Generic::Epoll::Watchers;
+use super::IPv4;
+
+const FLAG_FIN: u8 = 0x01;
+const FLAG_SYN: u8 = 0x02;
+const FLAG_RST: u8 = 0x04;
+const FLAG_PSH: u8 = 0x08;
+const FLAG_ACK: u8 = 0x10;
+
+const HEADER_SIZE: usize = 20;
+const OPTION_MSS: u8 = 2;
+const DEFAULT_MSS: usize = 536;
+
+pub const DEFAULT_BUFFER: usize = 65536;
+const MAX_WINDOW: usize = 65535; // No window scaling
+
+const INITIAL_RTO: u64 = 1000;
+const MIN_RTO: u64 = 200;
+const MAX_RTO: u64 = 60000;
+const MAX_RETRIES: u32 = 8;
+const TIME_WAIT: u64 = 4000;
+const ORPHAN_TIMEOUT: u64 = 30000; // How long a closed socket may sit in FIN_WAIT_2 waiting for the peer
+
+// Held by a connection until its handshake is over, so its listener knows how many it has half-open.
+// Dropping it (along with the connection, or once it's promoted) takes it back off the count.
+struct HalfOpen(Arc<AtomicUsize>);
+
+impl Drop for HalfOpen {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1,Ordering::SeqCst);
+    }
+}
+
+// Nearly every path through Receive() can change what Poll() says, so it just tells the watchers on the way out
+struct NotifyOnDrop(Watchers);
+
+impl Drop for NotifyOnDrop {
+    fn drop(&mut self) {
+        self.0.Notify();
+    }
+}
+
+#[derive(Clone,Copy,PartialEq,Eq,Debug)]
+pub enum TCPState {
+    Closed,
+    Listen,
+    SynSent,
+    SynReceived,
+    Established,
+    FinWait1,
+    FinWait2,
+    CloseWait,
+    Closing,
+    LastAck,
+    TimeWait,
+}
+
+// Sequence numbers wrap, so they're compared by the sign of their difference
+fn SeqLT(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+fn SeqLE(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) <= 0
+}
+fn SeqGT(a: u32, b: u32) -> bool {
+    SeqLT(b,a)
+}
+
+pub struct TCB {
+    pub state: TCPState,
+    pub local: (u32,u16),
+    pub remote: (u32,u16),
+    // Send side, send_buffer starts at snd_una (or right after our SYN while it's unacknowledged)
+    iss: u32,
+    snd_una: u32,
+    snd_nxt: u32,
+    snd_max: u32,
+    snd_wnd: u32,
+    snd_wl1: u32,
+    snd_wl2: u32,
+    send_buffer: VecDeque<u8>,
+    pub send_limit: usize,
+    fin_queued: bool,
+    fin_sent: bool,
+    fin_acked: bool,
+    mss: usize,
+    cwnd: usize,
+    ssthresh: usize,
+    dup_acks: u32,
+    // Receive side
+    rcv_nxt: u32,
+    recv_buffer: VecDeque<u8>,
+    pub recv_limit: usize,
+    last_window: usize,
+    pub fin_received: bool,
+    pub read_shutdown: bool,
+    // Timers
+    rto: u64,
+    srtt: u64,
+    rttvar: u64,
+    rtt_sample: Option<(u32,u64)>,
+    retransmit_at: Option<u64>,
+    retries: u32,
+    deadline: Option<u64>,
+    // Listening sockets keep their finished handshakes here until accept() picks them up
+    pub backlog: VecDeque<Arc<Mutex<TCB>>>,
+    pub backlog_limit: usize,
+    half_open: Arc<AtomicUsize>, // These count against the backlog too, or a SYN flood could grow the connection table forever
+    parent: Option<Weak<Mutex<TCB>>>,
+    handshake: Option<HalfOpen>,
+    pub error: Option<i32>,
+    pub orphaned: bool,
+    pub reuse_addr: bool,
+    pub connected: bool, // Set once the handshake completes, even after the connection closes again
+    pub watchers: Watchers,
+}
+
+struct Entry {
+    local: (u32,u16),
+    remote: (u32,u16),
+    listening: bool,
+    tcb: Arc<Mutex<TCB>>,
+}
+
+// Lock ordering: a TCB is always locked before this table, never the other way around.
+static CONNECTIONS: Mutex<Vec<Entry>> = Mutex::new(Vec::new());
+static NEXT_EPHEMERAL: AtomicU16 = AtomicU16::new(super::UDP::EPHEMERAL_START);
+static ISS_COUNTER: AtomicU32 = AtomicU32::new(0);
+
+fn GenerateISS() -> u32 {
+    // RFC 793's 4 microsecond clock, offset so back-to-back connections don't share a starting point
+    ((crate::arch::Timer::GetMicroseconds() / 4) as u32).wrapping_add(ISS_COUNTER.fetch_add(64000,Ordering::Relaxed))
+}
+
+fn BuildSegment(local: (u32,u16), remote: (u32,u16), seq: u32, ack: u32, flags: u8, window: u16, mss: Option<u16>, data: &[u8]) -> Vec<u8> {
+    let header = if mss.is_some() {HEADER_SIZE + 4} else {HEADER_SIZE};
+    let mut segment = Vec::with_capacity(header + data.len());
+    segment.extend_from_slice(&local.1.to_be_bytes());
+    segment.extend_from_slice(&remote.1.to_be_bytes());
+    segment.extend_from_slice(&seq.to_be_bytes());
+    segment.extend_from_slice(&ack.to_be_bytes());
+    segment.push(((header / 4) as u8) << 4);
+    segment.push(flags);
+    segment.extend_from_slice(&window.to_be_bytes());
+    segment.extend_from_slice(&[0,0,0,0]);
+    if let Some(mss) = mss {
+        segment.push(OPTION_MSS);
+        segment.push(4);
+        segment.extend_from_slice(&mss.to_be_bytes());
+    }
+    segment.extend_from_slice(data);
+    let checksum = super::ChecksumFinish(super::ChecksumAdd(super::PseudoHeaderSum(local.0,remote.0,IPv4::PROTO_TCP,segment.len()),segment.as_slice()));
+    segment[16..18].copy_from_slice(&checksum.to_be_bytes());
+    segment
+}
+
+fn SendReset(local: (u32,u16), remote: (u32,u16), seq: u32, ack: Option<u32>) {
+    let segment = match ack {
+        Some(ack) => BuildSegment(local,remote,seq,ack,FLAG_RST | FLAG_ACK,0,None,&[]),
+        None => BuildSegment(local,remote,seq,0,FLAG_RST,0,None,&[]),
+    };
+    let _ = IPv4::Send(local.0,remote.0,IPv4::PROTO_TCP,segment.as_slice());
+}
+
+impl TCB {
+    pub fn new() -> Self {
+        Self {
+            state: TCPState::Closed,
+            local: (0,0),
+            remote: (0,0),
+            iss: 0,
+            snd_una: 0,
+            snd_nxt: 0,
+            snd_max: 0,
+            snd_wnd: 0,
+            snd_wl1: 0,
+            snd_wl2: 0,
+            send_buffer: VecDeque::new(),
+            send_limit: DEFAULT_BUFFER,
+            fin_queued: false,
+            fin_sent: false,
+            fin_acked: false,
+            mss: DEFAULT_MSS,
+            cwnd: 0,
+            ssthresh: MAX_WINDOW,
+            dup_acks: 0,
+            rcv_nxt: 0,
+            recv_buffer: VecDeque::new(),
+            recv_limit: DEFAULT_BUFFER,
+            last_window: 0,
+            fin_received: false,
+            read_shutdown: false,
+            rto: INITIAL_RTO,
+            srtt: 0,
+            rttvar: 0,
+            rtt_sample: None,
+            retransmit_at: None,
+            retries: 0,
+            deadline: None,
+            backlog: VecDeque::new(),
+            backlog_limit: 0,
+            half_open: Arc::new(AtomicUsize::new(0)),
+            parent: None,
+            handshake: None,
+            error: None,
+            orphaned: false,
+            reuse_addr: false,
+            connected: false,
+            watchers: Watchers::new(),
+        }
+    }
+
+    fn HandshakeDone(&self) -> bool {
+        !matches!(self.state,TCPState::Closed | TCPState::Listen | TCPState::SynSent | TCPState::SynReceived)
+    }
+
+    // Sequence number of the first byte in send_buffer
+    fn DataStart(&self) -> u32 {
+        if self.HandshakeDone() {self.snd_una} else {self.iss.wrapping_add(1)}
+    }
+
+    fn InFlight(&self) -> usize {
+        self.snd_nxt.wrapping_sub(self.snd_una) as usize
+    }
+
+    fn RecvWindow(&self) -> usize {
+        let free = self.recv_limit.saturating_sub(self.recv_buffer.len());
+        if free > MAX_WINDOW {MAX_WINDOW} else {free}
+    }
+
+    fn OurMSS(&self) -> usize {
+        IPv4::MaxPayload(self.remote.0).map_or(DEFAULT_MSS,|p| p - HEADER_SIZE)
+    }
+
+    fn Transmit(&mut self, seq: u32, flags: u8, data: &[u8]) {
+        let window = self.RecvWindow();
+        self.last_window = window;
+        let mss = if flags & FLAG_SYN != 0 {Some(self.OurMSS() as u16)} else {None};
+        let segment = BuildSegment(self.local,self.remote,seq,self.rcv_nxt,flags,window as u16,mss,data);
+        let _ = IPv4::Send(self.local.0,self.remote.0,IPv4::PROTO_TCP,segment.as_slice());
+    }
+
+    fn SendAck(&mut self) {
+        let seq = self.snd_nxt;
+        self.Transmit(seq,FLAG_ACK,&[]);
+    }
+
+    fn SendSyn(&mut self) {
+        let iss = self.iss;
+        if self.state == TCPState::SynSent {
+            self.Transmit(iss,FLAG_SYN,&[]);
+        } else {
+            self.Transmit(iss,FLAG_SYN | FLAG_ACK,&[]);
+        }
+    }
+
+    fn Advance(&mut self, to: u32, now: u64) {
+        self.snd_nxt = to;
+        if SeqGT(to,self.snd_max) {
+            self.snd_max = to;
+            if self.rtt_sample.is_none() {
+                self.rtt_sample = Some((to,now));
+            }
+        }
+        if self.retransmit_at.is_none() {
+            self.retransmit_at = Some(now + self.rto);
+        }
+    }
+
+    // Sends whatever the peer's window and our congestion window allow. force sends a single byte
+    // past a closed window so we find out when it opens again.
+    fn Output(&mut self, now: u64, force: bool) {
+        if !self.HandshakeDone() || self.state == TCPState::TimeWait {
+            return;
+        }
+        let mut force = force;
+        loop {
+            let offset = self.snd_nxt.wrapping_sub(self.DataStart()) as usize;
+            let window = core::cmp::min(self.snd_wnd as usize,self.cwnd);
+            let unsent = self.send_buffer.len().saturating_sub(offset);
+            let mut length = core::cmp::min(core::cmp::min(window.saturating_sub(self.InFlight()),unsent),self.mss);
+            if length == 0 && force && unsent > 0 && self.InFlight() == 0 {
+                length = 1;
+            }
+            force = false;
+            if length > 0 {
+                let data: Vec<u8> = self.send_buffer.range(offset..offset+length).copied().collect();
+                let flags = if offset + length == self.send_buffer.len() {FLAG_ACK | FLAG_PSH} else {FLAG_ACK};
+                let seq = self.snd_nxt;
+                self.Transmit(seq,flags,data.as_slice());
+                self.Advance(seq.wrapping_add(length as u32),now);
+                continue;
+            }
+            if self.fin_queued && !self.fin_sent && unsent == 0 {
+                let seq = self.snd_nxt;
+                self.Transmit(seq,FLAG_FIN | FLAG_ACK,&[]);
+                self.fin_sent = true;
+                self.Advance(seq.wrapping_add(1),now);
+                match self.state {
+                    TCPState::Established => {self.state = TCPState::FinWait1;}
+                    TCPState::CloseWait => {self.state = TCPState::LastAck;}
+                    _ => {}
+                }
+            }
+            break;
+        }
+        if unsent_waiting(self) && self.InFlight() == 0 && self.retransmit_at.is_none() {
+            // Zero window, arm the timer so it turns into a probe
+            self.retransmit_at = Some(now + self.rto);
+        }
+        fn unsent_waiting(tcb: &TCB) -> bool {
+            tcb.send_buffer.len() > tcb.snd_nxt.wrapping_sub(tcb.DataStart()) as usize
+        }
+    }
+
+    fn Retransmit(&mut self, now: u64) {
+        self.retries += 1;
+        if self.retries > MAX_RETRIES {
+            self.Terminate(Some(Errors::ETIMEDOUT));
+            return;
+        }
+        self.rto = core::cmp::min(self.rto * 2,MAX_RTO);
+        self.rtt_sample = None; // Karn's algorithm
+        self.retransmit_at = Some(now + self.rto);
+        match self.state {
+            TCPState::SynSent | TCPState::SynReceived => {
+                self.SendSyn();
+                return;
+            }
+            _ => {}
+        }
+        let flight = self.InFlight();
+        if flight > 0 {
+            self.ssthresh = core::cmp::max(flight / 2,self.mss * 2);
+            self.cwnd = self.mss;
+        }
+        // Go back N, everything past snd_una gets sent again
+        self.snd_nxt = self.snd_una;
+        if self.fin_sent && !self.fin_acked {
+            self.fin_sent = false;
+        }
+        self.dup_acks = 0;
+        self.Output(now,true);
+    }
+
+    fn Terminate(&mut self, error: Option<i32>) {
+        if error.is_some() && self.error.is_none() {
+            self.error = error;
+        }
+        self.state = TCPState::Closed;
+        self.handshake = None;
+        self.retransmit_at = None;
+        self.deadline = None;
+        self.send_buffer.clear();
+    }
+
+    fn Abort(&mut self, error: Option<i32>) {
+        if matches!(self.state,TCPState::SynReceived | TCPState::Established | TCPState::FinWait1 | TCPState::FinWait2 | TCPState::CloseWait) {
+            SendReset(self.local,self.remote,self.snd_nxt,None);
+        }
+        self.Terminate(error);
+    }
+
+    fn UpdateRTT(&mut self, ack: u32, now: u64) {
+        if let Some((seq, sent)) = self.rtt_sample {
+            if SeqLE(seq,ack) {
+                // RFC 6298
+                let rtt = now.saturating_sub(sent);
+                if self.srtt == 0 {
+                    self.srtt = rtt.max(1);
+                    self.rttvar = rtt / 2;
+                } else {
+                    let delta = if self.srtt > rtt {self.srtt - rtt} else {rtt - self.srtt};
+                    self.rttvar = (3 * self.rttvar + delta) / 4;
+                    self.srtt = (7 * self.srtt + rtt) / 8;
+                }
+                self.rto = (self.srtt + core::cmp::max(4 * self.rttvar,10)).clamp(MIN_RTO,MAX_RTO);
+                self.rtt_sample = None;
+            }
+        }
+    }
+
+    fn HandshakeComplete(&mut self, ack: u32, window: u16, seq: u32) {
+        self.state = TCPState::Established;
+        self.connected = true;
+        self.snd_una = ack;
+        if SeqGT(ack,self.snd_nxt) {
+            self.snd_nxt = ack;
+        }
+        self.snd_wnd = window as u32;
+        self.snd_wl1 = seq;
+        self.snd_wl2 = ack;
+        self.cwnd = core::cmp::min(4 * self.mss,core::cmp::max(2 * self.mss,4380));
+        self.retries = 0;
+        self.retransmit_at = None;
+    }
+
+    // Runs the ACK half of RFC 793's segment arrival, returns false if the segment should be dropped.
+    fn ProcessAck(&mut self, seq: u32, ack: u32, window: u16, data_len: usize, now: u64) -> bool {
+        if SeqGT(ack,self.snd_max) {
+            self.SendAck();
+            return false;
+        }
+        if SeqGT(ack,self.snd_una) {
+            let acked = ack.wrapping_sub(self.snd_una) as usize;
+            let data = core::cmp::min(acked,self.send_buffer.len());
+            self.send_buffer.drain(..data);
+            if self.fin_sent && acked > data {
+                self.fin_acked = true;
+            }
+            self.snd_una = ack;
+            if SeqLT(self.snd_nxt,ack) {
+                self.snd_nxt = ack;
+            }
+            self.UpdateRTT(ack,now);
+            if self.dup_acks >= 3 {
+                self.cwnd = self.ssthresh; // Leaving fast recovery
+            } else if self.cwnd < self.ssthresh {
+                self.cwnd += core::cmp::min(acked,self.mss);
+            } else {
+                self.cwnd += core::cmp::max(self.mss * self.mss / self.cwnd,1);
+            }
+            self.dup_acks = 0;
+            self.retries = 0;
+            self.retransmit_at = if self.InFlight() > 0 {Some(now + self.rto)} else {None};
+        } else if ack == self.snd_una && data_len == 0 && window as u32 == self.snd_wnd && self.InFlight() > 0 {
+            self.dup_acks += 1;
+            if self.dup_acks == 3 {
+                // Fast retransmit of the segment the peer is missing
+                self.ssthresh = core::cmp::max(self.InFlight() / 2,self.mss * 2);
+                self.cwnd = self.ssthresh + 3 * self.mss;
+                let length = core::cmp::min(self.mss,self.send_buffer.len());
+                if length > 0 {
+                    let data: Vec<u8> = self.send_buffer.range(..length).copied().collect();
+                    let una = self.snd_una;
+                    self.Transmit(una,FLAG_ACK,data.as_slice());
+                    self.rtt_sample = None;
+                }
+            } else if self.dup_acks > 3 {
+                self.cwnd += self.mss;
+            }
+        }
+        if SeqLT(self.snd_wl1,seq) || (self.snd_wl1 == seq && SeqLE(self.snd_wl2,ack)) {
+            self.snd_wnd = window as u32;
+            self.snd_wl1 = seq;
+            self.snd_wl2 = ack;
+            if self.snd_wnd == 0 {
+                self.retries = 0; // The peer is answering our probes, it just has no room yet
+            }
+        }
+        if self.fin_acked {
+            match self.state {
+                TCPState::FinWait1 => {
+                    self.state = TCPState::FinWait2;
+                    if self.orphaned {
+                        self.deadline = Some(now + ORPHAN_TIMEOUT);
+                    }
+                }
+                TCPState::Closing => {
+                    self.state = TCPState::TimeWait;
+                    self.deadline = Some(now + TIME_WAIT);
+                }
+                TCPState::LastAck => {
+                    self.Terminate(None);
+                    return false;
+                }
+                _ => {}
+            }
+        }
+        true
+    }
+}
+
+fn ParseMSS(options: &[u8]) -> Option<usize> {
+    let mut i = 0;
+    while i < options.len() {
+        match options[i] {
+            0 => {break;}
+            1 => {i += 1;}
+            kind => {
+                if i + 1 >= options.len() || options[i+1] < 2 {
+                    break;
+                }
+                let len = options[i+1] as usize;
+                if kind == OPTION_MSS && len == 4 && i + 4 <= options.len() {
+                    return Some(u16::from_be_bytes([options[i+2],options[i+3]]) as usize);
+                }
+                i += len;
+            }
+        }
+    }
+    None
+}
+
+fn Remove(tcb: &Arc<Mutex<TCB>>) {
+    CONNECTIONS.lock().retain(|e| !Arc::ptr_eq(&e.tcb,tcb));
+}
+
+fn UpdateEntry(tcb: &Arc<Mutex<TCB>>, local: (u32,u16), remote: (u32,u16), listening: bool) {
+    let mut lock = CONNECTIONS.lock();
+    match lock.iter_mut().find(|e| Arc::ptr_eq(&e.tcb,tcb)) {
+        Some(e) => {
+            e.local = local;
+            e.remote = remote;
+            e.listening = listening;
+        }
+        None => {
+            lock.push(Entry {
+                local,
+                remote,
+                listening,
+                tcb: tcb.clone(),
+            });
+        }
+    }
+}
+
+fn Lookup(src: u32, sport: u16, dst: u32, dport: u16) -> Option<Arc<Mutex<TCB>>> {
+    let lock = CONNECTIONS.lock();
+    let local_match = |e: &&Entry| e.local.1 == dport && (e.local.0 == 0 || e.local.0 == dst);
+    if let Some(e) = lock.iter().filter(local_match).find(|e| !e.listening && e.remote == (src,sport)) {
+        return Some(e.tcb.clone());
+    }
+    lock.iter().filter(local_match).find(|e| e.listening).map(|e| e.tcb.clone())
+}
+
+// Callers hold the TCB's lock, the Arc is only needed for the connection table.
+pub fn Bind(arc: &Arc<Mutex<TCB>>, tcb: &mut TCB, addr: u32, port: u16) -> Result<(),i32> {
+    if tcb.local.1 != 0 || tcb.state != TCPState::Closed || tcb.connected {
+        return Err(Errors::EINVAL);
+    }
+    if addr != 0 && !super::IsLocal(addr) {
+        return Err(Errors::EADDRNOTAVAIL);
+    }
+    let mut lock = CONNECTIONS.lock();
+    let port = if port == 0 {
+        let mut found = None;
+        for _ in super::UDP::EPHEMERAL_START..=u16::MAX {
+            let candidate = NEXT_EPHEMERAL.fetch_add(1,Ordering::Relaxed);
+            if candidate < super::UDP::EPHEMERAL_START {
+                NEXT_EPHEMERAL.store(super::UDP::EPHEMERAL_START,Ordering::Relaxed);
+                continue;
+            }
+            if !lock.iter().any(|e| e.local.1 == candidate) {
+                found = Some(candidate);
+                break;
+            }
+        }
+        match found {
+            Some(p) => p,
+            None => {return Err(Errors::EADDRINUSE);}
+        }
+    } else {
+        // SO_REUSEADDR lets us share the port with connections that are still winding down, but never with a listener
+        let conflict = lock.iter().any(|e| e.local.1 == port && (e.local.0 == 0 || addr == 0 || e.local.0 == addr) && (!tcb.reuse_addr || e.listening || e.remote.1 == 0));
+        if conflict {
+            return Err(Errors::EADDRINUSE);
+        }
+        port
+    };
+    tcb.local = (addr,port);
+    lock.push(Entry {
+        local: tcb.local,
+        remote: (0,0),
+        listening: false,
+        tcb: arc.clone(),
+    });
+    Ok(())
+}
+
+pub fn Listen(arc: &Arc<Mutex<TCB>>, tcb: &mut TCB, backlog: usize) -> Result<(),i32> {
+    match tcb.state {
+        TCPState::Listen => {
+            tcb.backlog_limit = backlog;
+            return Ok(());
+        }
+        TCPState::Closed if !tcb.connected => {}
+        _ => {return Err(Errors::EINVAL);}
+    }
+    if tcb.local.1 == 0 {
+        Bind(arc,tcb,0,0)?;
+    }
+    tcb.state = TCPState::Listen;
+    tcb.backlog_limit = backlog;
+    UpdateEntry(arc,tcb.local,(0,0),true);
+    Ok(())
+}
+
+pub fn Connect(arc: &Arc<Mutex<TCB>>, tcb: &mut TCB, addr: u32, port: u16) -> Result<(),i32> {
+    if tcb.state != TCPState::Closed || tcb.connected || tcb.error.is_some() {
+        return Err(Errors::EISCONN);
+    }
+    if port == 0 {
+        return Err(Errors::EADDRNOTAVAIL);
+    }
+    let src = match IPv4::SourceAddress(addr) {
+        Some(a) => a,
+        None => {return Err(Errors::ENETUNREACH);}
+    };
+    if tcb.local.1 == 0 {
+        Bind(arc,tcb,0,0)?;
+    }
+    if tcb.local.0 == 0 {
+        tcb.local.0 = src;
+    }
+    if CONNECTIONS.lock().iter().any(|e| !Arc::ptr_eq(&e.tcb,arc) && e.local == tcb.local && e.remote == (addr,port)) {
+        return Err(Errors::EADDRNOTAVAIL);
+    }
+    tcb.remote = (addr,port);
+    UpdateEntry(arc,tcb.local,tcb.remote,false);
+    let now = super::Now();
+    tcb.iss = GenerateISS();
+    tcb.snd_una = tcb.iss;
+    tcb.snd_nxt = tcb.iss;
+    tcb.snd_max = tcb.iss;
+    tcb.mss = tcb.OurMSS();
+    tcb.state = TCPState::SynSent;
+    tcb.SendSyn();
+    let next = tcb.iss.wrapping_add(1);
+    tcb.Advance(next,now);
+    Ok(())
+}
+
+pub fn Send(tcb: &mut TCB, data: &[u8]) -> Result<usize,i32> {
+    match tcb.state {
+        TCPState::Established | TCPState::CloseWait if !tcb.fin_queued => {}
+        TCPState::SynSent | TCPState::SynReceived => {return Err(Errors::EAGAIN);}
+        _ if tcb.error.is_some() => {return Err(tcb.error.take().unwrap());}
+        _ if tcb.connected => {return Err(Errors::EPIPE);}
+        _ => {return Err(Errors::ENOTCONN);}
+    }
+    let space = tcb.send_limit.saturating_sub(tcb.send_buffer.len());
+    if space == 0 {
+        return Err(Errors::EAGAIN);
+    }
+    let length = core::cmp::min(space,data.len());
+    tcb.send_buffer.extend(data[..length].iter());
+    tcb.Output(super::Now(),false);
+    Ok(length)
+}
+
+pub fn Recv(tcb: &mut TCB, buffer: &mut [u8], peek: bool) -> Result<usize,i32> {
+    if tcb.recv_buffer.len() > 0 && !tcb.read_shutdown {
+        let length = core::cmp::min(buffer.len(),tcb.recv_buffer.len());
+        for (i, b) in tcb.recv_buffer.range(..length).enumerate() {
+            buffer[i] = *b;
+        }
+        if !peek {
+            tcb.recv_buffer.drain(..length);
+            // Let the peer know if we just reopened a window that had (nearly) closed
+            let window = tcb.RecvWindow();
+            if tcb.HandshakeDone() && tcb.state != TCPState::TimeWait && tcb.last_window < tcb.mss && window >= tcb.mss {
+                tcb.SendAck();
+            }
+        }
+        return Ok(length);
+    }
+    if tcb.fin_received || tcb.read_shutdown {
+        return Ok(0);
+    }
+    match tcb.state {
+        TCPState::SynSent | TCPState::SynReceived | TCPState::Established | TCPState::FinWait1 | TCPState::FinWait2 => Err(Errors::EAGAIN),
+        _ if tcb.error.is_some() => Err(tcb.error.take().unwrap()),
+        _ if tcb.connected => Ok(0),
+        _ => Err(Errors::ENOTCONN),
+    }
+}
+
+pub fn Available(tcb: &TCB) -> usize {
+    tcb.recv_buffer.len()
+}
+
+pub fn Poll(tcb: &TCB) -> i16 {
+    if tcb.state == TCPState::Listen {
+        return if tcb.backlog.len() > 0 {VFS::POLLIN} else {0};
+    }
+    let mut events = 0;
+    if tcb.recv_buffer.len() > 0 || tcb.fin_received || tcb.read_shutdown {
+        events |= VFS::POLLIN;
+    }
+    if matches!(tcb.state,TCPState::Established | TCPState::CloseWait) && !tcb.fin_queued && tcb.send_buffer.len() < tcb.send_limit {
+        events |= VFS::POLLOUT;
+    }
+    if tcb.error.is_some() {
+        events |= VFS::POLLERR;
+    }
+    if tcb.state == TCPState::Closed || (tcb.fin_received && tcb.fin_queued) {
+        events |= VFS::POLLHUP;
+    }
+    events
+}
+
+pub fn Shutdown(tcb: &mut TCB, read: bool, write: bool) -> Result<(),i32> {
+    if !tcb.connected && !matches!(tcb.state,TCPState::SynSent | TCPState::SynReceived) {
+        return Err(Errors::ENOTCONN);
+    }
+    if read {
+        tcb.read_shutdown = true;
+        tcb.recv_buffer.clear();
+    }
+    if write {
+        match tcb.state {
+            TCPState::SynSent => {tcb.Terminate(None);}
+            TCPState::SynReceived | TCPState::Established | TCPState::CloseWait => {
+                tcb.fin_queued = true;
+                tcb.Output(super::Now(),false);
+            }
+            _ => {}
+        }
+    }
+    tcb.watchers.Notify();
+    Ok(())
+}
+
+// The socket owning this TCB went away, finish the connection in the background.
+pub fn Close(arc: &Arc<Mutex<TCB>>) {
+    let mut tcb = arc.lock();
+    tcb.orphaned = true;
+    let mut children = VecDeque::new();
+    match tcb.state {
+        TCPState::Listen => {
+            children = core::mem::take(&mut tcb.backlog);
+            tcb.Terminate(None);
+        }
+        TCPState::Closed | TCPState::SynSent => {tcb.Terminate(None);}
+        TCPState::SynReceived | TCPState::Established | TCPState::CloseWait => {
+            if tcb.recv_buffer.len() > 0 {
+                // RFC 2525: closing with unread data resets the connection instead of silently dropping it
+                tcb.Abort(None);
+            } else {
+                tcb.fin_queued = true;
+                tcb.Output(super::Now(),false);
+            }
+        }
+        TCPState::FinWait2 => {tcb.deadline = Some(super::Now() + ORPHAN_TIMEOUT);}
+        _ => {}
+    }
+    let closed = tcb.state == TCPState::Closed;
+    drop(tcb);
+    if closed {
+        Remove(arc);
+    }
+    for child in children {
+        child.lock().Abort(None);
+        Remove(&child);
+    }
+}
+
+pub fn Receive(src: u32, dst: u32, packet: &[u8]) {
+    if packet.len() < HEADER_SIZE {
+        return;
+    }
+    if super::ChecksumFinish(super::ChecksumAdd(super::PseudoHeaderSum(src,dst,IPv4::PROTO_TCP,packet.len()),packet)) != 0 {
+        return;
+    }
+    let sport = u16::from_be_bytes([packet[0],packet[1]]);
+    let dport = u16::from_be_bytes([packet[2],packet[3]]);
+    let seq = u32::from_be_bytes([packet[4],packet[5],packet[6],packet[7]]);
+    let ack = u32::from_be_bytes([packet[8],packet[9],packet[10],packet[11]]);
+    let offset = ((packet[12] >> 4) as usize) * 4;
+    let flags = packet[13];
+    let window = u16::from_be_bytes([packet[14],packet[15]]);
+    if offset < HEADER_SIZE || offset > packet.len() || super::IsBroadcast(dst) {
+        return;
+    }
+    let options = &packet[HEADER_SIZE..offset];
+    let data = &packet[offset..];
+    let tcb = match Lookup(src,sport,dst,dport) {
+        Some(t) => t,
+        None => {
+            if flags & FLAG_RST == 0 {
+                let local = (dst,dport);
+                let remote = (src,sport);
+                if flags & FLAG_ACK != 0 {
+                    SendReset(local,remote,ack,None);
+                } else {
+                    let len = data.len() as u32 + (flags & FLAG_SYN != 0) as u32 + (flags & FLAG_FIN != 0) as u32;
+                    SendReset(local,remote,0,Some(seq.wrapping_add(len)));
+                }
+            }
+            return;
+        }
+    };
+    let mut lock = tcb.lock();
+    let _notify = NotifyOnDrop(lock.watchers.clone());
+    let now = super::Now();
+    match lock.state {
+        TCPState::Listen => {
+            if flags & FLAG_RST != 0 {
+                return;
+            }
+            if flags & FLAG_ACK != 0 {
+                SendReset((dst,dport),(src,sport),ack,None);
+                return;
+            }
+            if flags & FLAG_SYN == 0 || lock.backlog.len() + lock.half_open.load(Ordering::SeqCst) >= lock.backlog_limit {
+                return;
+            }
+            lock.half_open.fetch_add(1,Ordering::SeqCst);
+            let mut child = TCB::new();
+            child.local = (dst,dport);
+            child.remote = (src,sport);
+            child.send_limit = lock.send_limit;
+            child.recv_limit = lock.recv_limit;
+            child.parent = Some(Arc::downgrade(&tcb));
+            child.handshake = Some(HalfOpen(lock.half_open.clone()));
+            child.state = TCPState::SynReceived;
+            child.rcv_nxt = seq.wrapping_add(1);
+            child.snd_wnd = window as u32;
+            child.iss = GenerateISS();
+            child.snd_una = child.iss;
+            child.snd_nxt = child.iss;
+            child.snd_max = child.iss;
+            child.mss = core::cmp::min(ParseMSS(options).unwrap_or(DEFAULT_MSS),child.OurMSS());
+            child.SendSyn();
+            let next = child.iss.wrapping_add(1);
+            child.Advance(next,now);
+            let child = Arc::new(Mutex::new(child));
+            drop(lock);
+            CONNECTIONS.lock().push(Entry {
+                local: (dst,dport),
+                remote: (src,sport),
+                listening: false,
+                tcb: child,
+            });
+            return;
+        }
+        TCPState::SynSent => {
+            let ack_ok = flags & FLAG_ACK != 0 && SeqGT(ack,lock.iss) && SeqLE(ack,lock.snd_max);
+            if flags & FLAG_ACK != 0 && !ack_ok {
+                if flags & FLAG_RST == 0 {
+                    SendReset((dst,dport),(src,sport),ack,None);
+                }
+                return;
+            }
+            if flags & FLAG_RST != 0 {
+                if ack_ok {
+                    lock.Terminate(Some(Errors::ECONNREFUSED));
+                    drop(lock);
+                    Remove(&tcb);
+                }
+                return;
+            }
+            if flags & FLAG_SYN == 0 {
+                return;
+            }
+            lock.rcv_nxt = seq.wrapping_add(1);
+            lock.mss = core::cmp::min(ParseMSS(options).unwrap_or(DEFAULT_MSS),lock.OurMSS());
+            if ack_ok {
+                lock.UpdateRTT(ack,now);
+                lock.HandshakeComplete(ack,window,seq);
+                lock.SendAck();
+                lock.Output(now,false);
+            } else {
+                // Simultaneous open
+                lock.state = TCPState::SynReceived;
+                lock.SendSyn();
+            }
+            return;
+        }
+        TCPState::Closed => {
+            return;
+        }
+        _ => {}
+    }
+    // Trim anything we've already seen off the front of the segment
+    let mut seq = seq;
+    let mut flags = flags;
+    let mut data = data;
+    if SeqLT(seq,lock.rcv_nxt) {
+        let mut skip = lock.rcv_nxt.wrapping_sub(seq) as usize;
+        if flags & FLAG_SYN != 0 {
+            flags &= !FLAG_SYN;
+            skip -= 1;
+        }
+        if skip > data.len() || (skip == data.len() && flags & FLAG_FIN == 0 && data.len() > 0) {
+            if flags & FLAG_RST == 0 {
+                lock.SendAck();
+            }
+            return;
+        }
+        data = &data[skip..];
+        seq = lock.rcv_nxt;
+    }
+    if seq != lock.rcv_nxt {
+        // Out of order, we only take segments in sequence so ask for the one we're missing
+        if flags & FLAG_RST == 0 {
+            lock.SendAck();
+        }
+        return;
+    }
+    let window_left = lock.RecvWindow();
+    let truncated = data.len() > window_left;
+    if truncated {
+        data = &data[..window_left];
+        flags &= !FLAG_FIN;
+    }
+    if flags & FLAG_RST != 0 {
+        let error = match lock.state {
+            TCPState::SynReceived if lock.parent.is_some() => None,
+            TCPState::SynReceived => Some(Errors::ECONNREFUSED),
+            TCPState::Closing | TCPState::LastAck | TCPState::TimeWait => None,
+            _ => Some(Errors::ECONNRESET),
+        };
+        lock.Terminate(error);
+        drop(lock);
+        Remove(&tcb);
+        return;
+    }
+    if flags & FLAG_SYN != 0 {
+        // RFC 5961 challenge ACK, a real peer that lost its state will answer with a reset
+        lock.SendAck();
+        return;
+    }
+    if flags & FLAG_ACK == 0 {
+        return;
+    }
+    if lock.state == TCPState::SynReceived {
+        if !SeqGT(ack,lock.snd_una) || SeqGT(ack,lock.snd_max) {
+            SendReset((dst,dport),(src,sport),ack,None);
+            return;
+        }
+        lock.UpdateRTT(ack,now);
+        lock.HandshakeComplete(ack,window,seq);
+        lock.handshake = None;
+        if let Some(parent) = lock.parent.take() {
+            let queued = match parent.upgrade() {
+                Some(parent) => {
+                    let mut parent = parent.lock();
+                    if parent.state == TCPState::Listen && parent.backlog.len() < parent.backlog_limit {
+                        parent.backlog.push_back(tcb.clone());
+                        parent.watchers.Notify();
+                        true
+                    } else {
+                        false
+                    }
+                }
+                None => false,
+            };
+            if !queued {
+                lock.Abort(None);
+                drop(lock);
+                Remove(&tcb);
+                return;
+            }
+        }
+    }
+    if !lock.ProcessAck(seq,ack,window,data.len(),now) {
+        let closed = lock.state == TCPState::Closed;
+        drop(lock);
+        if closed {
+            Remove(&tcb);
+        }
+        return;
+    }
+    let mut need_ack = truncated;
+    if data.len() > 0 {
+        match lock.state {
+            TCPState::Established | TCPState::FinWait1 | TCPState::FinWait2 => {
+                if !lock.read_shutdown {
+                    lock.recv_buffer.extend(data.iter());
+                }
+                lock.rcv_nxt = lock.rcv_nxt.wrapping_add(data.len() as u32);
+                need_ack = true;
+            }
+            _ => {}
+        }
+    }
+    if flags & FLAG_FIN != 0 {
+        lock.rcv_nxt = lock.rcv_nxt.wrapping_add(1);
+        lock.fin_received = true;
+        need_ack = true;
+        match lock.state {
+            TCPState::SynReceived | TCPState::Established => {lock.state = TCPState::CloseWait;}
+            TCPState::FinWait1 => {
+                if lock.fin_acked {
+                    lock.state = TCPState::TimeWait;
+                    lock.retransmit_at = None;
+                    lock.deadline = Some(now + TIME_WAIT);
+                } else {
+                    lock.state = TCPState::Closing;
+                }
+            }
+            TCPState::FinWait2 | TCPState::TimeWait => {
+                lock.state = TCPState::TimeWait;
+                lock.retransmit_at = None;
+                lock.deadline = Some(now + TIME_WAIT);
+            }
+            _ => {}
+        }
+    }
+    if need_ack {
+        lock.SendAck();
+    }
+    lock.Output(now,false);
+}
+
+// Retransmissions, window probes and the TIME_WAIT/orphan timeouts
+pub fn Timer(now: u64) {
+    let tcbs: Vec<Arc<Mutex<TCB>>> = CONNECTIONS.lock().iter().map(|e| e.tcb.clone()).collect();
+    for arc in tcbs.iter() {
+        let mut tcb = arc.lock();
+        if tcb.state == TCPState::Closed {
+            continue; // Bound but never used
+        }
+        let mut fired = false;
+        if let Some(deadline) = tcb.deadline {
+            if now >= deadline {
+                tcb.Terminate(None);
+                fired = true;
+            }
+        }
+        if let Some(at) = tcb.retransmit_at {
+            if now >= at {
+                fired = true;
+                if tcb.InFlight() == 0 && tcb.state != TCPState::SynSent && tcb.state != TCPState::SynReceived {
+                    // Nothing outstanding, so this is the persist timer firing on a zero window
+                    tcb.retransmit_at = None;
+                    tcb.Output(now,true);
+                } else {
+                    tcb.Retransmit(now);
+                }
+            }
+        }
+        if fired {
+            tcb.watchers.Notify(); // Giving up on a connection makes it readable
+        }
+        let closed = tcb.state == TCPState::Closed;
+        drop(tcb);
+        if closed {
+            Remove(arc);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A connection whose sequence numbers are about to wrap, with `sent` bytes out and unacknowledged. It has no
+    // interface to go out on, so anything it tries to send is dropped.
+    fn Established(sent: usize) -> TCB {
+        let mut tcb = TCB::new();
+        tcb.state = TCPState::Established;
+        tcb.iss = 0xFFFF_FF00;
+        tcb.snd_una = 0xFFFF_FFF0;
+        tcb.snd_nxt = tcb.snd_una.wrapping_add(sent as u32);
+        tcb.snd_max = tcb.snd_nxt;
+        tcb.snd_wnd = 4096;
+        tcb.snd_wl1 = 1000;
+        tcb.snd_wl2 = tcb.snd_una;
+        tcb.cwnd = 16 * DEFAULT_MSS;
+        tcb.send_buffer.extend((0..sent).map(|i| i as u8));
+        tcb
+    }
+
+    #[test]
+    fn SequenceNumbersCompareAcrossTheWrap() {
+        assert!(SeqLT(0xFFFF_FFF0,0x10));
+        assert!(SeqGT(0x10,0xFFFF_FFF0));
+        assert!(SeqLE(5,5) && !SeqLT(5,5) && !SeqGT(5,5));
+        assert!(SeqLT(0,0x7FFF_FFFF));
+        assert!(SeqGT(0,0x8000_0001)); // More than half the space ahead means it's really behind
+    }
+
+    #[test]
+    fn AcksAcrossTheWrapFreeWhatTheyCover() {
+        let mut tcb = Established(64);
+        let ack = tcb.snd_una.wrapping_add(40);
+        assert!(tcb.ProcessAck(1000,ack,4096,0,0));
+        assert_eq!(tcb.snd_una, 0x18);
+        assert_eq!(tcb.send_buffer.len(), 24);
+        assert_eq!(tcb.send_buffer.front(), Some(&40));
+        assert_eq!(tcb.InFlight(), 24);
+    }
+
+    #[test]
+    fn AcksForUnsentDataAreIgnored() {
+        let mut tcb = Established(64);
+        let una = tcb.snd_una;
+        let ack = tcb.snd_max.wrapping_add(1);
+        assert!(!tcb.ProcessAck(1000,ack,4096,0,0));
+        assert_eq!(tcb.snd_una, una);
+        assert_eq!(tcb.send_buffer.len(), 64);
+    }
+
+    #[test]
+    fn OlderSegmentsDontUpdateTheWindow() {
+        let mut tcb = Established(0);
+        let una = tcb.snd_una;
+        tcb.ProcessAck(999,una,0,0,0);
+        assert_eq!(tcb.snd_wnd, 4096);
+        tcb.ProcessAck(1001,una,100,0,0);
+        assert_eq!((tcb.snd_wnd, tcb.snd_wl1), (100, 1001));
+        // The same sequence number counts as long as the ack isn't older, and newer ones count across the wrap
+        tcb.snd_wl1 = 0xFFFF_FFFF;
+        tcb.ProcessAck(0xFFFF_FFFF,una,200,0,0);
+        assert_eq!(tcb.snd_wnd, 200);
+        tcb.ProcessAck(0x10,una,300,0,0);
+        assert_eq!((tcb.snd_wnd, tcb.snd_wl1), (300, 0x10));
+    }
+
+    #[test]
+    fn OutputStaysInsideTheWindow() {
+        let mut tcb = Established(0);
+        tcb.snd_wnd = 1000;
+        tcb.send_buffer.extend([0u8; 3000].iter());
+        tcb.Output(0,false);
+        assert_eq!(tcb.InFlight(), 1000);
+        assert!(SeqGT(tcb.snd_nxt,0)); // Wrapped
+        // Once that's acknowledged into a closed window nothing more goes out, the probe timer goes off instead
+        let ack = tcb.snd_nxt;
+        tcb.ProcessAck(1001,ack,0,0,10);
+        tcb.Output(10,false);
+        assert_eq!(tcb.InFlight(), 0);
+        assert_eq!(tcb.send_buffer.len(), 2000);
+        assert!(tcb.retransmit_at.is_some());
+        tcb.Output(20,true);
+        assert_eq!(tcb.InFlight(), 1);
+    }
+
+    #[test]
+    fn ReceiveWindowShrinksAsDataWaits() {
+        let mut tcb = TCB::new();
+        assert_eq!(tcb.RecvWindow(), MAX_WINDOW);
+        tcb.recv_limit = 4096;
+        tcb.recv_buffer.extend([0u8; 1000].iter());
+        assert_eq!(tcb.RecvWindow(), 3096);
+        tcb.recv_limit = 500;
+        assert_eq!(tcb.RecvWindow(), 0);
+    }
+}
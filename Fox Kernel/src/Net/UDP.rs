@@ -0,0 +1,198 @@
+use spin::Mutex;
+use alloc::sync::{Arc,Weak};
+use alloc::vec::Vec;
+use alloc::collections::{BTreeMap,VecDeque};
+use core::sync::atomic::{AtomicU16,Ordering};
+use crate::Syscall::Errors;
+use crate::FS::VFS;
+use crate::Drivers::Generic::Epoll::Watchers;
+use super::IPv4;
+
+const HEADER_SIZE: usize = 8;
+pub const DEFAULT_RECV_BUFFER: usize = 65536;
+pub const EPHEMERAL_START: u16 = 49152;
+
+pub struct Datagram {
+    pub src: u32,
+    pub port: u16,
+    pub data: Vec<u8>,
+}
+
+pub struct UDPSocket {
+    pub local: (u32,u16), // An address of 0 accepts datagrams sent to any of our addresses
+    pub remote: Option<(u32,u16)>,
+    pub queue: VecDeque<Datagram>,
+    pub queued: usize,
+    pub recv_buffer: usize,
+    pub error: Option<i32>,
+    pub broadcast: bool,
+    pub watchers: Watchers,
+}
+
+impl UDPSocket {
+    pub fn new() -> Self {
+        Self {
+            local: (0,0),
+            remote: None,
+            queue: VecDeque::new(),
+            queued: 0,
+            recv_buffer: DEFAULT_RECV_BUFFER,
+            error: None,
+            broadcast: false,
+            watchers: Watchers::new(),
+        }
+    }
+}
+
+static PORTS: Mutex<BTreeMap<u16,Weak<Mutex<UDPSocket>>>> = Mutex::new(BTreeMap::new());
+static NEXT_EPHEMERAL: AtomicU16 = AtomicU16::new(EPHEMERAL_START);
+
+fn InUse(ports: &BTreeMap<u16,Weak<Mutex<UDPSocket>>>, port: u16) -> bool {
+    ports.get(&port).map_or(false,|w| w.strong_count() > 0)
+}
+
+// Callers hold the socket's lock, so it's passed in both as the guard and as the Arc the port map points to.
+pub fn Bind(arc: &Arc<Mutex<UDPSocket>>, sock: &mut UDPSocket, addr: u32, port: u16) -> Result<(),i32> {
+    if sock.local.1 != 0 {
+        return Err(Errors::EINVAL);
+    }
+    if addr != 0 && !super::IsLocal(addr) && !super::IsBroadcast(addr) {
+        return Err(Errors::EADDRNOTAVAIL);
+    }
+    let mut ports = PORTS.lock();
+    let port = if port == 0 {
+        let mut found = None;
+        for _ in EPHEMERAL_START..=u16::MAX {
+            let candidate = NEXT_EPHEMERAL.fetch_add(1,Ordering::Relaxed);
+            if candidate < EPHEMERAL_START {
+                NEXT_EPHEMERAL.store(EPHEMERAL_START,Ordering::Relaxed);
+                continue;
+            }
+            if !InUse(&ports,candidate) {
+                found = Some(candidate);
+                break;
+            }
+        }
+        match found {
+            Some(p) => p,
+            None => {return Err(Errors::EADDRINUSE);}
+        }
+    } else if InUse(&ports,port) {
+        return Err(Errors::EADDRINUSE);
+    } else {
+        port
+    };
+    ports.insert(port,Arc::downgrade(arc));
+    sock.local = (addr,port);
+    Ok(())
+}
+
+pub fn SendTo(sock: &UDPSocket, dst: u32, port: u16, data: &[u8]) -> Result<usize,i32> {
+    if port == 0 {
+        return Err(Errors::EINVAL);
+    }
+    if super::IsBroadcast(dst) && !sock.broadcast {
+        return Err(Errors::EACCES);
+    }
+    if HEADER_SIZE + data.len() > u16::MAX as usize - IPv4::HEADER_SIZE {
+        return Err(Errors::EMSGSIZE);
+    }
+    let src = if sock.local.0 != 0 && !super::IsBroadcast(sock.local.0) {sock.local.0} else {
+        match IPv4::SourceAddress(dst) {
+            Some(a) => a,
+            None => {return Err(Errors::ENETUNREACH);}
+        }
+    };
+    let length = HEADER_SIZE + data.len();
+    let mut packet = Vec::with_capacity(length);
+    packet.extend_from_slice(&sock.local.1.to_be_bytes());
+    packet.extend_from_slice(&port.to_be_bytes());
+    packet.extend_from_slice(&(length as u16).to_be_bytes());
+    packet.extend_from_slice(&[0,0]);
+    packet.extend_from_slice(data);
+    let mut checksum = super::ChecksumFinish(super::ChecksumAdd(super::PseudoHeaderSum(src,dst,IPv4::PROTO_UDP,length),packet.as_slice()));
+    if checksum == 0 {
+        checksum = 0xFFFF;
+    }
+    packet[6..8].copy_from_slice(&checksum.to_be_bytes());
+    IPv4::Send(src,dst,IPv4::PROTO_UDP,packet.as_slice())?;
+    Ok(data.len())
+}
+
+pub fn Poll(sock: &UDPSocket) -> i16 {
+    let mut events = VFS::POLLOUT; // Datagrams go straight out, so there's never anything to wait for
+    if sock.queue.len() > 0 {
+        events |= VFS::POLLIN;
+    }
+    if sock.error.is_some() {
+        events |= VFS::POLLERR;
+    }
+    events
+}
+
+pub fn Receive(src: u32, dst: u32, packet: &[u8], ip_packet: &[u8]) {
+    if packet.len() < HEADER_SIZE {
+        return;
+    }
+    let sport = u16::from_be_bytes([packet[0],packet[1]]);
+    let dport = u16::from_be_bytes([packet[2],packet[3]]);
+    let length = u16::from_be_bytes([packet[4],packet[5]]) as usize;
+    let checksum = u16::from_be_bytes([packet[6],packet[7]]);
+    if length < HEADER_SIZE || length > packet.len() {
+        return;
+    }
+    let packet = &packet[..length];
+    if checksum != 0 && super::ChecksumFinish(super::ChecksumAdd(super::PseudoHeaderSum(src,dst,IPv4::PROTO_UDP,length),packet)) != 0 {
+        return;
+    }
+    let mut ports = PORTS.lock();
+    let found = ports.get(&dport).map(|w| w.upgrade());
+    let sock = match found {
+        Some(Some(s)) => Some(s),
+        Some(None) => {
+            ports.remove(&dport);
+            None
+        }
+        None => None,
+    };
+    drop(ports);
+    let sock = match sock {
+        Some(s) => s,
+        None => {
+            super::ICMP::DestinationUnreachable(src,dst,super::ICMP::CODE_PORT_UNREACHABLE,ip_packet);
+            return;
+        }
+    };
+    let mut sock = sock.lock();
+    if sock.local.0 != 0 && sock.local.0 != dst && !super::IsBroadcast(dst) {
+        return;
+    }
+    if let Some(remote) = sock.remote {
+        if remote != (src,sport) {
+            return;
+        }
+    }
+    let data = &packet[HEADER_SIZE..];
+    if sock.queued + data.len() > sock.recv_buffer {
+        return;
+    }
+    sock.queued += data.len();
+    sock.queue.push_back(Datagram {
+        src,
+        port: sport,
+        data: data.to_vec(),
+    });
+    sock.watchers.Notify();
+}
+
+// An ICMP port unreachable came back for something we sent from port to dst:dport
+pub fn Unreachable(port: u16, dst: u32, dport: u16) {
+    let sock = PORTS.lock().get(&port).and_then(|w| w.upgrade());
+    if let Some(sock) = sock {
+        let mut sock = sock.lock();
+        if sock.remote == Some((dst,dport)) {
+            sock.error = Some(Errors::ECONNREFUSED);
+            sock.watchers.Notify();
+        }
+    }
+}
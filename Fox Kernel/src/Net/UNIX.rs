@@ -0,0 +1,522 @@
+use spin::Mutex;
+use alloc::sync::{Arc,Weak};
+use alloc::vec::Vec;
+use alloc::string::String;
+use alloc::collections::VecDeque;
+use crate::FS::VFS;
+use crate::Syscall::Errors;
+use crate::Drivers::Generic::Epoll::Watchers;
+use super::Socket::AF_UNIX;
+
+pub const DEFAULT_BUFFER: usize = 65536;
+const MAX_PATH: usize = 108; // sun_path's size
+
+#[derive(Clone,Copy)]
+pub struct Credentials {
+    pub pid: i32,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+impl Credentials {
+    // struct ucred, as handed out by SO_PEERCRED
+    pub fn Encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(12);
+        buf.extend_from_slice(&self.pid.to_ne_bytes());
+        buf.extend_from_slice(&self.uid.to_ne_bytes());
+        buf.extend_from_slice(&self.gid.to_ne_bytes());
+        buf
+    }
+}
+
+// Binding and connecting resolve paths and check permissions the same way open() does, so they need to know who's asking.
+pub struct Caller {
+    pub cred: Credentials,
+    pub supgroups: Vec<u32>,
+    pub cwd: String,
+    pub umask: i32,
+}
+
+impl Caller {
+    pub fn new(proc: &crate::Process::Process) -> Self {
+        Self {
+            cred: Credentials {pid: proc.id, uid: proc.euid, gid: proc.egid},
+            supgroups: proc.supgroups.clone(),
+            cwd: proc.cwd.clone(),
+            umask: proc.umask,
+        }
+    }
+}
+
+// A descriptor on its way to another process. Like a dup() it holds its own reference to the file, which is given back if it
+// never gets delivered.
+pub struct InFlight(Option<VFS::FileDescriptor>);
+
+impl InFlight {
+    pub fn new(fd: &VFS::FileDescriptor) -> Self {
+        let _ = fd.inode.Open(usize::MAX);
+        InFlight(Some(fd.clone()))
+    }
+
+    pub fn Deliver(mut self) -> VFS::FileDescriptor {
+        self.0.take().unwrap()
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        if let Some(fd) = self.0.take() {
+            fd.inode.Close();
+        }
+    }
+}
+
+struct Message {
+    data: Vec<u8>,
+    offset: usize, // How much of a stream message has already been read
+    fds: Vec<InFlight>,
+    from: Option<String>,
+}
+
+#[derive(PartialEq,Clone,Copy)]
+pub enum UNIXState {
+    Unconnected,
+    Listening,
+    Connected,
+}
+
+pub struct UNIXSocket {
+    pub stream: bool,
+    pub state: UNIXState,
+    pub path: Option<String>,
+    pub cred: Credentials,
+    pub peer_cred: Option<Credentials>,
+    peer: Option<Weak<Mutex<UNIXSocket>>>,
+    queue: VecDeque<Message>,
+    queued: usize,
+    pub recv_limit: usize,
+    backlog: VecDeque<Arc<Mutex<UNIXSocket>>>,
+    backlog_limit: usize,
+    peer_closed: bool,
+    read_shutdown: bool,
+    write_shutdown: bool,
+    pub watchers: Watchers,
+    senders: Vec<Weak<Mutex<UNIXSocket>>>, // Sockets connected to this one, whose POLLOUT depends on room in our queue
+}
+
+impl UNIXSocket {
+    pub fn new(stream: bool, cred: Credentials) -> Self {
+        Self {
+            stream,
+            state: UNIXState::Unconnected,
+            path: None,
+            cred,
+            peer_cred: None,
+            peer: None,
+            queue: VecDeque::new(),
+            queued: 0,
+            recv_limit: DEFAULT_BUFFER,
+            backlog: VecDeque::new(),
+            backlog_limit: 0,
+            peer_closed: false,
+            read_shutdown: false,
+            write_shutdown: false,
+            watchers: Watchers::new(),
+            senders: Vec::new(),
+        }
+    }
+}
+
+// Sockets bound to a path, keyed by the socket inode that bind() left in the filesystem. Like UDP's port map, entries whose
+// socket is gone are just skipped and swept up later.
+static BOUND: Mutex<Vec<(Arc<dyn VFS::Inode>,Weak<Mutex<UNIXSocket>>)>> = Mutex::new(Vec::new());
+
+fn SameInode(a: &Arc<dyn VFS::Inode>, b: &Arc<dyn VFS::Inode>) -> bool {
+    Arc::as_ptr(a) as *const u8 == Arc::as_ptr(b) as *const u8
+}
+
+pub fn ParsePath(addr: &[u8]) -> Result<&str,i32> {
+    if addr.len() <= 2 {
+        return Err(Errors::EINVAL);
+    }
+    if u16::from_ne_bytes([addr[0],addr[1]]) as usize != AF_UNIX {
+        return Err(Errors::EAFNOSUPPORT);
+    }
+    let path = &addr[2..];
+    let path = &path[..path.iter().position(|b| *b == 0).unwrap_or(path.len())];
+    if path.len() == 0 {
+        return Err(Errors::EINVAL); // Abstract names and autobinding aren't supported
+    }
+    if path.len() >= MAX_PATH {
+        return Err(Errors::ENAMETOOLONG);
+    }
+    core::str::from_utf8(path).map_err(|_| Errors::EINVAL)
+}
+
+// Unnamed sockets are reported with just the family, like Linux does
+pub fn EncodePath(path: Option<&str>) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(3 + path.map_or(0,|p| p.len()));
+    buf.extend_from_slice(&(AF_UNIX as u16).to_ne_bytes());
+    if let Some(path) = path {
+        buf.extend_from_slice(path.as_bytes());
+        buf.push(0);
+    }
+    buf
+}
+
+pub fn Lookup(caller: &Caller, path: &str) -> Result<Arc<Mutex<UNIXSocket>>,i32> {
+    let inode = VFS::LookupPath(VFS::GetAbsPath(path,caller.cwd.as_str()).as_str()).map_err(|e| e as i32)?;
+    let metadata = inode.Stat().map_err(|e| e as i32)?;
+    if metadata.mode & 0o0170000 != VFS::FTYPE_SOCK as i32 {
+        return Err(Errors::ECONNREFUSED);
+    }
+    if !VFS::HasPermission(&metadata,caller.cred.uid,caller.cred.gid,&caller.supgroups,0b10) {
+        return Err(Errors::EACCES);
+    }
+    let mut bound = BOUND.lock();
+    bound.retain(|(_, s)| s.strong_count() > 0);
+    bound.iter().find(|(i, _)| SameInode(i,&inode)).and_then(|(_, s)| s.upgrade()).ok_or(Errors::ECONNREFUSED)
+}
+
+// Callers hold the socket's lock, so it's passed in both as the guard and as the Arc the bound list points to.
+pub fn Bind(arc: &Arc<Mutex<UNIXSocket>>, sock: &mut UNIXSocket, caller: &Caller, path: &str) -> Result<(),i32> {
+    if sock.path.is_some() {
+        return Err(Errors::EINVAL);
+    }
+    let abspath = VFS::GetAbsPath(path,caller.cwd.as_str());
+    let mut parent: Vec<_> = abspath.split("/").filter(|e| *e != "" && *e != ".").collect();
+    let name = match parent.pop() {
+        Some(n) => n,
+        None => {return Err(Errors::EADDRINUSE);}
+    };
+    let parinode = VFS::LookupPath([String::from("/"),parent.join("/")].join("").as_str()).map_err(|e| e as i32)?;
+    if parinode.Lookup(name).is_ok() {
+        return Err(Errors::EADDRINUSE);
+    }
+    let inode = parinode.Creat(name,VFS::FTYPE_SOCK as i32 | (0o777 & !caller.umask)).map_err(|e| if e as i32 == Errors::EEXIST {Errors::EADDRINUSE} else {e as i32})?;
+    inode.ChOwn(caller.cred.uid as i32,caller.cred.gid as i32);
+    BOUND.lock().push((inode,Arc::downgrade(arc)));
+    sock.path = Some(abspath);
+    Ok(())
+}
+
+pub fn Listen(sock: &mut UNIXSocket, backlog: usize) -> Result<(),i32> {
+    if !sock.stream {
+        return Err(Errors::EOPNOTSUPP);
+    }
+    if sock.path.is_none() || sock.state == UNIXState::Connected {
+        return Err(Errors::EINVAL);
+    }
+    sock.state = UNIXState::Listening;
+    sock.backlog_limit = backlog;
+    Ok(())
+}
+
+// Stream connections are set up right away: the listener gets a ready-made socket in its backlog that's already paired with ours.
+pub fn Connect(arc: &Arc<Mutex<UNIXSocket>>, caller: &Caller, path: &str) -> Result<(),i32> {
+    let target = Lookup(caller,path)?;
+    let target_stream = target.lock().stream;
+    let mut sock = arc.lock();
+    if !sock.stream {
+        if target_stream {
+            return Err(Errors::EPROTOTYPE);
+        }
+        sock.peer = Some(Arc::downgrade(&target));
+        sock.peer_cred = None;
+        sock.state = UNIXState::Connected;
+        drop(sock);
+        let mut target = target.lock();
+        target.senders.retain(|s| s.strong_count() > 0 && s.as_ptr() != Arc::as_ptr(arc));
+        target.senders.push(Arc::downgrade(arc));
+        return Ok(());
+    }
+    match sock.state {
+        UNIXState::Connected => {return Err(Errors::EISCONN);}
+        UNIXState::Listening => {return Err(Errors::EINVAL);}
+        UNIXState::Unconnected => {}
+    }
+    drop(sock);
+    if Arc::ptr_eq(arc,&target) {
+        return Err(Errors::ECONNREFUSED);
+    }
+    // Listener before connector, and a listening socket never connects, so two sockets can't wait on each other here
+    let mut listener = target.lock();
+    if !listener.stream {
+        return Err(Errors::EPROTOTYPE);
+    }
+    if listener.state != UNIXState::Listening {
+        return Err(Errors::ECONNREFUSED);
+    }
+    if listener.backlog.len() >= listener.backlog_limit {
+        return Err(Errors::EAGAIN);
+    }
+    let mut sock = arc.lock();
+    if sock.state != UNIXState::Unconnected {
+        return Err(Errors::EISCONN);
+    }
+    let mut child = UNIXSocket::new(true,listener.cred);
+    child.state = UNIXState::Connected;
+    child.path = listener.path.clone();
+    child.recv_limit = listener.recv_limit;
+    child.peer = Some(Arc::downgrade(arc));
+    child.peer_cred = Some(caller.cred);
+    child.senders.push(Arc::downgrade(arc));
+    let child = Arc::new(Mutex::new(child));
+    sock.peer = Some(Arc::downgrade(&child));
+    sock.senders.push(Arc::downgrade(&child));
+    sock.peer_cred = Some(listener.cred);
+    sock.state = UNIXState::Connected;
+    listener.backlog.push_back(child);
+    sock.watchers.Notify();
+    listener.watchers.Notify();
+    Ok(())
+}
+
+pub fn Pair(stream: bool, cred: Credentials) -> (Arc<Mutex<UNIXSocket>>,Arc<Mutex<UNIXSocket>>) {
+    let a = Arc::new(Mutex::new(UNIXSocket::new(stream,cred)));
+    let b = Arc::new(Mutex::new(UNIXSocket::new(stream,cred)));
+    for (this, other) in [(&a,&b),(&b,&a)] {
+        let mut sock = this.lock();
+        sock.state = UNIXState::Connected;
+        sock.peer = Some(Arc::downgrade(other));
+        sock.peer_cred = Some(cred);
+        sock.senders.push(Arc::downgrade(other));
+    }
+    (a,b)
+}
+
+pub fn Accept(sock: &mut UNIXSocket) -> Result<Arc<Mutex<UNIXSocket>>,i32> {
+    if sock.state != UNIXState::Listening {
+        return Err(Errors::EINVAL);
+    }
+    sock.backlog.pop_front().ok_or(Errors::EAGAIN)
+}
+
+// Delivers straight into the receiving socket's queue. target is the resolved address for unconnected datagram sockets.
+pub fn Send(arc: &Arc<Mutex<UNIXSocket>>, data: &[u8], fds: Vec<InFlight>, target: Option<Arc<Mutex<UNIXSocket>>>) -> Result<usize,i32> {
+    let sock = arc.lock();
+    if sock.write_shutdown {
+        return Err(Errors::EPIPE);
+    }
+    if sock.stream && sock.state != UNIXState::Connected {
+        return Err(Errors::ENOTCONN);
+    }
+    let stream = sock.stream;
+    let from = sock.path.clone();
+    let peer = sock.peer.clone();
+    drop(sock);
+    let target = match target {
+        Some(_) if stream => {return Err(Errors::EISCONN);}
+        Some(t) => t,
+        None => match peer.as_ref().and_then(|p| p.upgrade()) {
+            Some(p) => p,
+            None if stream => {return Err(Errors::EPIPE);}
+            None if peer.is_some() => {return Err(Errors::ECONNREFUSED);}
+            None => {return Err(Errors::EDESTADDRREQ);}
+        }
+    };
+    let mut receiver = target.lock();
+    if stream {
+        if receiver.read_shutdown {
+            return Err(Errors::EPIPE);
+        }
+        let space = receiver.recv_limit.saturating_sub(receiver.queued);
+        if data.len() == 0 {
+            return Ok(0);
+        }
+        if space == 0 {
+            return Err(Errors::EAGAIN);
+        }
+        let length = core::cmp::min(space,data.len());
+        receiver.queued += length;
+        receiver.queue.push_back(Message {data: data[..length].to_vec(), offset: 0, fds, from: None});
+        receiver.watchers.Notify();
+        return Ok(length);
+    }
+    if receiver.stream {
+        return Err(Errors::EPROTOTYPE);
+    }
+    if let Some(p) = &receiver.peer {
+        if p.as_ptr() != Arc::as_ptr(arc) {
+            return Err(Errors::EPERM); // It only takes datagrams from whoever it connected to
+        }
+    }
+    if receiver.read_shutdown {
+        return Err(Errors::ECONNREFUSED);
+    }
+    if data.len() > receiver.recv_limit {
+        return Err(Errors::EMSGSIZE);
+    }
+    if receiver.queued + data.len() > receiver.recv_limit {
+        return Err(Errors::EAGAIN);
+    }
+    receiver.queued += data.len();
+    receiver.queue.push_back(Message {data: data.to_vec(), offset: 0, fds, from});
+    receiver.watchers.Notify();
+    Ok(data.len())
+}
+
+pub struct Received {
+    pub length: usize,
+    pub from: Option<String>,
+    pub fds: Vec<InFlight>,
+    pub truncated: bool,
+}
+
+// Stream reads never run past a message carrying descriptors, so they arrive alongside the first byte that was sent with them.
+pub fn Recv(sock: &mut UNIXSocket, buffer: &mut [u8], peek: bool) -> Result<Received,i32> {
+    let mut received = Received {length: 0, from: None, fds: Vec::new(), truncated: false};
+    if sock.queue.is_empty() {
+        if sock.peer_closed || sock.read_shutdown {
+            return Ok(received);
+        }
+        if sock.stream && sock.state != UNIXState::Connected {
+            return Err(Errors::ENOTCONN);
+        }
+        return Err(Errors::EAGAIN);
+    }
+    if !sock.stream {
+        let message = sock.queue.front().unwrap();
+        let length = core::cmp::min(buffer.len(),message.data.len());
+        buffer[..length].copy_from_slice(&message.data[..length]);
+        received.length = length;
+        received.truncated = length < message.data.len();
+        received.from = message.from.clone();
+        if !peek {
+            let mut message = sock.queue.pop_front().unwrap();
+            sock.queued -= message.data.len();
+            received.fds.append(&mut message.fds);
+        }
+        return Ok(received);
+    }
+    let mut index = 0;
+    while received.length < buffer.len() {
+        let message = match sock.queue.get_mut(index) {
+            Some(m) => m,
+            None => {break;}
+        };
+        if received.length > 0 && !message.fds.is_empty() {
+            break;
+        }
+        let length = core::cmp::min(buffer.len() - received.length,message.data.len() - message.offset);
+        buffer[received.length..received.length+length].copy_from_slice(&message.data[message.offset..message.offset+length]);
+        received.length += length;
+        if peek {
+            index += 1;
+            continue;
+        }
+        received.fds.append(&mut message.fds);
+        message.offset += length;
+        if message.offset == message.data.len() {
+            sock.queue.pop_front();
+        }
+    }
+    if !peek {
+        sock.queued -= received.length;
+    }
+    Ok(received)
+}
+
+pub fn PeerPath(arc: &Arc<Mutex<UNIXSocket>>) -> Result<Option<String>,i32> {
+    let sock = arc.lock();
+    if sock.state != UNIXState::Connected {
+        return Err(Errors::ENOTCONN);
+    }
+    let peer = sock.peer.clone();
+    drop(sock);
+    Ok(peer.and_then(|p| p.upgrade()).and_then(|p| p.lock().path.clone()))
+}
+
+// Called after a read makes room in the queue, so anyone waiting to send to us gets another look
+pub fn NotifySenders(arc: &Arc<Mutex<UNIXSocket>>) {
+    let mut sock = arc.lock();
+    sock.senders.retain(|s| s.strong_count() > 0);
+    let senders: Vec<_> = sock.senders.iter().filter_map(|s| s.upgrade()).collect();
+    drop(sock);
+    for sender in senders.iter() {
+        let sender = sender.lock();
+        // Datagram sockets may have connected somewhere else since
+        if sender.peer.as_ref().map_or(false,|p| p.as_ptr() == Arc::as_ptr(arc)) {
+            sender.watchers.Notify();
+        }
+    }
+}
+
+pub fn Available(sock: &UNIXSocket) -> usize {
+    if sock.stream {sock.queued} else {sock.queue.front().map_or(0,|m| m.data.len())}
+}
+
+pub fn Poll(arc: &Arc<Mutex<UNIXSocket>>) -> i16 {
+    let sock = arc.lock();
+    if sock.state == UNIXState::Listening {
+        return if sock.backlog.len() > 0 {VFS::POLLIN} else {0};
+    }
+    let mut events = 0;
+    if sock.queue.len() > 0 || sock.peer_closed || sock.read_shutdown {
+        events |= VFS::POLLIN;
+    }
+    if sock.peer_closed && sock.write_shutdown {
+        events |= VFS::POLLHUP;
+    }
+    let stream = sock.stream;
+    let write_shutdown = sock.write_shutdown;
+    let peer = sock.peer.clone();
+    drop(sock);
+    if write_shutdown {
+        return events;
+    }
+    match peer.as_ref().map(|p| p.upgrade()) {
+        Some(Some(p)) => {
+            let receiver = p.lock();
+            if receiver.queued < receiver.recv_limit {
+                events |= VFS::POLLOUT;
+            }
+        }
+        Some(None) if stream => {events |= VFS::POLLHUP;}
+        Some(None) => {events |= VFS::POLLERR;} // Sending would get ECONNREFUSED
+        None if !stream => {events |= VFS::POLLOUT;} // Where it goes is up to each sendto()
+        None => {}
+    }
+    events
+}
+
+pub fn Shutdown(arc: &Arc<Mutex<UNIXSocket>>, read: bool, write: bool) -> Result<(),i32> {
+    let mut sock = arc.lock();
+    if sock.state != UNIXState::Connected {
+        return Err(Errors::ENOTCONN);
+    }
+    sock.read_shutdown |= read;
+    sock.write_shutdown |= write;
+    sock.watchers.Notify();
+    let peer = sock.peer.as_ref().and_then(|p| p.upgrade());
+    drop(sock);
+    if let Some(peer) = peer.filter(|_| write) {
+        let mut peer = peer.lock();
+        if peer.stream {
+            peer.peer_closed = true;
+            peer.watchers.Notify();
+        }
+    }
+    Ok(())
+}
+
+pub fn Close(arc: &Arc<Mutex<UNIXSocket>>) {
+    let mut sock = arc.lock();
+    sock.read_shutdown = true;
+    sock.write_shutdown = true;
+    sock.queue.clear(); // Drops any descriptors that were still in flight
+    sock.queued = 0;
+    let backlog: Vec<_> = sock.backlog.drain(..).collect();
+    let peer = if sock.stream {sock.peer.take().and_then(|p| p.upgrade())} else {None};
+    drop(sock);
+    // Connections nobody accepted get hung up on
+    for child in backlog.iter() {
+        Close(child);
+    }
+    if let Some(peer) = peer {
+        let mut peer = peer.lock();
+        peer.peer_closed = true;
+        peer.watchers.Notify();
+    }
+    NotifySenders(arc);
+}
@@ -0,0 +1,259 @@
+use spin::Mutex;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64,Ordering};
+use crate::Drivers::Generic::NetworkDevice;
+
+pub mod Loopback;
+pub mod ARP;
+pub mod IPv4;
+pub mod ICMP;
+pub mod UDP;
+pub mod TCP;
+pub mod UNIX;
+pub mod Socket;
+
+pub const ETHERTYPE_IPV4: u16 = 0x0800;
+pub const ETHERTYPE_ARP: u16 = 0x0806;
+pub const BROADCAST_MAC: [u8; 6] = [0xFF; 6];
+
+// Addresses are kept in host byte order everywhere inside the stack, they only get swapped when packets are built or parsed.
+pub struct IPInterface {
+    pub iface: Arc<NetworkDevice::NetworkInterface>,
+    pub address: u32,
+    pub netmask: u32,
+    pub gateway: Option<u32>,
+    pub loopback: bool,
+}
+
+impl IPInterface {
+    pub fn Broadcast(&self) -> u32 {
+        self.address | !self.netmask
+    }
+    pub fn MTU(&self) -> usize {
+        self.iface.device.MTU()
+    }
+}
+
+pub static IP_INTERFACES: Mutex<Vec<Arc<IPInterface>>> = Mutex::new(Vec::new());
+static POLL_LOCK: Mutex<()> = Mutex::new(());
+static LAST_TICK: AtomicU64 = AtomicU64::new(0);
+// Poll runs in the timer interrupt, so a flood of frames can't be allowed to keep it there. Whatever's left over stays
+// queued on its interface until the next time around.
+const POLL_BUDGET: usize = 64;
+
+// Milliseconds since boot, which is all the precision the protocol timers need.
+pub fn Now() -> u64 {
+    crate::arch::Timer::GetMicroseconds() / 1000
+}
+
+pub fn FormatAddress(addr: u32) -> alloc::string::String {
+    let b = addr.to_be_bytes();
+    alloc::format!("{}.{}.{}.{}", b[0], b[1], b[2], b[3])
+}
+
+pub fn ParseAddress(s: &str) -> Option<u32> {
+    let mut addr: u32 = 0;
+    let mut count = 0;
+    for part in s.split('.') {
+        let val: u8 = part.parse().ok()?;
+        addr = (addr << 8) | val as u32;
+        count += 1;
+    }
+    if count != 4 {
+        return None;
+    }
+    Some(addr)
+}
+
+// Parses "a.b.c.d/prefix" into an address and netmask, the prefix defaults to /24.
+fn ParseCIDR(s: &str) -> Option<(u32,u32)> {
+    let mut split = s.splitn(2,'/');
+    let addr = ParseAddress(split.next()?)?;
+    let prefix: u32 = match split.next() {
+        Some(p) => p.parse().ok()?,
+        None => 24,
+    };
+    if prefix > 32 {
+        return None;
+    }
+    let mask = if prefix == 0 {0} else {u32::MAX << (32 - prefix)};
+    Some((addr,mask))
+}
+
+// The internet checksum, summed over a pseudo-header (if any) and then the packet itself
+pub fn ChecksumAdd(mut sum: u32, data: &[u8]) -> u32 {
+    let mut i = 0;
+    while i + 1 < data.len() {
+        sum += u16::from_be_bytes([data[i],data[i+1]]) as u32;
+        i += 2;
+    }
+    if i < data.len() {
+        sum += (data[i] as u32) << 8;
+    }
+    sum
+}
+
+pub fn ChecksumFinish(mut sum: u32) -> u16 {
+    while sum >> 16 != 0 {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+pub fn Checksum(data: &[u8]) -> u16 {
+    ChecksumFinish(ChecksumAdd(0,data))
+}
+
+pub fn PseudoHeaderSum(src: u32, dst: u32, protocol: u8, length: usize) -> u32 {
+    (src >> 16) + (src & 0xFFFF) + (dst >> 16) + (dst & 0xFFFF) + protocol as u32 + length as u32
+}
+
+pub fn IsLocal(addr: u32) -> bool {
+    if addr >> 24 == 127 {
+        return true;
+    }
+    IP_INTERFACES.lock().iter().any(|i| i.address == addr)
+}
+
+pub fn IsBroadcast(addr: u32) -> bool {
+    addr == u32::MAX || IP_INTERFACES.lock().iter().any(|i| !i.loopback && i.Broadcast() == addr)
+}
+
+pub fn Loopback() -> Option<Arc<IPInterface>> {
+    IP_INTERFACES.lock().iter().find(|i| i.loopback).cloned()
+}
+
+// Picks the interface a packet to dst leaves through, along with the next hop it should be handed to.
+pub fn Route(dst: u32) -> Option<(Arc<IPInterface>,u32)> {
+    if IsLocal(dst) {
+        return Loopback().map(|i| (i,dst));
+    }
+    let lock = IP_INTERFACES.lock();
+    let candidates = || lock.iter().filter(|i| !i.loopback && i.iface.LinkUp());
+    if dst == u32::MAX {
+        return candidates().next().map(|i| (i.clone(),dst));
+    }
+    if let Some(i) = candidates().find(|i| dst & i.netmask == i.address & i.netmask) {
+        return Some((i.clone(),dst));
+    }
+    candidates().find(|i| i.gateway.is_some()).map(|i| (i.clone(),i.gateway.unwrap()))
+}
+
+pub fn SendFrame(ipif: &IPInterface, dst: [u8; 6], ethertype: u16, payload: &[u8]) {
+    let mut frame = Vec::with_capacity(14 + payload.len());
+    frame.extend_from_slice(&dst);
+    frame.extend_from_slice(&ipif.iface.device.MACAddress());
+    frame.extend_from_slice(&ethertype.to_be_bytes());
+    frame.extend_from_slice(payload);
+    // Ethernet frames have to be at least 60 bytes before the FCS
+    if frame.len() < 60 {
+        frame.resize(60,0);
+    }
+    ipif.iface.Send(frame);
+}
+
+fn Receive(ipif: &Arc<IPInterface>, frame: &[u8]) {
+    if frame.len() < 14 {
+        return;
+    }
+    let ethertype = u16::from_be_bytes([frame[12],frame[13]]);
+    match ethertype {
+        ETHERTYPE_ARP => ARP::Receive(ipif,&frame[14..]),
+        ETHERTYPE_IPV4 => IPv4::Receive(ipif,&frame[14..]),
+        _ => {}
+    }
+}
+
+// Handles whatever the interfaces have received, up to POLL_BUDGET frames. Only one hart runs the stack at a time, the rest
+// just skip it.
+pub fn Poll() {
+    let guard = match POLL_LOCK.try_lock() {
+        Some(g) => g,
+        None => {return;}
+    };
+    let interfaces = IP_INTERFACES.lock().clone();
+    let mut budget = POLL_BUDGET;
+    loop {
+        let mut received = false;
+        for ipif in interfaces.iter() {
+            ipif.iface.Poll();
+            while budget > 0 {
+                let frame = match ipif.iface.Receive() {
+                    Some(f) => f,
+                    None => {break;}
+                };
+                Receive(ipif,frame.as_slice());
+                received = true;
+                budget -= 1;
+            }
+        }
+        if !received || budget == 0 {
+            break;
+        }
+    }
+    drop(guard);
+}
+
+// Called from the scheduler tick on the BSP
+pub fn Tick() {
+    let now = Now();
+    if now.saturating_sub(LAST_TICK.load(Ordering::Relaxed)) < 10 {
+        return;
+    }
+    LAST_TICK.store(now,Ordering::Relaxed);
+    if IP_INTERFACES.try_lock().map_or(true,|l| l.is_empty()) {
+        return;
+    }
+    Poll();
+    ARP::Timer(now);
+    TCP::Timer(now);
+    Poll();
+}
+
+fn AddInterface(iface: Arc<NetworkDevice::NetworkInterface>, address: u32, netmask: u32, gateway: Option<u32>, loopback: bool) {
+    match gateway {
+        Some(gw) => log::info!("{}: {}/{} via {}", iface.name, FormatAddress(address), netmask.count_ones(), FormatAddress(gw)),
+        None => log::info!("{}: {}/{}", iface.name, FormatAddress(address), netmask.count_ones()),
+    }
+    IP_INTERFACES.lock().push(Arc::new(IPInterface {
+        iface,
+        address,
+        netmask,
+        gateway,
+        loopback,
+    }));
+}
+
+pub fn Initalize() {
+    let options = crate::CommandLine::OPTIONS.get().unwrap();
+    let lo = NetworkDevice::Register(alloc::string::String::from("lo"),Arc::new(Loopback::LoopbackDevice::new()));
+    AddInterface(lo,0x7F000001,0xFF000000,None,true);
+    let interfaces = NetworkDevice::INTERFACES.lock().clone();
+    for iface in interfaces.iter() {
+        if iface.name.as_str() == "lo" {
+            continue;
+        }
+        let ip = options.get(alloc::format!("--net.{}.ip",iface.name).as_str()).copied();
+        let gateway = options.get(alloc::format!("--net.{}.gateway",iface.name).as_str()).copied();
+        let (address, netmask, gateway) = match ip {
+            Some("none") => {continue;}
+            Some(ip) => {
+                let (address, netmask) = match ParseCIDR(ip) {
+                    Some(a) => a,
+                    None => {
+                        log::error!("{}: Invalid address \"{}\", leaving the interface unconfigured", iface.name, ip);
+                        continue;
+                    }
+                };
+                (address,netmask,gateway.and_then(ParseAddress))
+            }
+            // There's no DHCP yet, so an address has to be given on the command line
+            None => {
+                log::info!("{}: No --net.{}.ip given, leaving the interface unconfigured", iface.name, iface.name);
+                continue;
+            }
+        };
+        AddInterface(iface.clone(),address,netmask,gateway,false);
+    }
+}
@@ -1,5 +1,5 @@
 use alloc::collections::BTreeMap;
-use core::sync::atomic::{AtomicI32, AtomicU32, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicI32, AtomicU32, Ordering};
 use spin::mutex::Mutex;
 use crate::arch::Task::{State,FloatState};
 use crate::CurrentHart;
@@ -39,6 +39,7 @@ pub mod Signals {
     pub const SIGTTIN: u8 = 0x15; // Stop
     pub const SIGTTOU: u8 = 0x16; // Stop
     pub const SIGURG: u8 =  0x17; // Ignored
+    pub const SIGWINCH: u8 = 0x1c; // Ignored
 }
 
 pub enum ProcessStatus {
@@ -52,6 +53,14 @@ pub enum ProcessStatus {
     SLEEPING(i64),
 }
 
+// A system call that's sleeping until its files are ready, kept around across the times it gets run again until it's done
+pub struct Wait {
+    pub ip: usize, // The address just past the call, which is where it returns to if a signal interrupts it
+    pub deadline: Option<u64>,
+    pub watches: Vec<Arc<crate::Drivers::Generic::Epoll::Watch>>,
+    pub polled: bool, // Some of the files can't tell us when they change, so they have to be looked at every so often
+}
+
 pub trait TaskState: Send + Sync {
     fn SetIP(&mut self, ip: usize);
     fn GetIP(&self) -> usize;
@@ -70,6 +79,7 @@ pub trait TaskState: Send + Sync {
     fn Save(&mut self, state: &State);
     fn Enter(&self) -> !;
     fn Exit(&self);
+    fn IsKernel(&self) -> bool; // Whether this runs in kernel mode, which only kernel threads (and the idle thread) do
 }
 
 pub trait TaskFloatState: Send + Sync {
@@ -107,12 +117,16 @@ pub struct Process {
 
     pub memory_segments: Arc<Mutex<Vec<(usize,usize,String,u8,i64,usize)>>>,
 
-    pub signals: [usize; 25],
+    pub signals: [usize; 29],
 
     pub supgroups: Vec<u32>,
+
+    pub wait: Option<Wait>,
+    pub woken: Arc<AtomicBool>, // Set by the files a sleeping system call is watching, see Syscall::WaitForReady
 }
 
 pub const USERSPACE_STACK_SIZE: u64 = 0x4000;
+pub const KERNEL_THREAD_STACK_SIZE: u64 = 0x10000;
 
 impl Process {
     pub fn new(name: String, parent: i32) -> Self {
@@ -146,9 +160,12 @@ impl Process {
 
             memory_segments: Arc::new(Mutex::new(Vec::new())),
 
-            signals: [0; 25],
+            signals: [0; 29],
 
             supgroups: Vec::new(),
+
+            wait: None,
+            woken: Arc::new(AtomicBool::new(false)),
         }
     }
     pub fn ContextSwitch(&self) -> ! {
@@ -195,6 +212,18 @@ impl Process {
         let mut lock = PROCESSES.lock();
         match lock.get_mut(&pid) {
             Some(proc) => {
+                if proc.task_state.IsKernel() {
+                    drop(lock);
+                    return -crate::Syscall::Errors::EPERM as isize;
+                }
+                if crate::Drivers::Generic::SignalFD::Claim(pid,sig) {
+                    drop(lock);
+                    return 0;
+                }
+                if sig as usize >= proc.signals.len() {
+                    drop(lock);
+                    return -crate::Syscall::Errors::EINVAL as isize;
+                }
                 let sighandle = proc.signals[sig as usize];
                 if matches!(proc.status,ProcessStatus::SIGNAL(_,_)) || proc.sig_state.GetIP() != 0 {
                     drop(lock);
@@ -226,6 +255,13 @@ impl Process {
                     drop(lock);
                     return 0;
                 } else {
+                    // A system call that was sleeping gets interrupted rather than started over once the handler returns
+                    if matches!(proc.status,ProcessStatus::SLEEPING(_)) {
+                        if let Some(wait) = proc.wait.take() {
+                            proc.task_state.SetIP(wait.ip);
+                            proc.task_state.SetSC0((-crate::Syscall::Errors::EINTR as isize) as usize);
+                        }
+                    }
                     proc.sig_state.Save(&proc.task_state);
                     proc.status = ProcessStatus::SIGNAL(sighandle,sig as usize);
                     drop(lock);
@@ -239,6 +275,16 @@ impl Process {
         }
 
     }
+    // Sends a signal to every process in a group, which is how terminals deliver the ones typed at them
+    pub fn SignalGroup(pgid: i32, sig: u8) {
+        if pgid <= 0 {
+            return;
+        }
+        let members: Vec<i32> = PROCESSES.lock().values().filter(|p| p.pgid == pgid && p.id != 1).map(|p| p.id).collect();
+        for pid in members {
+            Process::SendSignal(pid,sig);
+        }
+    }
     pub fn StartProcess(pid: i32, ip: usize, sp: usize) {
         let mut lock = PROCESSES.lock();
         let proc = lock.get_mut(&pid).unwrap();
@@ -268,6 +314,19 @@ impl Process {
         }
         drop(lock);
     }
+    // Starts `entry` running in kernel mode as a process of its own, so the scheduler can share the hart with it. It starts
+    // out with interrupts off, see Scheduler::Sleep for how it gets to give the hart up.
+    pub fn StartKernelThread(name: &str, entry: fn() -> !) -> i32 {
+        let mut proc = Process::new(String::from(name),-1);
+        proc.task_state = State::new(true);
+        proc.task_state.SetFlags(0x2); // Only the always-set bit, so IF is clear
+        proc.hart.store(CurrentHart(),Ordering::SeqCst);
+        let stack = crate::PageFrame::Allocate(KERNEL_THREAD_STACK_SIZE).expect("Couldn't allocate a kernel thread stack") as u64;
+        let pid = Process::AddProcess(proc);
+        // As if entry had been called, so it finds the stack aligned the way it expects
+        Process::StartProcess(pid,entry as usize,(stack + KERNEL_THREAD_STACK_SIZE - 8) as usize);
+        pid
+    }
     pub fn Fork(&mut self, stack: usize) -> Self {
         let mut task_state = State::new(false);
         task_state.Save(&self.task_state);
@@ -311,6 +370,9 @@ impl Process {
             signals: self.signals.clone(),
 
             supgroups: self.supgroups.clone(),
+
+            wait: None,
+            woken: Arc::new(AtomicBool::new(false)),
         }
     }
     pub fn Exec(pid: i32, path: &str, argv: Option<*const usize>, envv: Option<*const usize>) -> usize {
@@ -52,6 +52,12 @@ impl Scheduler {
                                 drop(plock);
                                 return val;
                             }
+                            ProcessStatus::SLEEPING(deadline) if crate::arch::Timer::GetMicroseconds() as i64 >= deadline || proc.woken.swap(false,Ordering::SeqCst) => {
+                                proc.status = ProcessStatus::RUNNABLE;
+                                drop(pqlock);
+                                drop(plock);
+                                return val;
+                            }
                             ProcessStatus::SIGNAL(ip, sig) => {
                                 proc.task_state.SetIP(ip);
                                 proc.task_state.SetSC1(sig);
@@ -133,11 +139,18 @@ impl Scheduler {
         self.idle_thread.Enter();
     }
     pub fn Start(hartid: u32) -> ! {
-        let mut writelock = SCHEDULERS.lock();
         let sched = Scheduler::new();
         if CurrentHart() == 0 {
-            sched.process_queue.lock().push_front(1);
+            // Init, along with any kernel threads that got started before there was a scheduler to queue them on
+            let mut queue = sched.process_queue.lock();
+            for (pid, proc) in PROCESSES.lock().iter().rev() {
+                if matches!(proc.status,ProcessStatus::RUNNABLE) && proc.hart.load(Ordering::SeqCst) == 0 {
+                    queue.push_front(*pid);
+                }
+            }
+            drop(queue);
         }
+        let mut writelock = SCHEDULERS.lock();
         writelock.insert(hartid,Box::new(sched));
         let ptr = writelock.get(&hartid).unwrap().as_ref() as *const Scheduler;
         drop(writelock);
@@ -150,6 +163,9 @@ impl Scheduler {
     }
     #[allow(unreachable_code)]
     pub fn Tick(hartid: u32, state: &State) { // When a timer interrupt goes off, call this function.
+        if hartid == 0 {
+            crate::Net::Tick();
+        }
         let l = SCHEDULERS.lock();
         let sched = l.get(&hartid).unwrap();
         let cur_task = sched.current_proc_id.load(Ordering::SeqCst);
@@ -174,6 +190,50 @@ impl Scheduler {
         }
         panic!("You'll never see this message, isn't that weird?");
     }
+    // Kernel threads run with interrupts off just like a system call does, so nothing takes the hart away from them while
+    // they're holding a lock. These two are the only places they let go of it, so they must never be called with a lock
+    // held that anything else could go for. Outside of a kernel thread they just spin.
+    pub fn Sleep(ms: isize) {
+        use crate::arch::Timer;
+        let deadline = Timer::GetMicroseconds() + (ms as u64 * 1000);
+        let pid = match Scheduler::CurrentKernelThread() {
+            Some(pid) => pid,
+            None => {
+                Timer::Sleep(ms);
+                return;
+            }
+        };
+        if let Some(proc) = PROCESSES.lock().get_mut(&pid) {
+            proc.status = ProcessStatus::SLEEPING(deadline as i64);
+        }
+        // The next tick sees we're asleep and runs something else, and we don't get picked again until the deadline passes
+        while Timer::GetMicroseconds() < deadline {
+            x86_64::instructions::interrupts::enable_and_hlt();
+            x86_64::instructions::interrupts::disable();
+        }
+        if let Some(proc) = PROCESSES.lock().get_mut(&pid) {
+            proc.status = ProcessStatus::RUNNABLE;
+        }
+    }
+    pub fn Yield() {
+        if Scheduler::CurrentKernelThread().is_some() {
+            // Whatever interrupt comes next wakes us up, and if it's the timer everything else gets a turn first
+            x86_64::instructions::interrupts::enable_and_hlt();
+            x86_64::instructions::interrupts::disable();
+        } else {
+            core::hint::spin_loop();
+        }
+    }
+    fn CurrentKernelThread() -> Option<i32> {
+        if !SCHEDULER_STARTED.load(Ordering::SeqCst) {
+            return None;
+        }
+        let pid = SCHEDULERS.lock().get(&CurrentHart())?.current_proc_id.load(Ordering::SeqCst);
+        match PROCESSES.lock().get(&pid) {
+            Some(proc) if proc.task_state.IsKernel() => Some(pid),
+            _ => None,
+        }
+    }
     pub fn CurrentPID() -> i32 {
         let l = SCHEDULERS.lock();
         let sched = l.get(&CurrentHart()).unwrap();
@@ -191,8 +191,417 @@ const MAP_SHARED: usize = 0x2;
 const MAP_FIXED: usize = 0x4;
 const MAP_ANONYOMUS: usize = 0x8;
 
+#[repr(C)]
+pub struct SockMsgStruct {
+    fd: usize,
+    buf: usize,
+    len: usize,
+    flags: usize,
+    addr: usize,
+    addrlen: usize, // The length itself for sendto, a pointer to a socklen_t for recvfrom
+}
+
+#[repr(C)]
+pub struct SockOptStruct {
+    fd: usize,
+    level: usize,
+    name: usize,
+    value: usize,
+    len: usize, // The length itself for setsockopt, a pointer to a socklen_t for getsockopt
+}
+
+// struct msghdr and friends, laid out the way mlibc has them
+#[repr(C)]
+pub struct MsgHdr {
+    name: usize,
+    namelen: u32,
+    iov: usize,
+    iovlen: i32,
+    control: usize,
+    controllen: u32,
+    flags: i32,
+}
+
+#[repr(C)]
+pub struct IOVec {
+    base: usize,
+    len: usize,
+}
+
+#[repr(C)]
+pub struct CMsgHdr {
+    len: u32,
+    level: i32,
+    kind: i32,
+}
+
+const CMSG_HEADER_SIZE: usize = 16; // CMSG_ALIGN(sizeof(struct cmsghdr))
+const MAX_RECVMSG: usize = 0x100000;
+
+#[repr(C)]
+pub struct PollFd {
+    fd: i32,
+    events: i16,
+    revents: i16,
+}
+
+#[repr(C)]
+pub struct SelectStruct {
+    nfds: usize,
+    readfds: usize,
+    writefds: usize,
+    exceptfds: usize,
+    timeout: usize, // A struct timeval, or null to wait forever
+}
+
+// struct timespec and struct timeval, which only differ in what the second field counts
+#[repr(C)]
+pub struct TimeSpec {
+    sec: i64,
+    frac: i64,
+}
+
+const FD_SETSIZE: usize = 1024;
+
+#[repr(C)]
+pub struct ITimerSpec {
+    interval: TimeSpec,
+    value: TimeSpec,
+}
+
+#[repr(C)]
+pub struct TimerSetStruct {
+    fd: usize,
+    flags: usize,
+    new: usize,
+    old: usize, // Can be null
+}
+
+#[repr(C, packed)]
+pub struct EpollEvent {
+    events: u32,
+    data: u64,
+}
+
+#[repr(C)]
+pub struct EpollCtlStruct {
+    epfd: usize,
+    op: usize,
+    fd: usize,
+    event: usize, // Ignored for EPOLL_CTL_DEL
+}
+
+#[repr(C)]
+pub struct EpollWaitStruct {
+    epfd: usize,
+    events: usize,
+    maxevents: usize,
+    timeout: isize, // In milliseconds, negative waits forever
+}
+
+fn GetSocket(curproc: i32, fd: usize) -> Result<Arc<crate::Net::Socket::Socket>,i32> {
+    VFS::Downcast(GetDescriptor(curproc,fd)?).ok_or(Errors::ENOTSOCK)
+}
+
+// Socket calls that can block do their work in functions of their own, so whatever they allocated is already gone by the
+// time we get here with just the socket, since nothing on this stack gets dropped once we're waiting. Blocking sockets
+// sleep until they can get somewhere instead of handing EAGAIN back, so only nonblocking ones ever see it.
+fn SocketReturn(curproc: i32, regs: &mut State, sock: Arc<crate::Net::Socket::Socket>, result: Result<usize,i32>) {
+    match result {
+        Ok(val) => regs.SetSC0(val),
+        Err(Errors::EAGAIN) if !sock.NonBlocking() => {
+            let e = WaitForReady(curproc,regs,alloc::vec![sock as Arc<dyn VFS::Inode>],None).err().unwrap_or(Errors::EAGAIN);
+            regs.SetSC0((-e as isize) as usize);
+        }
+        Err(e) => regs.SetSC0((-e as isize) as usize),
+    }
+}
+
+fn InstallDescriptor(curproc: i32, inode: Arc<dyn VFS::Inode>, close_on_exec: bool) -> i64 {
+    let mut plock = crate::Process::PROCESSES.lock();
+    let proc = plock.get_mut(&curproc).unwrap();
+    let len = if proc.fds.keys().last().is_some() {(*proc.fds.keys().last().unwrap())+1} else {0};
+    proc.fds.insert(len,VFS::FileDescriptor {
+        inode,
+        path: String::from(""),
+        offset: 0,
+        mode: 3,
+        is_dir: false,
+        close_on_exec,
+    });
+    len
+}
+
+fn GetCaller(curproc: i32) -> crate::Net::UNIX::Caller {
+    let plock = crate::Process::PROCESSES.lock();
+    crate::Net::UNIX::Caller::new(plock.get(&curproc).unwrap())
+}
+
+// Copies a socket address or option out to userspace, truncating it to the buffer and reporting its full length like POSIX wants.
+fn CopyOutSized(addr: &[u8], ptr: usize, lenptr: usize) {
+    if ptr == 0 || lenptr == 0 {
+        return;
+    }
+    let lenptr = lenptr as *mut u32;
+    let len = unsafe {*lenptr} as usize;
+    unsafe {
+        core::ptr::copy(addr.as_ptr(),ptr as *mut u8,if len < addr.len() {len} else {addr.len()});
+        *lenptr = addr.len() as u32;
+    }
+}
+
+fn SocketAccept(curproc: i32, regs: &State, sock: &crate::Net::Socket::Socket) -> Result<usize,i32> {
+    let (conn, addr) = sock.Accept()?;
+    CopyOutSized(addr.as_slice(),regs.GetSC2(),regs.GetSC3());
+    Ok(InstallDescriptor(curproc,conn,false) as usize)
+}
+
+fn SocketConnect(curproc: i32, regs: &State, sock: &crate::Net::Socket::Socket) -> Result<usize,i32> {
+    let addr = unsafe {core::slice::from_raw_parts(regs.GetSC2() as *const u8,regs.GetSC3())};
+    sock.Connect(addr,&GetCaller(curproc))?;
+    Ok(0)
+}
+
+fn SocketSendTo(curproc: i32, args: &SockMsgStruct, sock: &crate::Net::Socket::Socket) -> Result<usize,i32> {
+    let buf = unsafe {core::slice::from_raw_parts(args.buf as *const u8,args.len)};
+    let addr = if args.addr != 0 {Some(unsafe {core::slice::from_raw_parts(args.addr as *const u8,args.addrlen)})} else {None};
+    sock.SendTo(buf,addr,Some(&GetCaller(curproc)),Vec::new())
+}
+
+fn SocketRecvFrom(args: &SockMsgStruct, sock: &crate::Net::Socket::Socket) -> Result<usize,i32> {
+    let buf = unsafe {core::slice::from_raw_parts_mut(args.buf as *mut u8,args.len)};
+    let (len, addr) = sock.RecvFrom(buf,args.flags)?;
+    if let Some(addr) = addr {
+        CopyOutSized(addr.as_slice(),args.addr,args.addrlen);
+    } else if args.addrlen != 0 {
+        unsafe {*(args.addrlen as *mut u32) = 0;}
+    }
+    Ok(len)
+}
+
+fn SocketSendMsg(curproc: i32, regs: &State, sock: &crate::Net::Socket::Socket) -> Result<usize,i32> {
+    let msg = unsafe {&*(regs.GetSC2() as *const MsgHdr)};
+    let iov = unsafe {core::slice::from_raw_parts(msg.iov as *const IOVec,msg.iovlen.max(0) as usize)};
+    let mut data: Vec<u8> = Vec::new();
+    for v in iov.iter() {
+        data.extend_from_slice(unsafe {core::slice::from_raw_parts(v.base as *const u8,v.len)});
+    }
+    let addr = if msg.name != 0 {Some(unsafe {core::slice::from_raw_parts(msg.name as *const u8,msg.namelen as usize)})} else {None};
+    let plock = crate::Process::PROCESSES.lock();
+    let proc = plock.get(&curproc).unwrap();
+    let caller = crate::Net::UNIX::Caller::new(proc);
+    let mut fds = Vec::new();
+    let mut offset = 0;
+    while offset + CMSG_HEADER_SIZE <= msg.controllen as usize {
+        let hdr = unsafe {&*((msg.control + offset) as *const CMsgHdr)};
+        if (hdr.len as usize) < CMSG_HEADER_SIZE || offset + hdr.len as usize > msg.controllen as usize {
+            drop(plock);
+            return Err(Errors::EINVAL);
+        }
+        if hdr.level as usize != crate::Net::Socket::SOL_SOCKET || hdr.kind as usize != crate::Net::Socket::SCM_RIGHTS {
+            drop(plock);
+            return Err(Errors::EINVAL);
+        }
+        let count = (hdr.len as usize - CMSG_HEADER_SIZE) / 4;
+        for i in 0..count {
+            let fd = unsafe {*((msg.control + offset + CMSG_HEADER_SIZE + i * 4) as *const i32)};
+            match proc.fds.get(&(fd as i64)) {
+                Some(f) => {fds.push(crate::Net::UNIX::InFlight::new(f));}
+                None => {
+                    drop(plock);
+                    return Err(Errors::EBADF);
+                }
+            }
+        }
+        offset += (hdr.len as usize + 7) & !7;
+    }
+    drop(plock);
+    sock.SendTo(data.as_slice(),addr,Some(&caller),fds)
+}
+
+fn SocketRecvMsg(curproc: i32, regs: &State, sock: &crate::Net::Socket::Socket) -> Result<usize,i32> {
+    let msg = unsafe {&mut *(regs.GetSC2() as *mut MsgHdr)};
+    let iov = unsafe {core::slice::from_raw_parts(msg.iov as *const IOVec,msg.iovlen.max(0) as usize)};
+    let total: usize = iov.iter().map(|v| v.len).sum();
+    let mut buffer: Vec<u8> = alloc::vec![0; total.min(MAX_RECVMSG)];
+    let (len, addr, fds, mut flags) = sock.RecvMsg(buffer.as_mut_slice(),regs.GetSC3())?;
+    let mut copied = 0;
+    for v in iov.iter() {
+        if copied == len {
+            break;
+        }
+        let chunk = v.len.min(len - copied);
+        unsafe {core::ptr::copy(buffer.as_ptr().add(copied),v.base as *mut u8,chunk);}
+        copied += chunk;
+    }
+    if msg.name != 0 {
+        match addr {
+            Some(addr) => {
+                unsafe {core::ptr::copy(addr.as_ptr(),msg.name as *mut u8,addr.len().min(msg.namelen as usize));}
+                msg.namelen = addr.len() as u32;
+            }
+            None => {msg.namelen = 0;}
+        }
+    }
+    // Whatever doesn't fit in the control buffer is closed, and the caller finds out through MSG_CTRUNC
+    let room = (msg.controllen as usize).saturating_sub(CMSG_HEADER_SIZE) / 4;
+    let count = fds.len().min(room);
+    if count < fds.len() {
+        flags |= crate::Net::Socket::MSG_CTRUNC;
+    }
+    msg.controllen = 0;
+    if count > 0 {
+        let mut plock = crate::Process::PROCESSES.lock();
+        let proc = plock.get_mut(&curproc).unwrap();
+        for (i, f) in fds.into_iter().take(count).enumerate() {
+            let fd = if let Some(k) = proc.fds.keys().last() {k+1} else {0};
+            proc.fds.insert(fd,VFS::FileDescriptor {
+                close_on_exec: false,
+                ..f.Deliver()
+            });
+            unsafe {*((msg.control + CMSG_HEADER_SIZE + i * 4) as *mut i32) = fd as i32;}
+        }
+        drop(plock);
+        let hdr = unsafe {&mut *(msg.control as *mut CMsgHdr)};
+        hdr.len = (CMSG_HEADER_SIZE + count * 4) as u32;
+        hdr.level = crate::Net::Socket::SOL_SOCKET as i32;
+        hdr.kind = crate::Net::Socket::SCM_RIGHTS as i32;
+        msg.controllen = hdr.len;
+    }
+    msg.flags = flags as i32;
+    Ok(len)
+}
+
+// Turns a timespec (scale 1000) or timeval (scale 1) into microseconds, a null pointer means forever.
+fn ReadTimeout(ptr: usize, scale: i64) -> Result<Option<u64>,i32> {
+    if ptr == 0 {
+        return Ok(None);
+    }
+    let time = unsafe {&*(ptr as *const TimeSpec)};
+    if time.sec < 0 || time.frac < 0 || time.frac >= 1000000 * scale {
+        return Err(Errors::EINVAL);
+    }
+    Ok(Some(time.sec as u64 * 1000000 + (time.frac / scale) as u64))
+}
+
+// How often files that can't tell us when they change get looked at again by a sleeping system call, in microseconds
+const POLL_INTERVAL: u64 = 10000;
+
+// Nothing's ready yet, so this puts the process to sleep until one of `watched` rings or the timeout runs out, pointing it
+// back at the syscall instruction so the whole system call runs again once it wakes up. The deadline and watches are kept
+// across those retries until SystemCall sees the call finish. This only returns once the deadline has passed, or with EINTR
+// when a signal shows up first. Otherwise it never returns at all, which is why it takes the files by value: nothing on
+// this stack gets dropped after it.
+fn WaitForReady(curproc: i32, regs: &mut State, watched: Vec<Arc<dyn VFS::Inode>>, timeout: Option<u64>) -> Result<(),i32> {
+    use crate::Process::{ProcessStatus, Wait};
+    // Files can take the process table themselves, so ask them for their watchers before we take it
+    let watchers: Vec<_> = watched.iter().map(|i| i.Watchers()).collect();
+    drop(watched);
+    let mut plock = crate::Process::PROCESSES.lock();
+    let proc = plock.get_mut(&curproc).unwrap();
+    if matches!(proc.status,ProcessStatus::SIGNAL(_,_)) {
+        // It came in while we were looking, so the handler returns to an interrupted call
+        proc.sig_state.Save(regs);
+        proc.sig_state.SetSC0((-Errors::EINTR as isize) as usize);
+        return Err(Errors::EINTR);
+    }
+    let now = crate::arch::Timer::GetMicroseconds();
+    if proc.wait.is_none() {
+        let mut wait = Wait {ip: regs.GetIP(), deadline: timeout.map(|t| now + t), watches: Vec::new(), polled: false};
+        for w in watchers.iter() {
+            match w {
+                Some(w) => wait.watches.push(w.Wake(&proc.woken)),
+                None => wait.polled = true,
+            }
+        }
+        proc.wait = Some(wait);
+        // Anything that changed before the watches were in place went unnoticed, so look once more before really sleeping
+        proc.woken.store(true,core::sync::atomic::Ordering::SeqCst);
+    }
+    let wait = proc.wait.as_ref().unwrap();
+    let mut until = wait.deadline.unwrap_or(i64::MAX as u64);
+    if now >= until {
+        return Ok(());
+    }
+    if wait.polled && now + POLL_INTERVAL < until {
+        until = now + POLL_INTERVAL;
+    }
+    proc.status = ProcessStatus::SLEEPING(until as i64);
+    regs.SetIP(regs.GetIP()-2); // Both syscall and int 0x80 are two bytes long
+    proc.task_state.Save(regs); // So a signal sent before the tick below already sees where we'll be
+    drop(plock);
+    drop(watchers);
+    Scheduler::Tick(CurrentHart(),regs);
+    panic!("You'll never see this message, isn't that weird?");
+}
+
+fn PollFds(curproc: i32, regs: &mut State, fds: &mut [PollFd], timeout: Option<u64>) {
+    // Inodes can take the process table themselves (to signal, say), so poll them with it unlocked
+    let plock = crate::Process::PROCESSES.lock();
+    let proc = plock.get(&curproc).unwrap();
+    let inodes: Vec<Option<Arc<dyn VFS::Inode>>> = fds.iter().map(|pfd| proc.fds.get(&(pfd.fd as i64)).map(|fd| fd.inode.clone())).collect();
+    drop(plock);
+    let mut ready = 0;
+    for (pfd, inode) in fds.iter_mut().zip(inodes.iter()) {
+        pfd.revents = 0;
+        if pfd.fd < 0 {
+            continue;
+        }
+        pfd.revents = match inode {
+            Some(inode) => inode.Poll() & (pfd.events | VFS::POLLERR | VFS::POLLHUP),
+            None => VFS::POLLNVAL,
+        };
+        if pfd.revents != 0 {
+            ready += 1;
+        }
+    }
+    if ready > 0 {
+        regs.SetSC0(ready);
+        return;
+    }
+    match WaitForReady(curproc,regs,inodes.into_iter().flatten().collect(),timeout) {
+        Ok(()) => regs.SetSC0(0),
+        Err(e) => regs.SetSC0((-e as isize) as usize),
+    }
+}
+
+fn GetDescriptor(curproc: i32, fd: usize) -> Result<Arc<dyn VFS::Inode>,i32> {
+    let plock = crate::Process::PROCESSES.lock();
+    let proc = plock.get(&curproc).unwrap();
+    let inode = proc.fds.get(&(fd as i64)).map(|f| f.inode.clone());
+    drop(plock);
+    inode.ok_or(Errors::EBADF)
+}
+
+fn ToITimerSpec(value: u64, interval: u64) -> ITimerSpec {
+    ITimerSpec {
+        interval: TimeSpec {sec: (interval / 1000000) as i64, frac: ((interval % 1000000) * 1000) as i64},
+        value: TimeSpec {sec: (value / 1000000) as i64, frac: ((value % 1000000) * 1000) as i64},
+    }
+}
+
+fn GetEpoll(curproc: i32, fd: usize) -> Result<Arc<crate::Drivers::Generic::Epoll::Epoll>,i32> {
+    VFS::Downcast(GetDescriptor(curproc,fd)?).ok_or(Errors::EINVAL)
+}
+
+fn GetTimerFD(curproc: i32, fd: usize) -> Result<Arc<crate::Drivers::Generic::TimerFD::TimerFD>,i32> {
+    VFS::Downcast(GetDescriptor(curproc,fd)?).ok_or(Errors::EINVAL)
+}
+
+fn FdIsSet(set: usize, fd: usize) -> bool {
+    set != 0 && unsafe {*((set + fd/8) as *const u8)} & (1 << (fd%8)) != 0
+}
+
 pub fn SystemCall(regs: &mut State) {
     let curproc = Scheduler::CurrentPID();
+    Dispatch(curproc,regs);
+    // A call that's still waiting never makes it back here, so whatever it was waiting for is over now
+    if let Some(proc) = crate::Process::PROCESSES.lock().get_mut(&curproc) {
+        proc.wait = None;
+    }
+}
+
+fn Dispatch(curproc: i32, regs: &mut State) {
     match regs.GetSC0() {
         0x00 => { // yield
             Scheduler::Tick(CurrentHart(),regs);
@@ -268,12 +677,24 @@ pub fn SystemCall(regs: &mut State) {
                 drop(plock);
                 return;
             }
+            if metadata.mode & 0o0170000 == VFS::FTYPE_SOCK as i32 { // Sockets are reached through connect(), not open()
+                regs.SetSC0((-Errors::ENXIO as isize) as usize);
+                drop(plock);
+                return;
+            }
+            let abspath = VFS::GetAbsPath(path.ok().unwrap(),proc.cwd.as_str());
+            drop(plock); // Opening a PTY looks up who we are
+            if let Err(e) = file.as_ref().ok().unwrap().Open(mode) { // FIFOs can refuse, or ask us to come back once the other end is open
+                regs.SetSC0((-e as isize) as usize);
+                return;
+            }
+            let mut plock = crate::Process::PROCESSES.lock();
+            let proc = plock.get_mut(&curproc).unwrap();
             // We can finally create the File Descriptor!
             let len = if proc.fds.keys().last().is_some() {(*proc.fds.keys().last().unwrap())+1} else {0};
-            file.as_ref().ok().unwrap().Open(mode);
             proc.fds.insert(len,VFS::FileDescriptor {
                 inode: file.ok().unwrap(),
-                path: VFS::GetAbsPath(path.ok().unwrap(),proc.cwd.as_str()),
+                path: abspath,
                 offset: 0,
                 mode,
                 is_dir: metadata.mode & 0o0040000 != 0,
@@ -350,15 +771,19 @@ pub fn SystemCall(regs: &mut State) {
                     }
                 }
             } else {
-                let res = fd.as_ref().unwrap().inode.Read(fd.as_ref().unwrap().offset,buf);
+                // Drivers can take the process table themselves (signals, credentials), so it can't be held across the read
+                let inode = fd.as_ref().unwrap().inode.clone();
+                let offset = fd.as_ref().unwrap().offset;
+                drop(plock);
+                let res = inode.Read(offset,buf);
                 if res < 0 {
-                    drop(plock);
                     regs.SetSC0(res as usize);
                     return;
                 }
-                fd.unwrap().offset += res as i64;
+                if let Some(fd) = crate::Process::PROCESSES.lock().get_mut(&curproc).and_then(|p| p.fds.get_mut(&(regs.GetSC1() as i64))) {
+                    fd.offset += res as i64;
+                }
                 regs.SetSC0(res as usize);
-                drop(plock);
             }
         }
         0x06 => { // write
@@ -381,15 +806,18 @@ pub fn SystemCall(regs: &mut State) {
                 regs.SetSC0((-Errors::EINVAL) as usize);
                 return;
             }
-            let res = fd.as_ref().unwrap().inode.Write(fd.as_ref().unwrap().offset,buf);
+            let inode = fd.as_ref().unwrap().inode.clone();
+            let offset = fd.as_ref().unwrap().offset;
+            drop(plock);
+            let res = inode.Write(offset,buf);
             if res < 0 {
-                drop(plock);
                 regs.SetSC0(res as usize);
                 return;
             }
-            fd.unwrap().offset += res as i64;
+            if let Some(fd) = crate::Process::PROCESSES.lock().get_mut(&curproc).and_then(|p| p.fds.get_mut(&(regs.GetSC1() as i64))) {
+                fd.offset += res as i64;
+            }
             regs.SetSC0(res as usize);
-            drop(plock);
         }
         0x07 => { // lseek
             let mut plock = crate::Process::PROCESSES.lock();
@@ -400,6 +828,17 @@ pub fn SystemCall(regs: &mut State) {
                 regs.SetSC0((-Errors::EBADF) as usize);
                 return;
             }
+            if let Some(result) = fd.as_ref().unwrap().inode.Seek((regs.GetSC2() as isize) as i64,regs.GetSC3()) {
+                match result {
+                    Ok(offset) => {
+                        fd.as_mut().unwrap().offset = offset;
+                        regs.SetSC0(offset as usize);
+                    }
+                    Err(e) => regs.SetSC0((-e as isize) as usize),
+                }
+                drop(plock);
+                return;
+            }
             let max_size = fd.as_ref().unwrap().inode.Stat().ok().unwrap().size;
             match regs.GetSC3() {
                 1 => { // SEEK_CUR
@@ -614,14 +1053,14 @@ pub fn SystemCall(regs: &mut State) {
                 regs.SetSC0((-Errors::EBADF) as usize);
                 return;
             }
-            let result = fd.unwrap().inode.IOCtl(regs.GetSC2(),regs.GetSC3());
+            let inode = fd.unwrap().inode.clone();
+            drop(plock);
+            let result = inode.IOCtl(regs.GetSC2(),regs.GetSC3());
             if result.is_err() {
                 regs.SetSC0((-result.err().unwrap() as isize) as usize);
-                drop(plock);
                 return;
             }
             regs.SetSC0(result.ok().unwrap());
-            drop(plock);
         }
         0x12 => { // execve
             let path = unsafe {CStr::from_ptr(regs.GetSC1() as *const c_char)}.to_str();
@@ -803,7 +1242,7 @@ pub fn SystemCall(regs: &mut State) {
             drop(plock);
         }
         0x1e => { // signal
-            if regs.GetSC1() > 0x17 || regs.GetSC1() == 0 {
+            if regs.GetSC1() > 0x1c || regs.GetSC1() == 0 {
                 regs.SetSC0((-Errors::EINVAL as isize) as usize);
                 return;
             }
@@ -1062,6 +1501,430 @@ pub fn SystemCall(regs: &mut State) {
             drop(plock);
             regs.SetSC0(0);
         }
+        0x2a => { // socket
+            let cred = GetCaller(curproc).cred;
+            match crate::Net::Socket::Socket::new(regs.GetSC1(),regs.GetSC2(),regs.GetSC3(),cred) {
+                Ok(sock) => {
+                    let fd = InstallDescriptor(curproc,sock,regs.GetSC2() & crate::Net::Socket::SOCK_CLOEXEC != 0);
+                    regs.SetSC0(fd as usize);
+                }
+                Err(e) => {regs.SetSC0((-e as isize) as usize);}
+            }
+        }
+        0x2b => { // bind
+            let sock = match GetSocket(curproc,regs.GetSC1()) {
+                Ok(i) => i,
+                Err(e) => {regs.SetSC0((-e as isize) as usize); return;}
+            };
+            let addr = unsafe {core::slice::from_raw_parts(regs.GetSC2() as *const u8,regs.GetSC3())};
+            match sock.Bind(addr,&GetCaller(curproc)) {
+                Ok(_) => {regs.SetSC0(0);}
+                Err(e) => {regs.SetSC0((-e as isize) as usize);}
+            }
+        }
+        0x2c => { // listen
+            let sock = match GetSocket(curproc,regs.GetSC1()) {
+                Ok(i) => i,
+                Err(e) => {regs.SetSC0((-e as isize) as usize); return;}
+            };
+            match sock.Listen(regs.GetSC2()) {
+                Ok(_) => {regs.SetSC0(0);}
+                Err(e) => {regs.SetSC0((-e as isize) as usize);}
+            }
+        }
+        0x2d => { // accept
+            let sock = match GetSocket(curproc,regs.GetSC1()) {
+                Ok(i) => i,
+                Err(e) => {regs.SetSC0((-e as isize) as usize); return;}
+            };
+            let result = SocketAccept(curproc,regs,&sock);
+            SocketReturn(curproc,regs,sock,result);
+        }
+        0x2e => { // connect
+            let sock = match GetSocket(curproc,regs.GetSC1()) {
+                Ok(i) => i,
+                Err(e) => {regs.SetSC0((-e as isize) as usize); return;}
+            };
+            let result = SocketConnect(curproc,regs,&sock);
+            SocketReturn(curproc,regs,sock,result);
+        }
+        0x2f => { // sendto
+            let args = unsafe {&*(regs.GetSC1() as *const SockMsgStruct)};
+            let sock = match GetSocket(curproc,args.fd) {
+                Ok(i) => i,
+                Err(e) => {regs.SetSC0((-e as isize) as usize); return;}
+            };
+            let result = SocketSendTo(curproc,args,&sock);
+            SocketReturn(curproc,regs,sock,result);
+        }
+        0x30 => { // recvfrom
+            let args = unsafe {&*(regs.GetSC1() as *const SockMsgStruct)};
+            let sock = match GetSocket(curproc,args.fd) {
+                Ok(i) => i,
+                Err(e) => {regs.SetSC0((-e as isize) as usize); return;}
+            };
+            let result = SocketRecvFrom(args,&sock);
+            SocketReturn(curproc,regs,sock,result);
+        }
+        0x31 => { // setsockopt
+            let args = unsafe {&*(regs.GetSC1() as *const SockOptStruct)};
+            let sock = match GetSocket(curproc,args.fd) {
+                Ok(i) => i,
+                Err(e) => {regs.SetSC0((-e as isize) as usize); return;}
+            };
+            let value = unsafe {core::slice::from_raw_parts(args.value as *const u8,args.len)};
+            match sock.SetOption(args.level,args.name,value) {
+                Ok(_) => {regs.SetSC0(0);}
+                Err(e) => {regs.SetSC0((-e as isize) as usize);}
+            }
+        }
+        0x32 => { // getsockopt
+            let args = unsafe {&*(regs.GetSC1() as *const SockOptStruct)};
+            let sock = match GetSocket(curproc,args.fd) {
+                Ok(i) => i,
+                Err(e) => {regs.SetSC0((-e as isize) as usize); return;}
+            };
+            match sock.GetOption(args.level,args.name) {
+                Ok(val) => {
+                    CopyOutSized(val.as_slice(),args.value,args.len);
+                    regs.SetSC0(0);
+                }
+                Err(e) => {regs.SetSC0((-e as isize) as usize);}
+            }
+        }
+        0x33 => { // shutdown
+            let sock = match GetSocket(curproc,regs.GetSC1()) {
+                Ok(i) => i,
+                Err(e) => {regs.SetSC0((-e as isize) as usize); return;}
+            };
+            match sock.Shutdown(regs.GetSC2()) {
+                Ok(_) => {regs.SetSC0(0);}
+                Err(e) => {regs.SetSC0((-e as isize) as usize);}
+            }
+        }
+        0x34 => { // getsockname
+            let sock = match GetSocket(curproc,regs.GetSC1()) {
+                Ok(i) => i,
+                Err(e) => {regs.SetSC0((-e as isize) as usize); return;}
+            };
+            let addr = sock.LocalAddress();
+            CopyOutSized(addr.as_slice(),regs.GetSC2(),regs.GetSC3());
+            regs.SetSC0(0);
+        }
+        0x35 => { // getpeername
+            let sock = match GetSocket(curproc,regs.GetSC1()) {
+                Ok(i) => i,
+                Err(e) => {regs.SetSC0((-e as isize) as usize); return;}
+            };
+            match sock.PeerAddress() {
+                Ok(addr) => {
+                    CopyOutSized(addr.as_slice(),regs.GetSC2(),regs.GetSC3());
+                    regs.SetSC0(0);
+                }
+                Err(e) => {regs.SetSC0((-e as isize) as usize);}
+            }
+        }
+        0x36 => { // socketpair
+            let cred = GetCaller(curproc).cred;
+            match crate::Net::Socket::Socket::Pair(regs.GetSC1(),regs.GetSC2(),cred) {
+                Ok((a, b)) => {
+                    let close_on_exec = regs.GetSC2() & crate::Net::Socket::SOCK_CLOEXEC != 0;
+                    let ptr = regs.GetSC3() as *mut i32;
+                    unsafe {
+                        *ptr = InstallDescriptor(curproc,a,close_on_exec) as i32;
+                        *ptr.offset(1) = InstallDescriptor(curproc,b,close_on_exec) as i32;
+                    }
+                    regs.SetSC0(0);
+                }
+                Err(e) => {regs.SetSC0((-e as isize) as usize);}
+            }
+        }
+        0x37 => { // sendmsg
+            let sock = match GetSocket(curproc,regs.GetSC1()) {
+                Ok(i) => i,
+                Err(e) => {regs.SetSC0((-e as isize) as usize); return;}
+            };
+            let result = SocketSendMsg(curproc,regs,&sock);
+            SocketReturn(curproc,regs,sock,result);
+        }
+        0x38 => { // recvmsg
+            let sock = match GetSocket(curproc,regs.GetSC1()) {
+                Ok(i) => i,
+                Err(e) => {regs.SetSC0((-e as isize) as usize); return;}
+            };
+            let result = SocketRecvMsg(curproc,regs,&sock);
+            SocketReturn(curproc,regs,sock,result);
+        }
+        0x39 => { // mknod
+            let plock = crate::Process::PROCESSES.lock();
+            let proc = plock.get(&curproc).unwrap();
+            let path = unsafe {CStr::from_ptr(regs.GetSC1() as *const c_char)}.to_str();
+            if path.is_err() {
+                regs.SetSC0((-Errors::EINVAL as isize) as usize);
+                drop(plock);
+                return;
+            }
+            let mode = regs.GetSC2() as i32;
+            let kind = if mode & 0o0170000 == 0 {0o0100000} else {mode & 0o0170000};
+            if kind != VFS::FTYPE_FIFO as i32 && kind != 0o0100000 { // There's nowhere to put device numbers, and sockets come from bind()
+                regs.SetSC0((-(if kind == 0o0020000 || kind == 0o0060000 {Errors::EPERM} else {Errors::EINVAL}) as isize) as usize);
+                drop(plock);
+                return;
+            }
+            let abspath = VFS::GetAbsPath(path.ok().unwrap(),proc.cwd.as_str());
+            let mut parent: Vec<_> = abspath.split("/").filter(|e| *e != "" && *e != ".").collect();
+            let name = match parent.pop() {
+                Some(n) => n,
+                None => {
+                    regs.SetSC0((-Errors::EEXIST as isize) as usize);
+                    drop(plock);
+                    return;
+                }
+            };
+            let parinode = VFS::LookupPath([String::from("/"),parent.join("/")].join("").as_str());
+            if parinode.is_err() {
+                regs.SetSC0((-parinode.err().unwrap() as isize) as usize);
+                drop(plock);
+                return;
+            }
+            let inode = parinode.ok().unwrap().Creat(name,kind | (mode & 0o777 & !proc.umask));
+            if inode.is_err() {
+                regs.SetSC0((-inode.err().unwrap() as isize) as usize);
+                drop(plock);
+                return;
+            }
+            inode.ok().unwrap().ChOwn(proc.euid as i32,proc.egid as i32);
+            regs.SetSC0(0);
+            drop(plock);
+        }
+        0x3a => { // poll
+            let fds = unsafe {core::slice::from_raw_parts_mut(regs.GetSC1() as *mut PollFd, regs.GetSC2())};
+            let timeout = regs.GetSC3() as i32;
+            PollFds(curproc,regs,fds,if timeout < 0 {None} else {Some(timeout as u64 * 1000)});
+        }
+        0x3b => { // ppoll
+            // There's no signal masking yet, so this doesn't take a sigmask
+            let fds = unsafe {core::slice::from_raw_parts_mut(regs.GetSC1() as *mut PollFd, regs.GetSC2())};
+            match ReadTimeout(regs.GetSC3(),1000) {
+                Ok(timeout) => PollFds(curproc,regs,fds,timeout),
+                Err(e) => regs.SetSC0((-e as isize) as usize),
+            }
+        }
+        0x3c => { // select
+            let args = unsafe {&*(regs.GetSC1() as *const SelectStruct)};
+            if args.nfds > FD_SETSIZE {
+                regs.SetSC0((-Errors::EINVAL as isize) as usize);
+                return;
+            }
+            let timeout = match ReadTimeout(args.timeout,1) {
+                Ok(t) => t,
+                Err(e) => {
+                    regs.SetSC0((-e as isize) as usize);
+                    return;
+                }
+            };
+            // The caller's sets are only written once this returns, since every retry needs to read them again
+            let sets = [args.readfds, args.writefds, args.exceptfds];
+            let mut results = [[0u8; FD_SETSIZE/8]; 3];
+            let mut ready = 0;
+            // Gather the inodes first so they're polled with the process table unlocked, same as poll
+            let mut watched = Vec::new();
+            let plock = crate::Process::PROCESSES.lock();
+            let proc = plock.get(&curproc).unwrap();
+            for fd in 0..args.nfds {
+                let wanted = [FdIsSet(sets[0],fd), FdIsSet(sets[1],fd), FdIsSet(sets[2],fd)];
+                if !wanted.contains(&true) {
+                    continue;
+                }
+                match proc.fds.get(&(fd as i64)) {
+                    Some(f) => watched.push((fd,wanted,f.inode.clone())),
+                    None => {
+                        drop(plock);
+                        regs.SetSC0((-Errors::EBADF as isize) as usize);
+                        return;
+                    }
+                }
+            }
+            drop(plock);
+            for (fd, wanted, inode) in watched.iter() {
+                let (fd, wanted) = (*fd, *wanted);
+                let events = inode.Poll();
+                let got = [events & (VFS::POLLIN | VFS::POLLHUP | VFS::POLLERR) != 0, events & (VFS::POLLOUT | VFS::POLLERR) != 0, events & VFS::POLLPRI != 0];
+                for i in 0..3 {
+                    if wanted[i] && got[i] {
+                        results[i][fd/8] |= 1 << (fd%8);
+                        ready += 1;
+                    }
+                }
+            }
+            if ready == 0 {
+                if let Err(e) = WaitForReady(curproc,regs,watched.into_iter().map(|(_, _, inode)| inode).collect(),timeout) {
+                    regs.SetSC0((-e as isize) as usize);
+                    return;
+                }
+            }
+            for i in 0..3 {
+                if sets[i] != 0 {
+                    unsafe {core::ptr::copy(results[i].as_ptr(),sets[i] as *mut u8,(args.nfds+7)/8);}
+                }
+            }
+            regs.SetSC0(ready);
+        }
+        0x3d => { // epoll_create
+            use crate::Drivers::Generic::Epoll;
+            if regs.GetSC1() & !Epoll::EPOLL_CLOEXEC != 0 {
+                regs.SetSC0((-Errors::EINVAL as isize) as usize);
+                return;
+            }
+            let fd = InstallDescriptor(curproc,Epoll::Epoll::new(),regs.GetSC1() & Epoll::EPOLL_CLOEXEC != 0);
+            regs.SetSC0(fd as usize);
+        }
+        0x3e => { // epoll_ctl
+            let args = unsafe {&*(regs.GetSC1() as *const EpollCtlStruct)};
+            let epoll = match GetEpoll(curproc,args.epfd) {
+                Ok(e) => e,
+                Err(e) => {
+                    regs.SetSC0((-e as isize) as usize);
+                    return;
+                }
+            };
+            let plock = crate::Process::PROCESSES.lock();
+            let target = plock.get(&curproc).unwrap().fds.get(&(args.fd as i64)).map(|f| f.inode.clone());
+            drop(plock);
+            let target = match target {
+                Some(t) => t,
+                None => {
+                    regs.SetSC0((-Errors::EBADF as isize) as usize);
+                    return;
+                }
+            };
+            if Arc::as_ptr(&target) as *const u8 == Arc::as_ptr(&epoll) as *const u8 {
+                regs.SetSC0((-Errors::EINVAL as isize) as usize);
+                return;
+            }
+            let (events, data) = if args.event != 0 {
+                let event = unsafe {&*(args.event as *const EpollEvent)};
+                (event.events, event.data)
+            } else if args.op == crate::Drivers::Generic::Epoll::EPOLL_CTL_DEL {
+                (0, 0)
+            } else {
+                regs.SetSC0((-Errors::EFAULT as isize) as usize);
+                return;
+            };
+            match epoll.Control(args.op,args.fd as i64,target,events,data) {
+                Ok(()) => regs.SetSC0(0),
+                Err(e) => regs.SetSC0((-e as isize) as usize),
+            }
+        }
+        0x3f => { // epoll_wait
+            let args = unsafe {&*(regs.GetSC1() as *const EpollWaitStruct)};
+            if args.maxevents == 0 || args.maxevents > i32::MAX as usize {
+                regs.SetSC0((-Errors::EINVAL as isize) as usize);
+                return;
+            }
+            let epoll = match GetEpoll(curproc,args.epfd) {
+                Ok(e) => e,
+                Err(e) => {
+                    regs.SetSC0((-e as isize) as usize);
+                    return;
+                }
+            };
+            let ready = epoll.Collect(args.maxevents);
+            if ready.len() == 0 {
+                match WaitForReady(curproc,regs,alloc::vec![epoll as Arc<dyn VFS::Inode>],if args.timeout < 0 {None} else {Some(args.timeout as u64 * 1000)}) {
+                    Ok(()) => regs.SetSC0(0),
+                    Err(e) => regs.SetSC0((-e as isize) as usize),
+                }
+                return;
+            }
+            let out = unsafe {core::slice::from_raw_parts_mut(args.events as *mut EpollEvent, ready.len())};
+            for (i, (events, data)) in ready.iter().enumerate() {
+                out[i] = EpollEvent {events: *events, data: *data};
+            }
+            regs.SetSC0(ready.len());
+        }
+        0x40 => { // eventfd
+            use crate::Drivers::Generic::EventFD;
+            let flags = regs.GetSC2();
+            if flags & !(EventFD::EFD_SEMAPHORE | OpenFlags::O_NONBLOCK | OpenFlags::O_CLOEXEC) != 0 {
+                regs.SetSC0((-Errors::EINVAL as isize) as usize);
+                return;
+            }
+            let fd = InstallDescriptor(curproc,EventFD::EventFD::new(regs.GetSC1() as u32 as u64,flags),flags & OpenFlags::O_CLOEXEC != 0);
+            regs.SetSC0(fd as usize);
+        }
+        0x41 => { // timerfd_create
+            let flags = regs.GetSC2();
+            if flags & !(OpenFlags::O_NONBLOCK | OpenFlags::O_CLOEXEC) != 0 {
+                regs.SetSC0((-Errors::EINVAL as isize) as usize);
+                return;
+            }
+            match crate::Drivers::Generic::TimerFD::TimerFD::new(regs.GetSC1()) {
+                Ok(timer) => {
+                    let fd = InstallDescriptor(curproc,timer,flags & OpenFlags::O_CLOEXEC != 0);
+                    regs.SetSC0(fd as usize);
+                }
+                Err(e) => regs.SetSC0((-e as isize) as usize),
+            }
+        }
+        0x42 => { // timerfd_settime
+            let args = unsafe {&*(regs.GetSC1() as *const TimerSetStruct)};
+            let timer = match GetTimerFD(curproc,args.fd) {
+                Ok(t) => t,
+                Err(e) => {
+                    regs.SetSC0((-e as isize) as usize);
+                    return;
+                }
+            };
+            if args.new == 0 {
+                regs.SetSC0((-Errors::EFAULT as isize) as usize);
+                return;
+            }
+            let new = unsafe {&*(args.new as *const ITimerSpec)};
+            let value = ReadTimeout(&new.value as *const TimeSpec as usize,1000);
+            let interval = ReadTimeout(&new.interval as *const TimeSpec as usize,1000);
+            match (value, interval) {
+                (Ok(Some(value)), Ok(Some(interval))) => {
+                    let old = timer.Set(args.flags,value,interval);
+                    if args.old != 0 {
+                        unsafe {*(args.old as *mut ITimerSpec) = ToITimerSpec(old.0,old.1);}
+                    }
+                    regs.SetSC0(0);
+                }
+                _ => regs.SetSC0((-Errors::EINVAL as isize) as usize),
+            }
+        }
+        0x43 => { // timerfd_gettime
+            match GetTimerFD(curproc,regs.GetSC1()) {
+                Ok(timer) => {
+                    let current = timer.Get();
+                    unsafe {*(regs.GetSC2() as *mut ITimerSpec) = ToITimerSpec(current.0,current.1);}
+                    regs.SetSC0(0);
+                }
+                Err(e) => regs.SetSC0((-e as isize) as usize),
+            }
+        }
+        0x44 => { // signalfd
+            use crate::Drivers::Generic::SignalFD;
+            let flags = regs.GetSC3();
+            if flags & !(OpenFlags::O_NONBLOCK | OpenFlags::O_CLOEXEC) != 0 {
+                regs.SetSC0((-Errors::EINVAL as isize) as usize);
+                return;
+            }
+            let mask = unsafe {*(regs.GetSC2() as *const u64)};
+            if regs.GetSC1() as isize == -1 {
+                let fd = InstallDescriptor(curproc,SignalFD::SignalFD::new(curproc,mask),flags & OpenFlags::O_CLOEXEC != 0);
+                regs.SetSC0(fd as usize);
+                return;
+            }
+            match GetDescriptor(curproc,regs.GetSC1()).and_then(|i| VFS::Downcast::<SignalFD::SignalFD>(i).ok_or(Errors::EINVAL)) {
+                Ok(signals) => {
+                    signals.SetMask(mask);
+                    regs.SetSC0(regs.GetSC1());
+                }
+                Err(e) => regs.SetSC0((-e as isize) as usize),
+            }
+        }
         0xf0 => { // foxkernel_powerctl
             if curproc > 1 {
                 regs.SetSC0((-Errors::EACCES as isize) as usize);
@@ -1077,16 +1940,7 @@ pub fn SystemCall(regs: &mut State) {
             } else if regs.GetSC1() as u32 == 926892958 { // FOXKERNEL_SHUTDOWN
                 unsafe {crate::Console::QUIET = true;}
                 log::info!("It is now safe to turn off your computer");
-                if let Some(fb) = crate::Framebuffer::MainFramebuffer.lock().as_mut() {
-                    let ptr = fb.pointer as *mut u32;
-                    for i in 0..(fb.width*fb.height) {
-                        let pxl = unsafe {ptr.offset(i as isize).read()};
-                        let val = ((((pxl & 0xFF0000) >> 16)+((pxl & 0xFF00) >> 8)+(pxl & 0xFF))/3)/2;
-                        unsafe {ptr.offset(i as isize).write((val << 16) | (val << 8) | val)};
-                    }
-                    let msg = "It is now safe to turn off your computer";
-                    fb.DrawString((fb.width/2)-(msg.len()*8),fb.height/2-16,msg,0xFFFFFF,2);
-                }
+                crate::Framebuffer::ShutdownScreen();
                 crate::halt_other_harts!();
                 crate::halt!();
                 unreachable!();
@@ -1106,7 +1960,7 @@ pub fn SystemCall(regs: &mut State) {
                 regs.SetSC0((-Errors::EINVAL as isize) as usize);
                 return;
             }
-            print!("{}\n", msg.ok().unwrap());
+            crate::Drivers::Generic::KMsg::WriteCurrent(msg.ok().unwrap());
             regs.SetSC0(0);
         }
         _ => {
@@ -1,7 +1,7 @@
 #![no_std]
 #![no_main]
 #![feature(asm_sym,const_btree_new,naked_functions,map_first_last,
-const_mut_refs,panic_info_message,lang_items,rustc_private,int_roundings,linked_list_cursors)]
+const_mut_refs,panic_info_message,lang_items,rustc_private,int_roundings,linked_list_cursors,trait_upcasting)]
 #![allow(non_snake_case,unused_must_use,non_upper_case_globals,non_camel_case_types)]
 
 extern crate alloc;
@@ -23,8 +23,12 @@ pub mod Framebuffer;
 pub mod Syscall;
 pub mod FS;
 pub mod Drivers;
+pub mod Net;
 pub mod CommandLine;
 pub mod ELF;
+pub mod Backtrace;
+pub mod KernelLog;
+pub mod CrashLog;
 pub mod Stack;
 
 use core::panic::PanicInfo;
@@ -49,6 +53,7 @@ fn main(ramdisks: Vec<(String,&[u8])>) -> ! {
     crate::Framebuffer::Progress(0);
     FS::InitalizeEarly();
     Drivers::Initalize();
+    Net::Initalize();
     crate::Framebuffer::Progress(1);
     FS::Initalize(ramdisks);
     crate::Framebuffer::Progress(2);
@@ -66,6 +71,7 @@ fn main(ramdisks: Vec<(String,&[u8])>) -> ! {
             panic!("No command");
         }
     }
+    Drivers::Generic::USB::StartWorker();
     crate::Framebuffer::Progress(3);
     {
         let used = PageFrame::UsedMem.load(core::sync::atomic::Ordering::SeqCst);
@@ -91,11 +97,13 @@ fn panic(info: &PanicInfo) -> ! {
     unsafe {crate::Console::QUIET = false;}
     if unsafe{PANICKING} {
         print!("\n\x1b[31m!!!Nested Panic!!!\n");
+        crate::CrashLog::NestedPanic(info);
         halt!();
         loop {};
     }
     unsafe {PANICKING = true;}
     halt_other_harts!();
+    crate::CrashLog::Panic(info);
     let msg = info.message();
     match msg {
         Some(m) => {
@@ -108,6 +116,8 @@ fn panic(info: &PanicInfo) -> ! {
             print!("{}\n\x1b[0m", info.location().unwrap());
         }
     }
+    crate::Backtrace::PrintPanic();
+    crate::Framebuffer::PanicScreen(info);
     halt!();
     loop {}
 }
@@ -0,0 +1,19 @@
+#[path = "../../../../Fox Kernel/src/Drivers/Generic/BlockDevice.rs"]
+pub mod BlockDevice;
+#[path = "../../../../Fox Kernel/src/Drivers/Generic/Epoll.rs"]
+pub mod Epoll;
+#[path = "../../../../Fox Kernel/src/Drivers/Generic/LineDiscipline.rs"]
+pub mod LineDiscipline;
+#[path = "../../../../Fox Kernel/src/Drivers/Generic/NetworkDevice.rs"]
+pub mod NetworkDevice;
+
+pub mod PseudoTTY {
+    pub const TCGETS: usize = 0x4000;
+    pub const TCSETS: usize = 0x4001;
+    pub const TCSETSW: usize = 0x4002;
+    pub const TCSETSF: usize = 0x4003;
+    pub const TCXONC: usize = 0x4005;
+    pub const TCFLSH: usize = 0x4006;
+    pub const TIOCGPGRP: usize = 0x400A;
+    pub const TIOCSPGRP: usize = 0x400B;
+}
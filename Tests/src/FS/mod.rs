@@ -0,0 +1,23 @@
+#[path = "../../../Fox Kernel/src/FS/VFS.rs"]
+pub mod VFS;
+
+pub mod DevFS {
+    use super::VFS;
+    use alloc::sync::Arc;
+    use core::sync::atomic::{AtomicUsize,Ordering};
+
+    pub trait Device: Send + Sync {
+        fn DeviceID(&self) -> usize;
+        fn Inode(&self) -> Arc<dyn VFS::Inode>;
+    }
+
+    static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+    pub fn ReserveDeviceID() -> usize {
+        NEXT_ID.fetch_add(1,Ordering::SeqCst)
+    }
+
+    pub fn InstallDevice(_dev: Arc<dyn Device>) -> Result<(),i64> {
+        Ok(())
+    }
+}
@@ -0,0 +1,108 @@
+// Builds the parts of the Fox Kernel that don't touch hardware for the host, so their tests can run with a plain
+// `cargo test` from this directory. The kernel's files are pulled in as they are, and everything below stands in for
+// the modules they expect to find around them.
+#![feature(trait_upcasting)]
+#![allow(non_snake_case,unused_must_use,non_upper_case_globals,non_camel_case_types,dead_code,stable_features)]
+// The kernel isn't written to clippy's taste, only the lints that catch actual mistakes are left on
+#![allow(clippy::style,clippy::complexity)]
+
+extern crate alloc;
+// Lets `x86_64::instructions::...` in the kernel's files find the stand-ins below instead of the real crate, which
+// would try to turn interrupts off in user mode.
+extern crate self as x86_64;
+
+#[path = "../../Fox Kernel/src/CommandLine.rs"]
+pub mod CommandLine;
+#[path = "../../Fox Kernel/src/Net/mod.rs"]
+pub mod Net;
+pub mod FS;
+pub mod Drivers;
+
+pub static mut UNIX_EPOCH: u64 = 0;
+
+pub mod instructions {
+    pub mod interrupts {
+        pub fn without_interrupts<F: FnOnce() -> R, R>(f: F) -> R {
+            f()
+        }
+    }
+}
+
+pub mod arch {
+    pub mod Timer {
+        use core::sync::atomic::{AtomicU64,Ordering};
+
+        // Time only moves when a test says so
+        pub static NOW: AtomicU64 = AtomicU64::new(0);
+
+        pub fn GetMicroseconds() -> u64 {
+            NOW.load(Ordering::SeqCst)
+        }
+    }
+}
+
+pub mod Console {
+    pub static mut NO_COLOR: bool = false;
+    pub static mut QUIET: bool = false;
+    pub static mut SERIAL_LOG: bool = true;
+}
+
+pub mod Process {
+    use alloc::string::String;
+    use alloc::vec::Vec;
+
+    pub mod Signals {
+        pub const SIGINT: u8 = 0x02;
+        pub const SIGQUIT: u8 = 0x03;
+        pub const SIGTSTP: u8 = 0x14;
+    }
+
+    pub struct Process {
+        pub id: i32,
+        pub euid: u32,
+        pub egid: u32,
+        pub umask: i32,
+        pub cwd: String,
+        pub supgroups: Vec<u32>,
+    }
+
+    impl Process {
+        pub fn SignalGroup(_pgid: i32, _sig: u8) {}
+    }
+}
+
+pub mod Syscall {
+    pub mod Errors {
+        pub const EPERM: i32 = 1;  /* Operation not permitted */
+        pub const ENOENT: i32 = 2;  /* No such file or directory */
+        pub const EAGAIN: i32 = 11;  /* Try again */
+        pub const EACCES: i32 = 13;  /* Permission denied */
+        pub const EEXIST: i32 = 17;  /* File exists */
+        pub const ENODEV: i32 = 19;  /* No such device */
+        pub const EINVAL: i32 = 22;  /* Invalid argument */
+        pub const ENOTTY: i32 = 25;  /* Not a typewriter */
+        pub const ENOSPC: i32 = 28;  /* No space left on device */
+        pub const EPIPE: i32 = 32;  /* Broken pipe */
+        pub const ENAMETOOLONG: i32 = 36;  /* File name too long */
+        pub const ENOSYS: i32 = 38;  /* Function not implemented */
+        pub const ELOOP: i32 = 40;  /* Too many symbolic links encountered */
+        pub const EDESTADDRREQ: i32 = 89;  /* Destination address required */
+        pub const EMSGSIZE: i32 = 90;  /* Message too long */
+        pub const EPROTOTYPE: i32 = 91;  /* Protocol wrong type for socket */
+        pub const ENOPROTOOPT: i32 = 92;  /* Protocol not available */
+        pub const EPROTONOSUPPORT: i32 = 93;  /* Protocol not supported */
+        pub const EOPNOTSUPP: i32 = 95;  /* Operation not supported on transport endpoint */
+        pub const EAFNOSUPPORT: i32 = 97;  /* Address family not supported by protocol */
+        pub const EADDRINUSE: i32 = 98;  /* Address already in use */
+        pub const EADDRNOTAVAIL: i32 = 99;  /* Cannot assign requested address */
+        pub const ENETUNREACH: i32 = 101; /* Network is unreachable */
+        pub const ECONNRESET: i32 = 104; /* Connection reset by peer */
+        pub const EISCONN: i32 = 106; /* Transport endpoint is already connected */
+        pub const ENOTCONN: i32 = 107; /* Transport endpoint is not connected */
+        pub const ETIMEDOUT: i32 = 110; /* Connection timed out */
+        pub const ECONNREFUSED: i32 = 111; /* Connection refused */
+        pub const EHOSTUNREACH: i32 = 113; /* No route to host */
+        pub const EALREADY: i32 = 114; /* Operation already in progress */
+        pub const EINPROGRESS: i32 = 115; /* Operation now in progress */
+    }
+}
@@ -1,19 +1,17 @@
 use opapi::file::*;
-use pc_keyboard::{layouts, DecodedKey, Error, HandleControl, KeyState, KeyCode, KeyEvent, Keyboard, ScancodeSet1};
-use spin::Mutex;
-use alloc::vec;
-use core::sync::atomic::{AtomicBool,Ordering};
+use core::sync::atomic::{AtomicBool,AtomicUsize,Ordering};
 use opapi::sys::termios::*;
+use opapi::sys::signalfd::*;
 use crate::RUNLEVEL;
 
-pub static ALT: AtomicBool = AtomicBool::new(false);
-pub static CTRL: AtomicBool = AtomicBool::new(false);
-pub static SHIFT: AtomicBool = AtomicBool::new(false);
+const SIGINT: u8 = 2; // What the kernel sends us when CTRL+ALT+DEL is pressed
+
 pub static SESSION_STARTED: AtomicBool = AtomicBool::new(false);
-pub static PTY_READY: AtomicBool = AtomicBool::new(false);
+pub static NEXT_TTY: AtomicUsize = AtomicUsize::new(1);
+pub const TTY_COUNT: usize = 6;
 
 pub(crate) fn SetupConsole() -> bool {
-    let con = opapi::syscall::open("/dev/liminecon",O_RDWR | O_CLOEXEC);
+    let con = opapi::syscall::open("/dev/tty1",O_RDWR | O_CLOEXEC);
     if con.is_negative() {
         return false;
     }
@@ -31,93 +29,45 @@ pub(crate) fn SetupConsole() -> bool {
     return true;
 }
 
-
-
-pub enum KeyboardLayout {
-    En_US_104QWERTY(Keyboard<layouts::Us104Key, ScancodeSet1>),
-    En_GB_105QWERTY(Keyboard<layouts::Uk105Key, ScancodeSet1>),
-    Generic_104DVORAK(Keyboard<layouts::Dvorak104Key, ScancodeSet1>),
-}
-
-impl KeyboardLayout {
-    fn PushByte(&mut self, scancode: u8) -> Result<Option<KeyEvent>, Error> {
-        match self {
-            KeyboardLayout::En_US_104QWERTY(keyboard) => keyboard.add_byte(scancode),
-            KeyboardLayout::En_GB_105QWERTY(keyboard) => keyboard.add_byte(scancode),
-            KeyboardLayout::Generic_104DVORAK(keyboard) => keyboard.add_byte(scancode),
-        }
+// Opens the next virtual terminal as stdin, stdout and stderr
+pub(crate) fn OpenTTY() -> usize {
+    let index = NEXT_TTY.fetch_add(1,Ordering::SeqCst);
+    let path = alloc::format!("/dev/tty{}", index);
+    let tty = opapi::syscall::open(path.as_str(),O_RDWR);
+    if tty < 0 {
+        panic!("Failed to open {}, Reason: {}", path, tty);
     }
-
-    fn HandleKeyEvent(&mut self, key_event: KeyEvent) -> Option<DecodedKey> {
-        match self {
-            KeyboardLayout::En_US_104QWERTY(keyboard) => keyboard.process_keyevent(key_event),
-            KeyboardLayout::En_GB_105QWERTY(keyboard) => keyboard.process_keyevent(key_event),
-            KeyboardLayout::Generic_104DVORAK(keyboard) => keyboard.process_keyevent(key_event),
-        }
+    if (tty != 0 && opapi::syscall::dup2(tty,0).is_negative()) || opapi::syscall::dup2(tty,1).is_negative() || opapi::syscall::dup2(tty,2).is_negative() {
+        panic!("Failed to open {}, Reason: dup2 failed", path);
     }
+    index
+}
 
-    fn From(name: &str) -> Option<Self> {
-        match name {
-            "en_US_104qwerty" => Some(KeyboardLayout::En_US_104QWERTY(Keyboard::new(layouts::Us104Key, ScancodeSet1, HandleControl::MapLettersToUnicode))),
-            "en_GB_105qwerty" => Some(KeyboardLayout::En_GB_105QWERTY(Keyboard::new(layouts::Uk105Key, ScancodeSet1, HandleControl::MapLettersToUnicode))),
-            "generic_104dvorak" => Some(KeyboardLayout::Generic_104DVORAK(Keyboard::new(layouts::Dvorak104Key, ScancodeSet1, HandleControl::MapLettersToUnicode))),
-            _ => None,
-        }
+// Opens the first serial port as stdin, stdout and stderr, returns false on machines that don't have one
+pub(crate) fn OpenSerial() -> bool {
+    let tty = opapi::syscall::open("/dev/ttyS0",O_RDWR);
+    if tty < 0 {
+        return false;
+    }
+    if (tty != 0 && opapi::syscall::dup2(tty,0).is_negative()) || opapi::syscall::dup2(tty,1).is_negative() || opapi::syscall::dup2(tty,2).is_negative() {
+        panic!("Failed to open /dev/ttyS0, Reason: dup2 failed");
     }
+    true
 }
 
-pub static KEYBOARD: Mutex<Option<KeyboardLayout>> = Mutex::new(None);
-
+// The keyboard and terminals are handled by the kernel now, so all that's left for us is CTRL+ALT+DEL
 pub fn Loop() -> ! {
-    {
-        *KEYBOARD.lock() = Some(KeyboardLayout::From("en_US_104qwerty").unwrap());
+    let sigfd = opapi::syscall::signalfd(-1,SigMask(&[SIGINT]),SFD_CLOEXEC);
+    if sigfd < 0 {
+        panic!("Failed to watch for CTRL+ALT+DEL, Reason: {}", sigfd);
     }
-    if let Some(ref mut keyboard) = *KEYBOARD.lock() {
-        let kbd = File::Open("/dev/kbd",O_RDWR | O_CLOEXEC).expect("NO KEYBOARD CHARACTER STREAM?");
-        let pt_server = File::Open("/dev/ptmx",O_RDWR | O_CLOEXEC).expect("NO PTMX?");
-        PTY_READY.store(true, Ordering::Relaxed);
-        let mut buf = vec![0u8; 32];
-        loop {
-            let has_started = SESSION_STARTED.load(Ordering::Relaxed);
-            if kbd.Read(&mut buf[0..=0]).ok().unwrap() > 0 {
-                if let Ok(Some(key_event)) = keyboard.PushByte(buf[0]) {
-                    match key_event.code {
-                        KeyCode::AltLeft | KeyCode::AltRight => ALT.store(key_event.state == KeyState::Down, Ordering::Relaxed),
-                        KeyCode::ShiftLeft | KeyCode::ShiftRight => SHIFT.store(key_event.state == KeyState::Down, Ordering::Relaxed),
-                        KeyCode::ControlLeft | KeyCode::ControlRight => CTRL.store(key_event.state == KeyState::Down, Ordering::Relaxed),
-                        _ => {}
-                    }
-                    let is_alt = ALT.load(Ordering::Relaxed);
-                    let is_ctrl = CTRL.load(Ordering::Relaxed);
-                    let is_shift = SHIFT.load(Ordering::Relaxed);
-                    if let Some(key) = keyboard.HandleKeyEvent(key_event) {
-                        match key {
-                            DecodedKey::Unicode('\u{7f}') if is_alt && is_ctrl => {
-                                if !SESSION_STARTED.load(Ordering::SeqCst) {
-                                    SESSION_STARTED.store(true, Ordering::SeqCst);
-                                } else {
-                                    opapi::syscall::foxkernel_powerctl(926892958);
-                                }
-                            },
-                            DecodedKey::Unicode(c) => {
-                                if has_started {
-                                    buf[0] = c as u8;
-                                    drop(pt_server.Write(&mut buf[0..=0]));
-                                }
-                            },
-                            _ => {},
-                        }
-                    }
-                }
-                buf[0] = 0;
-            }
-            if let Ok(val) = pt_server.Read(buf.as_mut_slice()) {
-                if val > 0 {
-                    opapi::syscall::write(1,&buf[0..val]);
-                }
+    loop {
+        if let Ok(SIGINT) = opapi::syscall::signalfd_read(sigfd) {
+            if !SESSION_STARTED.load(Ordering::SeqCst) {
+                SESSION_STARTED.store(true, Ordering::SeqCst);
+            } else {
+                opapi::syscall::foxkernel_powerctl(926892958);
             }
-            opapi::syscall::sched_yield();
         }
     }
-    panic!("NO KEYBOARD?");
-}
\ No newline at end of file
+}
@@ -9,7 +9,6 @@ extern crate alloc;
 pub mod Console;
 
 use core::sync::atomic::Ordering;
-use opapi::file::*;
 use alloc::vec;
 use alloc::vec::Vec;
 use spin::Once;
@@ -34,7 +33,10 @@ fn main() {
         println!("Press CTRL+ALT+DEL to enter the shell...\n");
         Console::Loop();
     } else if RUNLEVEL.get().unwrap() >= &2 && RUNLEVEL.get().unwrap() <= &5 {
-        opapi::syscall::forkat(LoginThread as usize);
+        for _ in 0..Console::TTY_COUNT {
+            opapi::syscall::forkat(LoginThread as usize);
+        }
+        opapi::syscall::forkat(SerialLoginThread as usize);
         if !Console::SetupConsole() {
             panic!("Failed too early!");
         }
@@ -43,14 +45,7 @@ fn main() {
 }
 
 fn SingleUserThread() {
-    while !Console::PTY_READY.load(Ordering::Relaxed) {core::hint::spin_loop();}
-    let pts = opapi::syscall::open("/dev/pts/0",O_RDWR);
-    if pts < 0 {
-        panic!("Failed to open Pseudo-Teletype #0, Reason: {}", pts);
-    }
-    if opapi::syscall::dup2(pts,1).is_negative() || opapi::syscall::dup2(pts,2).is_negative() {
-        panic!("Failed to open Pseudo-Teletype #0, Reason: dup2 failed");
-    }
+    Console::OpenTTY();
     while !Console::SESSION_STARTED.load(Ordering::Relaxed) {opapi::syscall::sched_yield();}
     let result = opapi::process::exec("/bin/osh");
     if result != 0 {
@@ -60,14 +55,7 @@ fn SingleUserThread() {
 }
 
 fn LoginThread() {
-    while !Console::PTY_READY.load(Ordering::Relaxed) {core::hint::spin_loop();}
-    let pts = opapi::syscall::open("/dev/pts/0",O_RDWR);
-    if pts < 0 {
-        panic!("Failed to open Pseudo-Teletype #0, Reason: {}", pts);
-    }
-    if opapi::syscall::dup2(pts,1).is_negative() || opapi::syscall::dup2(pts,2).is_negative() {
-        panic!("Failed to open Pseudo-Teletype #0, Reason: dup2 failed");
-    }
+    Console::OpenTTY();
     print!("\x1b[?25lPress CTRL+ALT+DEL to startup UNIX Sessions.....[ ]\x08\x08");
     let mut counter = 0;
     while !Console::SESSION_STARTED.load(Ordering::Relaxed) {
@@ -81,4 +69,16 @@ fn LoginThread() {
         panic!("Failed to start /bin/login, Reason: {}", result);
     }
     panic!("You shouldn't be seeing this");
-}
\ No newline at end of file
+}
+
+// Nobody can press CTRL+ALT+DEL over a serial line, so this one goes straight to the login prompt
+fn SerialLoginThread() {
+    if !Console::OpenSerial() {
+        opapi::syscall::exit(0);
+    }
+    let result = opapi::process::exec("/bin/login");
+    if result != 0 {
+        panic!("Failed to start /bin/login, Reason: {}", result);
+    }
+    panic!("You shouldn't be seeing this");
+}
@@ -14,12 +14,21 @@ use alloc::vec;
 fn main() {
     opapi::syscall::setpgid(opapi::syscall::getpid(),opapi::syscall::getpid());
     loop {
-        println!("owlOS Nightly /dev/pts/0");
+        match opapi::sys::termios::ttyname(0) {
+            Ok(name) => println!("owlOS Nightly /dev/{}", name),
+            Err(_) => println!("owlOS Nightly"),
+        }
         loop {
             print!("owlOS login: ");
             let username = opapi::io::stdin().ReadLine().expect("Something went wrong!");
             print!("Password: ");
+            let termios = opapi::sys::termios::tcgetattr(0).expect("Something went wrong!");
+            let mut noecho = termios;
+            noecho.c_lflag &= !opapi::sys::termios::ECHO;
+            opapi::sys::termios::tcsetattr(0,&noecho);
             let password = opapi::io::stdin().ReadLine().expect("Something went wrong!");
+            opapi::sys::termios::tcsetattr(0,&termios);
+            println!();
             if username == "root" { // This is temporary
                 break;
             }
@@ -42,6 +42,10 @@ fn main() -> u8 {
 }
 
 fn RunProgram() {
+    // ^C and friends go to whatever's running, and never back to us
+    let pid = opapi::syscall::getpid();
+    opapi::syscall::setpgid(pid,pid);
+    opapi::sys::termios::tcsetpgrp(0,pid);
     let cmd = CMD.lock().clone();
     if cmd[0].as_bytes()[0] == '/' as u8 || cmd[0].as_bytes()[0] == '.' as u8 {
         opapi::process::exec(cmd[0].as_str());
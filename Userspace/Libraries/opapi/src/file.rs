@@ -21,6 +21,9 @@ pub const O_SYNC: usize      = 0x2000;
 pub const O_CLOEXEC: usize   = 0x4000;
 pub const O_PATH: usize      = 0x8000;
 
+pub const S_IFIFO: usize = 0o0010000;
+pub const S_IFREG: usize = 0o0100000;
+
 pub const SEEK_CUR: usize = 1;
 pub const SEEK_END: usize = 2;
 pub const SEEK_SET: usize = 3;
@@ -39,6 +42,9 @@ impl File {
             return Err(result);
         }
     }
+    pub fn Descriptor(&self) -> isize {
+        self.0
+    }
     pub fn Read(&self, buf: &mut [u8]) -> Result<usize,isize> {
         let result = crate::syscall::read(self.0,buf);
         if result < 0 {
@@ -46,32 +52,19 @@ impl File {
         }
         Ok(result as usize)
     }
+    // Line editing and echo are up to the terminal, so this only collects bytes up to the newline
     pub fn ReadLine(&self) -> Result<String,isize> {
-        if self.0 == 0 {
-            let mut buf = vec![0u8; 256];
-            let mut key_stroke = vec![0u8; 1];
-            let mut position = 0;
-            loop {
-                let result = crate::syscall::read(self.0,key_stroke.as_mut_slice());
-                if result < 0 {
-                    return Err(result);
-                }
-                if key_stroke[0] == '\n' as u8 {
-                    crate::syscall::write(self.0,b"\n");
-                    return Ok(String::from_utf8_lossy(&buf[0..position]).to_string());
-                } else if key_stroke[0] == '\x08' as u8 {
-                    if position > 0 {
-                        crate::syscall::write(self.0,b"\x08 \x08");
-                        position -= 1;
-                    }
-                } else {
-                    crate::syscall::write(self.0,key_stroke.as_slice());
-                    buf[position] = key_stroke[0];
-                    position += 1;
-                }
+        let mut buf = vec![];
+        let mut byte = [0u8; 1];
+        loop {
+            let result = crate::syscall::read(self.0,&mut byte);
+            if result < 0 {
+                return Err(result);
             }
-        } else {
-            return Err(crate::errors::ENOSYS);
+            if result == 0 || byte[0] == b'\n' {
+                return Ok(String::from_utf8_lossy(&buf).to_string());
+            }
+            buf.push(byte[0]);
         }
     }
     pub fn ReadToString(&self) -> Result<String,isize> {
@@ -0,0 +1,5 @@
+use crate::file::{O_CLOEXEC,O_NONBLOCK};
+
+pub const EFD_SEMAPHORE: usize = 1;
+pub const EFD_NONBLOCK: usize = O_NONBLOCK;
+pub const EFD_CLOEXEC: usize = O_CLOEXEC;
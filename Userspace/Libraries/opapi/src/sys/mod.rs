@@ -1 +1,8 @@
-pub mod termios;
\ No newline at end of file
+pub mod termios;
+pub mod socket;
+pub mod poll;
+pub mod epoll;
+pub mod eventfd;
+pub mod timerfd;
+pub mod signalfd;
+pub mod vt;
\ No newline at end of file
@@ -0,0 +1,49 @@
+pub const POLLIN: i16 = 0x01;
+pub const POLLOUT: i16 = 0x02;
+pub const POLLPRI: i16 = 0x04;
+pub const POLLHUP: i16 = 0x08;
+pub const POLLERR: i16 = 0x10;
+pub const POLLNVAL: i16 = 0x40;
+
+pub const FD_SETSIZE: usize = 1024;
+
+#[repr(C)]
+#[derive(Clone,Copy)]
+pub struct PollFd {
+    pub fd: i32,
+    pub events: i16,
+    pub revents: i16,
+}
+
+impl PollFd {
+    pub fn new(fd: isize, events: i16) -> Self {
+        Self {
+            fd: fd as i32,
+            events,
+            revents: 0,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Clone,Copy)]
+pub struct FdSet {
+    pub bits: [u8; FD_SETSIZE/8],
+}
+
+impl FdSet {
+    pub fn new() -> Self {
+        Self {
+            bits: [0; FD_SETSIZE/8],
+        }
+    }
+    pub fn Set(&mut self, fd: isize) {
+        self.bits[fd as usize/8] |= 1 << (fd as usize%8);
+    }
+    pub fn Clear(&mut self, fd: isize) {
+        self.bits[fd as usize/8] &= !(1 << (fd as usize%8));
+    }
+    pub fn IsSet(&self, fd: isize) -> bool {
+        self.bits[fd as usize/8] & (1 << (fd as usize%8)) != 0
+    }
+}
@@ -0,0 +1,16 @@
+use crate::file::{O_CLOEXEC,O_NONBLOCK};
+
+pub const SFD_NONBLOCK: usize = O_NONBLOCK;
+pub const SFD_CLOEXEC: usize = O_CLOEXEC;
+
+// Only the signal number is filled in, everything else in struct signalfd_siginfo is left as zero
+#[repr(C)]
+#[derive(Clone,Copy)]
+pub struct SignalFdSigInfo {
+    pub signo: u32,
+    pub reserved: [u8; 124],
+}
+
+pub fn SigMask(signals: &[u8]) -> u64 {
+    signals.iter().fold(0,|mask, sig| mask | (1 << (sig-1)))
+}
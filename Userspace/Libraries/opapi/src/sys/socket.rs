@@ -0,0 +1,93 @@
+pub const AF_INET: usize = 1;
+pub const AF_UNIX: usize = 4;
+
+pub const SOCK_DGRAM: usize = 1;
+pub const SOCK_STREAM: usize = 4;
+pub const SOCK_NONBLOCK: usize = 0x10000;
+pub const SOCK_CLOEXEC: usize = 0x20000;
+
+pub const IPPROTO_TCP: usize = 6;
+pub const IPPROTO_UDP: usize = 17;
+
+pub const SOL_SOCKET: usize = 1;
+pub const SO_BROADCAST: usize = 2;
+pub const SO_ERROR: usize = 5;
+pub const SO_KEEPALIVE: usize = 6;
+pub const SO_RCVBUF: usize = 9;
+pub const SO_REUSEADDR: usize = 12;
+pub const SO_SNDBUF: usize = 13;
+pub const SO_TYPE: usize = 16;
+pub const SO_PEERCRED: usize = 18;
+pub const SO_PASSCRED: usize = 20;
+pub const TCP_NODELAY: usize = 1;
+
+pub const SHUT_RD: usize = 1;
+pub const SHUT_RDWR: usize = 2;
+pub const SHUT_WR: usize = 3;
+
+pub const SCM_RIGHTS: usize = 1;
+
+pub const MSG_CTRUNC: usize = 0x1;
+pub const MSG_PEEK: usize = 0x20;
+pub const MSG_TRUNC: usize = 0x40;
+
+pub const INADDR_ANY: u32 = 0;
+pub const INADDR_LOOPBACK: u32 = 0x7F000001;
+
+#[repr(C)]
+#[derive(Clone,Copy)]
+pub struct SockAddrIn {
+    pub family: u16,
+    pub port: u16, // Network byte order
+    pub addr: u32, // Network byte order
+    pub zero: [u8; 8],
+}
+
+impl SockAddrIn {
+    pub fn new(addr: u32, port: u16) -> Self {
+        Self {
+            family: AF_INET as u16,
+            port: port.to_be(),
+            addr: addr.to_be(),
+            zero: [0; 8],
+        }
+    }
+    pub fn Address(&self) -> u32 {
+        u32::from_be(self.addr)
+    }
+    pub fn Port(&self) -> u16 {
+        u16::from_be(self.port)
+    }
+}
+
+#[repr(C)]
+#[derive(Clone,Copy)]
+pub struct SockAddrUn {
+    pub family: u16,
+    pub path: [u8; 108],
+}
+
+impl SockAddrUn {
+    // Paths that don't fit (with their terminator) are cut short
+    pub fn new(path: &str) -> Self {
+        let mut buf = [0; 108];
+        let len = core::cmp::min(path.len(),buf.len()-1);
+        buf[..len].copy_from_slice(&path.as_bytes()[..len]);
+        Self {
+            family: AF_UNIX as u16,
+            path: buf,
+        }
+    }
+    pub fn Path(&self) -> &str {
+        let len = self.path.iter().position(|b| *b == 0).unwrap_or(self.path.len());
+        core::str::from_utf8(&self.path[..len]).unwrap_or("")
+    }
+}
+
+#[repr(C)]
+#[derive(Clone,Copy)]
+pub struct UCred {
+    pub pid: i32,
+    pub uid: u32,
+    pub gid: u32,
+}
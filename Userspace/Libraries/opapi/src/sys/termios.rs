@@ -34,4 +34,148 @@ pub const TIOCMBIS: usize = 0x4011;
 pub const TIOCMBIC: usize = 0x4012;
 pub const TIOCMSET: usize = 0x4013;
 pub const TIOCGSOFTCAR: usize = 0x4014;
-pub const TIOCSSOFTCAR: usize = 0x4015;
\ No newline at end of file
+pub const TIOCSSOFTCAR: usize = 0x4015;
+pub const TIOCGPTN: usize = 0x4016;
+pub const TIOCSPTLCK: usize = 0x4017;
+pub const TIOCGPTLCK: usize = 0x4018;
+pub const TIOCGRANTPT: usize = 0x4019;
+
+pub const TIOCGNAME: usize = 0x4F01;
+
+pub const SIGWINCH: u8 = 0x1c; // Sent to the foreground group when TIOCSWINSZ changes the size
+
+pub const NCCS: usize = 19;
+
+#[repr(C)]
+#[derive(Clone,Copy)]
+pub struct Termios {
+    pub c_iflag: u32,
+    pub c_oflag: u32,
+    pub c_cflag: u32,
+    pub c_lflag: u32,
+    pub c_line: u8,
+    pub c_cc: [u8; NCCS],
+}
+
+pub const IGNBRK: u32 = 0o000001;
+pub const BRKINT: u32 = 0o000002;
+pub const IGNPAR: u32 = 0o000004;
+pub const PARMRK: u32 = 0o000010;
+pub const INPCK: u32 = 0o000020;
+pub const ISTRIP: u32 = 0o000040;
+pub const INLCR: u32 = 0o000100;
+pub const IGNCR: u32 = 0o000200;
+pub const ICRNL: u32 = 0o000400;
+pub const IXON: u32 = 0o002000;
+pub const IXOFF: u32 = 0o010000;
+pub const IUTF8: u32 = 0o040000;
+
+pub const OPOST: u32 = 0o000001;
+pub const ONLCR: u32 = 0o000004;
+pub const OCRNL: u32 = 0o000010;
+pub const ONOCR: u32 = 0o000020;
+pub const ONLRET: u32 = 0o000040;
+
+pub const CBAUD: u32 = 0o010017;
+pub const B0: u32 = 0o000000;
+pub const B300: u32 = 0o000007;
+pub const B1200: u32 = 0o000011;
+pub const B2400: u32 = 0o000013;
+pub const B4800: u32 = 0o000014;
+pub const B9600: u32 = 0o000015;
+pub const B19200: u32 = 0o000016;
+pub const B38400: u32 = 0o000017;
+pub const B57600: u32 = 0o010001;
+pub const B115200: u32 = 0o010002;
+pub const CSIZE: u32 = 0o000060;
+pub const CS5: u32 = 0o000000;
+pub const CS6: u32 = 0o000020;
+pub const CS7: u32 = 0o000040;
+pub const CS8: u32 = 0o000060;
+pub const CSTOPB: u32 = 0o000100;
+pub const CREAD: u32 = 0o000200;
+pub const PARENB: u32 = 0o000400;
+pub const PARODD: u32 = 0o001000;
+pub const HUPCL: u32 = 0o002000;
+pub const CLOCAL: u32 = 0o004000;
+
+pub const ISIG: u32 = 0o000001;
+pub const ICANON: u32 = 0o000002;
+pub const ECHO: u32 = 0o000010;
+pub const ECHOE: u32 = 0o000020;
+pub const ECHOK: u32 = 0o000040;
+pub const ECHONL: u32 = 0o000100;
+pub const NOFLSH: u32 = 0o000200;
+pub const TOSTOP: u32 = 0o000400;
+pub const ECHOCTL: u32 = 0o001000;
+pub const ECHOKE: u32 = 0o004000;
+pub const IEXTEN: u32 = 0o100000;
+
+pub const VINTR: usize = 0;
+pub const VQUIT: usize = 1;
+pub const VERASE: usize = 2;
+pub const VKILL: usize = 3;
+pub const VEOF: usize = 4;
+pub const VTIME: usize = 5;
+pub const VMIN: usize = 6;
+pub const VSTART: usize = 8;
+pub const VSTOP: usize = 9;
+pub const VSUSP: usize = 10;
+pub const VEOL: usize = 11;
+pub const VREPRINT: usize = 12;
+pub const VDISCARD: usize = 13;
+pub const VWERASE: usize = 14;
+pub const VLNEXT: usize = 15;
+pub const VEOL2: usize = 16;
+
+pub const TCIFLUSH: usize = 0;
+pub const TCOFLUSH: usize = 1;
+pub const TCIOFLUSH: usize = 2;
+
+pub fn tcgetattr(fd: isize) -> Result<Termios,isize> {
+    let mut termios = core::mem::MaybeUninit::<Termios>::uninit();
+    let ret = crate::syscall::ioctl(fd,TCGETS,termios.as_mut_ptr() as usize);
+    if ret < 0 {
+        return Err(ret);
+    }
+    Ok(unsafe {termios.assume_init()})
+}
+
+pub fn tcsetattr(fd: isize, termios: &Termios) -> isize {
+    crate::syscall::ioctl(fd,TCSETS,termios as *const Termios as usize)
+}
+
+// Makes a group the one that gets the signals typed at the terminal
+pub fn tcsetpgrp(fd: isize, pgrp: i32) -> isize {
+    crate::syscall::ioctl(fd,TIOCSPGRP,&pgrp as *const i32 as usize)
+}
+
+// Which /dev/pts/N goes with a /dev/ptmx file descriptor
+pub fn ptsname(fd: isize) -> Result<alloc::string::String,isize> {
+    let mut index: u32 = 0;
+    let ret = crate::syscall::ioctl(fd,TIOCGPTN,&mut index as *mut u32 as usize);
+    if ret < 0 {
+        return Err(ret);
+    }
+    Ok(alloc::format!("/dev/pts/{}", index))
+}
+
+pub fn unlockpt(fd: isize) -> isize {
+    let lock: i32 = 0;
+    crate::syscall::ioctl(fd,TIOCSPTLCK,&lock as *const i32 as usize)
+}
+
+pub fn grantpt(fd: isize) -> isize {
+    crate::syscall::ioctl(fd,TIOCGRANTPT,0)
+}
+
+// The terminal's name under /dev, like "tty1" or "pts/0"
+pub fn ttyname(fd: isize) -> Result<alloc::string::String,isize> {
+    let mut buf = [0u8; 32];
+    let ret = crate::syscall::ioctl(fd,TIOCGNAME,buf.as_mut_ptr() as usize);
+    if ret < 0 {
+        return Err(ret);
+    }
+    let len = buf.iter().position(|&c| c == 0).unwrap_or(buf.len());
+    Ok(alloc::string::String::from_utf8_lossy(&buf[..len]).into_owned())
+}
\ No newline at end of file
@@ -0,0 +1,22 @@
+use crate::file::{O_CLOEXEC,O_NONBLOCK};
+
+pub const CLOCK_REALTIME: usize = 0;
+pub const CLOCK_MONOTONIC: usize = 1;
+
+pub const TFD_TIMER_ABSTIME: usize = 1;
+pub const TFD_NONBLOCK: usize = O_NONBLOCK;
+pub const TFD_CLOEXEC: usize = O_CLOEXEC;
+
+#[repr(C)]
+#[derive(Clone,Copy,Default)]
+pub struct TimeSpec {
+    pub sec: i64,
+    pub nsec: i64,
+}
+
+#[repr(C)]
+#[derive(Clone,Copy,Default)]
+pub struct ITimerSpec {
+    pub interval: TimeSpec,
+    pub value: TimeSpec,
+}
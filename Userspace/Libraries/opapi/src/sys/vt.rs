@@ -0,0 +1,28 @@
+pub const KDSETMODE: usize = 0x4B3A;
+pub const KDGETMODE: usize = 0x4B3B;
+pub const KD_TEXT: usize = 0;
+pub const KD_GRAPHICS: usize = 1;
+
+pub const KDGKBMAP: usize = 0x4B80;
+pub const KDSKBMAP: usize = 0x4B81;
+pub const KDSKBLAYOUT: usize = 0x4B82; // Takes a NUL-terminated name, like "en_GB_105qwerty" or "generic_104dvorak"
+
+pub const VT_GETSTATE: usize = 0x5603;
+pub const VT_ACTIVATE: usize = 0x5606;
+
+// Unicode for each keycode, on its own, with shift held, and with AltGr held
+#[repr(C)]
+#[derive(Clone,Copy)]
+pub struct KeyMap {
+    pub plain: [u32; 128],
+    pub shift: [u32; 128],
+    pub altgr: [u32; 128],
+}
+
+#[repr(C)]
+#[derive(Clone,Copy,Default)]
+pub struct VTState {
+    pub v_active: u16,
+    pub v_signal: u16,
+    pub v_state: u16,
+}
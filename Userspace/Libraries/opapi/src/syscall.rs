@@ -1,6 +1,11 @@
 use crate::arch::Syscall;
 use cstr_core::{CString};
 use crate::Stat;
+use crate::sys::socket::{SockAddrIn,SockAddrUn,UCred};
+use crate::sys::poll::{PollFd,FdSet};
+use crate::sys::epoll::EpollEvent;
+use crate::sys::timerfd::ITimerSpec;
+use crate::sys::signalfd::SignalFdSigInfo;
 use alloc::string::String;
 use alloc::vec;
 
@@ -24,7 +29,13 @@ pub fn forkat(addr: usize) -> i32 {
 pub fn open(path: &str, mode: usize) -> isize {
     let cpath = CString::new(path).expect("owlOS Programmer API: String conversion failed");
     let ptr = cpath.into_raw();
-    let ret = Syscall(0x03,ptr as usize,mode,0);
+    let ret = loop {
+        let status = Syscall(0x03,ptr as usize,mode,0);
+        if status != -11 { // Opening a FIFO waits for the other end
+            break status;
+        }
+        Syscall(0,0,0,0); // Yield
+    };
     let _ = unsafe {CString::from_raw(ptr)}; // This prevents memory leaking from occuring.
     ret
 }
@@ -280,4 +291,381 @@ pub fn mmap(addr: usize, size: usize, prot: usize, flags: usize, fd: isize, offs
 
 pub fn foxkernel_powerctl(cmd: usize) -> isize {
     Syscall(0xf0,cmd,0,0)
-}
\ No newline at end of file
+}
+#[repr(C)]
+pub(crate) struct SockMsgStruct {
+    fd: usize,
+    buf: usize,
+    len: usize,
+    flags: usize,
+    addr: usize,
+    addrlen: usize,
+}
+
+#[repr(C)]
+pub(crate) struct SockOptStruct {
+    fd: usize,
+    level: usize,
+    name: usize,
+    value: usize,
+    len: usize,
+}
+
+pub fn socket(domain: usize, kind: usize, protocol: usize) -> isize {
+    Syscall(0x2a,domain,kind,protocol)
+}
+
+pub fn bind(fd: isize, addr: &SockAddrIn) -> isize {
+    Syscall(0x2b,fd as usize,addr as *const _ as usize,core::mem::size_of::<SockAddrIn>())
+}
+
+pub fn listen(fd: isize, backlog: usize) -> isize {
+    Syscall(0x2c,fd as usize,backlog,0)
+}
+
+pub fn accept(fd: isize, addr: Option<&mut SockAddrIn>) -> isize {
+    let mut len = core::mem::size_of::<SockAddrIn>() as u32;
+    let ptr = match addr {
+        Some(a) => a as *mut _ as usize,
+        None => 0,
+    };
+    Syscall(0x2d,fd as usize,ptr,if ptr != 0 {&mut len as *mut _ as usize} else {0})
+}
+
+pub fn connect(fd: isize, addr: &SockAddrIn) -> isize {
+    Syscall(0x2e,fd as usize,addr as *const _ as usize,core::mem::size_of::<SockAddrIn>())
+}
+
+pub fn sendto(fd: isize, buf: &[u8], flags: usize, addr: Option<&SockAddrIn>) -> isize {
+    let args = SockMsgStruct {
+        fd: fd as usize,
+        buf: buf.as_ptr() as usize,
+        len: buf.len(),
+        flags,
+        addr: addr.map_or(0,|a| a as *const _ as usize),
+        addrlen: if addr.is_some() {core::mem::size_of::<SockAddrIn>()} else {0},
+    };
+    Syscall(0x2f,&args as *const _ as usize,0,0)
+}
+
+pub fn send(fd: isize, buf: &[u8], flags: usize) -> isize {
+    sendto(fd,buf,flags,None)
+}
+
+pub fn recvfrom(fd: isize, buf: &mut [u8], flags: usize, addr: Option<&mut SockAddrIn>) -> isize {
+    let mut len = core::mem::size_of::<SockAddrIn>() as u32;
+    let ptr = match addr {
+        Some(a) => a as *mut _ as usize,
+        None => 0,
+    };
+    let args = SockMsgStruct {
+        fd: fd as usize,
+        buf: buf.as_mut_ptr() as usize,
+        len: buf.len(),
+        flags,
+        addr: ptr,
+        addrlen: if ptr != 0 {&mut len as *mut _ as usize} else {0},
+    };
+    Syscall(0x30,&args as *const _ as usize,0,0)
+}
+
+pub fn recv(fd: isize, buf: &mut [u8], flags: usize) -> isize {
+    recvfrom(fd,buf,flags,None)
+}
+
+pub fn setsockopt(fd: isize, level: usize, name: usize, value: i32) -> isize {
+    let args = SockOptStruct {
+        fd: fd as usize,
+        level,
+        name,
+        value: &value as *const _ as usize,
+        len: core::mem::size_of::<i32>(),
+    };
+    Syscall(0x31,&args as *const _ as usize,0,0)
+}
+
+pub fn getsockopt(fd: isize, level: usize, name: usize) -> Result<i32,isize> {
+    let mut value = 0i32;
+    let mut len = core::mem::size_of::<i32>() as u32;
+    let args = SockOptStruct {
+        fd: fd as usize,
+        level,
+        name,
+        value: &mut value as *mut _ as usize,
+        len: &mut len as *mut _ as usize,
+    };
+    let result = Syscall(0x32,&args as *const _ as usize,0,0);
+    if result < 0 {
+        return Err(result);
+    }
+    Ok(value)
+}
+
+pub fn shutdown(fd: isize, how: usize) -> isize {
+    Syscall(0x33,fd as usize,how,0)
+}
+
+pub fn getsockname(fd: isize, addr: &mut SockAddrIn) -> isize {
+    let mut len = core::mem::size_of::<SockAddrIn>() as u32;
+    Syscall(0x34,fd as usize,addr as *mut _ as usize,&mut len as *mut _ as usize)
+}
+
+pub fn getpeername(fd: isize, addr: &mut SockAddrIn) -> isize {
+    let mut len = core::mem::size_of::<SockAddrIn>() as u32;
+    Syscall(0x35,fd as usize,addr as *mut _ as usize,&mut len as *mut _ as usize)
+}
+
+pub fn bind_unix(fd: isize, addr: &SockAddrUn) -> isize {
+    Syscall(0x2b,fd as usize,addr as *const _ as usize,core::mem::size_of::<SockAddrUn>())
+}
+
+pub fn connect_unix(fd: isize, addr: &SockAddrUn) -> isize {
+    Syscall(0x2e,fd as usize,addr as *const _ as usize,core::mem::size_of::<SockAddrUn>())
+}
+
+pub fn socketpair(domain: usize, kind: usize) -> Result<(isize,isize),isize> {
+    let mut array = [0i32; 2];
+    let result = Syscall(0x36,domain,kind,array.as_mut_ptr() as usize);
+    if result == 0 {
+        return Ok((array[0] as isize,array[1] as isize));
+    }
+    Err(result)
+}
+
+pub fn getpeercred(fd: isize) -> Result<UCred,isize> {
+    let mut value = UCred {pid: 0, uid: 0, gid: 0};
+    let mut len = core::mem::size_of::<UCred>() as u32;
+    let args = SockOptStruct {
+        fd: fd as usize,
+        level: crate::sys::socket::SOL_SOCKET,
+        name: crate::sys::socket::SO_PEERCRED,
+        value: &mut value as *mut _ as usize,
+        len: &mut len as *mut _ as usize,
+    };
+    let result = Syscall(0x32,&args as *const _ as usize,0,0);
+    if result < 0 {
+        return Err(result);
+    }
+    Ok(value)
+}
+
+#[repr(C)]
+pub(crate) struct MsgHdr {
+    name: usize,
+    namelen: u32,
+    iov: usize,
+    iovlen: i32,
+    control: usize,
+    controllen: u32,
+    flags: i32,
+}
+
+#[repr(C)]
+pub(crate) struct IOVec {
+    base: usize,
+    len: usize,
+}
+
+const CMSG_HEADER_SIZE: usize = 16;
+const MAX_PASSED_FDS: usize = 64;
+
+// Sends buf over a UNIX socket along with copies of the descriptors in fds
+pub fn sendmsg(fd: isize, buf: &[u8], fds: &[i32], flags: usize) -> isize {
+    if fds.len() > MAX_PASSED_FDS {
+        return -22; // EINVAL
+    }
+    let mut control = [0u32; (CMSG_HEADER_SIZE / 4) + MAX_PASSED_FDS];
+    control[0] = (CMSG_HEADER_SIZE + fds.len() * 4) as u32;
+    control[1] = crate::sys::socket::SOL_SOCKET as u32;
+    control[2] = crate::sys::socket::SCM_RIGHTS as u32;
+    for (i, f) in fds.iter().enumerate() {
+        control[(CMSG_HEADER_SIZE / 4) + i] = *f as u32;
+    }
+    let mut iov = IOVec {base: buf.as_ptr() as usize, len: buf.len()};
+    let msg = MsgHdr {
+        name: 0,
+        namelen: 0,
+        iov: &mut iov as *mut _ as usize,
+        iovlen: 1,
+        control: control.as_ptr() as usize,
+        controllen: if fds.len() > 0 {control[0]} else {0},
+        flags: 0,
+    };
+    Syscall(0x37,fd as usize,&msg as *const _ as usize,flags)
+}
+
+// Returns how many bytes came in and how many descriptors were stored into fds
+pub fn recvmsg(fd: isize, buf: &mut [u8], fds: &mut [i32], flags: usize) -> Result<(usize,usize),isize> {
+    let room = core::cmp::min(fds.len(),MAX_PASSED_FDS);
+    let mut control = [0u32; (CMSG_HEADER_SIZE / 4) + MAX_PASSED_FDS];
+    let mut iov = IOVec {base: buf.as_mut_ptr() as usize, len: buf.len()};
+    let mut msg = MsgHdr {
+        name: 0,
+        namelen: 0,
+        iov: &mut iov as *mut _ as usize,
+        iovlen: 1,
+        control: control.as_mut_ptr() as usize,
+        controllen: (CMSG_HEADER_SIZE + room * 4) as u32,
+        flags: 0,
+    };
+    let status = Syscall(0x38,fd as usize,&mut msg as *mut _ as usize,flags);
+    if status < 0 {
+        return Err(status);
+    }
+    let count = if msg.controllen as usize >= CMSG_HEADER_SIZE {(control[0] as usize - CMSG_HEADER_SIZE) / 4} else {0};
+    for i in 0..count {
+        fds[i] = control[(CMSG_HEADER_SIZE / 4) + i] as i32;
+    }
+    Ok((status as usize,count))
+}
+
+pub fn mknod(path: &str, mode: usize, dev: usize) -> isize {
+    let cpath = CString::new(path).expect("owlOS Programmer API: String conversion failed");
+    let ptr = cpath.into_raw();
+    let ret = Syscall(0x39,ptr as usize,mode,dev);
+    let _ = unsafe {CString::from_raw(ptr)}; // This prevents memory leaking from occuring.
+    ret
+}
+
+pub fn mkfifo(path: &str, mode: usize) -> isize {
+    mknod(path,crate::file::S_IFIFO | (mode & 0o777),0)
+}
+
+pub fn poll(fds: &mut [PollFd], timeout: i32) -> isize {
+    Syscall(0x3a,fds.as_mut_ptr() as usize,fds.len(),timeout as usize)
+}
+
+pub fn ppoll(fds: &mut [PollFd], timeout: Option<(i64,i64)>) -> isize {
+    let time = timeout.map(|t| [t.0,t.1]);
+    Syscall(0x3b,fds.as_mut_ptr() as usize,fds.len(),time.as_ref().map_or(0,|t| t.as_ptr() as usize))
+}
+
+#[repr(C)]
+struct SelectStruct {
+    nfds: usize,
+    readfds: usize,
+    writefds: usize,
+    exceptfds: usize,
+    timeout: usize,
+}
+
+// The timeout is in seconds and microseconds, like a struct timeval
+pub fn select(nfds: usize, readfds: Option<&mut FdSet>, writefds: Option<&mut FdSet>, exceptfds: Option<&mut FdSet>, timeout: Option<(i64,i64)>) -> isize {
+    let time = timeout.map(|t| [t.0,t.1]);
+    let args = SelectStruct {
+        nfds,
+        readfds: readfds.map_or(0,|s| s as *mut FdSet as usize),
+        writefds: writefds.map_or(0,|s| s as *mut FdSet as usize),
+        exceptfds: exceptfds.map_or(0,|s| s as *mut FdSet as usize),
+        timeout: time.as_ref().map_or(0,|t| t.as_ptr() as usize),
+    };
+    Syscall(0x3c,&args as *const _ as usize,0,0)
+}
+
+pub fn epoll_create(flags: usize) -> isize {
+    Syscall(0x3d,flags,0,0)
+}
+
+#[repr(C)]
+struct EpollCtlStruct {
+    epfd: usize,
+    op: usize,
+    fd: usize,
+    event: usize,
+}
+
+pub fn epoll_ctl(epfd: isize, op: usize, fd: isize, event: Option<&EpollEvent>) -> isize {
+    let args = EpollCtlStruct {
+        epfd: epfd as usize,
+        op,
+        fd: fd as usize,
+        event: event.map_or(0,|e| e as *const EpollEvent as usize),
+    };
+    Syscall(0x3e,&args as *const _ as usize,0,0)
+}
+
+#[repr(C)]
+struct EpollWaitStruct {
+    epfd: usize,
+    events: usize,
+    maxevents: usize,
+    timeout: isize,
+}
+
+pub fn epoll_wait(epfd: isize, events: &mut [EpollEvent], timeout: isize) -> isize {
+    let args = EpollWaitStruct {
+        epfd: epfd as usize,
+        events: events.as_mut_ptr() as usize,
+        maxevents: events.len(),
+        timeout,
+    };
+    Syscall(0x3f,&args as *const _ as usize,0,0)
+}
+
+pub fn eventfd(initval: u32, flags: usize) -> isize {
+    Syscall(0x40,initval as usize,flags,0)
+}
+
+pub fn eventfd_read(fd: isize) -> Result<u64,isize> {
+    let mut value = [0u8; 8];
+    let ret = read(fd,&mut value);
+    if ret < 0 {
+        return Err(ret);
+    }
+    Ok(u64::from_ne_bytes(value))
+}
+
+pub fn eventfd_write(fd: isize, value: u64) -> isize {
+    write(fd,&value.to_ne_bytes())
+}
+
+pub fn timerfd_create(clock: usize, flags: usize) -> isize {
+    Syscall(0x41,clock,flags,0)
+}
+
+#[repr(C)]
+struct TimerSetStruct {
+    fd: usize,
+    flags: usize,
+    new: usize,
+    old: usize,
+}
+
+pub fn timerfd_settime(fd: isize, flags: usize, new: &ITimerSpec) -> Result<ITimerSpec,isize> {
+    let mut old = ITimerSpec::default();
+    let args = TimerSetStruct {
+        fd: fd as usize,
+        flags,
+        new: new as *const ITimerSpec as usize,
+        old: &mut old as *mut ITimerSpec as usize,
+    };
+    let ret = Syscall(0x42,&args as *const _ as usize,0,0);
+    if ret < 0 {
+        return Err(ret);
+    }
+    Ok(old)
+}
+
+pub fn timerfd_gettime(fd: isize) -> Result<ITimerSpec,isize> {
+    let mut current = ITimerSpec::default();
+    let ret = Syscall(0x43,fd as usize,&mut current as *mut ITimerSpec as usize,0);
+    if ret < 0 {
+        return Err(ret);
+    }
+    Ok(current)
+}
+
+// Pass -1 as the fd to make a new signalfd, or an existing one to change which signals it takes
+pub fn signalfd(fd: isize, mask: u64, flags: usize) -> isize {
+    Syscall(0x44,fd as usize,&mask as *const u64 as usize,flags)
+}
+
+pub fn signalfd_read(fd: isize) -> Result<u8,isize> {
+    let mut info = SignalFdSigInfo {signo: 0, reserved: [0; 124]};
+    let buf = unsafe {core::slice::from_raw_parts_mut(&mut info as *mut SignalFdSigInfo as *mut u8,core::mem::size_of::<SignalFdSigInfo>())};
+    let ret = read(fd,buf);
+    if ret < 0 {
+        return Err(ret);
+    }
+    Ok(info.signo as u8)
+}